@@ -0,0 +1,251 @@
+//! A program logging like aya-log does: each call writes a record header and every argument,
+//! tagged with its type, into a per-CPU buffer, then sends the record to user space. The many
+//! inlined argument writers are what makes programs using aya-log slow to link.
+
+#![no_std]
+
+use core::{ffi::c_void, mem, ptr, ptr::addr_of_mut};
+
+const BUF_SIZE: usize = 8192;
+const BPF_F_CURRENT_CPU: u64 = 0xffff_ffff;
+
+static mut BUF: [u8; BUF_SIZE] = [0; BUF_SIZE];
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+enum ArgType {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    Str,
+    Ipv4,
+    Mac,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[inline(always)]
+fn write_arg(buf: &mut [u8], tag: ArgType, value: &[u8]) -> Option<usize> {
+    let len = 3 + value.len();
+    let buf = buf.get_mut(..len)?;
+    unsafe {
+        let dst = buf.as_mut_ptr();
+        dst.write(tag as u8);
+        dst.add(1)
+            .cast::<[u8; 2]>()
+            .write_unaligned((value.len() as u16).to_ne_bytes());
+        ptr::copy_nonoverlapping(value.as_ptr(), dst.add(3), value.len());
+    }
+    Some(len)
+}
+
+trait WriteArg {
+    fn write(&self, buf: &mut [u8]) -> Option<usize>;
+}
+
+macro_rules! impl_write_arg {
+    ($($ty:ty => $tag:ident),*) => {$(
+        impl WriteArg for $ty {
+            #[inline(always)]
+            fn write(&self, buf: &mut [u8]) -> Option<usize> {
+                write_arg(buf, ArgType::$tag, &self.to_ne_bytes())
+            }
+        }
+    )*};
+}
+
+impl_write_arg!(
+    i8 => I8, i16 => I16, i32 => I32, i64 => I64, u8 => U8, u16 => U16, u32 => U32, u64 => U64
+);
+
+impl WriteArg for &str {
+    #[inline(always)]
+    fn write(&self, buf: &mut [u8]) -> Option<usize> {
+        write_arg(buf, ArgType::Str, self.as_bytes())
+    }
+}
+
+struct Ipv4(u32);
+
+impl WriteArg for Ipv4 {
+    #[inline(always)]
+    fn write(&self, buf: &mut [u8]) -> Option<usize> {
+        write_arg(buf, ArgType::Ipv4, &self.0.to_be_bytes())
+    }
+}
+
+struct Mac([u8; 6]);
+
+impl WriteArg for Mac {
+    #[inline(always)]
+    fn write(&self, buf: &mut [u8]) -> Option<usize> {
+        write_arg(buf, ArgType::Mac, &self.0)
+    }
+}
+
+// bpf_perf_event_output
+#[inline(always)]
+unsafe fn output(ctx: *mut c_void, data: &[u8]) {
+    let helper: unsafe extern "C" fn(*mut c_void, *mut c_void, u64, *const c_void, u64) -> i64 =
+        mem::transmute(25usize);
+    let _ = helper(
+        ctx,
+        ptr::null_mut(),
+        BPF_F_CURRENT_CPU,
+        data.as_ptr().cast(),
+        data.len() as u64,
+    );
+}
+
+macro_rules! log {
+    ($ctx:expr, $level:expr, $msg:literal $(, $arg:expr)*) => {{
+        let buf = unsafe { &mut *addr_of_mut!(BUF) };
+        let mut len = 0;
+        let written = (|| {
+            len += write_arg(buf.get_mut(len..)?, ArgType::Str, module_path!().as_bytes())?;
+            len += write_arg(buf.get_mut(len..)?, ArgType::U32, &line!().to_ne_bytes())?;
+            len += write_arg(buf.get_mut(len..)?, ArgType::U8, &[$level as u8])?;
+            len += write_arg(buf.get_mut(len..)?, ArgType::Str, $msg.as_bytes())?;
+            $(len += WriteArg::write(&$arg, buf.get_mut(len..)?)?;)*
+            Some(())
+        })();
+        if written.is_some() {
+            if let Some(record) = buf.get(..len) {
+                unsafe { output($ctx, record) }
+            }
+        }
+    }};
+}
+
+#[repr(C)]
+pub struct Event {
+    pub pid: u32,
+    pub tgid: u32,
+    pub saddr: u32,
+    pub daddr: u32,
+    pub sport: u16,
+    pub dport: u16,
+    pub mac: [u8; 6],
+    pub len: i64,
+    pub flags: u8,
+    pub delta: i32,
+}
+
+#[no_mangle]
+#[link_section = "tracepoint/net/net_dev_xmit"]
+pub unsafe extern "C" fn prog(ctx: *mut c_void) -> u32 {
+    let event = &*ctx.cast::<Event>();
+    log!(
+        ctx,
+        Level::Info,
+        "xmit pid {} tgid {}",
+        event.pid,
+        event.tgid
+    );
+    log!(
+        ctx,
+        Level::Debug,
+        "from {}:{}",
+        Ipv4(event.saddr),
+        event.sport
+    );
+    log!(
+        ctx,
+        Level::Debug,
+        "to {}:{}",
+        Ipv4(event.daddr),
+        event.dport
+    );
+    log!(ctx, Level::Debug, "mac {}", Mac(event.mac));
+    log!(ctx, Level::Info, "len {} flags {}", event.len, event.flags);
+    if event.len < 0 {
+        log!(
+            ctx,
+            Level::Error,
+            "negative length {} from pid {}",
+            event.len,
+            event.pid
+        );
+        return 1;
+    }
+    if event.delta > 1000 {
+        log!(
+            ctx,
+            Level::Warn,
+            "slow xmit {} for {}:{}",
+            event.delta,
+            Ipv4(event.daddr),
+            event.dport
+        );
+    }
+    for i in 0..8u8 {
+        log!(
+            ctx,
+            Level::Debug,
+            "byte {} of mac {} is {}",
+            i,
+            Mac(event.mac),
+            event.mac[(i % 6) as usize]
+        );
+    }
+    match event.dport {
+        22 => log!(
+            ctx,
+            Level::Info,
+            "ssh {} -> {}",
+            Ipv4(event.saddr),
+            Ipv4(event.daddr)
+        ),
+        53 => log!(
+            ctx,
+            Level::Info,
+            "dns {} -> {}",
+            Ipv4(event.saddr),
+            Ipv4(event.daddr)
+        ),
+        80 | 443 => log!(
+            ctx,
+            Level::Info,
+            "http {} -> {} ({})",
+            Ipv4(event.saddr),
+            Ipv4(event.daddr),
+            event.dport
+        ),
+        port => log!(
+            ctx,
+            Level::Debug,
+            "other port {} len {} delta {}",
+            port,
+            event.len,
+            event.delta
+        ),
+    }
+    log!(
+        ctx,
+        Level::Debug,
+        "widths {} {} {} {}",
+        event.flags as i8,
+        event.sport as i16,
+        event.delta,
+        event.len as u64
+    );
+    log!(ctx, Level::Info, "done with {}", "xmit");
+    0
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
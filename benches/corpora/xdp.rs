@@ -0,0 +1,76 @@
+//! A small XDP program: parses the Ethernet and IPv4 headers and counts the packets of each IP
+//! protocol.
+
+#![no_std]
+
+use core::{mem, ptr::addr_of_mut};
+
+const XDP_PASS: u32 = 2;
+const ETH_P_IP: u16 = 0x0800;
+
+#[repr(C)]
+pub struct XdpMd {
+    pub data: u32,
+    pub data_end: u32,
+    pub data_meta: u32,
+    pub ingress_ifindex: u32,
+    pub rx_queue_index: u32,
+    pub egress_ifindex: u32,
+}
+
+#[repr(C)]
+struct EthHdr {
+    dst: [u8; 6],
+    src: [u8; 6],
+    ether_type: u16,
+}
+
+#[repr(C)]
+struct Ipv4Hdr {
+    version_ihl: u8,
+    tos: u8,
+    tot_len: u16,
+    id: u16,
+    frag_off: u16,
+    ttl: u8,
+    protocol: u8,
+    check: u16,
+    saddr: u32,
+    daddr: u32,
+}
+
+static mut PACKETS: [u64; 256] = [0; 256];
+
+#[inline(always)]
+fn header<T>(ctx: &XdpMd, offset: usize) -> Option<*const T> {
+    let start = ctx.data as usize + offset;
+    if start + mem::size_of::<T>() > ctx.data_end as usize {
+        return None;
+    }
+    Some(start as *const T)
+}
+
+#[no_mangle]
+#[link_section = "xdp"]
+pub unsafe extern "C" fn prog(ctx: *mut XdpMd) -> u32 {
+    let ctx = &*ctx;
+    let Some(eth) = header::<EthHdr>(ctx, 0) else {
+        return XDP_PASS;
+    };
+    if (*eth).ether_type != ETH_P_IP.to_be() {
+        return XDP_PASS;
+    }
+    let Some(ip) = header::<Ipv4Hdr>(ctx, mem::size_of::<EthHdr>()) else {
+        return XDP_PASS;
+    };
+    let packets = addr_of_mut!(PACKETS)
+        .cast::<u64>()
+        .add((*ip).protocol as usize);
+    *packets += 1;
+    XDP_PASS
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,405 @@
+//! Links representative corpora and reports the wall time and peak RSS of each stage of the link,
+//! to tell how a change to the linker affects them. Run with `cargo xtask bench [args]`.
+//!
+//! The corpora are:
+//!
+//! - `xdp`: a small XDP program, see `corpora/xdp.rs`.
+//! - `log`: a program logging like aya-log does, with many inlined argument writers, see
+//!   `corpora/log.rs`.
+//! - `core`: the whole `core` of a sysroot built with debug info, with BTF, which mostly
+//!   measures linking and the debug info sanitizer.
+//!
+//! The programs are compiled against the same sysroot as the compile tests, which is built in
+//! `target/sysroot` when missing.
+//!
+//! Arguments:
+//!
+//! - `--iterations <n>`: how many times each corpus is linked, 5 by default. The median time of
+//!   each stage and its highest peak RSS are reported.
+//! - `--save-baseline <path>`: save the results to `path`.
+//! - `--baseline <path>`: compare the results to the ones saved in `path`, failing if a stage got
+//!   slower or used more memory by more than the threshold.
+//! - `--threshold <percent>`: the regression threshold, 10 by default.
+//! - the names of the corpora to link, all of them by default.
+
+use std::{
+    env,
+    ffi::OsString,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    time::{Duration, Instant},
+};
+
+use bpf_linker::{Linker, LinkerOptions, LinkerOptionsBuilder, OptLevel};
+
+const TARGET: &str = "bpfel-unknown-none";
+const CORPORA: &[&str] = &["xdp", "log", "core"];
+// time differences below this are noise, whatever their percentage
+const MIN_TIME_DELTA: Duration = Duration::from_millis(2);
+
+struct Args {
+    iterations: usize,
+    save_baseline: Option<PathBuf>,
+    baseline: Option<PathBuf>,
+    threshold: f64,
+    corpora: Vec<String>,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Self {
+            iterations: 5,
+            save_baseline: None,
+            baseline: None,
+            threshold: 10.0,
+            corpora: Vec::new(),
+        };
+        let mut iter = env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().ok_or_else(|| format!("`{arg}` needs a value"));
+            match arg.as_str() {
+                // passed by `cargo bench`
+                "--bench" => {}
+                "--iterations" => {
+                    args.iterations = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or("`--iterations` must be a positive number")?
+                }
+                "--save-baseline" => args.save_baseline = Some(value()?.into()),
+                "--baseline" => args.baseline = Some(value()?.into()),
+                "--threshold" => {
+                    args.threshold = value()?
+                        .parse()
+                        .map_err(|_| "`--threshold` must be a number".to_owned())?
+                }
+                corpus if CORPORA.contains(&corpus) => args.corpora.push(corpus.to_owned()),
+                arg => {
+                    return Err(format!(
+                        "unknown argument `{arg}`, the corpora are {}",
+                        CORPORA.join(", ")
+                    ))
+                }
+            }
+        }
+        if args.corpora.is_empty() {
+            args.corpora = CORPORA.iter().map(|&corpus| corpus.to_owned()).collect();
+        }
+        Ok(args)
+    }
+}
+
+/// The measurements of a stage of the link of a corpus.
+#[derive(Clone, Debug)]
+struct StageResult {
+    corpus: String,
+    stage: String,
+    time: Duration,
+    peak_rss: u64,
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Returns false if a stage regressed compared to the baseline.
+fn run() -> Result<bool, String> {
+    let args = Args::parse()?;
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let sysroot = build_sysroot(root)?;
+    let build_dir = root.join("target/bench");
+    fs::create_dir_all(&build_dir).map_err(|e| format!("{}: {e}", build_dir.display()))?;
+    let core = find_rlib(&sysroot, "core")?;
+
+    let baseline = match &args.baseline {
+        Some(path) => {
+            let saved = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+            Some(load(&saved)?)
+        }
+        None => None,
+    };
+
+    let mut results = Vec::new();
+    for corpus in &args.corpora {
+        let input = match corpus.as_str() {
+            "core" => None,
+            program => Some(compile(root, &sysroot, &build_dir, program)?),
+        };
+        let options = || {
+            let options = match &input {
+                Some(bitcode) => LinkerOptions::builder()
+                    .input(bitcode)
+                    .library(&core)
+                    .export("prog"),
+                None => LinkerOptions::builder().input(&core),
+            };
+            options
+                .btf(true)
+                .optimize(OptLevel::Aggressive)
+                .output(build_dir.join(format!("{corpus}.o")))
+        };
+        results.extend(bench(corpus, options, args.iterations)?);
+    }
+
+    print!("{}", table(&results, baseline.as_deref()));
+    if let Some(path) = &args.save_baseline {
+        fs::write(path, save(&results)).map_err(|e| format!("{}: {e}", path.display()))?;
+    }
+    let Some(baseline) = baseline else {
+        return Ok(true);
+    };
+    let regressions = regressions(&results, &baseline, args.threshold);
+    if regressions.is_empty() {
+        return Ok(true);
+    }
+    println!("\nregressions over {}%:", args.threshold);
+    for regression in &regressions {
+        println!("    {regression}");
+    }
+    Ok(false)
+}
+
+// Links `corpus` `iterations` times, returning the median time of each stage, the highest peak
+// RSS of each stage and the total time of the link.
+fn bench(
+    corpus: &str,
+    options: impl Fn() -> LinkerOptionsBuilder,
+    iterations: usize,
+) -> Result<Vec<StageResult>, String> {
+    let mut stages: Vec<(String, Vec<Duration>, u64)> = Vec::new();
+    for _ in 0..iterations {
+        let options = options()
+            .measure_stage_memory(true)
+            .build()
+            .map_err(|e| format!("{corpus}: {e}"))?;
+        let start = Instant::now();
+        let mut linker = Linker::new(options).map_err(|e| format!("{corpus}: {e}"))?;
+        linker.link().map_err(|e| format!("{corpus}: {e}"))?;
+        let total = start.elapsed();
+
+        let stats = linker.stats();
+        // the peaks are only measured on Linux
+        let measured_rss = stats.stage_peak_rss.len() == stats.stage_times.len();
+        let measured = stats
+            .stage_times
+            .iter()
+            .enumerate()
+            .map(|(i, (name, time))| {
+                let rss = measured_rss.then(|| stats.stage_peak_rss[i].1);
+                ((*name).to_owned(), *time, rss)
+            })
+            .chain([(
+                "total".to_owned(),
+                total,
+                stats.stage_peak_rss.iter().map(|(_, rss)| *rss).max(),
+            )]);
+        // stages can run several times per link, eg codegen for each target
+        for (i, (name, time, rss)) in measured.enumerate() {
+            match stages.get_mut(i) {
+                Some((_, times, peak)) => {
+                    times.push(time);
+                    *peak = (*peak).max(rss.unwrap_or(0));
+                }
+                None => stages.push((name, vec![time], rss.unwrap_or(0))),
+            }
+        }
+    }
+    Ok(stages
+        .into_iter()
+        .map(|(stage, mut times, peak_rss)| {
+            times.sort();
+            StageResult {
+                corpus: corpus.to_owned(),
+                stage,
+                time: times[times.len() / 2],
+                peak_rss,
+            }
+        })
+        .collect())
+}
+
+// Builds the sysroot of the compile tests if it's missing, and returns its path.
+fn build_sysroot(root: &Path) -> Result<PathBuf, String> {
+    let rustc = Command::new(env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc")));
+    let rustc_src = rustc_build_sysroot::rustc_sysroot_src(rustc)
+        .map_err(|e| format!("could not find the sources of the sysroot: {e}"))?;
+    let directory = root.join("target/sysroot");
+    let _: rustc_build_sysroot::SysrootStatus =
+        rustc_build_sysroot::SysrootBuilder::new(&directory, TARGET)
+            .build_mode(rustc_build_sysroot::BuildMode::Build)
+            .sysroot_config(rustc_build_sysroot::SysrootConfig::NoStd)
+            // the core corpus is about debug info
+            .rustflag("-Cdebuginfo=2")
+            .build_from_source(&rustc_src)
+            .map_err(|e| format!("failed to build the sysroot: {e}"))?;
+    Ok(directory)
+}
+
+fn find_rlib(sysroot: &Path, name: &str) -> Result<PathBuf, String> {
+    let dir = sysroot.join("lib/rustlib").join(TARGET).join("lib");
+    let prefix = format!("lib{name}-");
+    fs::read_dir(&dir)
+        .map_err(|e| format!("{}: {e}", dir.display()))?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".rlib"))
+        })
+        .ok_or_else(|| format!("no {name} rlib in {}", dir.display()))
+}
+
+// Compiles the program `name` of `corpora/` to bitcode, the way cargo builds BPF programs.
+fn compile(root: &Path, sysroot: &Path, build_dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let src = root.join("benches/corpora").join(format!("{name}.rs"));
+    let output = build_dir.join(format!("{name}.bc"));
+    let mut rustc = Command::new(env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc")));
+    let _: &mut Command = rustc
+        .args([
+            "--edition",
+            "2021",
+            "--crate-type",
+            "lib",
+            "--target",
+            TARGET,
+        ])
+        .arg("--sysroot")
+        .arg(sysroot)
+        .args([
+            "-Copt-level=3",
+            "-Cdebuginfo=2",
+            "-Ccodegen-units=1",
+            "--emit=llvm-bc",
+        ])
+        .arg("-o")
+        .arg(&output)
+        .arg(&src);
+    let out = rustc
+        .output()
+        .map_err(|e| format!("failed to run {rustc:?}: {e}"))?;
+    if !out.status.success() {
+        return Err(format!(
+            "failed to compile {}:\n{}",
+            src.display(),
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+    Ok(output)
+}
+
+// Renders `results` as a table, with the difference to `baseline` if any.
+fn table(results: &[StageResult], baseline: Option<&[StageResult]>) -> String {
+    let mut table = format!(
+        "{:<8}{:<28}{:>12}{:>10}{:>16}{:>10}\n",
+        "corpus", "stage", "time (ms)", "", "peak RSS (KiB)", ""
+    );
+    for result in results {
+        let old = baseline.and_then(|baseline| find(baseline, result));
+        let (time_delta, rss_delta) = match old {
+            Some(old) => (
+                format_delta(delta(old.time.as_secs_f64(), result.time.as_secs_f64())),
+                format_delta(delta(old.peak_rss as f64, result.peak_rss as f64)),
+            ),
+            None => (String::new(), String::new()),
+        };
+        writeln!(
+            table,
+            "{:<8}{:<28}{:>12.3}{time_delta:>10}{:>16}{rss_delta:>10}",
+            result.corpus,
+            result.stage,
+            result.time.as_secs_f64() * 1000.0,
+            result.peak_rss / 1024,
+        )
+        .unwrap();
+    }
+    table
+}
+
+fn find<'a>(results: &'a [StageResult], result: &StageResult) -> Option<&'a StageResult> {
+    results
+        .iter()
+        .find(|old| old.corpus == result.corpus && old.stage == result.stage)
+}
+
+// The change from `old` to `new` in percent.
+fn delta(old: f64, new: f64) -> Option<f64> {
+    (old > 0.0).then(|| (new - old) / old * 100.0)
+}
+
+fn format_delta(delta: Option<f64>) -> String {
+    delta
+        .map(|delta| format!("{delta:+.1}%"))
+        .unwrap_or_default()
+}
+
+// The stages of `results` slower or using more memory than in `baseline` by more than
+// `threshold` percent.
+fn regressions(results: &[StageResult], baseline: &[StageResult], threshold: f64) -> Vec<String> {
+    let mut regressions = Vec::new();
+    for result in results {
+        let Some(old) = find(baseline, result) else {
+            continue;
+        };
+        let name = format!("{} {}", result.corpus, result.stage);
+        if result.time.saturating_sub(old.time) >= MIN_TIME_DELTA {
+            if let Some(delta) = delta(old.time.as_secs_f64(), result.time.as_secs_f64()) {
+                if delta > threshold {
+                    regressions.push(format!("{name}: time {delta:+.1}%"));
+                }
+            }
+        }
+        if let Some(delta) = delta(old.peak_rss as f64, result.peak_rss as f64) {
+            if delta > threshold {
+                regressions.push(format!("{name}: peak RSS {delta:+.1}%"));
+            }
+        }
+    }
+    regressions
+}
+
+// Baselines have a line per stage: the corpus, the stage, the time in nanoseconds and the peak
+// RSS in bytes, separated by tabs.
+fn save(results: &[StageResult]) -> String {
+    let mut saved = String::new();
+    for result in results {
+        writeln!(
+            saved,
+            "{}\t{}\t{}\t{}",
+            result.corpus,
+            result.stage,
+            result.time.as_nanos(),
+            result.peak_rss
+        )
+        .unwrap();
+    }
+    saved
+}
+
+fn load(saved: &str) -> Result<Vec<StageResult>, String> {
+    saved
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let invalid = || format!("invalid baseline line `{line}`");
+            let mut fields = line.split('\t');
+            let mut field = || fields.next().ok_or_else(invalid);
+            Ok(StageResult {
+                corpus: field()?.to_owned(),
+                stage: field()?.to_owned(),
+                time: Duration::from_nanos(field()?.parse().map_err(|_| invalid())?),
+                peak_rss: field()?.parse().map_err(|_| invalid())?,
+            })
+        })
+        .collect()
+}
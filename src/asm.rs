@@ -0,0 +1,198 @@
+//! Rewriting of the assembly generated by LLVM, see [`LinkerOptions::asm_dialect`],
+//! [`LinkerOptions::asm_without_btf`] and [`LinkerOptions::asm_with_source`].
+//!
+//! [`LinkerOptions::asm_dialect`]: crate::LinkerOptions::asm_dialect
+//! [`LinkerOptions::asm_without_btf`]: crate::LinkerOptions::asm_without_btf
+//! [`LinkerOptions::asm_with_source`]: crate::LinkerOptions::asm_with_source
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::AsmDialect;
+
+/// How to rewrite the assembly.
+pub(crate) struct AsmOptions {
+    pub dialect: AsmDialect,
+    pub with_btf: bool,
+    pub with_source: bool,
+}
+
+/// Rewrites the assembly `asm` according to `options`.
+pub(crate) fn rewrite(asm: &str, options: &AsmOptions) -> String {
+    let mut out = String::with_capacity(asm.len());
+    let mut files = HashMap::new();
+    let mut sources = Sources::default();
+    let mut in_btf = false;
+    let mut last_loc = None;
+    for line in asm.lines() {
+        let mut words = line.split_whitespace();
+        let directive = words.next().unwrap_or("");
+        match directive {
+            ".section" | ".text" | ".data" | ".bss" => {
+                let section = words.next().unwrap_or("").split(',').next().unwrap_or("");
+                in_btf = directive == ".section" && matches!(section, ".BTF" | ".BTF.ext");
+            }
+            ".file" if options.with_source => {
+                if let Some((number, path)) = parse_file(line) {
+                    let _: Option<PathBuf> = files.insert(number, path);
+                }
+            }
+            ".loc" if options.with_source => {
+                let file = words.next().and_then(|file| file.parse::<u32>().ok());
+                let line_number = words.next().and_then(|line| line.parse::<usize>().ok());
+                if let (Some(file), Some(line_number)) = (file, line_number) {
+                    // line 0 is used for code which doesn't come from any line
+                    if line_number != 0 && last_loc != Some((file, line_number)) {
+                        last_loc = Some((file, line_number));
+                        if let Some(path) = files.get(&file) {
+                            write_source_line(&mut out, &mut sources, path, line_number);
+                        }
+                    }
+                }
+            }
+            // GNU as doesn't support address significance tables
+            ".addrsig" | ".addrsig_sym" if matches!(options.dialect, AsmDialect::Gas) => continue,
+            _ => {}
+        }
+        if in_btf && !options.with_btf {
+            continue;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+// Parses `.file N "path"` or `.file N "directory" "name" [md5 ...]`.
+fn parse_file(line: &str) -> Option<(u32, PathBuf)> {
+    let rest = line.trim_start().strip_prefix(".file")?.trim_start();
+    let (number, mut rest) = rest.split_once(|c: char| c.is_whitespace())?;
+    let number = number.parse().ok()?;
+    let mut strings = Vec::new();
+    while let Some(s) = rest.trim_start().strip_prefix('"') {
+        let (string, remaining) = parse_string(s)?;
+        strings.push(string);
+        rest = remaining;
+    }
+    let path = match strings.as_slice() {
+        [path] => PathBuf::from(path),
+        [directory, name, ..] => Path::new(directory).join(name),
+        [] => return None,
+    };
+    Some((number, path))
+}
+
+// Parses the rest of a quoted string, returning it and what follows the closing quote.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let mut string = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &s[i + 1..])),
+            '\\' => string.push(chars.next()?.1),
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+#[derive(Default)]
+struct Sources {
+    files: HashMap<PathBuf, Option<Vec<String>>>,
+}
+
+impl Sources {
+    fn line(&mut self, path: &Path, line_number: usize) -> Option<&str> {
+        self.files
+            .entry(path.to_owned())
+            .or_insert_with(|| {
+                fs::read_to_string(path)
+                    .ok()
+                    .map(|source| source.lines().map(str::to_owned).collect())
+            })
+            .as_ref()?
+            .get(line_number - 1)
+            .map(String::as_str)
+    }
+}
+
+fn write_source_line(out: &mut String, sources: &mut Sources, path: &Path, line_number: usize) {
+    match sources.line(path, line_number) {
+        Some(source) => writeln!(out, "# {}:{line_number}: {}", path.display(), source.trim()),
+        None => writeln!(out, "# {}:{line_number}", path.display()),
+    }
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASM: &str = "\t.text
+\t.file\t\"prog\"
+\t.file\t0 \"/nonexistent\" \"prog.c\"
+\t.section\txdp,\"ax\",@progbits
+prog:
+\t.loc\t0 3 0
+\tr0 = 2
+\t.loc\t0 3 7 prologue_end
+\texit
+\t.section\t.BTF,\"\",@progbits
+\t.short\t60319
+\t.section\t.BTF.ext,\"\",@progbits
+\t.short\t60319
+\t.section\t.debug_line,\"\",@progbits
+\t.addrsig
+";
+
+    #[test]
+    fn test_rewrite_default() {
+        let options = AsmOptions {
+            dialect: AsmDialect::Llvm,
+            with_btf: true,
+            with_source: false,
+        };
+        assert_eq!(rewrite(ASM, &options), ASM);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let options = AsmOptions {
+            dialect: AsmDialect::Gas,
+            with_btf: false,
+            with_source: true,
+        };
+        assert_eq!(
+            rewrite(ASM, &options),
+            "\t.text
+\t.file\t\"prog\"
+\t.file\t0 \"/nonexistent\" \"prog.c\"
+\t.section\txdp,\"ax\",@progbits
+prog:
+# /nonexistent/prog.c:3
+\t.loc\t0 3 0
+\tr0 = 2
+\t.loc\t0 3 7 prologue_end
+\texit
+\t.section\t.debug_line,\"\",@progbits
+"
+        );
+    }
+
+    #[test]
+    fn test_parse_file() {
+        assert_eq!(
+            parse_file("\t.file\t1 \"/src\" \"a \\\"b\\\".c\" md5 0x00"),
+            Some((1, PathBuf::from("/src/a \"b\".c")))
+        );
+        assert_eq!(
+            parse_file("\t.file\t0 \"/tmp/c.c\""),
+            Some((0, PathBuf::from("/tmp/c.c")))
+        );
+        assert_eq!(parse_file("\t.file\t\"prog\""), None);
+    }
+}
@@ -4,243 +4,209 @@
 extern crate aya_rustc_llvm_proxy;
 
 use std::{
-    env, fs, io,
-    path::{Component, Path, PathBuf},
-    str::FromStr,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
 };
 
-use bpf_linker::{Cpu, Linker, LinkerOptions, OptLevel, OutputType};
-use clap::{
-    builder::{PathBufValueParser, TypedValueParser as _},
-    error::ErrorKind,
-    Parser,
+use anyhow::Context as _;
+use bpf_linker::{
+    BinaryOptions, CliError, CpuFeature, Diagnostic, Invocation, Linker, LinkerError, Severity,
 };
-use thiserror::Error;
-use tracing::{info, Level};
+use clap::error::ErrorKind;
+use tracing::{debug, info};
 use tracing_subscriber::{fmt::MakeWriter, prelude::*, EnvFilter};
 use tracing_tree::HierarchicalLayer;
 
-#[derive(Debug, Error)]
-enum CliError {
-    #[error("optimization level needs to be between 0-3, s or z (instead was `{0}`)")]
-    InvalidOptimization(String),
-    #[error("unknown emission type: `{0}` - expected one of: `llvm-bc`, `asm`, `llvm-ir`, `obj`")]
-    InvalidOutputType(String),
+/// Returns a [`HierarchicalLayer`](tracing_tree::HierarchicalLayer) for the
+/// given `writer`.
+fn tracing_layer<W>(writer: W) -> HierarchicalLayer<W>
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    const TRACING_IDENT: usize = 2;
+    HierarchicalLayer::new(TRACING_IDENT)
+        .with_indent_lines(true)
+        .with_writer(writer)
 }
 
-#[derive(Copy, Clone, Debug)]
-struct CliOptLevel(OptLevel);
-
-impl FromStr for CliOptLevel {
-    type Err = CliError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        use OptLevel::*;
-        Ok(CliOptLevel(match s {
-            "0" => No,
-            "1" => Less,
-            "2" => Default,
-            "3" => Aggressive,
-            "s" => Size,
-            "z" => SizeMin,
-            _ => return Err(CliError::InvalidOptimization(s.to_string())),
-        }))
+/// Logs the effective value of every option along with where the value came from, so it's
+/// obvious whether an option was set on the command line, in the `--config` file or left to its
+/// default.
+fn log_effective_options(invocation: &Invocation) {
+    for (name, values, source) in invocation.effective_options() {
+        debug!("option {name}: {values:?} ({source})");
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-struct CliOutputType(OutputType);
-
-impl FromStr for CliOutputType {
-    type Err = CliError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        use OutputType::*;
-        Ok(CliOutputType(match s {
-            "llvm-bc" => Bitcode,
-            "asm" => Assembly,
-            "llvm-ir" => LlvmAssembly,
-            "obj" => Object,
-            _ => return Err(CliError::InvalidOutputType(s.to_string())),
-        }))
-    }
+/// A log file which is rotated once it reaches `max_size` bytes: `<path>` is renamed to
+/// `<path>.1`, `<path>.1` to `<path>.2` and so on, keeping `max_files` rotated files. The logs of
+/// trace level DI sanitizing of large modules easily reach gigabytes otherwise.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
 }
 
-fn parent_and_file_name(p: PathBuf) -> anyhow::Result<(PathBuf, PathBuf)> {
-    let mut comps = p.components();
-    let file_name = comps
-        .next_back()
-        .map(|p| match p {
-            Component::Normal(p) => Ok(p),
-            p => Err(anyhow::anyhow!("unexpected path component {:?}", p)),
+impl RotatingFile {
+    fn new(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
         })
-        .transpose()?
-        .ok_or_else(|| anyhow::anyhow!("unexpected empty path"))?;
-    let parent = comps.as_path();
-    Ok((parent.to_path_buf(), Path::new(file_name).to_path_buf()))
-}
-
-#[derive(Debug, Parser)]
-#[command(version)]
-struct CommandLine {
-    /// LLVM target triple. When not provided, the target is inferred from the inputs
-    #[clap(long)]
-    target: Option<String>,
-
-    /// Target BPF processor. Can be one of `generic`, `probe`, `v1`, `v2`, `v3`
-    #[clap(long, default_value = "generic")]
-    cpu: Cpu,
-
-    /// Enable or disable CPU features. The available features are: alu32, dummy, dwarfris. Use
-    /// +feature to enable a feature, or -feature to disable it.  For example
-    /// --cpu-features=+alu32,-dwarfris
-    #[clap(long, value_name = "features", default_value = "")]
-    cpu_features: String,
-
-    /// Write output to <output>
-    #[clap(short, long)]
-    output: PathBuf,
-
-    /// Output type. Can be one of `llvm-bc`, `asm`, `llvm-ir`, `obj`
-    #[clap(long, default_value = "obj")]
-    emit: Vec<CliOutputType>,
-
-    /// Emit BTF information
-    #[clap(long)]
-    btf: bool,
-
-    /// Add a directory to the library search path
-    #[clap(short = 'L', number_of_values = 1)]
-    libs: Vec<PathBuf>,
-
-    /// Optimization level. 0-3, s, or z
-    #[clap(short = 'O', default_value = "2")]
-    optimize: Vec<CliOptLevel>,
-
-    /// Export the symbols specified in the file `path`. The symbols must be separated by new lines
-    #[clap(long, value_name = "path")]
-    export_symbols: Option<PathBuf>,
-
-    /// Output logs to the given `path`
-    #[clap(
-        long,
-        value_name = "path",
-        value_parser = PathBufValueParser::new().try_map(parent_and_file_name),
-    )]
-    log_file: Option<(PathBuf, PathBuf)>,
-
-    /// Set the log level. If not specified, no logging is used. Can be one of
-    /// `error`, `warn`, `info`, `debug`, `trace`.
-    #[clap(long, value_name = "level")]
-    log_level: Option<Level>,
-
-    /// Try hard to unroll loops. Useful when targeting kernels that don't support loops
-    #[clap(long)]
-    unroll_loops: bool,
-
-    /// Ignore `noinline`/`#[inline(never)]`. Useful when targeting kernels that don't support function calls
-    #[clap(long)]
-    ignore_inline_never: bool,
-
-    /// Dump the final IR module to the given `path` before generating the code
-    #[clap(long, value_name = "path")]
-    dump_module: Option<PathBuf>,
-
-    /// Extra command line arguments to pass to LLVM
-    #[clap(long, value_name = "args", use_value_delimiter = true, action = clap::ArgAction::Append)]
-    llvm_args: Vec<String>,
-
-    /// Disable passing --bpf-expand-memcpy-in-order to LLVM.
-    #[clap(long)]
-    disable_expand_memcpy_in_order: bool,
+    }
 
-    /// Disable exporting memcpy, memmove, memset, memcmp and bcmp. Exporting
-    /// those is commonly needed when LLVM does not manage to expand memory
-    /// intrinsics to a sequence of loads and stores.
-    #[clap(long)]
-    disable_memory_builtins: bool,
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
 
-    /// Input files. Can be object files or static libraries
-    #[clap(required = true)]
-    inputs: Vec<PathBuf>,
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for n in (1..self.max_files).rev() {
+                match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
 
-    /// Comma separated list of symbols to export. See also `--export-symbols`
-    #[clap(long, value_name = "symbols", use_value_delimiter = true, action = clap::ArgAction::Append)]
-    export: Vec<String>,
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // the non-blocking writer writes whole events, so they're never split across files
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
 
-    /// Whether to treat LLVM errors as fatal.
-    #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
-    fatal_errors: bool,
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
 
-    // The options below are for wasm-ld compatibility
-    #[clap(long = "debug", hide = true)]
-    _debug: bool,
+// Lists the warnings of a failed link, which the log only shows when asked to. The errors are
+// always logged.
+fn print_warnings(diagnostics: &[Diagnostic]) {
+    let warnings: Vec<_> = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+        .collect();
+    if warnings.is_empty() {
+        return;
+    }
+    eprintln!("warnings reported by the failed link:");
+    for warning in warnings {
+        eprintln!("  {warning}");
+    }
 }
 
-/// Returns a [`HierarchicalLayer`](tracing_tree::HierarchicalLayer) for the
-/// given `writer`.
-fn tracing_layer<W>(writer: W) -> HierarchicalLayer<W>
-where
-    W: for<'writer> MakeWriter<'writer> + 'static,
-{
-    const TRACING_IDENT: usize = 2;
-    HierarchicalLayer::new(TRACING_IDENT)
-        .with_indent_lines(true)
-        .with_writer(writer)
+// Tags link errors with their code, which scripts can match on.
+fn link_error(err: LinkerError) -> anyhow::Error {
+    let code = err.code();
+    anyhow::Error::new(err).context(format!("link failed with error {code}"))
 }
+
 fn main() -> anyhow::Result<()> {
-    let args = env::args().map(|arg| {
-        if arg == "-flavor" {
-            "--flavor".to_string()
-        } else {
-            arg
-        }
-    });
-    let CommandLine {
-        target,
-        cpu,
-        cpu_features,
-        output,
-        emit,
-        btf,
-        libs,
-        optimize,
-        export_symbols,
-        log_file,
-        log_level,
-        unroll_loops,
-        ignore_inline_never,
-        dump_module,
-        llvm_args,
-        disable_expand_memcpy_in_order,
-        disable_memory_builtins,
-        inputs,
-        export,
-        fatal_errors,
-        _debug,
-    } = match Parser::try_parse_from(args) {
-        Ok(command_line) => command_line,
-        Err(err) => match err.kind() {
-            ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => {
+    // when spawned by check_llvm_options or cpu_features
+    bpf_linker::answer_llvm_query();
+
+    let invocation = match Invocation::from_rustc_args(env::args_os()) {
+        Ok(invocation) => invocation,
+        Err(CliError::InvalidArgs(err)) => match err.kind() {
+            ErrorKind::DisplayHelp => {
+                print!("{err}");
+                return Ok(());
+            }
+            ErrorKind::DisplayVersion => {
                 print!("{err}");
+                match bpf_linker::FEATURES {
+                    [] => println!("features: none"),
+                    features => println!("features: {}", features.join(", ")),
+                }
                 return Ok(());
             }
             _ => return Err(err.into()),
         },
+        Err(err) => return Err(err.into()),
     };
+    let BinaryOptions {
+        config,
+        log_file,
+        log_file_max_size,
+        log_file_max_files,
+        log_level,
+        log_filter,
+        stats,
+        timings,
+        print_stack_usage,
+        print_removed_functions,
+        print_section_sizes,
+        fatal_errors,
+        print_cpu_features,
+        cpu,
+    } = invocation.binary.clone();
+
+    // Before the writer thread of the log file is spawned, as this may set LD_LIBRARY_PATH.
+    #[cfg(feature = "rust-llvm")]
+    let llvm = bpf_linker::llvm_proxy::configure()?;
 
     // Configure tracing.
     let _guard = {
         let filter = EnvFilter::from_default_env();
-        let filter = match log_level {
+        let mut filter = match log_level {
             None => filter,
             Some(log_level) => filter.add_directive(log_level.into()),
         };
+        for directive in &log_filter {
+            filter = filter.add_directive(
+                directive
+                    .parse()
+                    .with_context(|| format!("invalid --log-filter directive `{directive}`"))?,
+            );
+        }
         let subscriber_registry = tracing_subscriber::registry().with(filter);
         match log_file {
             Some((parent, file_name)) => {
-                let file_appender = tracing_appender::rolling::never(parent, file_name);
-                let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+                let (non_blocking, guard) = match log_file_max_size {
+                    None => tracing_appender::non_blocking(tracing_appender::rolling::never(
+                        parent, file_name,
+                    )),
+                    Some(max_size) => {
+                        let path = parent.join(file_name);
+                        let file = RotatingFile::new(path.clone(), max_size, log_file_max_files)
+                            .with_context(|| format!("failed to open {}", path.display()))?;
+                        tracing_appender::non_blocking(file)
+                    }
+                };
                 let subscriber = subscriber_registry
                     .with(tracing_layer(io::stdout))
                     .with(tracing_layer(non_blocking));
@@ -257,127 +223,76 @@ fn main() -> anyhow::Result<()> {
 
     info!(
         "command line: {:?}",
-        env::args().collect::<Vec<_>>().join(" ")
+        env::args_os()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join(" ")
     );
+    info!("features: {}", bpf_linker::FEATURES.join(", "));
+    if let Some(config) = &config {
+        info!("read options from {}", config.display());
+    }
+    log_effective_options(&invocation);
+    for arg in &invocation.ignored_args {
+        info!("ignoring `{arg}`, it has no effect when linking BPF");
+    }
 
-    let export_symbols = export_symbols.map(fs::read_to_string).transpose()?;
+    #[cfg(feature = "rust-llvm")]
+    info!("using LLVM from {}", llvm.display());
 
-    // TODO: the data is owned by this call frame; we could make this zero-alloc.
-    let export_symbols = export_symbols
-        .as_deref()
-        .into_iter()
-        .flat_map(str::lines)
-        .map(str::to_owned)
-        .chain(export)
-        .map(Into::into)
-        .collect();
+    // answers the LLVM queries which LLVM answers by printing or exiting
+    let exe = env::current_exe().context("failed to find the bpf-linker executable")?;
 
-    let output_type = match *emit.as_slice() {
-        [] => unreachable!("emit has a default value"),
-        [CliOutputType(output_type), ..] => output_type,
-    };
-    let optimize = match *optimize.as_slice() {
-        [] => unreachable!("emit has a default value"),
-        [.., CliOptLevel(optimize)] => optimize,
-    };
+    if print_cpu_features {
+        for CpuFeature { name, description } in bpf_linker::cpu_features(&exe, cpu)? {
+            println!("{name:<10} {description}");
+        }
+        return Ok(());
+    }
 
-    let mut linker = Linker::new(LinkerOptions {
-        target,
-        cpu,
-        cpu_features,
-        inputs,
-        output,
-        output_type,
-        libs,
-        optimize,
-        export_symbols,
-        unroll_loops,
-        ignore_inline_never,
-        dump_module,
-        llvm_args,
-        disable_expand_memcpy_in_order,
-        disable_memory_builtins,
-        btf,
-    });
+    let options = invocation.into_linker_options()?;
+    bpf_linker::check_llvm_options(&exe, &options).map_err(link_error)?;
+    let mut linker = Linker::new(options).map_err(link_error)?;
 
-    linker.link()?;
+    let ret = linker.link();
+    if ret.is_err() || (fatal_errors && linker.has_errors()) {
+        print_warnings(linker.diagnostics());
+    }
+    // also useful when the link fails, eg on symbols left undefined
+    eprint!("{}", linker.stats().symbol_explanations_report());
+    ret.map_err(link_error)?;
+
+    if let Some(path) = stats {
+        let json = linker.stats().to_json();
+        if path.as_os_str() == "-" {
+            eprintln!("{json}");
+        } else {
+            fs::write(&path, format!("{json}\n"))
+                .with_context(|| format!("failed to write stats to {}", path.display()))?;
+        }
+    }
 
-    if fatal_errors && linker.has_errors() {
-        return Err(anyhow::anyhow!(
-            "LLVM issued diagnostic with error severity"
-        ));
+    if timings {
+        eprint!("{}", linker.stats().timings_table());
     }
 
-    Ok(())
-}
+    if print_stack_usage {
+        eprint!("{}", linker.stats().stack_usage_table());
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    if print_removed_functions {
+        eprint!("{}", linker.stats().removed_functions_table());
+    }
 
-    // Test made to reproduce the following bug:
-    // https://github.com/aya-rs/bpf-linker/issues/27
-    // where --export argument followed by positional arguments resulted in
-    // parsing the positional args as `export`, not as `inputs`.
-    // There can be multiple exports, but they always have to be preceded by
-    // `--export` flag.
-    #[test]
-    fn test_export_input_args() {
-        let args = [
-            "bpf-linker",
-            "--export",
-            "foo",
-            "--export",
-            "bar",
-            "symbols.o", // this should be parsed as `input`, not `export`
-            "rcgu.o",    // this should be parsed as `input`, not `export`
-            "-L",
-            "target/debug/deps",
-            "-L",
-            "target/debug",
-            "-L",
-            "/home/foo/.rustup/toolchains/nightly-x86_64-unknown-linux-gnu/lib",
-            "-o",
-            "/tmp/bin.s",
-            "--target=bpf",
-            "--emit=asm",
-        ];
-        let CommandLine { inputs, export, .. } = Parser::parse_from(args);
-        assert_eq!(export, ["foo", "bar"]);
-        assert_eq!(
-            inputs,
-            [PathBuf::from("symbols.o"), PathBuf::from("rcgu.o")]
-        );
+    if print_section_sizes {
+        eprint!("{}", linker.stats().section_sizes_table());
     }
 
-    #[test]
-    fn test_export_delimiter() {
-        let args = [
-            "bpf-linker",
-            "--export",
-            "foo,bar",
-            "--export=ayy,lmao",
-            "symbols.o", // this should be parsed as `input`, not `export`
-            "--export=lol",
-            "--export",
-            "rotfl",
-            "rcgu.o", // this should be parsed as `input`, not `export`
-            "-L",
-            "target/debug/deps",
-            "-L",
-            "target/debug",
-            "-L",
-            "/home/foo/.rustup/toolchains/nightly-x86_64-unknown-linux-gnu/lib",
-            "-o",
-            "/tmp/bin.s",
-            "--target=bpf",
-            "--emit=asm",
-        ];
-        let CommandLine { inputs, export, .. } = Parser::parse_from(args);
-        assert_eq!(export, ["foo", "bar", "ayy", "lmao", "lol", "rotfl"]);
-        assert_eq!(
-            inputs,
-            [PathBuf::from("symbols.o"), PathBuf::from("rcgu.o")]
-        );
+    if fatal_errors && linker.has_errors() {
+        return Err(anyhow::anyhow!(
+            "LLVM issued diagnostic with error severity"
+        ));
     }
+
+    Ok(())
 }
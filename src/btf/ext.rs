@@ -0,0 +1,273 @@
+//! Merging of `.BTF.ext`, which describes the functions, lines and CO-RE relocations of the code
+//! sections with references to `.BTF`.
+
+use super::{BtfError, Merged, MAGIC};
+
+const HEADER_SIZE: usize = 32;
+
+// The fields of the records of each kind of info which are type ids and string offsets, after
+// the instruction offset which is always first.
+#[derive(Clone, Copy)]
+enum Field {
+    Type,
+    String,
+    Other,
+}
+
+const FUNC_INFO: &[Field] = &[Field::Type];
+const LINE_INFO: &[Field] = &[Field::String, Field::String, Field::Other];
+const CORE_RELO: &[Field] = &[Field::Type, Field::String, Field::Other];
+
+/// The records of one kind of info of a `.BTF.ext`, grouped by section.
+#[derive(Default)]
+pub(crate) struct ExtInfos {
+    pub rec_size: Option<u32>,
+    /// The name offset of each section and the words of its records.
+    pub sections: Vec<(u32, Vec<u32>)>,
+}
+
+/// Parses a `.BTF.ext` into its function infos, line infos and CO-RE relocations, in this order.
+/// The instruction offsets of the records are in bytes, from the start of their section.
+pub(crate) fn parse_ext(data: &[u8]) -> Result<[ExtInfos; 3], BtfError> {
+    let invalid = |msg: &str| BtfError(format!(".BTF.ext: {msg}"));
+    let magic = data.get(..2).ok_or_else(|| invalid("truncated header"))?;
+    let big_endian = match [magic[0], magic[1]] {
+        m if u16::from_le_bytes(m) == MAGIC => false,
+        m if u16::from_be_bytes(m) == MAGIC => true,
+        _ => return Err(invalid("bad magic")),
+    };
+    let u32_at = |offset: usize| -> Result<u32, BtfError> {
+        let bytes = data
+            .get(offset..offset + 4)
+            .ok_or_else(|| invalid("truncated"))?
+            .try_into()
+            .unwrap();
+        Ok(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    let hdr_len = u32_at(4)? as usize;
+    let mut infos: [ExtInfos; 3] = Default::default();
+    for (kind, fields) in [FUNC_INFO, LINE_INFO, CORE_RELO].into_iter().enumerate() {
+        // CO-RE relocations were added later, with a longer header
+        let header_field = 8 + kind * 8;
+        if header_field + 8 > hdr_len {
+            continue;
+        }
+        let off = hdr_len + u32_at(header_field)? as usize;
+        let len = u32_at(header_field + 4)? as usize;
+        if len == 0 {
+            continue;
+        }
+        let rec_size = u32_at(off)?;
+        if (rec_size as usize) < 4 * (fields.len() + 1) || rec_size % 4 != 0 {
+            return Err(invalid("invalid record size"));
+        }
+        infos[kind].rec_size = Some(rec_size);
+        let words_per_record = rec_size as usize / 4;
+        let mut offset = off + 4;
+        while offset < off + len {
+            let name_off = u32_at(offset)?;
+            let count = u32_at(offset + 4)? as usize;
+            offset += 8;
+            let mut records = Vec::with_capacity(count * words_per_record);
+            for _ in 0..count * words_per_record {
+                records.push(u32_at(offset)?);
+                offset += 4;
+            }
+            infos[kind].sections.push((name_off, records));
+        }
+    }
+    Ok(infos)
+}
+
+/// Merges the `.BTF.ext` sections `exts`, given with the index of their `.BTF` in `merged`.
+/// `section_offset(input, section)` is where the section `section` of the input `input` starts in
+/// the merged section, which the instruction offsets are relative to.
+pub(crate) fn merge_ext(
+    merged: &mut Merged<'_>,
+    exts: &[(usize, &[u8])],
+    section_offset: impl Fn(usize, &str) -> u64,
+) -> Result<Vec<u8>, BtfError> {
+    let invalid = |msg: &str| BtfError(format!(".BTF.ext: {msg}"));
+    let mut infos: [ExtInfos; 3] = Default::default();
+    for &(input, data) in exts {
+        let input_infos = parse_ext(data)?;
+        for ((infos, input_infos), fields) in infos
+            .iter_mut()
+            .zip(input_infos)
+            .zip([FUNC_INFO, LINE_INFO, CORE_RELO])
+        {
+            let Some(rec_size) = input_infos.rec_size else {
+                continue;
+            };
+            match infos.rec_size {
+                Some(size) if size != rec_size => {
+                    return Err(invalid("objects have different record sizes"))
+                }
+                _ => infos.rec_size = Some(rec_size),
+            }
+            let words_per_record = rec_size as usize / 4;
+            for (name_off, mut records) in input_infos.sections {
+                let base = section_offset(input, merged.input(input).string(name_off)) as u32;
+                let name = merged.string(input, name_off);
+                for record in records.chunks_exact_mut(words_per_record) {
+                    record[0] += base;
+                    for (word, field) in record[1..].iter_mut().zip(fields) {
+                        match field {
+                            Field::Type => {
+                                *word = merged
+                                    .type_id(input, *word)
+                                    .ok_or_else(|| invalid("invalid type id"))?
+                            }
+                            Field::String => *word = merged.string(input, *word),
+                            Field::Other => {}
+                        }
+                    }
+                }
+                match infos.sections.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, section_records)) => section_records.extend(records),
+                    None => infos.sections.push((name, records)),
+                }
+            }
+        }
+    }
+
+    let big_endian = merged.big_endian();
+    let put = |out: &mut Vec<u8>, word: u32| {
+        out.extend(if big_endian {
+            word.to_be_bytes()
+        } else {
+            word.to_le_bytes()
+        })
+    };
+    let mut blocks: Vec<Vec<u8>> = Vec::with_capacity(infos.len());
+    for infos in &infos {
+        let mut block = Vec::new();
+        if let Some(rec_size) = infos.rec_size {
+            put(&mut block, rec_size);
+            for (name, records) in &infos.sections {
+                put(&mut block, *name);
+                put(&mut block, (records.len() / (rec_size as usize / 4)) as u32);
+                for word in records {
+                    put(&mut block, *word);
+                }
+            }
+        }
+        blocks.push(block);
+    }
+
+    let mut out = Vec::new();
+    out.extend(if big_endian {
+        MAGIC.to_be_bytes()
+    } else {
+        MAGIC.to_le_bytes()
+    });
+    out.extend([1, 0]);
+    put(&mut out, HEADER_SIZE as u32);
+    let mut offset = 0;
+    for block in &blocks {
+        put(&mut out, offset as u32);
+        put(&mut out, block.len() as u32);
+        offset += block.len();
+    }
+    for block in blocks {
+        out.extend(block);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btf::{
+        tests::{btf_bytes, info},
+        Btf,
+    };
+
+    fn ext(func_info: &[u32], line_info: &[u32]) -> Vec<u8> {
+        let words: Vec<u32> = [
+            HEADER_SIZE as u32,
+            0,
+            func_info.len() as u32 * 4,
+            func_info.len() as u32 * 4,
+            line_info.len() as u32 * 4,
+            0,
+            0,
+        ]
+        .into_iter()
+        .chain(func_info.iter().copied())
+        .chain(line_info.iter().copied())
+        .collect();
+        let mut data = MAGIC.to_le_bytes().to_vec();
+        data.extend([1, 0]);
+        data.extend(words.iter().flat_map(|word| word.to_le_bytes()));
+        data
+    }
+
+    #[test]
+    fn test_merge_ext() {
+        // [1] func proto, [2] func
+        let strings = b"\0xdp\0prog\0a.c\0";
+        let a = btf_bytes(&[&[0, info(13, 0), 0], &[5, info(12, 1), 1]], strings);
+        let strings = b"\0b.c\0xdp\0other\0";
+        let b = btf_bytes(&[&[0, info(13, 0), 0], &[9, info(12, 1), 1]], strings);
+        let btfs = [Btf::parse(&a).unwrap(), Btf::parse(&b).unwrap()];
+        let mut merged = Merged::new(&btfs, |_, _| 0).unwrap();
+        let ext_a = ext(&[8, 1, 1, 0, 2], &[16, 1, 1, 0, 10, 10, 3 << 10]);
+        let ext_b = ext(&[8, 5, 1, 0, 2], &[16, 5, 1, 0, 1, 1, 7 << 10]);
+        let data = merge_ext(
+            &mut merged,
+            &[(0, &ext_a), (1, &ext_b)],
+            |input, section| {
+                assert_eq!(section, "xdp");
+                if input == 1 {
+                    64
+                } else {
+                    0
+                }
+            },
+        )
+        .unwrap();
+        let words: Vec<u32> = data[8..]
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        let other = merged.type_id(1, 2).unwrap();
+        assert_eq!(other, 3);
+        assert_eq!(
+            words,
+            [
+                // func_info at 0, 28 bytes, line_info at 28, 44 bytes, no CO-RE relocations
+                0,
+                28,
+                28,
+                44,
+                72,
+                0,
+                // func_info: prog at 0, other at 64
+                8,
+                1,
+                2,
+                0,
+                2,
+                64,
+                other,
+                // line_info
+                16,
+                1,
+                2,
+                0,
+                10,
+                10,
+                3 << 10,
+                64,
+                20,
+                20,
+                7 << 10,
+            ]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Checking of the kfuncs a program calls against the BTF of the kernel it's loaded into, so that
+//! mismatches are reported at link time rather than by the loader.
+
+use std::collections::HashMap;
+
+use super::{Btf, BtfKind, BtfType};
+
+// The linkage of extern functions, stored in the vlen of BTF_KIND_FUNC.
+const BTF_FUNC_EXTERN: u16 = 2;
+
+/// Checks the extern functions of `program`, ie the kfuncs it calls, against the functions of
+/// `kernel`. Returns a message for each kfunc the kernel doesn't have, and for each mismatch
+/// between the prototypes of the others.
+///
+/// Integers and enums are compatible when their sizes match, structs and unions when their names
+/// do, and the kernel's `void *` accepts any pointer.
+pub(crate) fn check_kfuncs(program: &Btf, kernel: &Btf) -> Vec<String> {
+    let kernel_funcs: HashMap<&str, &BtfType> = kernel
+        .types
+        .iter()
+        .filter(|ty| ty.kind == BtfKind::Func)
+        .map(|ty| (kernel.string(ty.name_off), ty))
+        .collect();
+
+    let mut errors = Vec::new();
+    for func in program
+        .types
+        .iter()
+        .filter(|ty| ty.kind == BtfKind::Func && ty.vlen == BTF_FUNC_EXTERN)
+    {
+        let name = program.string(func.name_off);
+        let Some(kernel_func) = kernel_funcs.get(name) else {
+            errors.push(format!("kfunc `{name}` is not in the kernel BTF"));
+            continue;
+        };
+        let (Some(proto), Some(kernel_proto)) = (
+            program.get(func.size_or_type),
+            kernel.get(kernel_func.size_or_type),
+        ) else {
+            continue;
+        };
+        let params: Vec<_> = proto.extra.chunks_exact(2).collect();
+        let kernel_params: Vec<_> = kernel_proto.extra.chunks_exact(2).collect();
+        if params.len() != kernel_params.len() {
+            errors.push(format!(
+                "kfunc `{name}` takes {} arguments in the kernel but {} in the program",
+                kernel_params.len(),
+                params.len()
+            ));
+            continue;
+        }
+        let checker = Checker { program, kernel };
+        if !checker.compatible(proto.size_or_type, kernel_proto.size_or_type) {
+            errors.push(format!(
+                "kfunc `{name}` returns `{}` in the kernel but `{}` in the program",
+                type_name(kernel, kernel_proto.size_or_type),
+                type_name(program, proto.size_or_type)
+            ));
+        }
+        for (i, (param, kernel_param)) in params.iter().zip(&kernel_params).enumerate() {
+            if !checker.compatible(param[1], kernel_param[1]) {
+                let param_name = match kernel.string(kernel_param[0]) {
+                    "" => String::new(),
+                    param_name => format!(" (`{param_name}`)"),
+                };
+                errors.push(format!(
+                    "argument {}{param_name} of kfunc `{name}` is `{}` in the kernel but `{}` in \
+                     the program",
+                    i + 1,
+                    type_name(kernel, kernel_param[1]),
+                    type_name(program, param[1])
+                ));
+            }
+        }
+    }
+    errors
+}
+
+struct Checker<'a> {
+    program: &'a Btf,
+    kernel: &'a Btf,
+}
+
+impl Checker<'_> {
+    // Whether the type `id` of the program is compatible with the type `kernel_id` of the kernel.
+    fn compatible(&self, id: u32, kernel_id: u32) -> bool {
+        self.compatible_at(id, kernel_id, 0)
+    }
+
+    fn compatible_at(&self, id: u32, kernel_id: u32, depth: usize) -> bool {
+        // pointers to pointers to ... are never that deep
+        const MAX_DEPTH: usize = 32;
+
+        let (program, kernel) = (self.program, self.kernel);
+        let (Some(ty), Some(kernel_ty)) = (program.resolve(id), kernel.resolve(kernel_id)) else {
+            return false;
+        };
+        use BtfKind::*;
+        match (ty.kind, kernel_ty.kind) {
+            (Void, Void) => true,
+            (Int | Enum | Enum64, Int | Enum | Enum64) | (Float, Float) => {
+                ty.size_or_type == kernel_ty.size_or_type
+            }
+            (Ptr, Ptr) => {
+                depth < MAX_DEPTH
+                    && (kernel
+                        .resolve(kernel_ty.size_or_type)
+                        .is_some_and(|pointee| pointee.kind == Void)
+                        || self.compatible_at(ty.size_or_type, kernel_ty.size_or_type, depth + 1))
+            }
+            (Struct | Union | Fwd, Struct | Union | Fwd) => {
+                // a forward declaration stands for a struct or a union depending on kind_flag
+                let is_union = |ty: &BtfType| ty.kind == Union || (ty.kind == Fwd && ty.kind_flag);
+                is_union(ty) == is_union(kernel_ty)
+                    && program.string(ty.name_off) == kernel.string(kernel_ty.name_off)
+            }
+            (Array, Array) => match (ty.array(), kernel_ty.array()) {
+                (Some((elem, nelems)), Some((kernel_elem, kernel_nelems))) => {
+                    nelems == kernel_nelems
+                        && depth < MAX_DEPTH
+                        && self.compatible_at(elem, kernel_elem, depth + 1)
+                }
+                _ => false,
+            },
+            // function pointers are rare in kfunc prototypes, don't go into them
+            (FuncProto, FuncProto) => true,
+            _ => false,
+        }
+    }
+}
+
+// Renders the type `id` of `btf` roughly as C would, eg `const struct task_struct *`.
+fn type_name(btf: &Btf, id: u32) -> String {
+    type_name_at(btf, id, 0)
+}
+
+fn type_name_at(btf: &Btf, id: u32, depth: usize) -> String {
+    let Some(ty) = btf.get(id) else {
+        return format!("<type {id}>");
+    };
+    if depth > 32 {
+        return "...".to_owned();
+    }
+    let name = btf.string(ty.name_off);
+    let inner = || type_name_at(btf, ty.size_or_type, depth + 1);
+    use BtfKind::*;
+    match ty.kind {
+        Void => "void".to_owned(),
+        Ptr => format!("{} *", inner()),
+        Const => format!("const {}", inner()),
+        Volatile => format!("volatile {}", inner()),
+        Restrict => format!("{} restrict", inner()),
+        TypeTag => inner(),
+        Struct => format!("struct {name}"),
+        Union => format!("union {name}"),
+        Enum | Enum64 => format!("enum {name}"),
+        Fwd if ty.kind_flag => format!("union {name}"),
+        Fwd => format!("struct {name}"),
+        Array => match ty.array() {
+            Some((elem, nelems)) => format!("{}[{nelems}]", type_name_at(btf, elem, depth + 1)),
+            None => "<array>".to_owned(),
+        },
+        FuncProto => "<function>".to_owned(),
+        _ => name.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btf::tests::{btf_bytes, info};
+
+    #[test]
+    fn test_check_kfuncs() {
+        let kernel = btf_bytes(
+            &[
+                // [1] int
+                &[1, info(1, 0), 4, (1 << 24) | 32],
+                // [2] struct task_struct
+                &[5, info(4, 0), 16],
+                // [3] struct task_struct *
+                &[0, info(2, 0), 2],
+                // [4] struct task_struct *(struct task_struct *p, int flags)
+                &[0, info(13, 2), 3, 17, 3, 19, 1],
+                // [5] bpf_task_acquire, global
+                &[25, info(12, 1), 4],
+                // [6] bpf_task_release, global
+                &[42, info(12, 1), 4],
+                // [7] bpf_other, global
+                &[59, info(12, 1), 4],
+            ],
+            b"\0int\0task_struct\0p\0flags\0bpf_task_acquire\0bpf_task_release\0bpf_other\0",
+        );
+        let program = btf_bytes(
+            &[
+                // [1] long
+                &[1, info(1, 0), 8, (1 << 24) | 64],
+                // [2] struct task_struct
+                &[6, info(4, 0), 8],
+                // [3] struct task_struct *
+                &[0, info(2, 0), 2],
+                // [4] struct task_struct *(struct task_struct *, long)
+                &[0, info(13, 2), 3, 0, 3, 0, 1],
+                // [5] struct task_struct *(struct task_struct *)
+                &[0, info(13, 1), 3, 0, 3],
+                // [6] bpf_task_acquire, extern
+                &[18, info(12, 2), 4],
+                // [7] bpf_task_release, extern
+                &[35, info(12, 2), 5],
+                // [8] bpf_missing, extern
+                &[52, info(12, 2), 5],
+                // [9] a static function, not a kfunc
+                &[64, info(12, 0), 5],
+            ],
+            b"\0long\0task_struct\0bpf_task_acquire\0bpf_task_release\0bpf_missing\0prog\0",
+        );
+        let errors = check_kfuncs(
+            &Btf::parse(&program).unwrap(),
+            &Btf::parse(&kernel).unwrap(),
+        );
+        assert_eq!(
+            errors,
+            [
+                "argument 2 (`flags`) of kfunc `bpf_task_acquire` is `int` in the kernel but \
+                 `long` in the program",
+                "kfunc `bpf_task_release` takes 2 arguments in the kernel but 1 in the program",
+                "kfunc `bpf_missing` is not in the kernel BTF",
+            ]
+        );
+    }
+}
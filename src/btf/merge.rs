@@ -0,0 +1,383 @@
+//! Merging of the BTF of several objects into a single deduplicated one.
+//!
+//! Types are deduplicated structurally: two types are the same if they have the same kind, name
+//! and attributes, and refer to types which are the same. This is computed by partition
+//! refinement, which handles reference cycles such as linked list nodes. Datasecs are merged by
+//! name instead, since each object describes its own part of a section.
+//!
+//! The types of the first BTF keep their ids and its string offsets stay valid, so that what
+//! refers to them, eg `.BTF.ext`, doesn't need to be rewritten.
+
+use std::collections::HashMap;
+
+use super::{Btf, BtfError, BtfKind, BtfType};
+
+/// Merges the contents of several `.BTF` sections into one, deduplicating the types they have in
+/// common. Returns the contents of the merged `.BTF` section.
+///
+/// Datasecs with the same name are merged into one, listing the variables of all of them.
+/// `section_offset(input, section)` is where the section named `section` of the object of the
+/// `input`th BTF starts in the merged object, which the offsets of its variables are moved by.
+pub fn merge(
+    btfs: &[&[u8]],
+    section_offset: impl Fn(usize, &str) -> u64,
+) -> Result<Vec<u8>, BtfError> {
+    let btfs = btfs
+        .iter()
+        .map(|data| Btf::parse(data))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Merged::new(&btfs, section_offset)?.to_bytes())
+}
+
+/// The result of merging BTF, which maps the type ids and strings of the merged BTFs to the
+/// merged one.
+pub(crate) struct Merged<'a> {
+    inputs: &'a [Btf],
+    btf: Btf,
+    strings: HashMap<String, u32>,
+    // the merged id of each type of each input
+    type_ids: Vec<Vec<u32>>,
+}
+
+impl<'a> Merged<'a> {
+    /// Merges `inputs`. `section_offset(input, section)` is where the section `section` of the
+    /// input `input` starts in the merged section, which the offsets of datasec variables are
+    /// relative to.
+    pub(crate) fn new(
+        inputs: &'a [Btf],
+        section_offset: impl Fn(usize, &str) -> u64,
+    ) -> Result<Self, BtfError> {
+        let Some(first) = inputs.first() else {
+            return Err(BtfError("nothing to merge".to_owned()));
+        };
+        if inputs.iter().any(|btf| btf.big_endian != first.big_endian) {
+            return Err(BtfError("the BTFs have different endianness".to_owned()));
+        }
+
+        // number the types of all the inputs, input by input
+        let mut bases = Vec::with_capacity(inputs.len());
+        let mut total = 0;
+        for btf in inputs {
+            bases.push(total);
+            total += btf.types.len();
+        }
+        for btf in inputs {
+            for ty in &btf.types {
+                if children(ty).any(|child| child as usize >= btf.types.len()) {
+                    return Err(BtfError("type refers to an invalid type id".to_owned()));
+                }
+            }
+        }
+        let classes = equivalence_classes(inputs, &bases, total);
+
+        let mut merged = Self {
+            inputs,
+            btf: first.clone(),
+            strings: HashMap::new(),
+            type_ids: Vec::with_capacity(inputs.len()),
+        };
+        merged.index_strings();
+
+        // the first type of each class, which the others are merged into
+        let mut representatives = HashMap::new();
+        for (g, class) in classes.iter().enumerate() {
+            let _: &mut usize = representatives.entry(*class).or_insert(g);
+        }
+        let mut datasecs = HashMap::new();
+        let mut merged_ids = vec![0; total];
+        // (input, id) of the types appended to the merged BTF
+        let mut appended = Vec::new();
+        for (input, btf) in inputs.iter().enumerate() {
+            for (id, ty) in btf.types.iter().enumerate() {
+                let g = bases[input] + id;
+                merged_ids[g] = if id == 0 {
+                    0
+                } else if ty.kind == BtfKind::Datasec {
+                    let name = btf.string(ty.name_off).to_owned();
+                    match datasecs.get(&name) {
+                        Some(merged_id) => *merged_id,
+                        None => {
+                            let merged_id = if input == 0 {
+                                id as u32
+                            } else {
+                                appended.push((input, id));
+                                (merged.btf.types.len() + appended.len() - 1) as u32
+                            };
+                            let _: Option<u32> = datasecs.insert(name, merged_id);
+                            merged_id
+                        }
+                    }
+                } else if input == 0 {
+                    id as u32
+                } else if representatives[&classes[g]] != g {
+                    merged_ids[representatives[&classes[g]]]
+                } else {
+                    appended.push((input, id));
+                    (merged.btf.types.len() + appended.len() - 1) as u32
+                };
+            }
+        }
+        merged.type_ids = bases
+            .iter()
+            .zip(inputs)
+            .map(|(base, btf)| merged_ids[*base..*base + btf.types.len()].to_vec())
+            .collect();
+
+        for (input, id) in appended {
+            let mut ty = inputs[input].types[id].clone();
+            ty.name_off = merged.string(input, ty.name_off);
+            for i in ty.extra_name_indices() {
+                ty.extra[i] = merged.string(input, ty.extra[i]);
+            }
+            if ty.kind == BtfKind::Datasec {
+                // the variables are added below, with those of the other inputs
+                ty.vlen = 0;
+                ty.size_or_type = 0;
+                ty.extra.clear();
+            } else {
+                merged.remap_types(input, &mut ty);
+            }
+            merged.btf.types.push(ty);
+        }
+
+        // merge the variables of the datasecs of the other inputs
+        for (input, btf) in inputs.iter().enumerate().skip(1) {
+            for ty in btf.types.iter().filter(|ty| ty.kind == BtfKind::Datasec) {
+                let name = btf.string(ty.name_off);
+                let offset = section_offset(input, name) as u32;
+                let merged_id = datasecs[name] as usize;
+                let mut vars = Vec::with_capacity(ty.extra.len());
+                for var in ty.extra.chunks_exact(3) {
+                    vars.extend([
+                        merged.type_ids[input][var[0] as usize],
+                        var[1] + offset,
+                        var[2],
+                    ]);
+                }
+                let datasec = &mut merged.btf.types[merged_id];
+                datasec.extra.extend(vars);
+                datasec.vlen = (datasec.extra.len() / 3) as u16;
+                datasec.size_or_type = datasec.size_or_type.max(offset + ty.size_or_type);
+            }
+        }
+
+        Ok(merged)
+    }
+
+    fn index_strings(&mut self) {
+        let mut start = 0;
+        for (i, c) in self.btf.strings.iter().enumerate() {
+            if *c == 0 {
+                let s = String::from_utf8_lossy(&self.btf.strings[start..i]).into_owned();
+                let _: &mut u32 = self.strings.entry(s).or_insert(start as u32);
+                start = i + 1;
+            }
+        }
+    }
+
+    fn remap_types(&self, input: usize, ty: &mut BtfType) {
+        let ids = &self.type_ids[input];
+        if ty.refers_to_type() {
+            ty.size_or_type = ids[ty.size_or_type as usize];
+        }
+        for i in ty.extra_type_indices() {
+            ty.extra[i] = ids[ty.extra[i] as usize];
+        }
+    }
+
+    /// Returns the merged id of the type `id` of the input `input`.
+    pub(crate) fn type_id(&self, input: usize, id: u32) -> Option<u32> {
+        self.type_ids.get(input)?.get(id as usize).copied()
+    }
+
+    /// Returns the input `input`.
+    pub(crate) fn input(&self, input: usize) -> &'a Btf {
+        &self.inputs[input]
+    }
+
+    /// Returns the merged offset of the string at `offset` of the input `input`, adding it to the
+    /// merged strings if needed.
+    pub(crate) fn string(&mut self, input: usize, offset: u32) -> u32 {
+        let s = self.inputs[input].string(offset);
+        if let Some(offset) = self.strings.get(s) {
+            return *offset;
+        }
+        let merged_offset = self.btf.strings.len() as u32;
+        self.btf.strings.extend(s.as_bytes());
+        self.btf.strings.push(0);
+        let _: Option<u32> = self.strings.insert(s.to_owned(), merged_offset);
+        merged_offset
+    }
+
+    pub(crate) fn big_endian(&self) -> bool {
+        self.btf.big_endian
+    }
+
+    /// Serializes the merged BTF.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.btf.to_bytes()
+    }
+}
+
+fn children(ty: &BtfType) -> impl Iterator<Item = u32> + '_ {
+    ty.refers_to_type()
+        .then_some(ty.size_or_type)
+        .into_iter()
+        .chain(ty.extra_type_indices().into_iter().map(|i| ty.extra[i]))
+}
+
+// Returns the class of each type of `inputs`, numbered from `bases`, such that two types are in
+// the same class if and only if they're structurally the same.
+fn equivalence_classes(inputs: &[Btf], bases: &[usize], total: usize) -> Vec<usize> {
+    // start from the attributes of the types themselves, ignoring what they refer to
+    let mut classes = vec![0; total];
+    let mut keys = HashMap::new();
+    for (input, btf) in inputs.iter().enumerate() {
+        for (id, ty) in btf.types.iter().enumerate() {
+            let g = bases[input] + id;
+            let type_indices = ty.extra_type_indices();
+            let name_indices = ty.extra_name_indices();
+            let words: Vec<u32> = ty
+                .extra
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !type_indices.contains(i) && !name_indices.contains(i))
+                .map(|(_, word)| *word)
+                .collect();
+            let names: Vec<&str> = name_indices
+                .iter()
+                .map(|i| btf.string(ty.extra[*i]))
+                .collect();
+            // datasecs are merged by name instead
+            let unique = (ty.kind == BtfKind::Datasec).then_some(g);
+            let key = (
+                unique,
+                ty.kind,
+                btf.string(ty.name_off),
+                ty.vlen,
+                ty.kind_flag,
+                (!ty.refers_to_type()).then_some(ty.size_or_type),
+                words,
+                names,
+            );
+            let next = keys.len();
+            classes[g] = *keys.entry(key).or_insert(next);
+        }
+    }
+
+    // split the classes whose types refer to types of different classes until nothing changes
+    let mut count = keys.len();
+    loop {
+        let mut signatures = HashMap::new();
+        let mut refined = vec![0; total];
+        for (input, btf) in inputs.iter().enumerate() {
+            for (id, ty) in btf.types.iter().enumerate() {
+                let g = bases[input] + id;
+                let children: Vec<usize> = children(ty)
+                    .map(|child| classes[bases[input] + child as usize])
+                    .collect();
+                let next = signatures.len();
+                refined[g] = *signatures.entry((classes[g], children)).or_insert(next);
+            }
+        }
+        classes = refined;
+        if signatures.len() == count {
+            return classes;
+        }
+        count = signatures.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btf::tests::{btf_bytes, info};
+
+    // struct node { struct node *next; int value; }, plus `extra` types
+    fn list(extra: &[&[u32]]) -> Vec<u8> {
+        // [1] int
+        let int = [1, info(1, 0), 4, 32];
+        // [2] struct node
+        let node = [5, info(4, 2), 16, 10, 3, 0, 15, 1, 64];
+        // [3] struct node *
+        let ptr = [0, info(2, 0), 2];
+        let mut types: Vec<&[u32]> = vec![&int, &node, &ptr];
+        types.extend(extra);
+        btf_bytes(&types, b"\0int\0node\0next\0value\0.maps\0m\0")
+    }
+
+    #[test]
+    fn test_merge_dedup() {
+        let a = list(&[]);
+        // the same types, plus a pointer to int
+        let b = list(&[&[0, info(2, 0), 1]]);
+        let merged = Btf::parse(&merge(&[&a, &b], |_, _| 0).unwrap()).unwrap();
+        // void, int, struct node, struct node *, int *
+        assert_eq!(merged.types.len(), 5);
+        assert_eq!(merged.types[4].kind, BtfKind::Ptr);
+        assert_eq!(merged.types[4].size_or_type, 1);
+    }
+
+    #[test]
+    fn test_merge_different() {
+        let a = list(&[]);
+        // struct node with a long value
+        let b = btf_bytes(
+            &[
+                &[1, info(1, 0), 8, 64],
+                &[6, info(4, 2), 16, 11, 3, 0, 16, 1, 64],
+                &[0, info(2, 0), 2],
+            ],
+            b"\0long\0node\0next\0value\0",
+        );
+        let merged = Btf::parse(&merge(&[&a, &b], |_, _| 0).unwrap()).unwrap();
+        assert_eq!(merged.types.len(), 7);
+        assert_eq!(merged.string(merged.types[4].name_off), "long");
+        // the second struct node refers to the second pointer type
+        assert_eq!(merged.types[5].extra[1], 6);
+        assert_eq!(merged.types[6].size_or_type, 5);
+    }
+
+    #[test]
+    fn test_merge_datasec() {
+        // [4] var m of type int, [5] datasec .maps with m
+        let a = list(&[&[27, info(14, 0), 1, 1], &[21, info(15, 1), 4, 4, 0, 4]]);
+        let b = list(&[&[27, info(14, 0), 3, 1], &[21, info(15, 1), 8, 4, 0, 8]]);
+        let btfs = [Btf::parse(&a).unwrap(), Btf::parse(&b).unwrap()];
+        let merged = Merged::new(&btfs, |input, _| if input == 1 { 8 } else { 0 }).unwrap();
+        let btf = Btf::parse(&merged.to_bytes()).unwrap();
+        let datasec = &btf.types[5];
+        assert_eq!(datasec.vlen, 2);
+        assert_eq!(datasec.extra, [4, 0, 4, 6, 8, 8]);
+        assert_eq!(datasec.size_or_type, 16);
+        assert_eq!(merged.type_id(1, 5), Some(5));
+        assert_eq!(merged.type_id(1, 3), Some(3));
+    }
+
+    #[test]
+    fn test_merge_datasec_offsets() {
+        let a = list(&[&[27, info(14, 0), 1, 1], &[21, info(15, 1), 4, 4, 0, 4]]);
+        let b = list(&[&[27, info(14, 0), 3, 1], &[21, info(15, 1), 8, 4, 0, 8]]);
+        // without the offsets, the variables would overlap
+        let offset = |input, section: &str| {
+            assert_eq!(section, ".maps");
+            if input == 1 {
+                4
+            } else {
+                0
+            }
+        };
+        let btf = Btf::parse(&merge(&[&a, &b], offset).unwrap()).unwrap();
+        let datasec = &btf.types[5];
+        assert_eq!(datasec.extra, [4, 0, 4, 6, 4, 8]);
+        assert_eq!(datasec.size_or_type, 12);
+    }
+
+    #[test]
+    fn test_merge_invalid() {
+        let a = list(&[]);
+        let b = btf_bytes(&[&[0, info(2, 0), 7]], b"\0");
+        assert!(merge(&[&a, &b], |_, _| 0).is_err());
+        assert!(merge(&[], |_, _| 0).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//! Parsing and merging of BTF, the BPF type format.
+//!
+//! See <https://docs.kernel.org/bpf/btf.html> for the format.
+
+mod ext;
+mod kfunc;
+mod merge;
+mod names;
+mod rust;
+mod struct_ops;
+
+use std::str;
+
+pub(crate) use ext::{merge_ext, parse_ext, ExtInfos};
+pub(crate) use kfunc::check_kfuncs;
+pub use merge::merge;
+pub(crate) use merge::Merged;
+pub use names::{sanitize_type_name, strip_flavor, MAX_KSYM_NAME_LEN};
+pub(crate) use rust::to_rust;
+pub(crate) use struct_ops::check_struct_ops;
+use thiserror::Error;
+
+const MAGIC: u16 = 0xeb9f;
+const HEADER_SIZE: usize = 24;
+
+/// Error parsing BTF.
+#[derive(Debug, Error)]
+#[error("invalid BTF: {0}")]
+pub struct BtfError(String);
+
+/// The kind of a BTF type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum BtfKind {
+    Void,
+    Int,
+    Ptr,
+    Array,
+    Struct,
+    Union,
+    Enum,
+    Fwd,
+    Typedef,
+    Volatile,
+    Const,
+    Restrict,
+    Func,
+    FuncProto,
+    Var,
+    Datasec,
+    Float,
+    DeclTag,
+    TypeTag,
+    Enum64,
+}
+
+impl BtfKind {
+    fn from_u32(kind: u32) -> Option<Self> {
+        use BtfKind::*;
+        Some(match kind {
+            0 => Void,
+            1 => Int,
+            2 => Ptr,
+            3 => Array,
+            4 => Struct,
+            5 => Union,
+            6 => Enum,
+            7 => Fwd,
+            8 => Typedef,
+            9 => Volatile,
+            10 => Const,
+            11 => Restrict,
+            12 => Func,
+            13 => FuncProto,
+            14 => Var,
+            15 => Datasec,
+            16 => Float,
+            17 => DeclTag,
+            18 => TypeTag,
+            19 => Enum64,
+            _ => return None,
+        })
+    }
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    // Size of the data following the common part of a type with `vlen` entries.
+    fn extra_size(self, vlen: usize) -> usize {
+        use BtfKind::*;
+        match self {
+            Int | Var | DeclTag => 4,
+            Array => 12,
+            Struct | Union | Datasec | Enum64 => vlen * 12,
+            Enum | FuncProto => vlen * 8,
+            _ => 0,
+        }
+    }
+}
+
+/// A BTF type.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct BtfType {
+    pub name_off: u32,
+    pub kind: BtfKind,
+    pub vlen: u16,
+    pub kind_flag: bool,
+    /// The size of the type for integers, structs, unions, enums, floats and datasecs, otherwise
+    /// the id of the type it refers to.
+    pub size_or_type: u32,
+    /// The data following the common part, as `u32` words.
+    pub extra: Vec<u32>,
+}
+
+impl BtfType {
+    /// The id of the element type and the number of elements of an array.
+    pub(crate) fn array(&self) -> Option<(u32, u32)> {
+        (self.kind == BtfKind::Array).then(|| (self.extra[0], self.extra[2]))
+    }
+
+    // Whether `size_or_type` is a type id.
+    pub(crate) fn refers_to_type(&self) -> bool {
+        use BtfKind::*;
+        matches!(
+            self.kind,
+            Ptr | Typedef
+                | Volatile
+                | Const
+                | Restrict
+                | TypeTag
+                | Func
+                | FuncProto
+                | Var
+                | DeclTag
+        )
+    }
+
+    // Indices of the words of `extra` which are type ids.
+    fn extra_type_indices(&self) -> Vec<usize> {
+        use BtfKind::*;
+        let len = self.extra.len();
+        match self.kind {
+            Array => vec![0, 1],
+            Struct | Union => (1..len).step_by(3).collect(),
+            FuncProto => (1..len).step_by(2).collect(),
+            Datasec => (0..len).step_by(3).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Indices of the words of `extra` which are string offsets.
+    fn extra_name_indices(&self) -> Vec<usize> {
+        use BtfKind::*;
+        let len = self.extra.len();
+        match self.kind {
+            Struct | Union | Enum64 => (0..len).step_by(3).collect(),
+            Enum | FuncProto => (0..len).step_by(2).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The name offset, type id and bit offset of the members of a struct or union.
+    pub(crate) fn members(&self) -> impl Iterator<Item = (u32, u32, u32)> + '_ {
+        let members = match self.kind {
+            BtfKind::Struct | BtfKind::Union => &self.extra[..],
+            _ => &[],
+        };
+        members.chunks_exact(3).map(|m| (m[0], m[1], m[2]))
+    }
+}
+
+/// Parsed BTF. Type ids index `types`, the type 0 being `void`.
+#[derive(Clone, Debug)]
+pub(crate) struct Btf {
+    pub big_endian: bool,
+    pub types: Vec<BtfType>,
+    pub strings: Vec<u8>,
+}
+
+impl Btf {
+    /// Parses the contents of a `.BTF` section.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, BtfError> {
+        let invalid = |msg: &str| BtfError(msg.to_owned());
+
+        let magic = data.get(..2).ok_or_else(|| invalid("truncated header"))?;
+        let big_endian = match [magic[0], magic[1]] {
+            m if u16::from_le_bytes(m) == MAGIC => false,
+            m if u16::from_be_bytes(m) == MAGIC => true,
+            _ => return Err(invalid("bad magic")),
+        };
+        let u32_at = |offset: usize| -> Option<u32> {
+            let bytes = data.get(offset..offset + 4)?.try_into().unwrap();
+            Some(if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            })
+        };
+        let header = (0..4)
+            .map(|i| u32_at(8 + i * 4))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("truncated header"))?;
+        let hdr_len = u32_at(4).ok_or_else(|| invalid("truncated header"))? as usize;
+        if hdr_len < HEADER_SIZE {
+            return Err(invalid("header too short"));
+        }
+        let [type_off, type_len, str_off, str_len] = [0, 1, 2, 3].map(|i| header[i] as usize);
+        let type_start = hdr_len + type_off;
+        let type_end = type_start + type_len;
+        let strings = data
+            .get(hdr_len + str_off..hdr_len + str_off + str_len)
+            .ok_or_else(|| invalid("truncated string section"))?
+            .to_vec();
+        if type_end > data.len() {
+            return Err(invalid("truncated type section"));
+        }
+
+        let mut types = vec![BtfType {
+            name_off: 0,
+            kind: BtfKind::Void,
+            vlen: 0,
+            kind_flag: false,
+            size_or_type: 0,
+            extra: Vec::new(),
+        }];
+        let mut offset = type_start;
+        while offset < type_end {
+            let truncated = || invalid("truncated type");
+            let name_off = u32_at(offset).ok_or_else(truncated)?;
+            let info = u32_at(offset + 4).ok_or_else(truncated)?;
+            let size_or_type = u32_at(offset + 8).ok_or_else(truncated)?;
+            let kind = BtfKind::from_u32((info >> 24) & 0x1f)
+                .ok_or_else(|| invalid(&format!("unknown kind {}", (info >> 24) & 0x1f)))?;
+            let vlen = (info & 0xffff) as u16;
+            offset += 12;
+            let extra_size = kind.extra_size(vlen.into());
+            if offset + extra_size > type_end {
+                return Err(truncated());
+            }
+            let extra = (0..extra_size / 4)
+                .map(|i| u32_at(offset + i * 4).unwrap())
+                .collect();
+            offset += extra_size;
+            types.push(BtfType {
+                name_off,
+                kind,
+                vlen,
+                kind_flag: info >> 31 == 1,
+                size_or_type,
+                extra,
+            });
+        }
+        Ok(Self {
+            big_endian,
+            types,
+            strings,
+        })
+    }
+
+    /// Serializes the BTF to the contents of a `.BTF` section.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut types = Vec::new();
+        for ty in &self.types[1..] {
+            let info =
+                (u32::from(ty.kind_flag) << 31) | (ty.kind.to_u32() << 24) | u32::from(ty.vlen);
+            for word in [ty.name_off, info, ty.size_or_type].iter().chain(&ty.extra) {
+                types.extend(if self.big_endian {
+                    word.to_be_bytes()
+                } else {
+                    word.to_le_bytes()
+                });
+            }
+        }
+        let mut data = Vec::with_capacity(HEADER_SIZE + types.len() + self.strings.len());
+        let magic = if self.big_endian {
+            MAGIC.to_be_bytes()
+        } else {
+            MAGIC.to_le_bytes()
+        };
+        data.extend(magic);
+        data.extend([1, 0]);
+        for word in [HEADER_SIZE, 0, types.len(), types.len(), self.strings.len()] {
+            let word = word as u32;
+            data.extend(if self.big_endian {
+                word.to_be_bytes()
+            } else {
+                word.to_le_bytes()
+            });
+        }
+        data.extend(types);
+        data.extend(&self.strings);
+        data
+    }
+
+    /// Returns the string at `offset` in the string section.
+    pub(crate) fn string(&self, offset: u32) -> &str {
+        let s = self.strings.get(offset as usize..).unwrap_or_default();
+        let end = s.iter().position(|c| *c == 0).unwrap_or(s.len());
+        str::from_utf8(&s[..end]).unwrap_or_default()
+    }
+
+    /// Returns the type `id`.
+    pub(crate) fn get(&self, id: u32) -> Option<&BtfType> {
+        self.types.get(id as usize)
+    }
+
+    /// Follows typedefs and type modifiers from the type `id`.
+    pub(crate) fn resolve(&self, mut id: u32) -> Option<&BtfType> {
+        // bound the walk in case of a reference cycle
+        for _ in 0..self.types.len() {
+            let ty = self.get(id)?;
+            match ty.kind {
+                BtfKind::Typedef
+                | BtfKind::Volatile
+                | BtfKind::Const
+                | BtfKind::Restrict
+                | BtfKind::TypeTag => id = ty.size_or_type,
+                _ => return Some(ty),
+            }
+        }
+        None
+    }
+
+    /// Returns the size in bytes of the type `id`.
+    pub(crate) fn size_of(&self, id: u32) -> Option<u32> {
+        let ty = self.resolve(id)?;
+        match ty.kind {
+            BtfKind::Int
+            | BtfKind::Struct
+            | BtfKind::Union
+            | BtfKind::Enum
+            | BtfKind::Enum64
+            | BtfKind::Float
+            | BtfKind::Datasec => Some(ty.size_or_type),
+            // BPF pointers are always 64 bits
+            BtfKind::Ptr => Some(8),
+            BtfKind::Array => {
+                let (elem, nelems) = ty.array()?;
+                self.size_of(elem)?.checked_mul(nelems)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the id of the first type of kind `kind` named `name`.
+    pub(crate) fn find(&self, kind: BtfKind, name: &str) -> Option<u32> {
+        self.types
+            .iter()
+            .position(|ty| ty.kind == kind && self.string(ty.name_off) == name)
+            .map(|id| id as u32)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds little endian BTF from `types`, given as the words of each type, and `strings`.
+    pub(crate) fn btf_bytes(types: &[&[u32]], strings: &[u8]) -> Vec<u8> {
+        let types: Vec<u8> = types
+            .iter()
+            .flat_map(|ty| ty.iter())
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let mut data = Vec::new();
+        data.extend(MAGIC.to_le_bytes());
+        data.extend([1, 0]);
+        for word in [HEADER_SIZE, 0, types.len(), types.len(), strings.len()] {
+            data.extend((word as u32).to_le_bytes());
+        }
+        data.extend(types);
+        data.extend(strings);
+        data
+    }
+
+    pub(crate) fn info(kind: u32, vlen: u32) -> u32 {
+        (kind << 24) | vlen
+    }
+
+    #[test]
+    fn test_parse() {
+        let strings = b"\0int\0arr\0s\0a\0";
+        let data = btf_bytes(
+            &[
+                // [1] int, 4 bytes
+                &[1, info(1, 0), 4, 32],
+                // [2] int[3]
+                &[0, info(3, 0), 0, 1, 1, 3],
+                // [3] typedef arr = int[3]
+                &[5, info(8, 0), 2],
+                // [4] struct s { arr a; }
+                &[9, info(4, 1), 12, 11, 3, 0],
+            ],
+            strings,
+        );
+        let btf = Btf::parse(&data).unwrap();
+        assert_eq!(btf.types.len(), 5);
+        assert_eq!(btf.find(BtfKind::Struct, "s"), Some(4));
+        assert_eq!(btf.size_of(3), Some(12));
+        assert_eq!(btf.resolve(3).unwrap().array(), Some((1, 3)));
+        let members: Vec<_> = btf.get(4).unwrap().members().collect();
+        assert_eq!(members, [(11, 3, 0)]);
+        assert_eq!(btf.string(11), "a");
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let data = btf_bytes(&[&[1, info(1, 0), 4, 32], &[0, info(2, 0), 1]], b"\0int\0");
+        assert_eq!(Btf::parse(&data).unwrap().to_bytes(), data);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Btf::parse(b"").is_err());
+        assert!(Btf::parse(&[0; 24]).is_err());
+        let mut data = btf_bytes(&[&[1, info(1, 0), 4, 32]], b"\0int\0");
+        // the type section extends past the data
+        data[12] = 100;
+        assert!(Btf::parse(&data).is_err());
+        let data = btf_bytes(&[&[1, info(30, 0), 4]], b"\0int\0");
+        assert!(Btf::parse(&data).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! The names bpf-linker gives types and functions in BTF.
+//!
+//! Rust names aren't valid C identifiers, eg `MyStruct<u64>`, and the kernel rejects them, so
+//! they're mangled in the BTF bpf-linker generates. User space tooling reading that BTF can use
+//! these functions to find the BTF name of a Rust type.
+
+use crate::hash::siphash13;
+
+/// The longest BTF name generated. `KSYM_NAME_LEN` of the kernel, set to the lowest value found
+/// across kernel versions for backward compatibility.
+pub const MAX_KSYM_NAME_LEN: usize = 128;
+
+/// Returns the BTF name of the Rust type or function `name`.
+///
+/// The characters which aren't valid in C identifiers are replaced by their code point in
+/// uppercase hex between underscores, eg `MyStruct<u64>` becomes `MyStruct_3C_u64_3E_`. Names
+/// longer than [`MAX_KSYM_NAME_LEN`] are then truncated and suffixed with `_` and the SipHash-1-3
+/// of the whole name with zero keys, in lowercase hex, to stay unique.
+pub fn sanitize_type_name<T: AsRef<str>>(name: T) -> String {
+    let n: String = name
+        .as_ref()
+        .chars()
+        .map(|ch| {
+            // Characters which are valid in C type names (alphanumeric and `_`).
+            if matches!(ch, '0'..='9' | 'A'..='Z' | 'a'..='z' | '_') {
+                ch.to_string()
+            } else {
+                format!("_{:X}_", ch as u32)
+            }
+        })
+        .collect();
+
+    // we trim type name if it is too long
+    if n.len() > MAX_KSYM_NAME_LEN {
+        let hash = format!("{:x}", siphash13(n.as_bytes()));
+        // leave space for underscore
+        let trim = MAX_KSYM_NAME_LEN - hash.len() - 1;
+        return format!("{}_{hash}", &n[..trim]);
+    }
+
+    n
+}
+
+/// Strips the flavor of `name`, the `___` suffix of shadow types like `task_struct___older`,
+/// which libbpf ignores when matching types against the kernel for CO-RE relocations. Like
+/// libbpf, the separator is the last `___` neither preceded nor followed by another `_`.
+///
+/// Flavored structs are named after the type they shadow in BTF, eg `task_struct`, before their
+/// name is sanitized.
+pub fn strip_flavor(name: &str) -> &str {
+    let bytes = name.as_bytes();
+    (1..bytes.len().saturating_sub(3))
+        .rev()
+        .find(|&i| bytes[i - 1] != b'_' && &bytes[i..i + 3] == b"___" && bytes[i + 3] != b'_')
+        .map_or(name, |i| &name[..i])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strip_generics() {
+        let name = "MyStruct<u64>";
+        assert_eq!(sanitize_type_name(name), "MyStruct_3C_u64_3E_");
+
+        let name = "MyStruct<u64, u64>";
+        assert_eq!(sanitize_type_name(name), "MyStruct_3C_u64_2C__20_u64_3E_");
+
+        let name = "my_function<aya_bpf::BpfContext>";
+        assert_eq!(
+            sanitize_type_name(name),
+            "my_function_3C_aya_bpf_3A__3A_BpfContext_3E_"
+        );
+
+        let name = "my_function<aya_bpf::BpfContext, aya_log_ebpf::WriteToBuf>";
+        assert_eq!(
+            sanitize_type_name(name),
+            "my_function_3C_aya_bpf_3A__3A_BpfContext_2C__20_aya_log_ebpf_3A__3A_WriteToBuf_3E_"
+        );
+
+        let name = "PerfEventArray<[u8; 32]>";
+        assert_eq!(
+            sanitize_type_name(name),
+            "PerfEventArray_3C__5B_u8_3B__20_32_5D__3E_"
+        );
+
+        let name = "my_function<aya_bpf::this::is::a::very::long::namespace::BpfContext, aya_log_ebpf::this::is::a::very::long::namespace::WriteToBuf>";
+        let san = sanitize_type_name(name);
+
+        assert_eq!(san.len(), 128);
+        assert_eq!(
+            san,
+            "my_function_3C_aya_bpf_3A__3A_this_3A__3A_is_3A__3A_a_3A__3A_very_3A__3A_long_3A__3A_namespace_3A__3A_BpfContex_94e4085604b3142f"
+        );
+    }
+
+    #[test]
+    fn test_strip_flavor() {
+        assert_eq!(strip_flavor("task_struct___older"), "task_struct");
+        assert_eq!(strip_flavor("task_struct___5_10"), "task_struct");
+        assert_eq!(strip_flavor("a___b___c"), "a___b");
+        assert_eq!(strip_flavor("task_struct"), "task_struct");
+        assert_eq!(strip_flavor("task_struct___"), "task_struct___");
+        assert_eq!(strip_flavor("___older"), "___older");
+        assert_eq!(strip_flavor("a____b"), "a____b");
+    }
+}
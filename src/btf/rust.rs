@@ -0,0 +1,324 @@
+//! Printing of BTF structs and unions as Rust type definitions, for user space code sharing types
+//! with BPF programs.
+
+use std::{collections::HashMap, fmt::Write as _};
+
+use super::{Btf, BtfKind};
+
+/// Prints the structs and unions of `btf` as `#[repr(C)]` Rust definitions, each followed by an
+/// assertion of its size.
+///
+/// Pointers become `u64`, as BPF pointers are always 64 bits whatever the user space. Members
+/// which can't be represented, eg bitfields, are left to the padding fields `__pad_<offset>`.
+/// Anonymous structs and unions are named `__anon_<id>`.
+pub(crate) fn to_rust(btf: &Btf) -> String {
+    let mut printer = Printer {
+        btf,
+        names: HashMap::new(),
+        aligns: HashMap::new(),
+    };
+    let mut ids = Vec::new();
+    for (id, ty) in btf.types.iter().enumerate() {
+        let id = id as u32;
+        if !matches!(ty.kind, BtfKind::Struct | BtfKind::Union) {
+            continue;
+        }
+        let name = match btf.string(ty.name_off) {
+            "" => format!("__anon_{id}"),
+            name => rust_ident(name),
+        };
+        // merged BTF may define a type more than once
+        if printer.names.values().any(|other| *other == name) {
+            continue;
+        }
+        let _: Option<String> = printer.names.insert(id, name);
+        ids.push(id);
+    }
+
+    let mut out = String::new();
+    for id in ids {
+        printer.print(&mut out, id);
+    }
+    out
+}
+
+struct Printer<'a> {
+    btf: &'a Btf,
+    names: HashMap<u32, String>,
+    aligns: HashMap<u32, u32>,
+}
+
+// A member of a struct or union which has a Rust type.
+struct Member {
+    name: String,
+    ty: String,
+    offset: u32,
+    size: u32,
+}
+
+impl Printer<'_> {
+    fn print(&mut self, out: &mut String, id: u32) {
+        let btf = self.btf;
+        let ty = &btf.types[id as usize];
+        let name = self.names[&id].clone();
+        let size = ty.size_or_type;
+        let union = ty.kind == BtfKind::Union;
+
+        let mut members = Vec::new();
+        for (i, (name_off, member_ty, offset)) in ty.members().enumerate() {
+            // with kind_flag, the bitfield size is in the top 8 bits of the offset
+            let (bitfield, offset) = if ty.kind_flag {
+                (offset >> 24, offset & 0xff_ffff)
+            } else {
+                (0, offset)
+            };
+            if bitfield != 0 || !offset.is_multiple_of(8) {
+                continue;
+            }
+            let (Some(rust_ty), Some(member_size)) =
+                (self.rust_type(member_ty), btf.size_of(member_ty))
+            else {
+                continue;
+            };
+            let name = match btf.string(name_off) {
+                "" => format!("__anon_{i}"),
+                name => rust_ident(name),
+            };
+            members.push(Member {
+                name,
+                ty: rust_ty,
+                offset: offset / 8,
+                size: member_size,
+            });
+        }
+        members.sort_by_key(|member| member.offset);
+
+        let packed = self.is_packed(id);
+        let repr = if packed { "C, packed" } else { "C" };
+        let keyword = if union { "union" } else { "struct" };
+        writeln!(out, "#[repr({repr})]").unwrap();
+        writeln!(out, "#[derive(Clone, Copy)]").unwrap();
+        writeln!(out, "pub {keyword} {name} {{").unwrap();
+        let mut end = 0;
+        for member in members {
+            if union {
+                end = end.max(member.size);
+            } else {
+                // overlaps the previous member, eg after a bitfield
+                if member.offset < end {
+                    continue;
+                }
+                if member.offset > end {
+                    writeln!(out, "    pub __pad_{end}: [u8; {}],", member.offset - end).unwrap();
+                }
+                end = member.offset + member.size;
+            }
+            writeln!(out, "    pub {}: {},", member.name, member.ty).unwrap();
+        }
+        if end < size {
+            if union {
+                writeln!(out, "    pub __pad_0: [u8; {size}],").unwrap();
+            } else {
+                writeln!(out, "    pub __pad_{end}: [u8; {}],", size - end).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        writeln!(
+            out,
+            "const _: () = assert!(core::mem::size_of::<{name}>() == {size});\n"
+        )
+        .unwrap();
+    }
+
+    // Returns the Rust type of the type `id`, None if it has none.
+    fn rust_type(&self, id: u32) -> Option<String> {
+        let btf = self.btf;
+        let id = self.resolve(id)?;
+        let ty = btf.get(id)?;
+        let size = ty.size_or_type;
+        Some(match ty.kind {
+            BtfKind::Int => {
+                let encoding = (ty.extra[0] >> 24) & 0xf;
+                if encoding & 0x4 != 0 {
+                    return Some("bool".to_owned());
+                }
+                int_type(size, encoding & 0x1 != 0)?
+            }
+            BtfKind::Enum | BtfKind::Enum64 => int_type(size, ty.kind_flag)?,
+            BtfKind::Float => match size {
+                4 => "f32".to_owned(),
+                8 => "f64".to_owned(),
+                _ => return None,
+            },
+            BtfKind::Ptr => "u64".to_owned(),
+            BtfKind::Array => {
+                let (elem, nelems) = ty.array()?;
+                format!("[{}; {nelems}]", self.rust_type(elem)?)
+            }
+            BtfKind::Struct | BtfKind::Union => self.names.get(&id)?.clone(),
+            _ => return None,
+        })
+    }
+
+    // Follows typedefs and type modifiers from the type `id`, returning the id of the type
+    // reached.
+    fn resolve(&self, mut id: u32) -> Option<u32> {
+        for _ in 0..self.btf.types.len() {
+            let ty = self.btf.get(id)?;
+            match ty.kind {
+                BtfKind::Typedef
+                | BtfKind::Volatile
+                | BtfKind::Const
+                | BtfKind::Restrict
+                | BtfKind::TypeTag => id = ty.size_or_type,
+                _ => return Some(id),
+            }
+        }
+        None
+    }
+
+    // Returns the alignment of the Rust type of the type `id`.
+    fn align(&mut self, id: u32) -> u32 {
+        let Some(id) = self.resolve(id) else {
+            return 1;
+        };
+        if let Some(align) = self.aligns.get(&id) {
+            return *align;
+        }
+        // guards against malformed BTF whose types contain themselves
+        let _: Option<u32> = self.aligns.insert(id, 1);
+        let btf = self.btf;
+        let ty = &btf.types[id as usize];
+        let align = match ty.kind {
+            BtfKind::Int | BtfKind::Enum | BtfKind::Enum64 | BtfKind::Float => {
+                // 16 byte integers are represented as [u64; 2]
+                ty.size_or_type.clamp(1, 8)
+            }
+            BtfKind::Ptr => 8,
+            BtfKind::Array => ty.array().map_or(1, |(elem, _)| self.align(elem)),
+            BtfKind::Struct | BtfKind::Union => {
+                if self.is_packed(id) {
+                    1
+                } else {
+                    self.natural_align(id)
+                }
+            }
+            _ => 1,
+        };
+        let _: Option<u32> = self.aligns.insert(id, align);
+        align
+    }
+
+    // The alignment of a struct or union if it isn't packed: the largest alignment of its
+    // members.
+    fn natural_align(&mut self, id: u32) -> u32 {
+        let ty = &self.btf.types[id as usize];
+        let members: Vec<u32> = ty.members().map(|(_, ty, _)| ty).collect();
+        members
+            .into_iter()
+            .map(|member| self.align(member))
+            .max()
+            .unwrap_or(1)
+    }
+
+    // Whether the struct or union `id` is laid out tighter than its members' alignments allow.
+    fn is_packed(&mut self, id: u32) -> bool {
+        let ty = &self.btf.types[id as usize];
+        let size = ty.size_or_type;
+        let kind_flag = ty.kind_flag;
+        let members: Vec<(u32, u32)> = ty.members().map(|(_, ty, offset)| (ty, offset)).collect();
+        let align = self.natural_align(id);
+        !size.is_multiple_of(align)
+            || members.into_iter().any(|(member, offset)| {
+                let (bitfield, offset) = if kind_flag {
+                    (offset >> 24, offset & 0xff_ffff)
+                } else {
+                    (0, offset)
+                };
+                bitfield == 0 && !offset.is_multiple_of(self.align(member) * 8)
+            })
+    }
+}
+
+fn int_type(size: u32, signed: bool) -> Option<String> {
+    let bits = match size {
+        1 | 2 | 4 | 8 => size * 8,
+        16 => return Some("[u64; 2]".to_owned()),
+        _ => return None,
+    };
+    Some(format!("{}{bits}", if signed { 'i' } else { 'u' }))
+}
+
+// Makes `name` a valid Rust identifier.
+fn rust_ident(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do",
+        "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in",
+        "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+        "return", "static", "struct", "trait", "true", "try", "type", "typeof", "union", "unsafe",
+        "unsized", "use", "virtual", "where", "while", "yield",
+    ];
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    if KEYWORDS.contains(&ident.as_str()) {
+        ident.insert_str(0, "r#");
+    } else if matches!(ident.as_str(), "self" | "Self" | "super" | "crate" | "_") {
+        // can't be raw identifiers
+        ident.push('_');
+    }
+    ident
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btf::tests::{btf_bytes, info};
+
+    #[test]
+    fn test_to_rust() {
+        let data = btf_bytes(
+            &[
+                // [1] unsigned int
+                &[1, info(1, 0), 4, 32],
+                // [2] char, signed
+                &[5, info(1, 0), 1, (1 << 24) | 8],
+                // [3] char[16]
+                &[0, info(3, 0), 0, 2, 1, 16],
+                // [4] struct event { unsigned int pid; char comm[16]; void *ptr; }
+                &[10, info(4, 3), 32, 16, 1, 0, 20, 3, 32, 25, 5, 192],
+                // [5] void *
+                &[0, info(2, 0), 0],
+                // [6] struct type { char a; unsigned int b; } __attribute__((packed))
+                &[29, info(4, 2), 5, 34, 2, 0, 36, 1, 8],
+            ],
+            b"\0int\0char\0event\0pid\0comm\0ptr\0type\0a\0b\0",
+        );
+        assert_eq!(
+            to_rust(&Btf::parse(&data).unwrap()),
+            "#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct event {
+    pub pid: u32,
+    pub comm: [i8; 16],
+    pub __pad_20: [u8; 4],
+    pub ptr: u64,
+}
+const _: () = assert!(core::mem::size_of::<event>() == 32);
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct r#type {
+    pub a: i8,
+    pub b: u32,
+}
+const _: () = assert!(core::mem::size_of::<r#type>() == 5);
+
+"
+        );
+    }
+}
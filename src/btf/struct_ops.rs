@@ -0,0 +1,131 @@
+//! Checking of struct_ops maps against the programs their members point to.
+
+use super::{Btf, BtfKind};
+
+/// Checks the struct_ops maps of the datasec `section` of `btf`, given the `relocations` of the
+/// section as the offset they apply to and the symbol they refer to. Returns a message for each
+/// member initialized with a function whose prototype doesn't match the function pointer type of
+/// the member, and for each member initialized with a function which isn't a function pointer.
+pub(crate) fn check_struct_ops(
+    btf: &Btf,
+    section: &str,
+    relocations: &[(u64, String)],
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    let Some(datasec) = btf
+        .find(BtfKind::Datasec, section)
+        .and_then(|id| btf.get(id))
+    else {
+        return errors;
+    };
+    for var in datasec.extra.chunks_exact(3) {
+        let (var, var_offset) = (var[0], u64::from(var[1]));
+        let Some(var) = btf.get(var) else {
+            continue;
+        };
+        let map = btf.string(var.name_off);
+        let Some(ty) = btf.resolve(var.size_or_type) else {
+            continue;
+        };
+        for (name_off, member_type, bit_offset) in ty.members() {
+            // the high bits are the size of bitfields, which can't hold pointers anyway
+            let bit_offset = if ty.kind_flag {
+                bit_offset & 0xff_ffff
+            } else {
+                bit_offset
+            };
+            let offset = var_offset + u64::from(bit_offset / 8);
+            let Some((_, function)) = relocations.iter().find(|(at, _)| *at == offset) else {
+                continue;
+            };
+            let member = btf.string(name_off);
+            let Some(expected) = btf
+                .resolve(member_type)
+                .filter(|ty| ty.kind == BtfKind::Ptr)
+                .and_then(|ty| btf.resolve(ty.size_or_type))
+                .filter(|ty| ty.kind == BtfKind::FuncProto)
+            else {
+                errors.push(format!(
+                    "`{map}.{member}` is initialized with `{function}` but isn't a function pointer"
+                ));
+                continue;
+            };
+            let Some(proto) = btf
+                .find(BtfKind::Func, function)
+                .and_then(|id| btf.get(id))
+                .and_then(|func| btf.get(func.size_or_type))
+            else {
+                continue;
+            };
+            if proto.vlen != expected.vlen {
+                errors.push(format!(
+                    "`{map}.{member}` takes {} arguments but `{function}` takes {}",
+                    expected.vlen, proto.vlen
+                ));
+            }
+            let returns_void = |ty: u32| btf.resolve(ty).is_some_and(|ty| ty.kind == BtfKind::Void);
+            if returns_void(expected.size_or_type) != returns_void(proto.size_or_type) {
+                let returns = |ty: u32| {
+                    if returns_void(ty) {
+                        "nothing"
+                    } else {
+                        "a value"
+                    }
+                };
+                errors.push(format!(
+                    "`{map}.{member}` returns {} but `{function}` returns {}",
+                    returns(expected.size_or_type),
+                    returns(proto.size_or_type)
+                ));
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btf::tests::{btf_bytes, info};
+
+    #[test]
+    fn test_check_struct_ops() {
+        let btf = btf_bytes(
+            &[
+                // [1] int
+                &[1, info(1, 0), 4, (1 << 24) | 32],
+                // [2] int (*)(int, int)
+                &[0, info(13, 2), 1, 0, 1, 0, 1],
+                // [3] ptr to [2]
+                &[0, info(2, 0), 2],
+                // [4] struct ops { int (*init)(int, int); int flags; int (*exit)(int, int); }
+                &[5, info(4, 3), 24, 9, 3, 0, 14, 1, 64, 20, 3, 128],
+                // [5] int (int)
+                &[0, info(13, 1), 1, 0, 1],
+                // [6] my_init
+                &[25, info(12, 1), 2],
+                // [7] my_exit
+                &[33, info(12, 1), 5],
+                // [8] my_ops
+                &[41, info(14, 0), 4, 1],
+                // [9] .struct_ops
+                &[48, info(15, 1), 24, 8, 0, 24],
+            ],
+            b"\0int\0ops\0init\0flags\0exit\0my_init\0my_exit\0my_ops\0.struct_ops\0",
+        );
+        let btf = Btf::parse(&btf).unwrap();
+        let relocations = [
+            (0, "my_init".to_owned()),
+            (8, "my_exit".to_owned()),
+            (16, "my_exit".to_owned()),
+        ];
+        assert_eq!(
+            check_struct_ops(&btf, ".struct_ops", &relocations),
+            [
+                "`my_ops.flags` is initialized with `my_exit` but isn't a function pointer",
+                "`my_ops.exit` takes 2 arguments but `my_exit` takes 1",
+            ]
+        );
+        assert!(check_struct_ops(&btf, ".struct_ops.link", &relocations).is_empty());
+    }
+}
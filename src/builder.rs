@@ -0,0 +1,394 @@
+//! A builder for [`LinkerOptions`] for library users.
+//!
+//! The builder starts from [`LinkerOptions::default`], the defaults of the `bpf-linker` command
+//! line, and validates the options when building them. The resulting [`LinkerOptions`] can still be
+//! adjusted field by field.
+
+use std::{borrow::Cow, ffi::CString, path::PathBuf};
+
+use crate::{
+    cli::is_bpf_target, linker::path_to_cstring, BpfTrap, CodeModel, Cpu, DiagnosticCategory,
+    DiagnosticLevel, ExtraOutput, LinkerError, LinkerInput, LinkerOptions, OptLevel, OutputType,
+    PassOptions, RelocModel, UndefinedSymbols,
+};
+
+impl LinkerOptions {
+    /// Returns a builder for linker options.
+    ///
+    /// ```no_run
+    /// use bpf_linker::{Cpu, Linker, LinkerOptions};
+    ///
+    /// let options = LinkerOptions::builder()
+    ///     .target("bpfel-unknown-none")
+    ///     .cpu(Cpu::V3)
+    ///     .feature("+alu32")
+    ///     .input("prog.o")
+    ///     .output("prog.bpf.o")
+    ///     .export("prog")
+    ///     .build()?;
+    /// Linker::new(options)?.link()?;
+    /// # Ok::<(), bpf_linker::LinkerError>(())
+    /// ```
+    pub fn builder() -> LinkerOptionsBuilder {
+        LinkerOptionsBuilder::default()
+    }
+}
+
+/// Builder for [`LinkerOptions`], see [`LinkerOptions::builder`].
+#[derive(Debug, Default)]
+pub struct LinkerOptionsBuilder {
+    options: LinkerOptions,
+    output: Option<PathBuf>,
+    features: Vec<String>,
+    allow_non_bpf_target: bool,
+}
+
+impl LinkerOptionsBuilder {
+    /// Sets the target triple, eg `bpfel-unknown-none`. Inferred from the inputs if not set.
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.options.target = Some(target.into());
+        self
+    }
+
+    /// Accepts a target which isn't a BPF target.
+    pub fn allow_non_bpf_target(mut self, allow: bool) -> Self {
+        self.allow_non_bpf_target = allow;
+        self
+    }
+
+    /// Sets the BPF processor.
+    pub fn cpu(mut self, cpu: Cpu) -> Self {
+        self.options.cpu = cpu;
+        self
+    }
+
+    /// Enables (`+feature`) or disables (`-feature`) a CPU feature.
+    pub fn feature(mut self, feature: impl Into<String>) -> Self {
+        self.features.push(feature.into());
+        self
+    }
+
+    /// Adds an input file.
+    pub fn input(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.inputs.push(LinkerInput::File(path.into()));
+        self
+    }
+
+    /// Adds an in-memory input. `name` identifies it in logs and errors.
+    pub fn input_buffer(mut self, name: impl Into<String>, bytes: impl Into<Vec<u8>>) -> Self {
+        self.options.inputs.push(LinkerInput::Buffer {
+            name: name.into(),
+            bytes: bytes.into(),
+        });
+        self
+    }
+
+    /// Sets where to write the output. Required.
+    pub fn output(mut self, output: impl Into<PathBuf>) -> Self {
+        self.output = Some(output.into());
+        self
+    }
+
+    /// Sets the output format. Defaults to [`OutputType::Object`].
+    pub fn output_type(mut self, output_type: OutputType) -> Self {
+        self.options.output_type = output_type;
+        self
+    }
+
+    /// Adds an output written in addition to the main one, optimized at `optimize` or at the
+    /// optimization level of the main output when `None`.
+    pub fn extra_output(
+        mut self,
+        output_type: OutputType,
+        path: impl Into<PathBuf>,
+        optimize: Option<OptLevel>,
+    ) -> Self {
+        self.options.extra_outputs.push(ExtraOutput {
+            output_type,
+            path: path.into(),
+            optimize,
+        });
+        self
+    }
+
+    /// Adds a directory to the library search path.
+    pub fn lib(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.libs.push(path.into());
+        self
+    }
+
+    /// Only links the archive members whose name matches `pattern`, or one of the other patterns
+    /// added. All the members are linked by default.
+    pub fn archive_member_filter(mut self, pattern: impl Into<String>) -> Self {
+        self.options.archive_member_filters.push(pattern.into());
+        self
+    }
+
+    /// Adds a library archive, whose members are only linked when they define an undefined
+    /// symbol.
+    pub fn library(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.libraries.push(path.into());
+        self
+    }
+
+    /// Sets the optimization level. Defaults to [`OptLevel::Default`].
+    pub fn optimize(mut self, optimize: OptLevel) -> Self {
+        self.options.optimize = optimize;
+        self
+    }
+
+    /// Sets the optimization level of code generation. Defaults to the optimization level.
+    pub fn codegen_optimize(mut self, optimize: OptLevel) -> Self {
+        self.options.codegen_optimize = Some(optimize);
+        self
+    }
+
+    /// Exports a symbol.
+    pub fn export(mut self, symbol: impl Into<String>) -> Self {
+        let _: bool = self
+            .options
+            .export_symbols
+            .insert(Cow::Owned(symbol.into()));
+        self
+    }
+
+    /// Links the inputs into the bitcode module at `path` instead of an empty module.
+    pub fn base_module(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.base_module = Some(path.into());
+        self
+    }
+
+    /// Never removes the definitions matching the glob `pattern`.
+    pub fn keep_symbol(mut self, pattern: impl Into<String>) -> Self {
+        self.options.keep_symbols.push(pattern.into());
+        self
+    }
+
+    /// Pins the BTF-defined maps matching the glob `pattern` by name, under the pin root path of
+    /// the loader.
+    pub fn pin_maps(mut self, pattern: impl Into<String>) -> Self {
+        self.options.pin_maps.push(pattern.into());
+        self
+    }
+
+    /// Keeps the structs, unions and enums whose path matches the glob `pattern` in BTF, even when
+    /// nothing references them.
+    pub fn btf_keep_type(mut self, pattern: impl Into<String>) -> Self {
+        self.options.btf_keep_types.push(pattern.into());
+        self
+    }
+
+    /// Moves the definition of `symbol` to `section` before optimization.
+    pub fn symbol_section(mut self, symbol: impl Into<String>, section: impl Into<String>) -> Self {
+        self.options
+            .symbol_sections
+            .push((symbol.into(), section.into()));
+        self
+    }
+
+    /// Traces what internalization and optimization do to `symbol`, see
+    /// [`LinkerStats::symbol_explanations`](crate::LinkerStats::symbol_explanations).
+    pub fn why_internalized(mut self, symbol: impl Into<String>) -> Self {
+        self.options.why_internalized.push(symbol.into());
+        self
+    }
+
+    /// Emits BTF.
+    pub fn btf(mut self, btf: bool) -> Self {
+        self.options.btf = btf;
+        self
+    }
+
+    /// Checks the kfuncs the program calls against the kernel BTF at `path`, eg
+    /// `/sys/kernel/btf/vmlinux`.
+    pub fn kernel_btf(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.kernel_btf = Some(path.into());
+        self
+    }
+
+    /// Skips the inputs whose bitcode is identical to that of one already linked. Enabled by
+    /// default.
+    pub fn dedup_inputs(mut self, dedup_inputs: bool) -> Self {
+        self.options.dedup_inputs = dedup_inputs;
+        self
+    }
+
+    /// Removes the maps which no program references.
+    pub fn gc_maps(mut self, gc_maps: bool) -> Self {
+        self.options.gc_maps = gc_maps;
+        self
+    }
+
+    /// Sets the tuning of the optimization pipeline.
+    pub fn pass_options(mut self, pass_options: PassOptions) -> Self {
+        self.options.pass_options = pass_options;
+        self
+    }
+
+    /// Runs the LLVM verifier on the linked and optimized module. Enabled by default in debug
+    /// builds.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.options.verify = verify;
+        self
+    }
+
+    /// Sets the OSABI byte of the ELF header of the emitted object, eg `3` for `ELFOSABI_LINUX`.
+    pub fn elf_osabi(mut self, osabi: u8) -> Self {
+        self.options.elf_osabi = Some(osabi);
+        self
+    }
+
+    /// Sets the `e_flags` of the ELF header of the emitted object.
+    pub fn elf_flags(mut self, flags: u32) -> Self {
+        self.options.elf_flags = Some(flags);
+        self
+    }
+
+    /// Parses the emitted object with aya-obj, reporting failures as
+    /// [`DiagnosticCategory::AyaObj`]. Requires the `aya-obj` feature.
+    pub fn check_aya_obj(mut self, check: bool) -> Self {
+        self.options.check_aya_obj = check;
+        self
+    }
+
+    /// Measures the peak RSS of each stage of the link, see
+    /// [`LinkerStats::stage_peak_rss`](crate::LinkerStats::stage_peak_rss).
+    pub fn measure_stage_memory(mut self, measure: bool) -> Self {
+        self.options.measure_stage_memory = measure;
+        self
+    }
+
+    /// Sets what to do with the symbols still undefined after linking.
+    pub fn undefined_symbols(mut self, undefined_symbols: UndefinedSymbols) -> Self {
+        self.options.undefined_symbols = undefined_symbols;
+        self
+    }
+
+    /// Sets what to do with traps.
+    pub fn bpf_trap(mut self, bpf_trap: BpfTrap) -> Self {
+        self.options.bpf_trap = bpf_trap;
+        self
+    }
+
+    /// Sets the code model of the generated code.
+    pub fn code_model(mut self, code_model: CodeModel) -> Self {
+        self.options.code_model = code_model;
+        self
+    }
+
+    /// Sets the relocation model of the generated code.
+    pub fn reloc_model(mut self, reloc_model: RelocModel) -> Self {
+        self.options.reloc_model = reloc_model;
+        self
+    }
+
+    /// Sets the level of a diagnostic category.
+    pub fn diagnostic_level(
+        mut self,
+        category: DiagnosticCategory,
+        level: DiagnosticLevel,
+    ) -> Self {
+        let _: Option<DiagnosticLevel> = self.options.diagnostic_levels.insert(category, level);
+        self
+    }
+
+    /// Writes the output to a temporary file renamed once complete. Enabled by default.
+    pub fn atomic_output(mut self, atomic_output: bool) -> Self {
+        self.options.atomic_output = atomic_output;
+        self
+    }
+
+    /// Passes an argument to LLVM.
+    pub fn llvm_arg(mut self, arg: impl Into<String>) -> Self {
+        self.options.llvm_args.push(arg.into());
+        self
+    }
+
+    /// Validates the options and builds them.
+    pub fn build(self) -> Result<LinkerOptions, LinkerError> {
+        let Self {
+            mut options,
+            output,
+            features,
+            allow_non_bpf_target,
+        } = self;
+        options.output = output.ok_or(LinkerError::MissingOutput)?;
+        let _: CString = path_to_cstring(&options.output)?;
+        if let Some(target) = &options.target {
+            if !allow_non_bpf_target && !is_bpf_target(target) {
+                return Err(LinkerError::InvalidTarget(target.clone()));
+            }
+        }
+        for feature in &features {
+            let name = feature
+                .strip_prefix(['+', '-'])
+                .ok_or_else(|| LinkerError::InvalidCpuFeature(feature.clone()))?;
+            if name.is_empty() || name.contains(',') {
+                return Err(LinkerError::InvalidCpuFeature(feature.clone()));
+            }
+        }
+        options.cpu_features = features.join(",");
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt as _, path::Path};
+
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let options = LinkerOptions::builder()
+            .target("bpfel-unknown-none")
+            .cpu(Cpu::V3)
+            .feature("+alu32")
+            .feature("-dwarfris")
+            .input("prog.o")
+            .output("prog.bpf.o")
+            .export("prog")
+            .build()
+            .unwrap();
+        assert_eq!(options.target.as_deref(), Some("bpfel-unknown-none"));
+        assert_eq!(options.cpu_features, "+alu32,-dwarfris");
+        assert_eq!(options.output, PathBuf::from("prog.bpf.o"));
+        assert!(options.export_symbols.contains("prog"));
+    }
+
+    #[test]
+    fn test_build_non_utf8_output() {
+        let output = PathBuf::from(OsStr::from_bytes(b"prog\xff.o"));
+        let options = LinkerOptions::builder().output(&output).build().unwrap();
+        assert_eq!(options.output, output);
+        assert!(path_to_cstring(&options.output).is_ok());
+    }
+
+    #[test]
+    fn test_build_invalid() {
+        assert!(matches!(
+            LinkerOptions::builder().build(),
+            Err(LinkerError::MissingOutput)
+        ));
+        assert!(matches!(
+            LinkerOptions::builder().output("a.o").target("x86_64").build(),
+            Err(LinkerError::InvalidTarget(target)) if target == "x86_64"
+        ));
+        assert!(LinkerOptions::builder()
+            .output("a.o")
+            .target("x86_64")
+            .allow_non_bpf_target(true)
+            .build()
+            .is_ok());
+        assert!(matches!(
+            LinkerOptions::builder().output("a\0.o").build(),
+            Err(LinkerError::InvalidPath(path)) if path == Path::new("a\0.o")
+        ));
+        for feature in ["alu32", "+", "+alu32,+v3"] {
+            assert!(matches!(
+                LinkerOptions::builder().output("a.o").feature(feature).build(),
+                Err(LinkerError::InvalidCpuFeature(f)) if f == feature
+            ));
+        }
+    }
+}
@@ -0,0 +1,1824 @@
+//! Parsing of the linker command line, shared by the `bpf-linker` binary and
+//! [`Linker::from_env`](crate::Linker::from_env). The clap types stay private to the crate, the
+//! binary gets the parsed [`Invocation`].
+
+use std::{
+    ffi::OsString,
+    fs, io, mem,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::{
+    builder::{PathBufValueParser, TypedValueParser as _},
+    parser::ValueSource,
+    ArgMatches, CommandFactory as _, FromArgMatches as _, Parser,
+};
+use thiserror::Error;
+use tracing::Level;
+
+use crate::{
+    AsmDialect, BpfTrap, CodeModel, Cpu, DiagnosticCategory, DiagnosticLevel, ExtraOutput,
+    InstrumentFunctions, LinkerInput, LinkerOptions, OptLevel, OutputType, PassOptions, RelocModel,
+    UndefinedSymbols,
+};
+
+/// Command line error
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("optimization level needs to be between 0-3, s or z (instead was `{0}`)")]
+    InvalidOptimization(String),
+    #[error("unknown emission type: `{0}` - expected one of: `llvm-bc`, `asm`, `llvm-ir`, `obj`, `skel`, `rust-skel`, `btf-ids`, `disasm`")]
+    InvalidOutputType(String),
+    #[error(
+        "unsupported target: `{0}` - expected one of: `bpf`, `bpfel`, `bpfeb`, optionally \
+         followed by `-unknown-none` (pass --allow-non-bpf-target to use it anyway)"
+    )]
+    UnsupportedTarget(String),
+    #[error(transparent)]
+    InvalidArgs(#[from] clap::Error),
+    #[error("library `{0}` not found in the library search path")]
+    LibraryNotFound(String),
+    #[error("failed to read export symbols from `{0}`: {1}")]
+    ExportSymbols(PathBuf, io::Error),
+    #[error("invalid export symbols file `{0}`: {1}")]
+    InvalidExportSymbols(PathBuf, String),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct CliOptLevel(pub OptLevel);
+
+impl FromStr for CliOptLevel {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(CliOptLevel)
+            .map_err(|_| CliError::InvalidOptimization(s.to_string()))
+    }
+}
+
+/// An `--emit` value, `<type>` or `<type>=<path>` to write an output in addition to `-o`.
+#[derive(Clone, Debug)]
+pub(crate) struct CliOutputType(pub OutputType, pub Option<PathBuf>);
+
+impl FromStr for CliOutputType {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (output_type, path) = match s.split_once('=') {
+            Some((output_type, path)) => (output_type, Some(PathBuf::from(path))),
+            None => (s, None),
+        };
+        output_type
+            .parse()
+            .map(|output_type| CliOutputType(output_type, path))
+            .map_err(|_| CliError::InvalidOutputType(s.to_string()))
+    }
+}
+
+/// Returns whether `triple` is a BPF target triple, ie `bpf`, `bpfel` or `bpfeb` optionally
+/// followed by `-unknown-none`.
+pub(crate) fn is_bpf_target(triple: &str) -> bool {
+    let arch = triple.strip_suffix("-unknown-none").unwrap_or(triple);
+    matches!(arch, "bpf" | "bpfel" | "bpfeb")
+}
+
+/// Finds the library `name` passed with `-l` in the search path `libs`.
+fn find_library(libs: &[PathBuf], name: &str) -> Result<PathBuf, CliError> {
+    let file_name = match name.strip_prefix(':') {
+        Some(file_name) => file_name.to_owned(),
+        None => format!("lib{name}.a"),
+    };
+    libs.iter()
+        .map(|dir| dir.join(&file_name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| CliError::LibraryNotFound(name.to_owned()))
+}
+
+fn parse_rename(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_owned(), new.to_owned()))
+        }
+        _ => Err(format!("expected `old=new`, got `{s}`")),
+    }
+}
+
+// Parses a size in bytes, optionally followed by a `K`, `M` or `G` binary multiple, eg `100M`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, shift) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .filter(|&size| size > 0)
+        .ok_or_else(|| format!("expected a size like `1048576`, `512K` or `100M`, got `{s}`"))
+}
+
+// Parses an ELF OSABI, either a number or one of the names of the values BPF objects use.
+fn parse_elf_osabi(s: &str) -> Result<u8, String> {
+    match s {
+        "none" | "sysv" => Ok(0),
+        "gnu" | "linux" => Ok(3),
+        _ => parse_number(s)
+            .and_then(|osabi| u8::try_from(osabi).ok())
+            .ok_or_else(|| format!("expected `none`, `linux` or a number below 256, got `{s}`")),
+    }
+}
+
+fn parse_elf_flags(s: &str) -> Result<u32, String> {
+    parse_number(s)
+        .and_then(|flags| u32::try_from(flags).ok())
+        .ok_or_else(|| format!("expected a 32-bit number like `0` or `0x1`, got `{s}`"))
+}
+
+// Parses a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// The linkage of a symbol listed in an `--export-symbols` file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportLinkage {
+    /// Exported, the default.
+    Global,
+    /// Kept in the output as a local symbol instead of being exported, eg a static program.
+    Static,
+}
+
+/// A line of an `--export-symbols` file.
+#[derive(Debug, PartialEq, Eq)]
+struct ExportSymbol {
+    name: String,
+    section: Option<String>,
+    linkage: ExportLinkage,
+}
+
+impl ExportSymbol {
+    // A global symbol without a section, `name` being unquoted if it's quoted.
+    fn global(name: &str) -> Self {
+        let name = name
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+            .unwrap_or(name);
+        Self {
+            name: name.to_owned(),
+            section: None,
+            linkage: ExportLinkage::Global,
+        }
+    }
+}
+
+/// Parses an `--export-symbols` file: one symbol per line, optionally followed by whitespace
+/// separated `section=<section>` and `linkage=global|static` attributes. Empty lines are skipped,
+/// and so is what follows a `#` starting a word, for comments and directives.
+///
+/// The symbol files rustc writes for other linkers are accepted too: the global symbols of GNU ld
+/// version scripts, recognized by their leading `{`, and the exports of module-definition files,
+/// recognized by their leading `LIBRARY`, `NAME` or `EXPORTS`.
+fn parse_export_symbols(text: &str) -> Result<Vec<ExportSymbol>, String> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    fn words(line: &str) -> Vec<&str> {
+        line.split_whitespace()
+            .take_while(|word| !word.starts_with('#'))
+            .collect()
+    }
+    let first = text.lines().find_map(|line| words(line).first().copied());
+    match first {
+        Some(word) if word.starts_with('{') => {
+            return Ok(version_script_symbols(text.lines().flat_map(words)))
+        }
+        Some("LIBRARY" | "NAME" | "EXPORTS") => {
+            return Ok(module_definition_symbols(text.lines().map(words)))
+        }
+        _ => {}
+    }
+    let mut symbols = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let mut words = words(line).into_iter();
+        let Some(name) = words.next() else {
+            continue;
+        };
+        let mut symbol = ExportSymbol::global(name);
+        for word in words {
+            match word.split_once('=') {
+                Some(("section", section)) if !section.is_empty() => {
+                    symbol.section = Some(section.to_owned())
+                }
+                Some(("linkage", "global")) => symbol.linkage = ExportLinkage::Global,
+                Some(("linkage", "static")) => symbol.linkage = ExportLinkage::Static,
+                _ => {
+                    return Err(format!(
+                        "line {}: invalid attribute `{word}`, expected `section=<section>` or \
+                         `linkage=global|static`",
+                        i + 1
+                    ))
+                }
+            }
+        }
+        symbols.push(symbol);
+    }
+    Ok(symbols)
+}
+
+// Returns the global symbols of a GNU ld version script made of `words`. The script is tokenized on
+// `{`, `}`, `;` and `:` rather than parsed by line, as it can as well be written on a single one.
+fn version_script_symbols<'a>(words: impl Iterator<Item = &'a str>) -> Vec<ExportSymbol> {
+    let mut tokens = Vec::new();
+    for mut word in words {
+        while let Some(i) = word.find(['{', '}', ';', ':']) {
+            if i > 0 {
+                tokens.push(&word[..i]);
+            }
+            tokens.push(&word[i..i + 1]);
+            word = &word[i + 1..];
+        }
+        if !word.is_empty() {
+            tokens.push(word);
+        }
+    }
+    let mut symbols = Vec::new();
+    // symbols are global until a `local:`
+    let mut local = false;
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match token {
+            "{" | "}" | ";" | ":" => {}
+            "global" | "local" if tokens.peek() == Some(&":") => local = token == "local",
+            _ if local => {}
+            name => symbols.push(ExportSymbol::global(name)),
+        }
+    }
+    symbols
+}
+
+// Returns the exports of a module-definition file made of `lines` of words: the first word of each
+// line of the `EXPORTS` section, ignoring what follows it, eg `DATA` or `@<ordinal>`, and the
+// `=<internal name>` of renamed exports.
+fn module_definition_symbols<'a>(lines: impl Iterator<Item = Vec<&'a str>>) -> Vec<ExportSymbol> {
+    let mut symbols = Vec::new();
+    let mut exports = false;
+    for words in lines {
+        match words.first().copied() {
+            None => {}
+            Some("EXPORTS") => exports = true,
+            Some("LIBRARY" | "NAME") => exports = false,
+            Some(name) if exports => {
+                let name = name.split_once('=').map_or(name, |(name, _)| name);
+                symbols.push(ExportSymbol::global(name))
+            }
+            Some(_) => {}
+        }
+    }
+    symbols
+}
+
+fn parent_and_file_name(p: PathBuf) -> Result<(PathBuf, PathBuf), String> {
+    let mut comps = p.components();
+    let file_name = comps
+        .next_back()
+        .map(|p| match p {
+            Component::Normal(p) => Ok(p),
+            p => Err(format!("unexpected path component {:?}", p)),
+        })
+        .transpose()?
+        .ok_or_else(|| "unexpected empty path".to_owned())?;
+    let parent = comps.as_path();
+    Ok((parent.to_path_buf(), Path::new(file_name).to_path_buf()))
+}
+
+/// The command line of the `bpf-linker` binary, as passed by rustc.
+///
+/// Besides the options that map to [`LinkerOptions`], it includes the options which only make
+/// sense for the binary (logging, `--stats`, `--fatal-errors`).
+#[derive(Debug, Parser)]
+// Options given more than once take the last value, so that the command line overrides the
+// `--config` file, whose arguments come first.
+#[command(
+    name = "bpf-linker",
+    version,
+    about = None,
+    long_about = None,
+    args_override_self = true
+)]
+pub(crate) struct CommandLine {
+    /// LLVM target triple. Can be one of `bpf` (host endianness), `bpfel` (little endian) or
+    /// `bpfeb` (big endian), optionally followed by `-unknown-none`. When not provided, the target
+    /// is inferred from the inputs. Several comma separated targets, eg `bpfel,bpfeb`, generate
+    /// one output per target named `<output>.el.o`, `<output>.eb.o`, from inputs which weren't
+    /// compiled for `bpfel` or `bpfeb`
+    #[clap(long, value_delimiter = ',')]
+    pub target: Vec<String>,
+
+    /// Accept a `--target` which isn't a BPF target. Only useful to experiment with other LLVM
+    /// backends
+    #[clap(long)]
+    pub allow_non_bpf_target: bool,
+
+    /// Target BPF processor. Can be one of `generic`, `probe`, `v1`, `v2`, `v3`, `v4`, or `native`
+    /// to detect the newest version the running kernel supports
+    #[clap(long, default_value = "generic")]
+    pub cpu: Cpu,
+
+    /// Enable or disable CPU features. See `--print-cpu-features` for the available features. Use
+    /// +feature to enable a feature, or -feature to disable it.  For example
+    /// --cpu-features=+alu32,-dwarfris
+    #[clap(long, value_name = "features", default_value = "")]
+    pub cpu_features: String,
+
+    /// Print the CPU features that the BPF backend of the LLVM in use supports for `--cpu`, then
+    /// exit
+    #[clap(long)]
+    pub print_cpu_features: bool,
+
+    /// Write output to <output>
+    #[clap(short, long, required_unless_present = "print_cpu_features")]
+    pub output: Option<PathBuf>,
+
+    /// Output type. Can be one of `llvm-bc`, `asm`, `llvm-ir`, `obj`, `skel`, a libbpf skeleton
+    /// header embedding the object, `rust-skel`, a Rust module naming the programs and maps and
+    /// defining the BTF structs, `btf-ids`, the BTF type ids of the exported functions as Rust
+    /// constants, or as C defines if the output ends with `.h`, or `disasm`, the disassembly of
+    /// the object annotated with its BTF functions and source lines. The first type sets the
+    /// format of `-o`, the ones given as `<type>=<path>` write additional outputs, optimized at
+    /// the level of the `-O` following them if any, eg `--emit obj -O2 --emit llvm-ir=prog.ll -O0`
+    #[clap(long, default_value = "obj")]
+    pub emit: Vec<CliOutputType>,
+
+    /// Accept BPF object files without embedded bitcode, eg built by clang, and merge their
+    /// machine code into the output instead of failing. Only supported with `--emit obj`
+    #[clap(long)]
+    pub allow_prelinked_objects: bool,
+
+    /// Assembler to write the assembly for when emitting `asm`. Can be one of `llvm` or `gas`
+    #[clap(long, value_name = "dialect", default_value = "llvm")]
+    pub asm_dialect: AsmDialect,
+
+    /// Leave the `.BTF` and `.BTF.ext` sections out of the emitted assembly, as they are long and
+    /// meaningless to read
+    #[clap(long)]
+    pub asm_without_btf: bool,
+
+    /// Annotate the emitted assembly with the source lines it was generated from. Requires debug
+    /// info
+    #[clap(long)]
+    pub asm_with_source: bool,
+
+    /// Emit BTF information
+    #[clap(long)]
+    pub btf: bool,
+
+    /// Synthesize BTF for globals without debug info (eg statics placed in custom sections) or
+    /// whose debug info doesn't match their size, so that every global is described in the BTF
+    /// DATASEC of its section
+    #[clap(long, requires = "btf")]
+    pub btf_datasec_fixup: bool,
+
+    /// Pin the BTF-defined maps matching this glob pattern by name, by setting the libbpf
+    /// `pinning` attribute of their definitions. Loaders pin them under their pin root path, eg
+    /// /sys/fs/bpf/<map>. Can be repeated
+    #[clap(long, value_name = "pattern", requires = "btf")]
+    pub pin_maps: Vec<String>,
+
+    /// Keep the BTF of the structs, unions and enums whose path matches this glob pattern, eg
+    /// `my_crate::*`, even when no program or map references them. Can be repeated
+    #[clap(long, value_name = "glob", requires = "btf")]
+    pub btf_keep_type: Vec<String>,
+
+    /// Keep the BTF of all the structs, unions and enums found in the debug info of the inputs.
+    /// Same as `--btf-keep-type '*'`
+    #[clap(long, requires = "btf")]
+    pub btf_export_all_types: bool,
+
+    /// Check that the kfuncs the program calls exist in the kernel BTF at `path`, eg
+    /// /sys/kernel/btf/vmlinux, with compatible prototypes
+    #[clap(long, value_name = "path", requires = "btf")]
+    pub kernel_btf: Option<PathBuf>,
+
+    /// Add a directory to the library search path
+    #[clap(short = 'L', number_of_values = 1)]
+    pub libs: Vec<PathBuf>,
+
+    /// Link against the library `lib<name>.a`, or `<name>` if it starts with `:`, found in the
+    /// library search path. Library members are only linked if they define an undefined symbol
+    #[clap(short = 'l', value_name = "name", number_of_values = 1)]
+    pub library_names: Vec<String>,
+
+    /// Link against the library archive at `path`. Library members are only linked if they
+    /// define an undefined symbol
+    #[clap(long = "library", value_name = "path")]
+    pub libraries: Vec<PathBuf>,
+
+    /// Only link the archive members whose name matches this glob pattern, eg to leave out the
+    /// objects an rlib bundles which aren't needed. Applies to inputs and libraries. Can be
+    /// repeated
+    #[clap(long, value_name = "pattern")]
+    pub archive_member_filter: Vec<String>,
+
+    /// Link the inputs into the bitcode module at `path` instead of an empty module, eg a large
+    /// runtime linked and optimized once, to only link the code of each program on top of it
+    #[clap(long, value_name = "path")]
+    pub base_module: Option<PathBuf>,
+
+    /// Optimization level. 0-3, s, or z. 0 only inlines `#[inline(always)]` functions and removes
+    /// unreferenced code. The last one applies, except after an `--emit <type>=<path>`, see
+    /// `--emit`
+    #[clap(short = 'O', default_value = "2")]
+    pub optimize: Vec<CliOptLevel>,
+
+    /// Optimization level of code generation, the level of -O when not set. 0-3, s, or z, s and z
+    /// generating code as 2 does
+    #[clap(long, value_name = "level")]
+    pub codegen_opt_level: Option<CliOptLevel>,
+
+    /// Never remove the definitions matching this glob pattern, even if they look unused. Can be
+    /// repeated
+    #[clap(long, value_name = "pattern")]
+    pub keep_symbol: Vec<String>,
+
+    /// Explain on stderr what internalization and optimization did to `symbol`, eg why it's
+    /// missing from the output. Can be repeated
+    #[clap(long, value_name = "symbol")]
+    pub why_internalized: Vec<String>,
+
+    /// What to do with symbols still undefined after linking. Can be one of `ksyms` (move them
+    /// to the `.ksyms` section so the loader resolves them against kernel symbols), `keep` (leave
+    /// them undefined and warn) or `error`. Externs already in `.ksyms` are never reported
+    #[clap(long, value_name = "policy", default_value = "keep")]
+    pub undefined_symbols: UndefinedSymbols,
+
+    /// What to do with traps, eg the calls to `llvm.trap` emitted for panics. Can be one of
+    /// `keep` (leave them to LLVM, which from LLVM 21 on lowers them to calls to the `__bpf_trap`
+    /// kfunc that the loader must resolve from `.ksyms`) or `return` (rewrite them into returns
+    /// of -1 at link time)
+    #[clap(long, value_name = "mode", default_value = "keep")]
+    pub bpf_trap: BpfTrap,
+
+    /// The code model of the generated code. Can be one of `default`, `tiny`, `small`, `kernel`,
+    /// `medium` or `large`
+    #[clap(long, value_name = "model", default_value = "default")]
+    pub code_model: CodeModel,
+
+    /// The relocation model of the generated code. Can be one of `default`, `static`, `pic` or
+    /// `dynamic-no-pic`
+    #[clap(long, value_name = "model", default_value = "default")]
+    pub relocation_model: RelocModel,
+
+    /// Export the symbols specified in the file `path`, one per line. A symbol can be followed by
+    /// `section=<section>`, to move its definition to that section, and `linkage=static`, to keep
+    /// it as a local symbol instead of exporting it
+    #[clap(long, value_name = "path")]
+    pub export_symbols: Option<PathBuf>,
+
+    /// Read options from the file `path`, one argument per line, eg `--cpu=v3`, ignoring empty
+    /// lines and lines starting with `#`. The options given on the command line override the ones
+    /// of the file, or add to them for options which can be given several times
+    #[clap(long, value_name = "path")]
+    pub config: Option<PathBuf>,
+
+    /// Output logs to the given `path`
+    #[clap(
+        long,
+        value_name = "path",
+        value_parser = PathBufValueParser::new().try_map(parent_and_file_name),
+    )]
+    pub log_file: Option<(PathBuf, PathBuf)>,
+
+    /// Rotate the log file once it reaches `size` bytes, optionally followed by `K`, `M` or `G`:
+    /// `<path>` is renamed to `<path>.1`, `<path>.1` to `<path>.2` and so on
+    #[clap(long, value_name = "size", value_parser = parse_size, requires = "log_file")]
+    pub log_file_max_size: Option<u64>,
+
+    /// The number of rotated log files to keep, the oldest being removed
+    #[clap(
+        long,
+        value_name = "count",
+        default_value = "5",
+        requires = "log_file_max_size"
+    )]
+    pub log_file_max_files: usize,
+
+    /// Set the log level. If not specified, no logging is used. Can be one of
+    /// `error`, `warn`, `info`, `debug`, `trace`.
+    #[clap(long, value_name = "level")]
+    pub log_level: Option<Level>,
+
+    /// Set the log level per module, with `RUST_LOG` style directives, eg
+    /// `bpf_linker::llvm::di=trace,bpf_linker=info`. Applied on top of `--log-level` and
+    /// `RUST_LOG`. Can be repeated
+    #[clap(long, value_name = "directives", value_delimiter = ',')]
+    pub log_filter: Vec<String>,
+
+    /// Try hard to unroll loops. Useful when targeting kernels that don't support loops. When
+    /// given comma separated function names or glob patterns, eg `--unroll-loops=prog1,prog2`,
+    /// only the loops of those functions and of the functions they call are unrolled
+    #[clap(
+        long,
+        value_name = "functions",
+        num_args = 0..=1,
+        require_equals = true,
+        value_delimiter = ','
+    )]
+    pub unroll_loops: Option<Vec<String>>,
+
+    /// Convert the counted loops too big to be unrolled into `bpf_loop` calls, available starting
+    /// from kernel 5.17. Experimental
+    #[clap(long, conflicts_with = "unroll_loops")]
+    pub convert_loops_to_bpf_loop: bool,
+
+    /// Ignore `noinline`/`#[inline(never)]`. Useful when targeting kernels that don't support function calls
+    #[clap(long)]
+    pub ignore_inline_never: bool,
+
+    /// Keep `noinline` on the functions matching this glob pattern when `--ignore-inline-never`
+    /// is passed, so they remain BPF-to-BPF calls. Can be repeated
+    #[clap(long, value_name = "pattern")]
+    pub no_ignore_inline_never_for: Vec<String>,
+
+    /// Dump the final IR module to the given `path` before generating the code
+    #[clap(long, value_name = "path")]
+    pub dump_module: Option<PathBuf>,
+
+    /// Also write the IR of each input to the --dump-module directory as `<input name>.ll`, as
+    /// parsed and before linking, to find which input introduces a construct
+    #[clap(long, requires = "dump_module")]
+    pub emit_llvm_ir_per_input: bool,
+
+    /// Extra command line arguments to pass to LLVM
+    #[clap(long, value_name = "args", use_value_delimiter = true, action = clap::ArgAction::Append)]
+    pub llvm_args: Vec<String>,
+
+    /// Disable passing --bpf-expand-memcpy-in-order to LLVM.
+    #[clap(long)]
+    pub disable_expand_memcpy_in_order: bool,
+
+    /// Disable exporting memcpy, memmove, memset, memcmp and bcmp. Exporting
+    /// those is commonly needed when LLVM does not manage to expand memory
+    /// intrinsics to a sequence of loads and stores.
+    #[clap(long)]
+    pub disable_memory_builtins: bool,
+
+    /// Input files. Can be object files or static libraries
+    #[clap(required_unless_present = "print_cpu_features")]
+    pub inputs: Vec<PathBuf>,
+
+    /// Comma separated list of symbols to export. See also `--export-symbols`
+    #[clap(long, value_name = "symbols", use_value_delimiter = true, action = clap::ArgAction::Append)]
+    pub export: Vec<String>,
+
+    /// Run the Rhai script at `path` against the linked module before generating code, failing
+    /// the link if the script reports violations by calling `fail(message)`
+    #[clap(long, value_name = "path")]
+    pub validation_script: Option<PathBuf>,
+
+    /// Prepend `prefix` to the names of all defined symbols which are not exported
+    #[clap(long, value_name = "prefix")]
+    pub prefix_symbols: Option<String>,
+
+    /// Rename the defined symbol `old` to `new` after optimization, along with its debug info.
+    /// Can be repeated
+    #[clap(long, value_name = "old=new", value_parser = parse_rename)]
+    pub rename_symbol: Vec<(String, String)>,
+
+    /// Write LLVM optimization remarks to `path` as YAML, even when the link fails
+    #[clap(long, value_name = "path")]
+    pub remarks_file: Option<PathBuf>,
+
+    /// Only write remarks from the passes whose name matches `regex`, eg `unroll`. Requires
+    /// `--remarks-file`
+    #[clap(long, value_name = "regex", requires = "remarks_file")]
+    pub remarks_filter: Option<String>,
+
+    /// Write statistics about the link as JSON to `path` with `--stats=path`, or to stderr if no
+    /// path is given
+    #[clap(
+        long,
+        value_name = "path",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-"
+    )]
+    pub stats: Option<PathBuf>,
+
+    /// Print the time spent in each stage of the link and its peak RSS to stderr
+    #[clap(long)]
+    pub timings: bool,
+
+    /// Write the output directly instead of through a temporary file renamed once complete. For
+    /// filesystems that don't support renaming
+    #[clap(long)]
+    pub no_atomic_output: bool,
+
+    /// Write a `.bpf.linker.meta` section recording the bpf-linker and LLVM versions, a hash of the
+    /// options and a hash of each input, to tell what produced an object
+    #[clap(long)]
+    pub linker_metadata: bool,
+
+    /// Remove the maps which no program references after dead code elimination, along with their
+    /// BTF. The removed maps are listed in `--stats`
+    #[clap(long)]
+    pub gc_maps: bool,
+
+    /// Instrument every function for profiling. Can be one of `hooks` (call the user-provided
+    /// `__bpf_profile_enter(u32 id)` and `__bpf_profile_exit(u32 id)`) or `counters` (count the
+    /// calls in the `__bpf_profile_counters` array). The ids are listed in `--stats`
+    #[clap(long, value_name = "mode")]
+    pub instrument_functions: Option<InstrumentFunctions>,
+
+    /// Don't merge identical read-only globals, eg string constants duplicated by several inputs,
+    /// once optimized. The optimization pipeline may still merge some of them
+    #[clap(long)]
+    pub no_merge_constants: bool,
+
+    /// Link every input, even those whose bitcode is identical to that of an input already linked,
+    /// eg an rlib passed twice. By default they are skipped
+    #[clap(long)]
+    pub no_dedup_inputs: bool,
+
+    /// Don't interleave loop iterations during optimization
+    #[clap(long)]
+    pub no_loop_interleaving: bool,
+
+    /// Don't vectorize loops during optimization
+    #[clap(long)]
+    pub no_loop_vectorization: bool,
+
+    /// Vectorize straight-line code during optimization
+    #[clap(long)]
+    pub slp_vectorization: bool,
+
+    /// Don't let LICM promote the memory accessed in loops to registers, which can produce loops
+    /// the verifier rejects
+    #[clap(long)]
+    pub no_licm_promotion: bool,
+
+    /// Write a Make-style dependency file listing every file read by the link to `path`: the
+    /// inputs, the libraries and thin archive members linked, `--export-symbols` and
+    /// `--validation-script`
+    #[clap(long, value_name = "path")]
+    pub dep_file: Option<PathBuf>,
+
+    /// Append the contents of `path` to the module level asm before optimization. Can be
+    /// repeated
+    #[clap(long, value_name = "path")]
+    pub module_asm: Vec<PathBuf>,
+
+    /// Write a SHA-256 of the output to `path`, covering its code, data, BTF, relocations and
+    /// symbols but not its debug info, to tell whether a program changed between builds
+    #[clap(long, value_name = "path")]
+    pub emit_hash: Option<PathBuf>,
+
+    /// Run the LLVM verifier on the module after linking and after optimization, failing on
+    /// invalid IR. Always enabled in debug builds of the linker
+    #[clap(long)]
+    pub verify: bool,
+
+    /// Set the OSABI of the ELF header of the output object, for loaders which check it. Can be
+    /// `none`, `linux` or a number. LLVM emits `none`
+    #[clap(long, value_name = "osabi", value_parser = parse_elf_osabi)]
+    pub elf_osabi: Option<u8>,
+
+    /// Set the `e_flags` of the ELF header of the output object, for loaders which check them.
+    /// Decimal, or hexadecimal with a `0x` prefix
+    #[clap(long, value_name = "flags", value_parser = parse_elf_flags)]
+    pub elf_flags: Option<u32>,
+
+    /// Parse the output object with aya-obj, like the aya loader does, reporting failures as
+    /// `aya-obj` diagnostics. Needs the `aya-obj` feature
+    #[clap(long)]
+    pub check_aya_obj: bool,
+
+    /// Print the stack used by each function to stderr, marking the functions over the 512 bytes
+    /// the verifier allows
+    #[clap(long)]
+    pub print_stack_usage: bool,
+
+    /// Print the functions removed by optimization to stderr, with their number of IR
+    /// instructions and estimated code size. They're also in the --stats output
+    #[clap(long)]
+    pub print_removed_functions: bool,
+
+    /// Print the size of the program, map, BTF and read-only data sections of the output to
+    /// stderr
+    #[clap(long)]
+    pub print_section_sizes: bool,
+
+    /// Whether to treat LLVM errors as fatal.
+    #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
+    pub fatal_errors: bool,
+
+    /// Fail the link if any warning is reported
+    #[clap(long)]
+    pub fatal_warnings: bool,
+
+    /// Report diagnostics of a category as warnings. Categories are `memory-builtins`,
+    /// `inline-never`, `data-carrying-enum`, `optnone`, `missing-debug-info`,
+    /// `no-embedded-bitcode`, `undefined-symbols`, `btf-datasec`, `llvm`, `stack-usage`,
+    /// `unmatched-exports` and `aya-obj`
+    #[clap(long, value_name = "category")]
+    pub warn: Vec<DiagnosticCategory>,
+
+    /// Don't report diagnostics of a category. Overridden by `--warn` and `--deny`
+    #[clap(long, value_name = "category")]
+    pub allow: Vec<DiagnosticCategory>,
+
+    /// Fail the link if diagnostics of a category are reported. Overrides `--allow` and `--warn`
+    #[clap(long, value_name = "category")]
+    pub deny: Vec<DiagnosticCategory>,
+
+    /// Fail the link if an exported symbol isn't defined by any input. Same as
+    /// `--deny unmatched-exports`
+    #[clap(long)]
+    pub strict_exports: bool,
+
+    // The options below are for wasm-ld compatibility
+    #[clap(long = "debug", hide = true)]
+    pub _debug: bool,
+
+    /// The wasm-ld and ld.lld flags which were ignored, see [`LLD_FLAGS`].
+    #[clap(skip)]
+    pub ignored_args: Vec<String>,
+
+    /// The number of arguments read from the [`config`](Self::config) file, which are parsed
+    /// right after the program name.
+    #[clap(skip)]
+    pub config_args: usize,
+
+    /// The optimization level of each `--emit` value, set from the `-O` following an
+    /// `--emit <type>=<path>`, which then doesn't apply to the main output.
+    #[clap(skip)]
+    pub emit_optimize: Vec<Option<CliOptLevel>>,
+}
+
+/// What to do with a wasm-ld or ld.lld flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LldFlag {
+    /// Ignore the flag.
+    Ignore,
+    /// Ignore the flag and its value, given as the next argument or after `=`.
+    IgnoreWithValue,
+    /// Pass the flag and its value as the given option.
+    Map(&'static str),
+}
+
+/// The flags rustc may pass when it invokes the linker as wasm-ld or ld.lld, which `bpf-linker`
+/// doesn't otherwise accept. Most of them don't mean anything for BPF: there's no entry point,
+/// no dynamic linking, and unreferenced code is always removed.
+const LLD_FLAGS: &[(&str, LldFlag)] = &[
+    ("-flavor", LldFlag::IgnoreWithValue),
+    ("--flavor", LldFlag::IgnoreWithValue),
+    ("-z", LldFlag::IgnoreWithValue),
+    ("--no-entry", LldFlag::Ignore),
+    ("--gc-sections", LldFlag::Ignore),
+    ("--no-gc-sections", LldFlag::Ignore),
+    ("--as-needed", LldFlag::Ignore),
+    ("--no-as-needed", LldFlag::Ignore),
+    ("--whole-archive", LldFlag::Ignore),
+    ("--no-whole-archive", LldFlag::Ignore),
+    ("-Bstatic", LldFlag::Ignore),
+    ("-Bdynamic", LldFlag::Ignore),
+    ("--eh-frame-hdr", LldFlag::Ignore),
+    ("--export-dynamic", LldFlag::Ignore),
+    ("--allow-undefined", LldFlag::Ignore),
+    ("--strip-debug", LldFlag::Ignore),
+    ("--strip-all", LldFlag::Ignore),
+    ("-nodefaultlibs", LldFlag::Ignore),
+    ("--stack-first", LldFlag::Ignore),
+    ("--export-dynamic-symbol", LldFlag::Map("--export")),
+];
+
+/// Maps or drops the wasm-ld and ld.lld flags of `args` as [`LLD_FLAGS`] says. Returns the
+/// resulting arguments along with the ignored ones.
+fn lld_compat(args: impl IntoIterator<Item = OsString>) -> (Vec<OsString>, Vec<String>) {
+    let mut mapped = Vec::new();
+    let mut ignored = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // none of the flags handled here are valid with non UTF-8 names or values
+        let Some(arg) = arg.to_str().map(str::to_owned) else {
+            mapped.push(arg);
+            continue;
+        };
+        if arg == "--" {
+            mapped.push(arg.into());
+            mapped.extend(args);
+            break;
+        }
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with('-') => (name, Some(value)),
+            _ => (arg.as_str(), None),
+        };
+        // `-z` is also passed as `-zkeyword`
+        let (name, value) = match name.strip_prefix("-z") {
+            Some(keyword) if !keyword.is_empty() && value.is_none() => ("-z", Some(keyword)),
+            _ => (name, value),
+        };
+        match LLD_FLAGS.iter().find(|(flag, _)| *flag == name) {
+            None => mapped.push(arg.into()),
+            Some((_, LldFlag::Ignore)) => ignored.push(arg),
+            Some((_, LldFlag::IgnoreWithValue)) => match value {
+                Some(_) => ignored.push(arg),
+                None => ignored.push(args.next().map_or_else(
+                    || arg.clone(),
+                    |value| format!("{arg} {}", value.to_string_lossy()),
+                )),
+            },
+            Some((_, LldFlag::Map(option))) => {
+                mapped.push(option.into());
+                if let Some(value) = value {
+                    mapped.push(value.into());
+                }
+            }
+        }
+    }
+    (mapped, ignored)
+}
+
+// Returns the path given with `--config`, if any.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1).take_while(|arg| *arg != "--");
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            path = args.next().map(PathBuf::from);
+        } else if let Some(value) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            path = Some(PathBuf::from(value));
+        }
+    }
+    path
+}
+
+// Reads the arguments of the `--config` file, one per line, skipping empty lines and comments.
+fn read_config(path: &Path) -> Result<Vec<OsString>, clap::Error> {
+    let config = fs::read_to_string(path).map_err(|e| {
+        clap::Error::raw(
+            clap::error::ErrorKind::Io,
+            format!("failed to read config file {}: {e}\n", path.display()),
+        )
+    })?;
+    Ok(config
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(OsString::from)
+        .collect())
+}
+
+impl CommandLine {
+    /// Parses a linker command line as passed by rustc. The first argument is the program name.
+    /// Paths don't need to be valid UTF-8. The wasm-ld and ld.lld flags rustc may pass are mapped
+    /// to options or ignored, the ignored ones end up in [`ignored_args`](Self::ignored_args).
+    ///
+    /// The arguments of the [`config`](Self::config) file are parsed before the other ones.
+    ///
+    /// Returns the matches along with the parsed command line, so that callers can tell which
+    /// options were set explicitly, see [`option_source`](Self::option_source).
+    pub fn try_parse_rustc_args<I, T>(args: I) -> Result<(Self, ArgMatches), clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let (mut args, ignored_args) = lld_compat(args.into_iter().map(Into::into));
+        let config_args = match config_path(&args) {
+            Some(path) => read_config(&path)?,
+            None => Vec::new(),
+        };
+        // right after the program name, so that the command line overrides them
+        let config_len = config_args.len();
+        let rest = args.split_off(args.len().min(1));
+        args.extend(config_args);
+        args.extend(rest);
+        let matches = Self::command().try_get_matches_from(args)?;
+        let mut command_line = Self::from_arg_matches(&matches)?;
+        command_line.ignored_args = ignored_args;
+        command_line.config_args = config_len;
+        command_line.pair_opt_levels(&matches);
+        Ok((command_line, matches))
+    }
+
+    /// Returns where the value of the option `id` in `matches` came from, eg `command line` or
+    /// `config file`, or `None` if the option isn't set.
+    pub fn option_source(&self, matches: &ArgMatches, id: &str) -> Option<&'static str> {
+        Some(match matches.value_source(id)? {
+            ValueSource::CommandLine
+                if self.config_args > 0
+                    && matches.indices_of(id).is_some_and(|mut indices| {
+                        indices.all(|index| index <= self.config_args)
+                    }) =>
+            {
+                "config file"
+            }
+            ValueSource::CommandLine => "command line",
+            ValueSource::EnvVariable => "environment",
+            ValueSource::DefaultValue => "default",
+            _ => "unknown",
+        })
+    }
+
+    // Moves the `-O` values following an `--emit <type>=<path>`, up to the next `--emit`, to
+    // `emit_optimize`, so that each extra output can have its own optimization level.
+    fn pair_opt_levels(&mut self, matches: &ArgMatches) {
+        let explicit = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if !explicit("emit") || !explicit("optimize") {
+            return;
+        }
+        let (Some(emits), Some(levels)) =
+            (matches.indices_of("emit"), matches.indices_of("optimize"))
+        else {
+            return;
+        };
+        let emits: Vec<_> = emits.collect();
+        self.emit_optimize = vec![None; self.emit.len()];
+        let mut optimize = Vec::new();
+        for (level, index) in self.optimize.iter().zip(levels) {
+            match emits.iter().rposition(|&emit| emit < index) {
+                Some(emit) if self.emit[emit].1.is_some() => {
+                    self.emit_optimize[emit] = Some(*level)
+                }
+                _ => optimize.push(*level),
+            }
+        }
+        if optimize.is_empty() {
+            optimize.push(CliOptLevel(OptLevel::Default));
+        }
+        self.optimize = optimize;
+    }
+
+    /// Validates the command line and converts it to [`LinkerOptions`], reading the
+    /// `--export-symbols` file if any.
+    pub fn into_linker_options(self) -> Result<LinkerOptions, CliError> {
+        let Self {
+            target,
+            allow_non_bpf_target,
+            cpu,
+            cpu_features,
+            print_cpu_features: _,
+            output,
+            emit,
+            allow_prelinked_objects,
+            asm_dialect,
+            asm_without_btf,
+            asm_with_source,
+            btf,
+            btf_datasec_fixup,
+            pin_maps,
+            mut btf_keep_type,
+            btf_export_all_types,
+            kernel_btf,
+            archive_member_filter,
+            libs,
+            library_names,
+            mut libraries,
+            base_module,
+            optimize,
+            codegen_opt_level,
+            export_symbols,
+            mut keep_symbol,
+            why_internalized,
+            undefined_symbols,
+            bpf_trap,
+            code_model,
+            relocation_model,
+            config: _,
+            log_file: _,
+            log_file_max_size: _,
+            log_file_max_files: _,
+            log_level: _,
+            log_filter: _,
+            unroll_loops,
+            convert_loops_to_bpf_loop,
+            ignore_inline_never,
+            no_ignore_inline_never_for,
+            dump_module,
+            emit_llvm_ir_per_input,
+            llvm_args,
+            disable_expand_memcpy_in_order,
+            disable_memory_builtins,
+            inputs,
+            mut export,
+            validation_script,
+            prefix_symbols,
+            rename_symbol,
+            remarks_file,
+            remarks_filter,
+            stats,
+            timings,
+            print_stack_usage,
+            print_removed_functions,
+            print_section_sizes: _,
+            gc_maps,
+            instrument_functions,
+            no_merge_constants,
+            no_dedup_inputs,
+            no_loop_interleaving,
+            no_loop_vectorization,
+            slp_vectorization,
+            no_licm_promotion,
+            dep_file,
+            module_asm,
+            emit_hash,
+            verify,
+            elf_osabi,
+            elf_flags,
+            check_aya_obj,
+            linker_metadata,
+            no_atomic_output,
+            fatal_errors: _,
+            fatal_warnings,
+            warn,
+            allow,
+            deny,
+            strict_exports,
+            _debug,
+            ignored_args: _,
+            config_args: _,
+            emit_optimize,
+        } = self;
+
+        if btf_export_all_types {
+            btf_keep_type.push("*".to_owned());
+        }
+        if let Some(target) = target
+            .iter()
+            .find(|target| !allow_non_bpf_target && !is_bpf_target(target))
+        {
+            return Err(CliError::UnsupportedTarget(target.clone()));
+        }
+        let (target, targets) = match <[_; 1]>::try_from(target) {
+            Ok([target]) => (Some(target), Vec::new()),
+            Err(targets) => (None, targets),
+        };
+
+        for name in library_names {
+            libraries.push(find_library(&libs, &name)?);
+        }
+
+        let dependencies = export_symbols.iter().cloned().collect();
+        let export_file = export_symbols
+            .map(|path| {
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| CliError::ExportSymbols(path.clone(), e))?;
+                parse_export_symbols(&text).map_err(|e| CliError::InvalidExportSymbols(path, e))
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let mut symbol_sections = Vec::new();
+        for ExportSymbol {
+            name,
+            section,
+            linkage,
+        } in export_file
+        {
+            if let Some(section) = section {
+                symbol_sections.push((name.clone(), section));
+            }
+            match linkage {
+                ExportLinkage::Global => export.push(name),
+                ExportLinkage::Static => keep_symbol.push(name),
+            }
+        }
+        let export_symbols = export.into_iter().map(Into::into).collect();
+
+        // rustc appends its own `--emit` after the user's, so the first one wins
+        let output_type = emit
+            .iter()
+            .find_map(|CliOutputType(output_type, path)| path.is_none().then_some(*output_type))
+            .unwrap_or(OutputType::Object);
+        let extra_outputs = emit
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, CliOutputType(output_type, path))| {
+                Some(ExtraOutput {
+                    output_type,
+                    path: path?,
+                    optimize: emit_optimize
+                        .get(i)
+                        .copied()
+                        .flatten()
+                        .map(|CliOptLevel(level)| level),
+                })
+            })
+            .collect();
+        let optimize = match *optimize.as_slice() {
+            [] => unreachable!("-O has a default value"),
+            [.., CliOptLevel(optimize)] => optimize,
+        };
+
+        let diagnostic_levels = allow
+            .into_iter()
+            .map(|category| (category, DiagnosticLevel::Allow))
+            .chain(
+                warn.into_iter()
+                    .map(|category| (category, DiagnosticLevel::Warn)),
+            )
+            .chain(
+                deny.into_iter()
+                    .chain(strict_exports.then_some(DiagnosticCategory::UnmatchedExports))
+                    .map(|category| (category, DiagnosticLevel::Deny)),
+            )
+            .collect();
+
+        // options the command line doesn't set keep the defaults library users get
+        #[allow(clippy::needless_update)]
+        let options = LinkerOptions {
+            target,
+            targets,
+            cpu,
+            cpu_features,
+            inputs: inputs.into_iter().map(LinkerInput::File).collect(),
+            // only missing with --print-cpu-features, which doesn't link
+            output: output.unwrap_or_default(),
+            output_type,
+            extra_outputs,
+            archive_member_filters: archive_member_filter,
+            libs,
+            libraries,
+            optimize,
+            codegen_optimize: codegen_opt_level.map(|CliOptLevel(level)| level),
+            export_symbols,
+            base_module,
+            keep_symbols: keep_symbol,
+            symbol_sections,
+            why_internalized,
+            unroll_loops: unroll_loops.is_some(),
+            unroll_functions: unroll_loops.unwrap_or_default(),
+            convert_loops_to_bpf_loop,
+            ignore_inline_never,
+            keep_inline_never: no_ignore_inline_never_for,
+            dump_module,
+            dump_inputs: emit_llvm_ir_per_input,
+            llvm_args,
+            disable_expand_memcpy_in_order,
+            disable_memory_builtins,
+            btf,
+            validation_script,
+            prefix_symbols,
+            rename_symbols: rename_symbol,
+            remarks_file,
+            remarks_filter,
+            btf_datasec_fixup,
+            pin_maps,
+            btf_keep_types: btf_keep_type,
+            kernel_btf,
+            undefined_symbols,
+            bpf_trap,
+            code_model,
+            reloc_model: relocation_model,
+            diagnostic_levels,
+            fatal_warnings,
+            stack_usage: print_stack_usage,
+            removed_functions: print_removed_functions,
+            linker_metadata,
+            atomic_output: !no_atomic_output,
+            asm_dialect,
+            asm_without_btf,
+            asm_with_source,
+            allow_prelinked_objects,
+            gc_maps,
+            instrument_functions,
+            dep_file,
+            dependencies,
+            merge_constants: !no_merge_constants,
+            dedup_inputs: !no_dedup_inputs,
+            pass_options: PassOptions {
+                loop_interleaving: !no_loop_interleaving,
+                loop_vectorization: !no_loop_vectorization,
+                slp_vectorization,
+                licm_promotion: !no_licm_promotion,
+            },
+            module_asm,
+            emit_hash,
+            verify: verify || cfg!(debug_assertions),
+            elf_osabi,
+            elf_flags,
+            check_aya_obj,
+            measure_stage_memory: stats.is_some() || timings,
+            ..LinkerOptions::default()
+        };
+        Ok(options)
+    }
+}
+
+/// The options of the `bpf-linker` binary which don't map to [`LinkerOptions`]: logging and what
+/// to report once linked.
+#[derive(Clone, Debug)]
+pub struct BinaryOptions {
+    /// The file the options were read from, see `--config`.
+    pub config: Option<PathBuf>,
+    /// The directory and name of the log file, see `--log-file`.
+    pub log_file: Option<(PathBuf, PathBuf)>,
+    /// The size at which the log file is rotated, see `--log-file-max-size`.
+    pub log_file_max_size: Option<u64>,
+    /// The number of rotated log files to keep.
+    pub log_file_max_files: usize,
+    /// The log level, no logging if None.
+    pub log_level: Option<Level>,
+    /// `RUST_LOG` style directives, see `--log-filter`.
+    pub log_filter: Vec<String>,
+    /// Where to write the statistics of the link, `-` for stderr, see `--stats`.
+    pub stats: Option<PathBuf>,
+    /// Print the time spent in each stage of the link.
+    pub timings: bool,
+    /// Print the stack usage of each function.
+    pub print_stack_usage: bool,
+    /// Print the functions removed by optimization.
+    pub print_removed_functions: bool,
+    /// Print the size of each section of the output.
+    pub print_section_sizes: bool,
+    /// Fail if LLVM issued diagnostics with error severity.
+    pub fatal_errors: bool,
+    /// Print the CPU features supported for `cpu` instead of linking.
+    pub print_cpu_features: bool,
+    /// The target BPF processor, see `--cpu`.
+    pub cpu: Cpu,
+}
+
+/// An invocation of the `bpf-linker` binary: its command line as passed by rustc, parsed into
+/// the [`LinkerOptions`] and the [`BinaryOptions`].
+#[derive(Debug)]
+pub struct Invocation {
+    pub binary: BinaryOptions,
+    /// The wasm-ld and ld.lld flags which were ignored, as they have no effect when linking BPF.
+    pub ignored_args: Vec<String>,
+    command_line: CommandLine,
+    matches: ArgMatches,
+}
+
+impl Invocation {
+    /// Parses a linker command line as passed by rustc, see [`Linker::from_env`]. The first
+    /// argument is the program name. Requests for the help or the version are returned as
+    /// [`CliError::InvalidArgs`] errors of kind `DisplayHelp` and `DisplayVersion`.
+    ///
+    /// [`Linker::from_env`]: crate::Linker::from_env
+    pub fn from_rustc_args<I, T>(args: I) -> Result<Self, CliError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let (mut command_line, matches) = CommandLine::try_parse_rustc_args(args)?;
+        let binary = BinaryOptions {
+            config: command_line.config.clone(),
+            log_file: command_line.log_file.clone(),
+            log_file_max_size: command_line.log_file_max_size,
+            log_file_max_files: command_line.log_file_max_files,
+            log_level: command_line.log_level,
+            log_filter: command_line.log_filter.clone(),
+            stats: command_line.stats.clone(),
+            timings: command_line.timings,
+            print_stack_usage: command_line.print_stack_usage,
+            print_removed_functions: command_line.print_removed_functions,
+            print_section_sizes: command_line.print_section_sizes,
+            fatal_errors: command_line.fatal_errors,
+            print_cpu_features: command_line.print_cpu_features,
+            cpu: command_line.cpu,
+        };
+        let ignored_args = mem::take(&mut command_line.ignored_args);
+        Ok(Self {
+            binary,
+            ignored_args,
+            command_line,
+            matches,
+        })
+    }
+
+    /// Returns the name of every option shown in the help, along with its effective values and
+    /// where they came from: `command line`, `config file`, `default`, or `unset`.
+    pub fn effective_options(&self) -> Vec<(String, Vec<String>, &'static str)> {
+        CommandLine::command()
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set())
+            .map(|arg| {
+                let id = arg.get_id().as_str();
+                let source = self
+                    .command_line
+                    .option_source(&self.matches, id)
+                    .unwrap_or("unset");
+                let values = self
+                    .matches
+                    .get_raw(id)
+                    .map(|values| {
+                        values
+                            .map(|value| value.to_string_lossy().into_owned())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                (id.to_owned(), values, source)
+            })
+            .collect()
+    }
+
+    /// Validates the command line and converts it to [`LinkerOptions`], reading the
+    /// `--export-symbols` file if any.
+    pub fn into_linker_options(self) -> Result<LinkerOptions, CliError> {
+        self.command_line.into_linker_options()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Test made to reproduce the following bug:
+    // https://github.com/aya-rs/bpf-linker/issues/27
+    // where --export argument followed by positional arguments resulted in
+    // parsing the positional args as `export`, not as `inputs`.
+    // There can be multiple exports, but they always have to be preceded by
+    // `--export` flag.
+    #[test]
+    fn test_export_input_args() {
+        let args = [
+            "bpf-linker",
+            "--export",
+            "foo",
+            "--export",
+            "bar",
+            "symbols.o", // this should be parsed as `input`, not `export`
+            "rcgu.o",    // this should be parsed as `input`, not `export`
+            "-L",
+            "target/debug/deps",
+            "-L",
+            "target/debug",
+            "-L",
+            "/home/foo/.rustup/toolchains/nightly-x86_64-unknown-linux-gnu/lib",
+            "-o",
+            "/tmp/bin.s",
+            "--target=bpf",
+            "--emit=asm",
+        ];
+        let CommandLine { inputs, export, .. } = Parser::parse_from(args);
+        assert_eq!(export, ["foo", "bar"]);
+        assert_eq!(
+            inputs,
+            [PathBuf::from("symbols.o"), PathBuf::from("rcgu.o")]
+        );
+    }
+
+    #[test]
+    fn test_export_delimiter() {
+        let args = [
+            "bpf-linker",
+            "--export",
+            "foo,bar",
+            "--export=ayy,lmao",
+            "symbols.o", // this should be parsed as `input`, not `export`
+            "--export=lol",
+            "--export",
+            "rotfl",
+            "rcgu.o", // this should be parsed as `input`, not `export`
+            "-L",
+            "target/debug/deps",
+            "-L",
+            "target/debug",
+            "-L",
+            "/home/foo/.rustup/toolchains/nightly-x86_64-unknown-linux-gnu/lib",
+            "-o",
+            "/tmp/bin.s",
+            "--target=bpf",
+            "--emit=asm",
+        ];
+        let CommandLine { inputs, export, .. } = Parser::parse_from(args);
+        assert_eq!(export, ["foo", "bar", "ayy", "lmao", "lol", "rotfl"]);
+        assert_eq!(
+            inputs,
+            [PathBuf::from("symbols.o"), PathBuf::from("rcgu.o")]
+        );
+    }
+
+    #[test]
+    fn test_unroll_loops() {
+        let args = ["bpf-linker", "--unroll-loops", "rcgu.o", "-o", "/tmp/bin.o"];
+        let CommandLine {
+            inputs,
+            unroll_loops,
+            ..
+        } = Parser::parse_from(args);
+        assert_eq!(unroll_loops, Some(vec![]));
+        // without `=`, what follows is an input
+        assert_eq!(inputs, [PathBuf::from("rcgu.o")]);
+
+        let args = [
+            "bpf-linker",
+            "--unroll-loops=prog1,prog2",
+            "rcgu.o",
+            "-o",
+            "/tmp/bin.o",
+        ];
+        let CommandLine { unroll_loops, .. } = Parser::parse_from(args);
+        assert_eq!(
+            unroll_loops,
+            Some(vec!["prog1".to_owned(), "prog2".to_owned()])
+        );
+
+        let args = ["bpf-linker", "rcgu.o", "-o", "/tmp/bin.o"];
+        let CommandLine { unroll_loops, .. } = Parser::parse_from(args);
+        assert_eq!(unroll_loops, None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let args = ["bpf-linker", "--stats", "rcgu.o", "-o", "/tmp/bin.o"];
+        let CommandLine { inputs, stats, .. } = Parser::parse_from(args);
+        assert_eq!(stats, Some(PathBuf::from("-")));
+        // without `=`, what follows is an input
+        assert_eq!(inputs, [PathBuf::from("rcgu.o")]);
+
+        let args = [
+            "bpf-linker",
+            "--stats=stats.json",
+            "rcgu.o",
+            "-o",
+            "/tmp/bin.o",
+        ];
+        let CommandLine { inputs, stats, .. } = Parser::parse_from(args);
+        assert_eq!(stats, Some(PathBuf::from("stats.json")));
+        assert_eq!(inputs, [PathBuf::from("rcgu.o")]);
+
+        let args = ["bpf-linker", "rcgu.o", "-o", "/tmp/bin.o"];
+        let CommandLine { stats, .. } = Parser::parse_from(args);
+        assert_eq!(stats, None);
+    }
+
+    #[test]
+    fn test_lld_compat() {
+        let args = [
+            "bpf-linker",
+            "-flavor",
+            "wasm",
+            "--no-entry",
+            "-z",
+            "noexecstack",
+            "-zrelro",
+            "--gc-sections",
+            "--export-dynamic-symbol=foo",
+            "--export-dynamic-symbol",
+            "bar",
+            "-o",
+            "prog.o",
+            "input.o",
+        ];
+        let (command_line, _) = CommandLine::try_parse_rustc_args(args).unwrap();
+        assert_eq!(
+            command_line.ignored_args,
+            [
+                "-flavor wasm",
+                "--no-entry",
+                "-z noexecstack",
+                "-zrelro",
+                "--gc-sections"
+            ]
+        );
+        assert_eq!(command_line.export, ["foo", "bar"]);
+        assert_eq!(command_line.inputs, [PathBuf::from("input.o")]);
+    }
+
+    #[test]
+    fn test_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("bpf-linker.conf");
+        fs::write(
+            &config,
+            "# shared by every crate\n--log-level=info\n\n  --btf\n--export\nfoo\n",
+        )
+        .unwrap();
+        let config = config.to_str().unwrap();
+        let args = [
+            "bpf-linker",
+            "--log-level",
+            "debug",
+            "--config",
+            config,
+            "--export=bar",
+            "-o",
+            "prog.o",
+            "input.o",
+        ];
+        let (command_line, matches) = CommandLine::try_parse_rustc_args(args).unwrap();
+        assert_eq!(command_line.config_args, 4);
+        // the command line overrides the config file, or adds to it
+        assert_eq!(command_line.log_level, Some(Level::DEBUG));
+        assert!(command_line.btf);
+        assert_eq!(command_line.export, ["foo", "bar"]);
+        assert_eq!(command_line.inputs, [PathBuf::from("input.o")]);
+
+        let source = |id| command_line.option_source(&matches, id);
+        assert_eq!(source("btf"), Some("config file"));
+        assert_eq!(source("log_level"), Some("command line"));
+        assert_eq!(source("export"), Some("command line"));
+        assert_eq!(source("output"), Some("command line"));
+        assert_eq!(source("code_model"), Some("default"));
+        assert_eq!(source("export_symbols"), None);
+
+        let args = ["bpf-linker", "--config=missing.conf", "-o", "prog.o"];
+        assert_eq!(
+            CommandLine::try_parse_rustc_args(args).unwrap_err().kind(),
+            clap::error::ErrorKind::Io
+        );
+    }
+
+    #[test]
+    fn test_invocation() {
+        let args = [
+            "bpf-linker",
+            "--log-level=debug",
+            "--stats",
+            "--btf",
+            "-o",
+            "prog.o",
+            "input.o",
+        ];
+        let invocation = Invocation::from_rustc_args(args).unwrap();
+        assert_eq!(invocation.binary.log_level, Some(Level::DEBUG));
+        assert_eq!(invocation.binary.stats, Some(PathBuf::from("-")));
+        let options = invocation.effective_options();
+        assert!(options.contains(&("btf".to_owned(), vec!["true".to_owned()], "command line")));
+        assert!(options.contains(&("cpu".to_owned(), vec!["generic".to_owned()], "default")));
+        assert!(options.contains(&("log_file".to_owned(), Vec::new(), "unset")));
+        let options = invocation.into_linker_options().unwrap();
+        assert!(options.btf);
+        assert!(options.measure_stage_memory);
+
+        assert!(matches!(
+            Invocation::from_rustc_args(["bpf-linker", "--help"]),
+            Err(CliError::InvalidArgs(err)) if err.kind() == clap::error::ErrorKind::DisplayHelp
+        ));
+    }
+
+    #[test]
+    fn test_non_utf8_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt as _};
+
+        let output = OsStr::from_bytes(b"prog\xff.o");
+        let input = OsStr::from_bytes(b"\xfeinput.o");
+        let args = [
+            OsStr::new("bpf-linker"),
+            OsStr::new("-o"),
+            output,
+            OsStr::new("--dump-module"),
+            input,
+            input,
+        ];
+        let (command_line, _) = CommandLine::try_parse_rustc_args(args).unwrap();
+        assert_eq!(command_line.output.as_deref(), Some(Path::new(output)));
+        assert_eq!(command_line.inputs, [PathBuf::from(input)]);
+        assert_eq!(command_line.dump_module.as_deref(), Some(Path::new(input)));
+    }
+
+    #[test]
+    fn test_parse_rename() {
+        assert_eq!(
+            parse_rename("xdp_main=xdp_main_v2"),
+            Ok(("xdp_main".to_owned(), "xdp_main_v2".to_owned()))
+        );
+        assert!(parse_rename("xdp_main").is_err());
+        assert!(parse_rename("=xdp_main").is_err());
+        assert!(parse_rename("xdp_main=").is_err());
+    }
+
+    #[test]
+    fn test_parse_elf_header_fields() {
+        assert_eq!(parse_elf_osabi("none"), Ok(0));
+        assert_eq!(parse_elf_osabi("linux"), Ok(3));
+        assert_eq!(parse_elf_osabi("97"), Ok(97));
+        assert!(parse_elf_osabi("256").is_err());
+        assert!(parse_elf_osabi("hpux").is_err());
+        assert_eq!(parse_elf_flags("0"), Ok(0));
+        assert_eq!(parse_elf_flags("0x80000001"), Ok(0x8000_0001));
+        assert!(parse_elf_flags("0x100000000").is_err());
+        assert!(parse_elf_flags("-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("100m"), Ok(100 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn test_parse_export_symbols() {
+        assert_eq!(
+            parse_export_symbols(
+                "foo\n\nprog section=xdp/prog1 linkage=global\nhelper linkage=static\n"
+            ),
+            Ok(vec![
+                ExportSymbol {
+                    name: "foo".to_owned(),
+                    section: None,
+                    linkage: ExportLinkage::Global,
+                },
+                ExportSymbol {
+                    name: "prog".to_owned(),
+                    section: Some("xdp/prog1".to_owned()),
+                    linkage: ExportLinkage::Global,
+                },
+                ExportSymbol {
+                    name: "helper".to_owned(),
+                    section: None,
+                    linkage: ExportLinkage::Static,
+                },
+            ])
+        );
+        assert_eq!(
+            parse_export_symbols("foo\nbar linkage=weak\n"),
+            Err(
+                "line 2: invalid attribute `linkage=weak`, expected `section=<section>` or \
+                 `linkage=global|static`"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_export_symbols_formats() {
+        let names = |text| {
+            parse_export_symbols(text)
+                .unwrap()
+                .into_iter()
+                .map(|symbol| symbol.name)
+                .collect::<Vec<_>>()
+        };
+        // the files rustc 1.95 writes for `#[no_mangle]` `prog` and `VERSION`, for bpf-linker and
+        // for the linkers of x86_64-unknown-linux-gnu and x86_64-pc-windows-msvc
+        for text in [
+            include_str!("../tests/symbols/bpf.txt"),
+            include_str!("../tests/symbols/gnu.map"),
+            include_str!("../tests/symbols/msvc.def"),
+        ] {
+            assert_eq!(names(text), ["VERSION", "prog"], "{text}");
+        }
+        // comments, directives, CRLF line endings and a byte order mark
+        assert_eq!(
+            names(
+                "\u{feff}# exported symbols\r\n#pragma once\r\nprog # the program\r\n\r\n\
+                 helper linkage=static\r\n"
+            ),
+            ["prog", "helper"]
+        );
+        // version scripts and module-definition files as people write them
+        assert_eq!(
+            names("{ global: prog; \"helper\"; local: *; };\n"),
+            ["prog", "helper"]
+        );
+        assert_eq!(
+            names("# exports\n{global:prog;helper;local:*;};"),
+            ["prog", "helper"]
+        );
+        assert_eq!(names("{ prog; helper; };\n"), ["prog", "helper"]);
+        assert_eq!(
+            names("LIBRARY foo.dll\nEXPORTS\n  prog @1\n  helper=internal PRIVATE\n"),
+            ["prog", "helper"]
+        );
+    }
+
+    #[test]
+    fn test_is_bpf_target() {
+        for triple in [
+            "bpf",
+            "bpfel",
+            "bpfeb",
+            "bpfel-unknown-none",
+            "bpfeb-unknown-none",
+        ] {
+            assert!(is_bpf_target(triple), "{triple}");
+        }
+        for triple in [
+            "x86_64-unknown-linux-gnu",
+            "bpfel-unknown-linux",
+            "bpfxx",
+            "",
+        ] {
+            assert!(!is_bpf_target(triple), "{triple}");
+        }
+    }
+
+    #[test]
+    fn test_multiple_targets() {
+        let args = [
+            "bpf-linker",
+            "--target",
+            "bpfel,bpfeb",
+            "-o",
+            "prog.o",
+            "input.o",
+        ];
+        let (command_line, _) = CommandLine::try_parse_rustc_args(args).unwrap();
+        let options = command_line.into_linker_options().unwrap();
+        assert_eq!(options.target, None);
+        assert_eq!(options.targets, ["bpfel", "bpfeb"]);
+
+        let args = ["bpf-linker", "--target", "bpfeb", "-o", "prog.o", "input.o"];
+        let (command_line, _) = CommandLine::try_parse_rustc_args(args).unwrap();
+        let options = command_line.into_linker_options().unwrap();
+        assert_eq!(options.target.as_deref(), Some("bpfeb"));
+        assert!(options.targets.is_empty());
+
+        let args = [
+            "bpf-linker",
+            "--target",
+            "bpfel,x86_64",
+            "-o",
+            "prog.o",
+            "input.o",
+        ];
+        let (command_line, _) = CommandLine::try_parse_rustc_args(args).unwrap();
+        assert!(matches!(
+            command_line.into_linker_options(),
+            Err(CliError::UnsupportedTarget(target)) if target == "x86_64"
+        ));
+    }
+
+    #[test]
+    fn test_emit_opt_levels() {
+        let args = [
+            "bpf-linker",
+            "-O3",
+            "--emit",
+            "obj",
+            "-O1",
+            "--emit=llvm-ir=prog.ll",
+            "-O0",
+            "--emit",
+            "asm=prog.s",
+            "-o",
+            "prog.o",
+            "input.o",
+            "--emit=asm",
+        ];
+        let (command_line, _) = CommandLine::try_parse_rustc_args(args).unwrap();
+        let options = command_line.into_linker_options().unwrap();
+        assert_eq!(options.output_type, OutputType::Object);
+        assert_eq!(options.optimize, OptLevel::Less);
+        let extra_outputs: Vec<_> = options
+            .extra_outputs
+            .iter()
+            .map(|extra| (extra.output_type, extra.path.as_path(), extra.optimize))
+            .collect();
+        assert_eq!(
+            extra_outputs,
+            [
+                (
+                    OutputType::LlvmAssembly,
+                    Path::new("prog.ll"),
+                    Some(OptLevel::No)
+                ),
+                (OutputType::Assembly, Path::new("prog.s"), None),
+            ]
+        );
+
+        // with only extra outputs, all the levels apply to them
+        let args = [
+            "bpf-linker",
+            "--emit=asm=prog.s",
+            "-O0",
+            "-o",
+            "prog.o",
+            "input.o",
+        ];
+        let (command_line, _) = CommandLine::try_parse_rustc_args(args).unwrap();
+        let options = command_line.into_linker_options().unwrap();
+        assert_eq!(options.output_type, OutputType::Object);
+        assert_eq!(options.optimize, OptLevel::Default);
+        assert_eq!(options.extra_outputs[0].optimize, Some(OptLevel::No));
+    }
+
+    #[test]
+    fn test_parse_output_type_and_opt_level() {
+        for s in [
+            "llvm-bc",
+            "asm",
+            "llvm-ir",
+            "obj",
+            "skel",
+            "rust-skel",
+            "btf-ids",
+            "disasm",
+        ] {
+            assert_eq!(s.parse::<OutputType>().unwrap().to_string(), s);
+        }
+        for s in ["0", "1", "2", "3", "s", "z"] {
+            assert_eq!(s.parse::<OptLevel>().unwrap().to_string(), s);
+        }
+        assert!(matches!(
+            "exe".parse::<OutputType>(),
+            Err(crate::LinkerError::InvalidOutputType(s)) if s == "exe"
+        ));
+        assert!(matches!(
+            "4".parse::<CliOptLevel>(),
+            Err(CliError::InvalidOptimization(s)) if s == "4"
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+//! Transparent decompression of gzip and zstd compressed inputs, as stored by build caches.
+
+use std::io;
+
+/// Compression format of an input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression format from the magic number at the start of `data`.
+    pub(crate) fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x1f\x8b") {
+            Some(Self::Gzip)
+        } else if data.starts_with(b"\x28\xb5\x2f\xfd") {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    #[cfg(feature = "compressed-inputs")]
+    pub(crate) fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        use std::io::Read as _;
+
+        match self {
+            Self::Gzip => {
+                let mut decompressed = Vec::new();
+                let _: usize = flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            Self::Zstd => zstd::stream::decode_all(data),
+        }
+    }
+
+    #[cfg(not(feature = "compressed-inputs"))]
+    pub(crate) fn decompress(self, _data: &[u8]) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "input is {} compressed but bpf-linker was built without the `compressed-inputs` \
+                 feature",
+                self.name()
+            ),
+        ))
+    }
+}
+
+#[cfg(all(test, feature = "compressed-inputs"))]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decompress() {
+        let data = b"BC\xc0\xde some bitcode";
+        for (compressed, compression) in [
+            (gzip(data), Compression::Gzip),
+            (zstd::encode_all(&data[..], 0).unwrap(), Compression::Zstd),
+        ] {
+            assert_eq!(Compression::detect(&compressed), Some(compression));
+            assert_eq!(compression.decompress(&compressed).unwrap(), data);
+        }
+        // the magic numbers followed by garbage
+        assert!(Compression::Gzip.decompress(b"\x1f\x8bgarbage").is_err());
+        assert!(Compression::Zstd
+            .decompress(b"\x28\xb5\x2f\xfdgarbage")
+            .is_err());
+        assert_eq!(Compression::detect(data), None);
+        assert_eq!(Compression::detect(b"\x1f"), None);
+    }
+}
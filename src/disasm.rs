@@ -0,0 +1,161 @@
+//! Disassembly of object files, annotated with the BTF functions and lines of their instructions
+//! and with the symbols the instructions are relocated against, so that verifier errors can be
+//! triaged without an `llvm-objdump` of a matching version.
+
+use std::fmt::Write as _;
+
+use crate::{
+    btf::{self, Btf},
+    elf, llvm, LinkerError, LinkerOutput,
+};
+
+const INSN_SIZE: usize = 8;
+
+/// Disassembles the code sections of the object file `object`, eg:
+///
+/// ```text
+/// Disassembly of section xdp:
+///
+/// 0000000000000000 <prog>:
+/// ; func prog
+/// ; prog.c:6:3: return XDP_PASS;
+///     0: b7 00 00 00 02 00 00 00  r0 = 2
+///     1: 95 00 00 00 00 00 00 00  exit
+/// ```
+pub(crate) fn disassemble(object: &[u8]) -> Result<String, LinkerError> {
+    let invalid = |e: &dyn ToString| LinkerError::InvalidOutput(e.to_string());
+    let (sections, big_endian) = elf::code_sections(object).map_err(|e| invalid(&e))?;
+    let output = LinkerOutput::new(object.to_vec());
+    let btf = output
+        .section(".BTF")?
+        .map(|data| Btf::parse(&data))
+        .transpose()
+        .map_err(|e| invalid(&e))?;
+    let ext = output
+        .section(".BTF.ext")?
+        .map(|data| btf::parse_ext(&data))
+        .transpose()
+        .map_err(|e| invalid(&e))?;
+
+    let mut out = String::new();
+    // LLVM always emits `.text`, even when it's empty
+    for section in sections
+        .into_iter()
+        .filter(|section| !section.code.is_empty())
+    {
+        let mut annotations = Vec::new();
+        if let (Some(btf), Some([func_info, line_info, _])) = (&btf, &ext) {
+            for record in section_records(btf, func_info, &section.name) {
+                let name = btf
+                    .get(record[1])
+                    .map_or("", |func| btf.string(func.name_off));
+                annotations.push((record[0] as usize, format!("func {name}")));
+            }
+            for record in section_records(btf, line_info, &section.name) {
+                let (file, source) = (btf.string(record[1]), btf.string(record[2]).trim());
+                let (line, column) = (record[3] >> 10, record[3] & 0x3ff);
+                let mut annotation = format!("{file}:{line}:{column}");
+                if !source.is_empty() {
+                    write!(annotation, ": {source}").unwrap();
+                }
+                annotations.push((record[0] as usize, annotation));
+            }
+        }
+        let relocations = elf::relocations(object, &section.name).map_err(|e| invalid(&e))?;
+
+        writeln!(out, "Disassembly of section {}:", section.name).unwrap();
+        let instructions = unsafe { llvm::disassemble(big_endian, &section.code) }
+            .map_err(LinkerError::InvalidOutput)?;
+        let mut offset = 0;
+        for (size, text) in instructions {
+            for (_, name) in section
+                .functions
+                .iter()
+                .filter(|(at, _)| *at as usize == offset)
+            {
+                writeln!(out, "\n{offset:016x} <{name}>:").unwrap();
+            }
+            for (_, annotation) in annotations.iter().filter(|(at, _)| *at == offset) {
+                writeln!(out, "; {annotation}").unwrap();
+            }
+            let bytes: Vec<_> = section.code[offset..offset + size]
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            write!(
+                out,
+                "{:>5}: {:<23}  {}",
+                offset / INSN_SIZE,
+                bytes.join(" "),
+                text.as_deref().unwrap_or("<unknown>")
+            )
+            .unwrap();
+            for (_, symbol) in relocations.iter().filter(|(at, _)| *at as usize == offset) {
+                write!(out, "  ; {symbol}").unwrap();
+            }
+            out.push('\n');
+            offset += size;
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+// Returns the records of `infos` for the section `name`, as their words.
+fn section_records<'a>(
+    btf: &'a Btf,
+    infos: &'a btf::ExtInfos,
+    name: &'a str,
+) -> impl Iterator<Item = &'a [u32]> {
+    let words_per_record = infos.rec_size.unwrap_or(4) as usize / 4;
+    infos
+        .sections
+        .iter()
+        .filter(move |(name_off, _)| btf.string(*name_off) == name)
+        .flat_map(move |(_, records)| records.chunks_exact(words_per_record))
+}
+
+#[cfg(test)]
+mod tests {
+    use llvm_sys::core::{LLVMContextCreate, LLVMContextDispose};
+
+    use super::*;
+    use crate::{Linker, LinkerOptions, OutputType};
+
+    #[test]
+    fn test_disassemble() {
+        let bitcode = unsafe {
+            let context = LLVMContextCreate();
+            let bitcode = llvm::ir_to_bitcode(
+                context,
+                r#"
+                target triple = "bpfel"
+                define i32 @prog(i8* %ctx) section "xdp" { ret i32 2 }
+                "#,
+            );
+            LLVMContextDispose(context);
+            bitcode.unwrap()
+        };
+        let options = LinkerOptions::builder()
+            .input_buffer("input.ll", bitcode)
+            .export("prog")
+            .output("prog.o")
+            .build()
+            .unwrap();
+        let buffers = Linker::new(options)
+            .unwrap()
+            .link_to_buffers(&[OutputType::Object, OutputType::Disassembly])
+            .unwrap();
+        let disassembly = disassemble(&buffers[&OutputType::Object]).unwrap();
+        assert_eq!(
+            disassembly,
+            "Disassembly of section xdp:\n\n0000000000000000 <prog>:\n    0: b7 00 00 00 02 00 00 \
+             00  r0 = 2\n    1: 95 00 00 00 00 00 00 00  exit\n\n"
+        );
+        // what `--emit disasm` writes
+        assert_eq!(
+            String::from_utf8_lossy(&buffers[&OutputType::Disassembly]),
+            disassembly
+        );
+    }
+}
@@ -0,0 +1,1114 @@
+//! Merging of relocatable BPF ELF objects, for inputs which carry machine code but no bitcode.
+//!
+//! This works like the libbpf static linker: sections with the same name are concatenated,
+//! global symbols are resolved by name and relocations are rebased. BPF relocations are `REL`, so
+//! the addend of relocations against section symbols is stored in the instruction or data being
+//! relocated, and is patched with the offset of the section in the merged one. DWARF is dropped,
+//! and the `.BTF` and `.BTF.ext` of the objects are merged with [`crate::btf`].
+
+use std::{collections::HashMap, str};
+
+use thiserror::Error;
+
+use crate::{
+    btf::{self, Btf, BtfError},
+    llvm,
+};
+
+const EM_BPF: u16 = 247;
+const ET_REL: u16 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2MSB: u8 = 2;
+
+const EI_OSABI: usize = 7;
+const E_FLAGS: usize = 0x30;
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const REL_SIZE: usize = 16;
+
+const SHT_NULL: u32 = 0;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+const SHT_LLVM_ADDRSIG: u32 = 0x6fff_4c03;
+
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const SHN_COMMON: u16 = 0xfff2;
+
+const STB_LOCAL: u8 = 0;
+const STB_WEAK: u8 = 2;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+const R_BPF_64_64: u32 = 1;
+const R_BPF_64_ABS64: u32 = 2;
+const R_BPF_64_ABS32: u32 = 3;
+const R_BPF_64_NODYLD32: u32 = 4;
+const R_BPF_64_32: u32 = 10;
+
+const INSN_SIZE: u64 = 8;
+
+/// Error merging ELF objects.
+#[derive(Debug, Error)]
+pub enum ElfError {
+    #[error("invalid ELF object: {0}")]
+    Invalid(String),
+    #[error("`{0}` is defined in more than one object")]
+    DuplicateSymbol(String),
+    #[error("section `{0}` has different types in different objects")]
+    SectionMismatch(String),
+    #[error("the objects have different endianness")]
+    EndiannessMismatch,
+    #[error("unsupported relocation type {0} in `{1}`")]
+    UnsupportedRelocation(u32, String),
+    #[error(transparent)]
+    Btf(#[from] BtfError),
+}
+
+fn invalid(msg: impl Into<String>) -> ElfError {
+    ElfError::Invalid(msg.into())
+}
+
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, data: &[u8], offset: usize) -> Result<u16, ElfError> {
+        let bytes = data
+            .get(offset..offset + 2)
+            .ok_or_else(|| invalid("truncated"))?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(self, data: &[u8], offset: usize) -> Result<u32, ElfError> {
+        let bytes = data
+            .get(offset..offset + 4)
+            .ok_or_else(|| invalid("truncated"))?;
+        let bytes = bytes.try_into().unwrap();
+        Ok(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(self, data: &[u8], offset: usize) -> Result<u64, ElfError> {
+        let bytes = data
+            .get(offset..offset + 8)
+            .ok_or_else(|| invalid("truncated"))?;
+        let bytes = bytes.try_into().unwrap();
+        Ok(if self.big {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    fn put_u16(self, out: &mut Vec<u8>, value: u16) {
+        out.extend(if self.big {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        });
+    }
+
+    fn put_u32(self, out: &mut Vec<u8>, value: u32) {
+        out.extend(if self.big {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        });
+    }
+
+    fn put_u64(self, out: &mut Vec<u8>, value: u64) {
+        out.extend(if self.big {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        });
+    }
+
+    // Adds `delta` to the 32 bit value at `offset`.
+    fn add_u32(self, data: &mut [u8], offset: usize, delta: u64) -> Result<(), ElfError> {
+        let value = self.u32(data, offset)?.wrapping_add(delta as u32);
+        data[offset..offset + 4].copy_from_slice(&if self.big {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        });
+        Ok(())
+    }
+
+    // Adds `delta` to the 64 bit value at `offset`.
+    fn add_u64(self, data: &mut [u8], offset: usize, delta: u64) -> Result<(), ElfError> {
+        let value = self.u64(data, offset)?.wrapping_add(delta);
+        data[offset..offset + 8].copy_from_slice(&if self.big {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        });
+        Ok(())
+    }
+}
+
+/// Returns whether `data` is a relocatable BPF ELF object.
+pub(crate) fn is_bpf_object(data: &[u8]) -> bool {
+    let Some(ident) = data.get(..16) else {
+        return false;
+    };
+    let endian = Endian {
+        big: ident[5] == ELFDATA2MSB,
+    };
+    ident[..4] == *b"\x7fELF"
+        && ident[4] == ELFCLASS64
+        && endian.u16(data, 16).ok() == Some(ET_REL)
+        && endian.u16(data, 18).ok() == Some(EM_BPF)
+}
+
+/// Returns whether `data` is a relocatable BPF object without embedded bitcode.
+pub(crate) fn is_prelinked_object(data: &[u8]) -> bool {
+    Object::parse(data).is_ok_and(|object| {
+        object
+            .sections
+            .iter()
+            .all(|s| !llvm::BITCODE_SECTIONS.contains(&s.name))
+    })
+}
+
+/// Overwrites the OSABI and the flags of the ELF header of the 64-bit object `data`, leaving the
+/// ones which are `None` untouched.
+pub(crate) fn set_header(
+    data: &mut [u8],
+    osabi: Option<u8>,
+    flags: Option<u32>,
+) -> Result<(), ElfError> {
+    if data.len() < EHDR_SIZE || data[..4] != *b"\x7fELF" || data[4] != ELFCLASS64 {
+        return Err(invalid("not a 64-bit ELF object"));
+    }
+    if let Some(osabi) = osabi {
+        data[EI_OSABI] = osabi;
+    }
+    if let Some(flags) = flags {
+        let bytes = if data[5] == ELFDATA2MSB {
+            flags.to_be_bytes()
+        } else {
+            flags.to_le_bytes()
+        };
+        data[E_FLAGS..E_FLAGS + 4].copy_from_slice(&bytes);
+    }
+    Ok(())
+}
+
+struct Section<'a> {
+    name: &'a str,
+    sh_type: u32,
+    flags: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+    size: u64,
+    data: &'a [u8],
+}
+
+struct Symbol<'a> {
+    name: &'a str,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+impl Symbol<'_> {
+    fn bind(&self) -> u8 {
+        self.info >> 4
+    }
+
+    fn kind(&self) -> u8 {
+        self.info & 0xf
+    }
+}
+
+struct Object<'a> {
+    endian: Endian,
+    flags: u32,
+    sections: Vec<Section<'a>>,
+    symbols: Vec<Symbol<'a>>,
+}
+
+fn c_str(data: &[u8], offset: usize) -> Result<&str, ElfError> {
+    let s = data
+        .get(offset..)
+        .ok_or_else(|| invalid("invalid string offset"))?;
+    let end = s
+        .iter()
+        .position(|c| *c == 0)
+        .ok_or_else(|| invalid("unterminated string"))?;
+    str::from_utf8(&s[..end]).map_err(|_| invalid("invalid string"))
+}
+
+impl<'a> Object<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if !is_bpf_object(data) {
+            return Err(invalid("not a relocatable BPF object"));
+        }
+        let endian = Endian {
+            big: data[5] == ELFDATA2MSB,
+        };
+        let flags = endian.u32(data, E_FLAGS)?;
+        let shoff = endian.u64(data, 0x28)? as usize;
+        let shnum = usize::from(endian.u16(data, 0x3c)?);
+        let shstrndx = usize::from(endian.u16(data, 0x3e)?);
+
+        let mut headers = Vec::with_capacity(shnum);
+        for i in 0..shnum {
+            let offset = shoff + i * SHDR_SIZE;
+            let sh_type = endian.u32(data, offset + 4)?;
+            let file_offset = endian.u64(data, offset + 24)? as usize;
+            let size = endian.u64(data, offset + 32)?;
+            let contents = if sh_type == SHT_NOBITS || sh_type == SHT_NULL {
+                &[][..]
+            } else {
+                data.get(file_offset..file_offset + size as usize)
+                    .ok_or_else(|| invalid("truncated section"))?
+            };
+            headers.push((
+                endian.u32(data, offset)? as usize,
+                Section {
+                    name: "",
+                    sh_type,
+                    flags: endian.u64(data, offset + 8)?,
+                    link: endian.u32(data, offset + 40)?,
+                    info: endian.u32(data, offset + 44)?,
+                    align: endian.u64(data, offset + 48)?,
+                    entsize: endian.u64(data, offset + 56)?,
+                    size,
+                    data: contents,
+                },
+            ));
+        }
+        let shstrtab = headers
+            .get(shstrndx)
+            .map(|(_, section)| section.data)
+            .ok_or_else(|| invalid("missing section name table"))?;
+        let mut sections = Vec::with_capacity(shnum);
+        for (name, mut section) in headers {
+            section.name = c_str(shstrtab, name)?;
+            sections.push(section);
+        }
+
+        let mut symbols = Vec::new();
+        if let Some(symtab) = sections.iter().find(|s| s.sh_type == SHT_SYMTAB) {
+            let strtab = sections
+                .get(symtab.link as usize)
+                .ok_or_else(|| invalid("missing symbol name table"))?
+                .data;
+            for sym in symtab.data.chunks_exact(SYM_SIZE) {
+                symbols.push(Symbol {
+                    name: c_str(strtab, endian.u32(sym, 0)? as usize)?,
+                    info: sym[4],
+                    other: sym[5],
+                    shndx: endian.u16(sym, 6)?,
+                    value: endian.u64(sym, 8)?,
+                    size: endian.u64(sym, 16)?,
+                });
+            }
+        }
+
+        Ok(Self {
+            endian,
+            flags,
+            sections,
+            symbols,
+        })
+    }
+}
+
+// A section of the merged object.
+struct OutSection {
+    name: String,
+    sh_type: u32,
+    flags: u64,
+    align: u64,
+    entsize: u64,
+    size: u64,
+    data: Vec<u8>,
+    // (offset, symbol, type) of the relocations of the section
+    relocations: Vec<(u64, SymbolRef, u32)>,
+    symbol: Option<usize>,
+}
+
+// A symbol of the merged object, locals and globals being numbered separately since locals must
+// come first in the symbol table.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SymbolRef {
+    Local(usize),
+    Global(usize),
+}
+
+struct OutSymbol {
+    name: String,
+    info: u8,
+    other: u8,
+    // index in the merged sections, `None` for undefined symbols
+    section: Option<usize>,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+#[derive(Default)]
+struct Merger {
+    sections: Vec<OutSection>,
+    locals: Vec<OutSymbol>,
+    globals: Vec<OutSymbol>,
+    global_names: HashMap<String, usize>,
+    // the offset of each section of each object in the merged section with the same name
+    section_offsets: Vec<HashMap<String, u64>>,
+}
+
+impl Merger {
+    fn section(&mut self, input: &Section<'_>) -> Result<usize, ElfError> {
+        if let Some(index) = self.sections.iter().position(|s| s.name == input.name) {
+            let section = &self.sections[index];
+            if (section.sh_type == SHT_NOBITS) != (input.sh_type == SHT_NOBITS)
+                || section.flags != input.flags
+            {
+                return Err(ElfError::SectionMismatch(input.name.to_owned()));
+            }
+            return Ok(index);
+        }
+        self.sections.push(OutSection {
+            name: input.name.to_owned(),
+            sh_type: input.sh_type,
+            flags: input.flags,
+            align: 1,
+            entsize: input.entsize,
+            size: 0,
+            data: Vec::new(),
+            relocations: Vec::new(),
+            symbol: None,
+        });
+        Ok(self.sections.len() - 1)
+    }
+
+    // Returns the section symbol of the merged section `index`.
+    fn section_symbol(&mut self, index: usize) -> SymbolRef {
+        if let Some(symbol) = self.sections[index].symbol {
+            return SymbolRef::Local(symbol);
+        }
+        self.locals.push(OutSymbol {
+            name: String::new(),
+            info: (STB_LOCAL << 4) | STT_SECTION,
+            other: 0,
+            section: Some(index),
+            shndx: 0,
+            value: 0,
+            size: 0,
+        });
+        self.sections[index].symbol = Some(self.locals.len() - 1);
+        SymbolRef::Local(self.locals.len() - 1)
+    }
+
+    // Merges the `.BTF` and `.BTF.ext` of `objects`, which must have been added, into new
+    // sections. Their relocations are dropped like libbpf does, since they only matter to DWARF.
+    fn merge_btf<'a>(&mut self, objects: &[Object<'a>]) -> Result<(), ElfError> {
+        let section = |object: &Object<'a>, name: &str| -> Option<&'a [u8]> {
+            object
+                .sections
+                .iter()
+                .find(|section| section.name == name)
+                .map(|section| section.data)
+        };
+        // (object, .BTF, .BTF.ext) of the objects with BTF
+        let with_btf: Vec<_> = objects
+            .iter()
+            .enumerate()
+            .filter_map(|(i, object)| {
+                Some((i, section(object, ".BTF")?, section(object, ".BTF.ext")))
+            })
+            .collect();
+        if with_btf.is_empty() {
+            return Ok(());
+        }
+        let btfs = with_btf
+            .iter()
+            .map(|(_, data, _)| Btf::parse(data))
+            .collect::<Result<Vec<_>, _>>()?;
+        let section_offset = |input: usize, name: &str| {
+            let (object, _, _) = with_btf[input];
+            self.section_offsets[object]
+                .get(name)
+                .copied()
+                .unwrap_or_default()
+        };
+        let mut merged = btf::Merged::new(&btfs, section_offset)?;
+        let exts: Vec<_> = with_btf
+            .iter()
+            .enumerate()
+            .filter_map(|(input, (_, _, ext))| Some((input, (*ext)?)))
+            .collect();
+        let ext = (!exts.is_empty())
+            .then(|| btf::merge_ext(&mut merged, &exts, section_offset))
+            .transpose()?;
+        let mut push = |name: &str, data: Vec<u8>| {
+            self.sections.push(OutSection {
+                name: name.to_owned(),
+                sh_type: SHT_PROGBITS,
+                flags: 0,
+                align: 4,
+                entsize: 0,
+                size: data.len() as u64,
+                data,
+                relocations: Vec::new(),
+                symbol: None,
+            })
+        };
+        push(".BTF", merged.to_bytes());
+        if let Some(ext) = ext {
+            push(".BTF.ext", ext);
+        }
+        Ok(())
+    }
+
+    fn global(&mut self, symbol: OutSymbol) -> Result<SymbolRef, ElfError> {
+        let Some(&index) = self.global_names.get(&symbol.name) else {
+            self.globals.push(symbol);
+            let index = self.globals.len() - 1;
+            let _: Option<usize> = self
+                .global_names
+                .insert(self.globals[index].name.clone(), index);
+            return Ok(SymbolRef::Global(index));
+        };
+        let existing = &mut self.globals[index];
+        let defined = |s: &OutSymbol| s.section.is_some() || s.shndx != SHN_UNDEF;
+        let weak = |s: &OutSymbol| s.info >> 4 == STB_WEAK;
+        match (defined(existing), defined(&symbol)) {
+            (_, false) => {}
+            (false, true) => *existing = symbol,
+            (true, true) => match (weak(existing), weak(&symbol)) {
+                (true, false) => *existing = symbol,
+                (_, true) => {}
+                (false, false) => return Err(ElfError::DuplicateSymbol(symbol.name)),
+            },
+        }
+        Ok(SymbolRef::Global(index))
+    }
+
+    fn add(&mut self, object: &Object<'_>) -> Result<(), ElfError> {
+        // where the sections of the object end up, as (merged section, offset)
+        let mut placement = vec![None; object.sections.len()];
+        let mut section_offsets = HashMap::new();
+        for (index, section) in object.sections.iter().enumerate() {
+            let skip = match section.sh_type {
+                SHT_NULL | SHT_SYMTAB | SHT_STRTAB | SHT_REL | SHT_RELA | SHT_LLVM_ADDRSIG => true,
+                _ => {
+                    // BTF is merged separately, once all the sections are placed
+                    section.name.starts_with(".debug_")
+                        || matches!(section.name, ".BTF" | ".BTF.ext")
+                }
+            };
+            if skip {
+                continue;
+            }
+            let out = self.section(section)?;
+            let out_section = &mut self.sections[out];
+            let align = section.align.max(1);
+            let offset = out_section.size.next_multiple_of(align);
+            out_section.align = out_section.align.max(align);
+            if section.sh_type != SHT_NOBITS {
+                out_section.data.resize(offset as usize, 0);
+                out_section.data.extend_from_slice(section.data);
+            }
+            out_section.size = offset + section.size;
+            placement[index] = Some((out, offset));
+            let _: Option<u64> = section_offsets.insert(section.name.to_owned(), offset);
+        }
+        self.section_offsets.push(section_offsets);
+
+        let mut symbols = Vec::with_capacity(object.symbols.len());
+        for symbol in &object.symbols {
+            let placed = placement.get(usize::from(symbol.shndx)).copied().flatten();
+            let mapped = match (symbol.kind(), symbol.bind()) {
+                (STT_FILE, _) => None,
+                _ if symbol.name.is_empty() && symbol.kind() != STT_SECTION => None,
+                (STT_SECTION, _) => placed.map(|(out, offset)| (self.section_symbol(out), offset)),
+                (_, bind) => {
+                    let special = matches!(symbol.shndx, SHN_UNDEF | SHN_ABS | SHN_COMMON);
+                    // symbols in dropped sections such as DWARF
+                    if !special && placed.is_none() {
+                        symbols.push(None);
+                        continue;
+                    }
+                    let out = OutSymbol {
+                        name: symbol.name.to_owned(),
+                        info: symbol.info,
+                        other: symbol.other,
+                        section: placed.map(|(out, _)| out),
+                        shndx: if special { symbol.shndx } else { 0 },
+                        value: symbol.value + placed.map_or(0, |(_, offset)| offset),
+                        size: symbol.size,
+                    };
+                    if bind == STB_LOCAL {
+                        self.locals.push(out);
+                        Some((SymbolRef::Local(self.locals.len() - 1), 0))
+                    } else {
+                        Some((self.global(out)?, 0))
+                    }
+                }
+            };
+            symbols.push(mapped);
+        }
+
+        for section in object.sections.iter() {
+            if section.sh_type == SHT_RELA {
+                return Err(invalid("RELA relocations are not supported"));
+            }
+            if section.sh_type != SHT_REL {
+                continue;
+            }
+            let Some((out, base)) = placement.get(section.info as usize).copied().flatten() else {
+                continue;
+            };
+            for rel in section.data.chunks_exact(REL_SIZE) {
+                let offset = object.endian.u64(rel, 0)?;
+                let info = object.endian.u64(rel, 8)?;
+                let (sym, rel_type) = ((info >> 32) as usize, info as u32);
+                let Some((symbol, addend)) = symbols.get(sym).copied().flatten() else {
+                    return Err(invalid(format!(
+                        "relocation in `{}` against an unknown symbol",
+                        section.name
+                    )));
+                };
+                let offset = base + offset;
+                // relocations against section symbols have their addend in the relocated data,
+                // which must account for where the section moved
+                if addend != 0 {
+                    let data = &mut self.sections[out].data;
+                    let endian = object.endian;
+                    let at = offset as usize;
+                    match rel_type {
+                        R_BPF_64_64 => endian.add_u32(data, at + 4, addend)?,
+                        R_BPF_64_ABS64 => endian.add_u64(data, at, addend)?,
+                        R_BPF_64_ABS32 | R_BPF_64_NODYLD32 => endian.add_u32(data, at, addend)?,
+                        R_BPF_64_32 => endian.add_u32(data, at + 4, addend / INSN_SIZE)?,
+                        _ => {
+                            return Err(ElfError::UnsupportedRelocation(
+                                rel_type,
+                                section.name.to_owned(),
+                            ))
+                        }
+                    }
+                }
+                self.sections[out]
+                    .relocations
+                    .push((offset, symbol, rel_type));
+            }
+        }
+        Ok(())
+    }
+
+    fn write(self, endian: Endian, flags: u32) -> Vec<u8> {
+        let Self {
+            sections,
+            locals,
+            globals,
+            ..
+        } = self;
+        let symbol_index = |symbol: SymbolRef| match symbol {
+            // the first symbol is the null symbol
+            SymbolRef::Local(i) => 1 + i,
+            SymbolRef::Global(i) => 1 + locals.len() + i,
+        };
+
+        let mut shstrtab = StringTable::default();
+        let mut strtab = StringTable::default();
+
+        // section indices: null, merged sections, relocations, symtab, strtab, shstrtab
+        let rel_sections: Vec<usize> = (0..sections.len())
+            .filter(|i| !sections[*i].relocations.is_empty())
+            .collect();
+        let symtab_index = 1 + sections.len() + rel_sections.len();
+        let strtab_index = symtab_index + 1;
+        let shstrtab_index = strtab_index + 1;
+
+        let mut symtab = vec![0; SYM_SIZE];
+        for symbol in locals.iter().chain(&globals) {
+            let shndx = match symbol.section {
+                Some(section) => (section + 1) as u16,
+                None => symbol.shndx,
+            };
+            endian.put_u32(&mut symtab, strtab.add(&symbol.name));
+            symtab.extend([symbol.info, symbol.other]);
+            endian.put_u16(&mut symtab, shndx);
+            endian.put_u64(&mut symtab, symbol.value);
+            endian.put_u64(&mut symtab, symbol.size);
+        }
+
+        // (name, type, flags, data, size, link, info, align, entsize)
+        let mut headers = Vec::new();
+        for section in &sections {
+            headers.push((
+                shstrtab.add(&section.name),
+                section.sh_type,
+                section.flags,
+                section.data.clone(),
+                section.size,
+                0,
+                0,
+                section.align,
+                section.entsize,
+            ));
+        }
+        for &index in &rel_sections {
+            let section = &sections[index];
+            let mut data = Vec::with_capacity(section.relocations.len() * REL_SIZE);
+            for &(offset, symbol, rel_type) in &section.relocations {
+                endian.put_u64(&mut data, offset);
+                endian.put_u64(
+                    &mut data,
+                    ((symbol_index(symbol) as u64) << 32) | u64::from(rel_type),
+                );
+            }
+            let size = data.len() as u64;
+            headers.push((
+                shstrtab.add(&format!(".rel{}", section.name)),
+                SHT_REL,
+                SHF_INFO_LINK,
+                data,
+                size,
+                symtab_index as u32,
+                (index + 1) as u32,
+                8,
+                REL_SIZE as u64,
+            ));
+        }
+        let symtab_size = symtab.len() as u64;
+        headers.push((
+            shstrtab.add(".symtab"),
+            SHT_SYMTAB,
+            0,
+            symtab,
+            symtab_size,
+            strtab_index as u32,
+            (1 + locals.len()) as u32,
+            8,
+            SYM_SIZE as u64,
+        ));
+        let strtab = strtab.data;
+        let strtab_size = strtab.len() as u64;
+        headers.push((
+            shstrtab.add(".strtab"),
+            SHT_STRTAB,
+            0,
+            strtab,
+            strtab_size,
+            0,
+            0,
+            1,
+            0,
+        ));
+        let shstrtab_name = shstrtab.add(".shstrtab");
+        let shstrtab = shstrtab.data;
+        let shstrtab_size = shstrtab.len() as u64;
+        headers.push((
+            shstrtab_name,
+            SHT_STRTAB,
+            0,
+            shstrtab,
+            shstrtab_size,
+            0,
+            0,
+            1,
+            0,
+        ));
+
+        let mut out = vec![0; EHDR_SIZE];
+        let mut offsets = Vec::with_capacity(headers.len());
+        for (_, sh_type, _, data, _, _, _, align, _) in &headers {
+            let offset = out.len().next_multiple_of((*align).max(1) as usize);
+            out.resize(offset, 0);
+            if *sh_type != SHT_NOBITS {
+                out.extend_from_slice(data);
+            }
+            offsets.push(offset as u64);
+        }
+        let shoff = out.len().next_multiple_of(8);
+        out.resize(shoff, 0);
+        out.extend([0; SHDR_SIZE]);
+        for ((name, sh_type, flags, _, size, link, info, align, entsize), offset) in
+            headers.iter().zip(offsets)
+        {
+            endian.put_u32(&mut out, *name);
+            endian.put_u32(&mut out, *sh_type);
+            endian.put_u64(&mut out, *flags);
+            endian.put_u64(&mut out, 0);
+            endian.put_u64(&mut out, offset);
+            endian.put_u64(&mut out, *size);
+            endian.put_u32(&mut out, *link);
+            endian.put_u32(&mut out, *info);
+            endian.put_u64(&mut out, *align);
+            endian.put_u64(&mut out, *entsize);
+        }
+
+        let mut ehdr = Vec::with_capacity(EHDR_SIZE);
+        ehdr.extend(b"\x7fELF");
+        ehdr.extend([ELFCLASS64, if endian.big { ELFDATA2MSB } else { 1 }, 1]);
+        ehdr.extend([0; 9]);
+        endian.put_u16(&mut ehdr, ET_REL);
+        endian.put_u16(&mut ehdr, EM_BPF);
+        endian.put_u32(&mut ehdr, 1);
+        endian.put_u64(&mut ehdr, 0);
+        endian.put_u64(&mut ehdr, 0);
+        endian.put_u64(&mut ehdr, shoff as u64);
+        endian.put_u32(&mut ehdr, flags);
+        endian.put_u16(&mut ehdr, EHDR_SIZE as u16);
+        endian.put_u16(&mut ehdr, 0);
+        endian.put_u16(&mut ehdr, 0);
+        endian.put_u16(&mut ehdr, SHDR_SIZE as u16);
+        endian.put_u16(&mut ehdr, (headers.len() + 1) as u16);
+        endian.put_u16(&mut ehdr, shstrtab_index as u16);
+        out[..EHDR_SIZE].copy_from_slice(&ehdr);
+        out
+    }
+}
+
+#[derive(Default)]
+struct StringTable {
+    data: Vec<u8>,
+    offsets: HashMap<String, u32>,
+}
+
+impl StringTable {
+    fn add(&mut self, s: &str) -> u32 {
+        if self.data.is_empty() {
+            self.data.push(0);
+        }
+        if s.is_empty() {
+            return 0;
+        }
+        if let Some(offset) = self.offsets.get(s) {
+            return *offset;
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend(s.as_bytes());
+        self.data.push(0);
+        let _: Option<u32> = self.offsets.insert(s.to_owned(), offset);
+        offset
+    }
+}
+
+/// Returns the relocations of the section `name` of the relocatable BPF object `data`, as the
+/// offset they apply to and the name of the symbol they refer to. Relocations against section
+/// symbols, eg of static functions, are left out as they name no symbol.
+pub(crate) fn relocations(data: &[u8], name: &str) -> Result<Vec<(u64, String)>, ElfError> {
+    let object = Object::parse(data)?;
+    let Some(index) = object.sections.iter().position(|s| s.name == name) else {
+        return Ok(Vec::new());
+    };
+    let mut relocations = Vec::new();
+    for section in object
+        .sections
+        .iter()
+        .filter(|s| s.sh_type == SHT_REL && s.info as usize == index)
+    {
+        for rel in section.data.chunks_exact(REL_SIZE) {
+            let offset = object.endian.u64(rel, 0)?;
+            let sym = (object.endian.u64(rel, 8)? >> 32) as usize;
+            let symbol = object
+                .symbols
+                .get(sym)
+                .ok_or_else(|| invalid(format!("relocation in `{name}` of an unknown symbol")))?;
+            if symbol.kind() != STT_SECTION {
+                relocations.push((offset, symbol.name.to_owned()));
+            }
+        }
+    }
+    Ok(relocations)
+}
+
+/// Returns the names of the global symbols the relocatable BPF object `data` defines, and of the
+/// ones it references without defining them.
+pub(crate) fn global_symbols(data: &[u8]) -> Result<(Vec<String>, Vec<String>), ElfError> {
+    let object = Object::parse(data)?;
+    let (mut defined, mut undefined) = (Vec::new(), Vec::new());
+    for symbol in &object.symbols {
+        if symbol.bind() == STB_LOCAL
+            || symbol.name.is_empty()
+            || matches!(symbol.kind(), STT_SECTION | STT_FILE)
+        {
+            continue;
+        }
+        if symbol.shndx == SHN_UNDEF {
+            undefined.push(symbol.name.to_owned());
+        } else {
+            defined.push(symbol.name.to_owned());
+        }
+    }
+    Ok((defined, undefined))
+}
+
+/// A code section of a relocatable BPF object.
+pub(crate) struct CodeSection {
+    pub name: String,
+    pub code: Vec<u8>,
+    /// The functions defined in the section, as their offset in the section and their name,
+    /// sorted by offset.
+    pub functions: Vec<(u64, String)>,
+}
+
+/// Returns the code sections of the relocatable BPF object `data`, and whether the object is big
+/// endian.
+pub(crate) fn code_sections(data: &[u8]) -> Result<(Vec<CodeSection>, bool), ElfError> {
+    let object = Object::parse(data)?;
+    let mut sections = Vec::new();
+    for (index, section) in object.sections.iter().enumerate() {
+        if section.sh_type != SHT_PROGBITS || section.flags & SHF_EXECINSTR == 0 {
+            continue;
+        }
+        let mut functions: Vec<_> = object
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.kind() == STT_FUNC && usize::from(symbol.shndx) == index)
+            .map(|symbol| (symbol.value, symbol.name.to_owned()))
+            .collect();
+        functions.sort();
+        sections.push(CodeSection {
+            name: section.name.to_owned(),
+            code: section.data.to_vec(),
+            functions,
+        });
+    }
+    Ok((sections, object.endian.big))
+}
+
+/// Merges the relocatable BPF objects `objects` into one.
+pub(crate) fn merge(objects: &[&[u8]]) -> Result<Vec<u8>, ElfError> {
+    let objects = objects
+        .iter()
+        .map(|data| Object::parse(data))
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(first) = objects.first() else {
+        return Err(invalid("no objects to merge"));
+    };
+    let (endian, flags) = (first.endian, first.flags);
+    let mut merger = Merger::default();
+    for object in &objects {
+        if object.endian.big != endian.big {
+            return Err(ElfError::EndiannessMismatch);
+        }
+        merger.add(object)?;
+    }
+    merger.merge_btf(&objects)?;
+    Ok(merger.write(endian, flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STB_GLOBAL: u8 = 1;
+    const SHF_ALLOC_EXEC: u64 = 0x6;
+    const SHF_WRITE_ALLOC: u64 = 0x3;
+
+    // Builds an object with the sections `sections` as (name, flags, data) and the symbols
+    // `symbols` as (name, bind, section, value), relocated by `relocations` as (section, offset,
+    // symbol, type). Section 0 is the first of `sections`, symbol 0 the first of `symbols`.
+    fn object(
+        sections: &[(&str, u64, &[u8])],
+        symbols: &[(&str, u8, usize, u64)],
+        relocations: &[(usize, u64, usize, u32)],
+    ) -> Vec<u8> {
+        let endian = Endian { big: false };
+        let mut merger = Merger::default();
+        for (name, flags, data) in sections {
+            merger.sections.push(OutSection {
+                name: (*name).to_owned(),
+                sh_type: 1,
+                flags: *flags,
+                align: 8,
+                entsize: 0,
+                size: data.len() as u64,
+                data: data.to_vec(),
+                relocations: Vec::new(),
+                symbol: None,
+            });
+        }
+        let mut refs = Vec::new();
+        for (name, bind, section, value) in symbols {
+            let symbol = OutSymbol {
+                name: (*name).to_owned(),
+                info: if name.is_empty() {
+                    (STB_LOCAL << 4) | STT_SECTION
+                } else {
+                    bind << 4
+                },
+                other: 0,
+                section: Some(*section),
+                shndx: 0,
+                value: *value,
+                size: 8,
+            };
+            if *bind == STB_LOCAL {
+                merger.locals.push(symbol);
+                refs.push(SymbolRef::Local(merger.locals.len() - 1));
+            } else {
+                merger.globals.push(symbol);
+                refs.push(SymbolRef::Global(merger.globals.len() - 1));
+            }
+        }
+        for (section, offset, symbol, rel_type) in relocations {
+            merger.sections[*section]
+                .relocations
+                .push((*offset, refs[*symbol], *rel_type));
+        }
+        merger.write(endian, 0)
+    }
+
+    // ld_imm64 r1, <imm>
+    fn ld_imm64(imm: u32) -> Vec<u8> {
+        let mut insn = vec![0x18, 0x01, 0, 0];
+        insn.extend(imm.to_le_bytes());
+        insn.extend([0; 8]);
+        insn
+    }
+
+    #[test]
+    fn test_set_header() {
+        let mut data = object(&[("xdp", SHF_ALLOC_EXEC, &ld_imm64(4))], &[], &[]);
+        set_header(&mut data, Some(3), None).unwrap();
+        assert_eq!(data[EI_OSABI], 3);
+        assert_eq!(Object::parse(&data).unwrap().flags, 0);
+        set_header(&mut data, None, Some(0x1234)).unwrap();
+        assert_eq!(data[EI_OSABI], 3);
+        assert_eq!(Object::parse(&data).unwrap().flags, 0x1234);
+        assert!(set_header(&mut data[..16], Some(0), None).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let a = object(
+            &[
+                ("xdp", SHF_ALLOC_EXEC, &ld_imm64(4)),
+                (".data", SHF_WRITE_ALLOC, &[1; 8]),
+            ],
+            &[("", STB_LOCAL, 1, 0), ("prog_a", STB_GLOBAL, 0, 0)],
+            &[(0, 0, 0, R_BPF_64_64)],
+        );
+        let b = object(
+            &[
+                ("xdp", SHF_ALLOC_EXEC, &ld_imm64(4)),
+                (".data", SHF_WRITE_ALLOC, &[2; 12]),
+            ],
+            &[
+                ("", STB_LOCAL, 1, 0),
+                ("prog_b", STB_GLOBAL, 0, 0),
+                ("counter", STB_GLOBAL, 1, 4),
+            ],
+            &[(0, 0, 0, R_BPF_64_64)],
+        );
+        let merged = merge(&[&a, &b]).unwrap();
+        let object = Object::parse(&merged).unwrap();
+
+        let section = |name| {
+            let index = object.sections.iter().position(|s| s.name == name).unwrap();
+            (index, &object.sections[index])
+        };
+        let (xdp, xdp_section) = section("xdp");
+        let (data, data_section) = section(".data");
+        assert_eq!(xdp_section.size, 32);
+        assert_eq!(data_section.size, 20);
+        // the second ld_imm64 points 4 bytes into the data of b, which now starts at 8
+        assert_eq!(&xdp_section.data[20..24], &12u32.to_le_bytes());
+
+        let symbol = |name| object.symbols.iter().find(|s| s.name == name).unwrap();
+        assert_eq!(usize::from(symbol("prog_b").shndx), xdp);
+        assert_eq!(symbol("prog_b").value, 16);
+        assert_eq!(usize::from(symbol("counter").shndx), data);
+        assert_eq!(symbol("counter").value, 12);
+
+        let (_, rel) = section(".relxdp");
+        let relocations: Vec<_> = rel
+            .data
+            .chunks_exact(REL_SIZE)
+            .map(|rel| {
+                let endian = Endian { big: false };
+                let info = endian.u64(rel, 8).unwrap();
+                let symbol = &object.symbols[(info >> 32) as usize];
+                (
+                    endian.u64(rel, 0).unwrap(),
+                    symbol.kind(),
+                    usize::from(symbol.shndx),
+                )
+            })
+            .collect();
+        assert_eq!(
+            relocations,
+            [(0, STT_SECTION, data), (16, STT_SECTION, data)]
+        );
+    }
+
+    #[test]
+    fn test_relocations() {
+        let object = object(
+            &[
+                ("struct_ops/init", SHF_ALLOC_EXEC, &ld_imm64(0)),
+                (".struct_ops", SHF_WRITE_ALLOC, &[0; 24]),
+            ],
+            &[
+                ("", STB_LOCAL, 0, 0),
+                ("init", STB_GLOBAL, 0, 0),
+                ("ops", STB_GLOBAL, 1, 0),
+            ],
+            &[(1, 8, 1, R_BPF_64_ABS64), (1, 16, 0, R_BPF_64_ABS64)],
+        );
+        assert_eq!(
+            relocations(&object, ".struct_ops").unwrap(),
+            [(8, "init".to_owned())]
+        );
+        assert_eq!(relocations(&object, ".maps").unwrap(), []);
+    }
+
+    #[test]
+    fn test_merge_duplicate_symbol() {
+        let a = object(
+            &[("xdp", SHF_ALLOC_EXEC, &[0; 8])],
+            &[("prog", STB_GLOBAL, 0, 0)],
+            &[],
+        );
+        assert!(matches!(
+            merge(&[&a, &a]),
+            Err(ElfError::DuplicateSymbol(name)) if name == "prog"
+        ));
+        let weak = object(
+            &[("xdp", SHF_ALLOC_EXEC, &[0; 8])],
+            &[("prog", STB_WEAK, 0, 0)],
+            &[],
+        );
+        assert!(merge(&[&a, &weak]).is_ok());
+    }
+
+    #[test]
+    fn test_is_bpf_object() {
+        assert!(is_bpf_object(&object(&[], &[], &[])));
+        assert!(!is_bpf_object(b"\x7fELF"));
+    }
+}
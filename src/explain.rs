@@ -0,0 +1,187 @@
+//! Explanations of what optimization did to some symbols and why, for finding out why a symbol
+//! is missing from the output.
+
+use std::fmt::Write as _;
+
+use crate::llvm::{SymbolKind, SymbolState};
+
+/// Why a symbol is exported, ie keeps external linkage through internalization.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExportReason {
+    /// Exported by the options, eg `--export` or `--export-symbols`.
+    Requested,
+    /// A memory builtin, exported unless memory builtins are disabled.
+    MemoryBuiltin,
+    /// A struct_ops map or program, which libbpf looks up by name.
+    StructOps,
+    /// The function call counters added by instrumentation.
+    ProfileCounters,
+}
+
+/// The decision path of the symbol `name` through optimization, one step per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolExplanation {
+    pub name: String,
+    pub steps: Vec<String>,
+}
+
+impl SymbolExplanation {
+    /// Starts the explanation of `name` from its state in the linked module, before
+    /// internalization.
+    pub(crate) fn before(
+        name: &str,
+        state: Option<SymbolState>,
+        export: Option<ExportReason>,
+        kept: bool,
+    ) -> Self {
+        let mut steps = Vec::new();
+        match state {
+            None => steps.push("no input defines or references it".to_owned()),
+            Some(state) if !state.defined => {
+                steps.push(format!(
+                    "declared but not defined by the inputs ({})",
+                    kind(state)
+                ));
+            }
+            Some(state) => {
+                steps.push(format!("defined by the inputs ({})", kind(state)));
+                if name.starts_with("llvm.") {
+                    steps.push("LLVM intrinsics are never internalized".to_owned());
+                } else if let Some(export) = export {
+                    steps.push(match export {
+                        ExportReason::Requested => "exported: in the export list".to_owned(),
+                        ExportReason::MemoryBuiltin => {
+                            "exported: memory builtin, exported unless memory builtins are \
+                             disabled"
+                                .to_owned()
+                        }
+                        ExportReason::StructOps => {
+                            "exported: struct_ops map or program, looked up by libbpf".to_owned()
+                        }
+                        ExportReason::ProfileCounters => {
+                            "exported: function call counters added by instrumentation".to_owned()
+                        }
+                    });
+                } else {
+                    steps.push("internalized: not in the export list".to_owned());
+                }
+                if kept {
+                    steps.push("kept in llvm.used by a keep-symbol pattern".to_owned());
+                }
+            }
+        }
+        SymbolExplanation {
+            name: name.to_owned(),
+            steps,
+        }
+    }
+
+    /// Ends the explanation with the state of the symbol after optimization, given its state
+    /// `before`.
+    pub(crate) fn after(
+        &mut self,
+        before: SymbolState,
+        state: Option<SymbolState>,
+        map_removed: bool,
+        ksyms: bool,
+    ) {
+        let step = match state {
+            None if !before.defined => "unreferenced once optimized, dropped from the output",
+            None if map_removed => "removed after optimization: a map no program references",
+            None => {
+                "removed by optimization: unreferenced once internalized, eg inlined into every \
+                 caller"
+            }
+            Some(state) if !state.defined && state.ksyms => "undefined, already in .ksyms",
+            Some(state) if !state.defined && !state.used => {
+                "unreferenced declaration, dropped from the output"
+            }
+            Some(state) if !state.defined && ksyms => {
+                "undefined after optimization, moved to .ksyms for the loader to resolve against \
+                 kernel symbols"
+            }
+            Some(state) if !state.defined => "undefined after optimization",
+            Some(state) if state.internal => "kept as a local symbol of the output",
+            Some(_) => "in the output as a global symbol",
+        };
+        self.steps.push(step.to_owned());
+    }
+}
+
+fn kind(state: SymbolState) -> &'static str {
+    match state.kind {
+        SymbolKind::Function => "function",
+        SymbolKind::Global => "global variable",
+    }
+}
+
+/// Renders `explanations` as one indented list of steps per symbol.
+pub(crate) fn report(explanations: &[SymbolExplanation]) -> String {
+    let mut report = String::new();
+    for SymbolExplanation { name, steps } in explanations {
+        writeln!(report, "{name}:").unwrap();
+        for step in steps {
+            writeln!(report, "  {step}").unwrap();
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(defined: bool, internal: bool) -> Option<SymbolState> {
+        Some(SymbolState {
+            kind: SymbolKind::Function,
+            defined,
+            internal,
+            used: true,
+            ksyms: false,
+        })
+    }
+
+    #[test]
+    fn test_explanations() {
+        let mut inlined = SymbolExplanation::before("helper", state(true, false), None, false);
+        inlined.after(state(true, false).unwrap(), None, false, false);
+        let mut memcpy = SymbolExplanation::before(
+            "memcpy",
+            state(true, false),
+            Some(ExportReason::MemoryBuiltin),
+            false,
+        );
+        memcpy.after(
+            state(true, false).unwrap(),
+            state(true, false),
+            false,
+            false,
+        );
+        let mut kfunc = SymbolExplanation::before("bpf_kfunc", state(false, false), None, false);
+        kfunc.after(
+            state(false, false).unwrap(),
+            state(false, false),
+            false,
+            true,
+        );
+        let missing = SymbolExplanation::before("missing", None, None, false);
+
+        assert_eq!(
+            report(&[inlined, memcpy, kfunc, missing]),
+            "helper:
+  defined by the inputs (function)
+  internalized: not in the export list
+  removed by optimization: unreferenced once internalized, eg inlined into every caller
+memcpy:
+  defined by the inputs (function)
+  exported: memory builtin, exported unless memory builtins are disabled
+  in the output as a global symbol
+bpf_kfunc:
+  declared but not defined by the inputs (function)
+  undefined after optimization, moved to .ksyms for the loader to resolve against kernel symbols
+missing:
+  no input defines or references it
+"
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Shell style matching of symbol names.
+
+/// Returns whether `name` matches `pattern`, where `*` matches any sequence of characters and `?`
+/// matches a single character.
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+    let (mut p, mut n) = (0, 0);
+    // where to resume after the last `*` if what follows it stops matching
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    n = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("helper", "helper"));
+        assert!(!matches("helper", "helpers"));
+        assert!(matches("*helper*", "_ZN6my_lib6helper17h0123456789abcdefE"));
+        assert!(matches("help?r", "helper"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+        assert!(!matches("a*b*c", "aXXbYYb"));
+        assert!(!matches("", "a"));
+    }
+}
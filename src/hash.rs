@@ -0,0 +1,73 @@
+//! Small, stable content hashes. Unlike `std`'s `DefaultHasher`, their output doesn't change
+//! between Rust releases, so it can be written to the output and compared across builds. SHA-256,
+//! for hashes meant to be compared by other tools, comes from the `sha2` crate.
+
+use std::hash::Hasher;
+
+use siphasher::sip::SipHasher13;
+
+/// 64-bit FNV-1a.
+pub(crate) struct Fnv1a64(u64);
+
+impl Fnv1a64 {
+    pub(crate) fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Hashes `data` in one go.
+    pub(crate) fn hash(data: &[u8]) -> u64 {
+        let mut hasher = Self::new();
+        hasher.write(data);
+        hasher.finish()
+    }
+}
+
+impl Hasher for Fnv1a64 {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// SipHash-1-3 with zero keys, what `std`'s `DefaultHasher::new()` computes today, frozen so that
+/// the names it shortens don't change with the Rust release bpf-linker is built with.
+pub(crate) fn siphash13(data: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// Formats `bytes` as lowercase hex.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fnv1a64() {
+        assert_eq!(Fnv1a64::hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(Fnv1a64::hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(Fnv1a64::hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn test_siphash13() {
+        // what `DefaultHasher::new()` returns for the same writes as of Rust 1.95
+        assert_eq!(siphash13(b""), 0xd1fb_a762_150c_532c);
+        assert_eq!(siphash13(b"a"), 0x4074_48d2_b89b_1813);
+        assert_eq!(siphash13(b"abcdefgh"), 0x3f7b_849c_0b8e_35ea);
+        assert_eq!(
+            siphash13(b"the quick brown fox jumps"),
+            0x00c9_8b97_e4f7_0042
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Inspection of the inputs, to tell what the linker infers from them without linking them.
+
+use std::{
+    fs::{self, File},
+    io::Read as _,
+    path::Path,
+    str,
+};
+
+use llvm_sys::prelude::LLVMContextRef;
+
+use crate::{
+    elf,
+    linker::{detect_input_type, for_each_archive_member, InputType},
+    llvm,
+    output::with_context,
+    thin_archive, InputId, Linker, LinkerError, LinkerInput,
+};
+
+/// The format of an input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputKind {
+    /// LLVM bitcode.
+    Bitcode,
+    /// ELF object file, with or without embedded bitcode.
+    Elf,
+    /// Archive file, whose members are in [`InputInfo::members`].
+    Archive,
+    /// Thin archive, whose members are in [`InputInfo::members`].
+    ThinArchive,
+    /// Rust crate metadata, eg the `lib.rmeta` member of rlibs. The linker skips it, as it has no
+    /// code.
+    RustMetadata,
+    /// Textual LLVM IR. The linker doesn't accept it, it must be assembled to bitcode first.
+    Ir,
+    /// Anything else. The linker ignores such inputs.
+    Unknown,
+}
+
+/// What [`Linker::inspect`] found out about an input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputInfo {
+    pub id: InputId,
+    pub kind: InputKind,
+    /// The target triple of the module, if the input has one. For archives, the triple of the
+    /// members if they all agree.
+    pub target: Option<String>,
+    /// Whether the input contains BPF code: a module with a BPF target triple or a BPF object
+    /// file. For archives, whether any member does.
+    pub bpf: bool,
+    /// The members of an archive, empty for other inputs.
+    pub members: Vec<InputInfo>,
+}
+
+impl Linker {
+    /// Detects the type and the target triple of each of `inputs`, and whether they contain BPF
+    /// code, without linking them.
+    ///
+    /// When no target is set, the target is inferred from the first linked module: its own if it
+    /// contains BPF code, `bpf` otherwise. Build systems can use this to tell when to set
+    /// [`LinkerOptions::target`](crate::LinkerOptions::target) explicitly instead.
+    pub fn inspect(inputs: &[LinkerInput]) -> Result<Vec<InputInfo>, LinkerError> {
+        with_context(|context| {
+            inputs
+                .iter()
+                .map(|input| {
+                    let id = input.id();
+                    let data = match input {
+                        LinkerInput::File(path) => fs::read(path)
+                            .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?,
+                        LinkerInput::Buffer { bytes, .. } => bytes.clone(),
+                    };
+                    unsafe { inspect(context, id, data) }
+                })
+                .collect()
+        })
+    }
+}
+
+unsafe fn inspect(
+    context: LLVMContextRef,
+    id: InputId,
+    data: Vec<u8>,
+) -> Result<InputInfo, LinkerError> {
+    let mut members = Vec::new();
+    let (kind, target) = match detect_input_type(&data) {
+        Some(InputType::Compressed(compression)) => {
+            let data = compression
+                .decompress(&data)
+                .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+            return inspect(context, id, data);
+        }
+        Some(InputType::Bitcode) => {
+            let target = llvm::bitcode::unwrap(data.clone())
+                .and_then(|bitcode| llvm::bitcode_target(context, &bitcode));
+            (InputKind::Bitcode, target)
+        }
+        Some(InputType::RustMetadata) => (InputKind::RustMetadata, None),
+        Some(InputType::Elf) if llvm::is_rust_metadata(&data) => (InputKind::RustMetadata, None),
+        Some(InputType::Elf) => {
+            let target = match llvm::find_embedded_bitcode(&data) {
+                Ok(Some(llvm::EmbeddedBitcode { bitcode, .. })) => llvm::bitcode::unwrap(bitcode)
+                    .and_then(|bitcode| llvm::bitcode_target(context, &bitcode)),
+                _ => None,
+            };
+            (InputKind::Elf, target)
+        }
+        Some(InputType::Archive) => {
+            for_each_archive_member(&id, &data, |member, item| {
+                members.push(inspect(context, member, item.to_vec())?);
+                Ok(())
+            })?;
+            (InputKind::Archive, common_target(&members))
+        }
+        Some(InputType::ThinArchive) => {
+            let names = thin_archive::members(&data)
+                .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+            // member paths are relative to the archive
+            let dir = match &id {
+                InputId::File(path) => path.parent().unwrap_or(Path::new("")),
+                _ => Path::new(""),
+            };
+            for name in names {
+                let member = InputId::ArchiveMember {
+                    archive: Box::new(id.clone()),
+                    member: name.clone(),
+                };
+                let mut data = Vec::new();
+                let _: usize = File::open(dir.join(&name))
+                    .and_then(|mut file| file.read_to_end(&mut data))
+                    .map_err(|e| LinkerError::ReadInputError(member.clone(), e))?;
+                members.push(inspect(context, member, data)?);
+            }
+            (InputKind::ThinArchive, common_target(&members))
+        }
+        Some(InputType::MachO) | None => match ir_target(&data) {
+            Some(target) => (InputKind::Ir, Some(target)),
+            None => (InputKind::Unknown, None),
+        },
+    };
+    let target = target.filter(|target| !target.is_empty());
+    let bpf = match kind {
+        InputKind::Archive | InputKind::ThinArchive => members.iter().any(|member| member.bpf),
+        // like the linker when inferring the target
+        _ => {
+            target
+                .as_deref()
+                .is_some_and(|target| target.starts_with("bpf"))
+                || (kind == InputKind::Elf && target.is_none() && elf::is_bpf_object(&data))
+        }
+    };
+    Ok(InputInfo {
+        id,
+        kind,
+        target,
+        bpf,
+        members,
+    })
+}
+
+fn common_target(members: &[InputInfo]) -> Option<String> {
+    let mut targets = members.iter().filter_map(|member| member.target.as_deref());
+    let first = targets.next()?;
+    targets
+        .all(|target| target == first)
+        .then(|| first.to_owned())
+}
+
+// Returns the target triple of `data` if it's textual LLVM IR, empty if the IR doesn't set one.
+fn ir_target(data: &[u8]) -> Option<String> {
+    let text = str::from_utf8(data).ok()?;
+    let mut is_ir = false;
+    for line in text.lines() {
+        if let Some(triple) = line.strip_prefix("target triple = ") {
+            return Some(triple.trim_matches('"').to_owned());
+        }
+        is_ir |= [
+            "; ModuleID = ",
+            "source_filename = ",
+            "target datalayout = ",
+        ]
+        .iter()
+        .any(|prefix| line.starts_with(prefix));
+    }
+    is_ir.then(String::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process::Command};
+
+    use super::*;
+
+    #[test]
+    fn test_rust_metadata() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("dep.rs");
+        fs::write(&source, "pub fn f() -> u32 { 1 }\n").unwrap();
+        // an rlib, whose lib.rmeta member is an object file, and raw metadata
+        let status = Command::new(env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned()))
+            .args([
+                "--crate-type=rlib",
+                "--crate-name=dep",
+                "--emit=link,metadata",
+            ])
+            // the bitcode of the host rustc may be too recent for the LLVM the linker uses
+            .args(["-C", "embed-bitcode=no"])
+            .arg("--out-dir")
+            .arg(dir)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let rmeta = fs::read(dir.join("libdep.rmeta")).unwrap();
+        assert_eq!(detect_input_type(&rmeta), Some(InputType::RustMetadata));
+        let info = Linker::inspect(&[
+            LinkerInput::File(dir.join("libdep.rlib")),
+            LinkerInput::File(dir.join("libdep.rmeta")),
+        ])
+        .unwrap();
+
+        assert_eq!(info[0].kind, InputKind::Archive);
+        let kinds: Vec<_> = info[0]
+            .members
+            .iter()
+            .filter_map(|info| match &info.id {
+                InputId::ArchiveMember { member, .. } => Some((member.as_str(), info.kind)),
+                _ => None,
+            })
+            .collect();
+        assert!(kinds.contains(&("lib.rmeta", InputKind::RustMetadata)));
+        assert_eq!(info[1].kind, InputKind::RustMetadata);
+    }
+
+    #[test]
+    fn test_ir_target() {
+        assert_eq!(
+            ir_target(b"; ModuleID = 'a'\ntarget triple = \"bpfel\"\ndefine void @f() {\n"),
+            Some("bpfel".to_owned())
+        );
+        assert_eq!(
+            ir_target(b"source_filename = \"a.c\"\n"),
+            Some(String::new())
+        );
+        assert_eq!(ir_target(b"int main() {}\n"), None);
+        assert_eq!(ir_target(b"\xff\xfe"), None);
+    }
+}
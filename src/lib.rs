@@ -1,7 +1,56 @@
 #![deny(clippy::all)]
 #![deny(unused_results)]
 
+mod asm;
+pub mod btf;
+mod builder;
+mod cli;
+mod compression;
+mod disasm;
+mod elf;
+mod explain;
+mod glob;
+mod hash;
+mod inspect;
 mod linker;
 mod llvm;
+#[cfg(feature = "rust-llvm")]
+pub mod llvm_proxy;
+mod llvmcmd;
+mod output;
+mod policy;
+mod pool;
+mod probe;
+mod query;
+mod skel;
+mod stack;
+mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod thin_archive;
+mod validate;
 
+pub use builder::LinkerOptionsBuilder;
+pub use cli::{BinaryOptions, CliError, Invocation};
+pub use explain::SymbolExplanation;
+pub use inspect::{InputInfo, InputKind};
 pub use linker::*;
+pub use output::{LinkerOutput, Map, Program, ProgramType};
+pub use policy::{PolicySymbol, SymbolKind, SymbolLinkage, SymbolPolicy};
+pub use pool::LinkerPool;
+pub use query::{answer_llvm_query, check_llvm_options, cpu_features};
+pub use stats::LinkerStats;
+
+/// The cargo features bpf-linker was built with, which `bpf-linker --version` lists. Besides
+/// selecting the LLVM it uses, they only add optional dependencies and the `testing` helpers:
+/// the linking pipeline, eg BTF generation, is always built and enabled by options.
+pub const FEATURES: &[&str] = &[
+    #[cfg(feature = "rust-llvm")]
+    "rust-llvm",
+    #[cfg(feature = "aya-obj")]
+    "aya-obj",
+    #[cfg(feature = "compressed-inputs")]
+    "compressed-inputs",
+    #[cfg(feature = "testing")]
+    "testing",
+];
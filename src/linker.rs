@@ -1,31 +1,50 @@
 use std::{
     borrow::Cow,
-    collections::HashSet,
-    ffi::{CStr, CString},
-    fs::File,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    ffi::{CStr, CString, OsStr, OsString},
+    fmt::Write as _,
+    fs::{self, File},
     io,
     io::{Read, Seek},
+    mem,
     os::unix::ffi::OsStrExt as _,
     path::{Path, PathBuf},
-    ptr, str,
+    ptr,
+    rc::Rc,
+    str,
     str::FromStr,
+    time::Instant,
 };
 
-use ar::Archive;
 use llvm_sys::{
     bit_writer::LLVMWriteBitcodeToFile,
     core::{
-        LLVMContextCreate, LLVMContextDispose, LLVMContextSetDiagnosticHandler, LLVMDisposeModule,
-        LLVMGetTarget,
+        LLVMCloneModule, LLVMContextCreate, LLVMContextDispose, LLVMContextSetDiagnosticHandler,
+        LLVMDisposeModule, LLVMGetTarget,
     },
     error_handling::{LLVMEnablePrettyStackTrace, LLVMInstallFatalErrorHandler},
     prelude::{LLVMContextRef, LLVMModuleRef},
     target_machine::{LLVMCodeGenFileType, LLVMDisposeTargetMachine, LLVMTargetMachineRef},
 };
+use object::read::archive::ArchiveFile;
+use sha2::{Digest as _, Sha256};
 use thiserror::Error;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, field, info, info_span, warn, Span};
 
-use crate::llvm;
+use crate::{
+    asm::{self, AsmOptions},
+    btf::{self, Btf},
+    compression::Compression,
+    disasm, elf,
+    explain::{ExportReason, SymbolExplanation},
+    glob,
+    hash::{to_hex, Fnv1a64},
+    llvm,
+    llvmcmd::EmbeddedCmdline,
+    pool::{PoolState, TargetMachineKey},
+    probe, skel, stack, stats, thin_archive, validate, CliError, Invocation, LinkerOutput,
+    LinkerStats, PolicySymbol, SymbolLinkage, SymbolPolicy,
+};
 
 /// Linker error
 #[derive(Debug, Error)]
@@ -34,25 +53,113 @@ pub enum LinkerError {
     #[error("invalid CPU {0}")]
     InvalidCpu(String),
 
+    /// Invalid Cpu feature, features must be `+feature` or `-feature`.
+    #[error("invalid CPU feature {0}")]
+    InvalidCpuFeature(String),
+
+    /// No output was set.
+    #[error("no output set")]
+    MissingOutput,
+
+    /// Invalid undefined symbols policy.
+    #[error("invalid undefined symbols policy {0}")]
+    InvalidUndefinedSymbols(String),
+
+    /// Invalid optimization level.
+    #[error("invalid optimization level {0}, expected one of 0-3, s or z")]
+    InvalidOptLevel(String),
+
+    /// Invalid output type.
+    #[error(
+        "invalid output type {0}, expected one of llvm-bc, asm, llvm-ir, obj, skel, rust-skel, \
+         btf-ids or disasm"
+    )]
+    InvalidOutputType(String),
+
+    /// Invalid assembly dialect.
+    #[error("invalid assembly dialect {0}")]
+    InvalidAsmDialect(String),
+
+    /// Invalid function instrumentation mode.
+    #[error("invalid function instrumentation mode {0}")]
+    InvalidInstrumentFunctions(String),
+
+    /// Invalid trap handling mode.
+    #[error("invalid trap mode {0}")]
+    InvalidBpfTrap(String),
+
+    /// Invalid code model.
+    #[error("invalid code model {0}")]
+    InvalidCodeModel(String),
+
+    /// Invalid relocation model.
+    #[error("invalid relocation model {0}")]
+    InvalidRelocModel(String),
+
+    /// Instrumenting the functions failed.
+    #[error("failed to instrument functions: {0}")]
+    InstrumentError(String),
+
+    /// Invalid argument passed to LLVM.
+    #[error("invalid LLVM argument {0}")]
+    InvalidLlvmArg(String),
+
+    /// Invalid diagnostic category.
+    #[error("invalid diagnostic category {0}")]
+    InvalidDiagnosticCategory(String),
+
     /// Invalid LLVM target.
     #[error("invalid LLVM target {0}")]
     InvalidTarget(String),
 
     /// An IO Error occurred while linking a module.
-    #[error("`{0}`: {1}")]
-    IoError(PathBuf, io::Error),
+    #[error("error accessing `{0}`")]
+    IoError(PathBuf, #[source] io::Error),
+
+    /// An input could not be read.
+    #[error("error reading {0}")]
+    ReadInputError(InputId, #[source] io::Error),
+
+    /// An archive is corrupt from its member `index`, which follows the valid members ending at
+    /// byte `offset`: its header is invalid or its data is truncated.
+    #[error("corrupt archive {archive}: invalid member {index} at offset {offset}")]
+    CorruptArchive {
+        archive: InputId,
+        index: usize,
+        offset: u64,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The input is not bitcode, an object file containing bitcode or an archive file.
+    #[error("invalid input {0}")]
+    InvalidInputType(InputId),
 
-    /// The file is not bitcode, an object file containing bitcode or an archive file.
-    #[error("invalid input file `{0}`")]
-    InvalidInputType(PathBuf),
+    /// Linking a module failed, with the errors reported by LLVM.
+    #[error("failure linking module {0}: {1}")]
+    LinkModuleError(InputId, String),
 
-    /// Linking a module failed.
-    #[error("failure linking module {0}")]
-    LinkModuleError(PathBuf),
+    /// The input bitcode was produced by a newer LLVM than the one bpf-linker uses, which can't
+    /// read it.
+    #[error(
+        "{input} was produced by {producer}, which is newer than the LLVM {ours} used by \
+         bpf-linker. Use a bpf-linker built against the same or a newer LLVM, eg by enabling the \
+         `rust-llvm` feature to use the LLVM of the rust toolchain"
+    )]
+    BitcodeVersionMismatch {
+        input: InputId,
+        producer: String,
+        ours: String,
+    },
 
     /// Linking a module included in an archive failed.
-    #[error("failure linking module {1} from {0}")]
-    LinkArchiveModuleError(PathBuf, PathBuf),
+    #[error("failure linking module `{member}` from {archive}")]
+    LinkArchiveModuleError {
+        archive: InputId,
+        member: String,
+        #[source]
+        source: Box<LinkerError>,
+    },
 
     /// Optimizing the BPF code failed.
     #[error("LLVMRunPasses failed: {0}")]
@@ -76,7 +183,206 @@ pub enum LinkerError {
 
     /// The input object file does not have embedded bitcode.
     #[error("no bitcode section found in {0}")]
-    MissingBitcodeSection(PathBuf),
+    MissingBitcodeSection(InputId),
+
+    /// Symbols are used but defined nowhere.
+    #[error("undefined symbols: {}", .0.join(", "))]
+    UndefinedSymbols(Vec<String>),
+
+    /// A struct_ops map is invalid.
+    #[error("invalid struct_ops map: {0}")]
+    StructOpsError(String),
+
+    /// The validation script could not be evaluated.
+    #[error("error running validation script `{0}`: {1}")]
+    ValidationScriptError(PathBuf, String),
+
+    /// Diagnostics were reported in categories configured as errors.
+    #[error("denied diagnostics: {}", .0.join("; "))]
+    DeniedDiagnostics(Vec<String>),
+
+    /// The inputs without bitcode could not be merged into the output.
+    #[error("error merging prelinked objects: {0}")]
+    MergeObjectsError(String),
+
+    /// The output could not be parsed.
+    #[error("invalid output: {0}")]
+    InvalidOutput(String),
+
+    /// The validation script reported policy violations.
+    #[error("validation failed: {}", .0.join("; "))]
+    ValidationFailed(Vec<String>),
+
+    /// The CPU features of the BPF target could not be listed.
+    #[error("failed to list the CPU features of the BPF target")]
+    CpuFeaturesError,
+
+    /// Exported functions have no debug info once it was sanitized, so they'd have no BTF
+    /// `func_info` and the verifier would reject them when it requires one, eg for tracing
+    /// programs.
+    #[error(
+        "exported functions have no debug info to generate BTF from: {}",
+        .0.join(", ")
+    )]
+    MissingFuncInfo(Vec<String>),
+
+    /// A path can't be handed to LLVM, as it contains a NUL byte.
+    #[error("invalid path {0:?}: paths can't contain NUL bytes")]
+    InvalidPath(PathBuf),
+
+    /// The LLVM verifier found the linked module invalid.
+    #[error("invalid module {0}")]
+    ModuleVerification(String),
+
+    /// The kernel BTF the kfuncs are checked against could not be parsed.
+    #[error("invalid kernel BTF {0}: {1}")]
+    InvalidKernelBtf(PathBuf, String),
+
+    /// Kfuncs called by the program are missing from the kernel BTF or don't match their
+    /// prototype there.
+    #[error("kfuncs don't match the kernel BTF: {}", .0.join("; "))]
+    KfuncMismatch(Vec<String>),
+
+    /// Code compiled for a non-BPF target uses constructs the BPF backend can't generate code
+    /// for.
+    #[error(
+        "inputs compiled for a non-BPF target use constructs BPF doesn't support: {}",
+        .0.join("; ")
+    )]
+    NonBpfConstructs(Vec<String>),
+
+    /// A section set for a symbol can't be handed to LLVM, as it contains a NUL byte.
+    #[error("invalid section {1:?} for `{0}`: sections can't contain NUL bytes")]
+    InvalidSymbolSection(String, String),
+
+    /// A symbol could not be renamed.
+    #[error("failed to rename symbol: {0}")]
+    RenameSymbolError(String),
+
+    /// Inputs were compiled for BPF targets of different endianness and no target was set to
+    /// choose between them.
+    #[error(
+        "{input} targets {target} but {first} targets {first_target}, set the output --target \
+         explicitly"
+    )]
+    ConflictingEndianness {
+        first: InputId,
+        first_target: String,
+        input: InputId,
+        target: String,
+    },
+
+    /// An input was compiled for a BPF target of another endianness than one of the targets
+    /// code is generated for.
+    #[error(
+        "{input} targets {input_target}, code for {target} can't be generated from it as it was \
+         compiled for the other endianness"
+    )]
+    TargetEndianness {
+        input: InputId,
+        input_target: String,
+        target: String,
+    },
+
+    /// Functions left after optimization take more arguments than BPF passes in registers.
+    #[error(
+        "{}: BPF functions take at most 5 arguments, mark the functions #[inline(always)] or pass \
+         their arguments in a struct",
+        .0.join("; ")
+    )]
+    TooManyArguments(Vec<String>),
+
+    /// `--cpu native` couldn't detect the CPU version the running kernel supports.
+    #[error("can't detect the native CPU: {0}")]
+    NativeCpuError(String),
+
+    /// The `--elf-osabi` or `--elf-flags` of the object could not be set.
+    #[error("error setting the ELF header of the object: {0}")]
+    ElfHeaderError(String),
+
+    /// LLVM could not be set up for the link.
+    #[error("error initializing LLVM: {0}")]
+    LlvmInitError(String),
+
+    /// The command line of the process could not be parsed.
+    #[error(transparent)]
+    CommandLineError(#[from] CliError),
+
+    /// The emitted object could not be checked with aya-obj.
+    #[error("error checking the object with aya-obj: {0}")]
+    AyaObjError(String),
+
+    /// A link of a [`LinkerPool`](crate::LinkerPool) would run with LLVM options which an earlier
+    /// link of the process set and it doesn't.
+    #[error(
+        "an earlier link left the LLVM options `{}` in effect, which this link doesn't set and \
+         can't run with in a pool",
+        .0.join(" ")
+    )]
+    PooledLlvmArgs(Vec<String>),
+}
+
+impl LinkerError {
+    /// Returns the code of the error, eg `BPFLNK-0001`, which tools can match on instead of the
+    /// message. Codes are never changed nor reused: new errors get new codes.
+    pub fn code(&self) -> &'static str {
+        use LinkerError::*;
+        match self {
+            InvalidCpu(..) => "BPFLNK-0001",
+            InvalidCpuFeature(..) => "BPFLNK-0002",
+            MissingOutput => "BPFLNK-0003",
+            InvalidUndefinedSymbols(..) => "BPFLNK-0004",
+            InvalidOptLevel(..) => "BPFLNK-0005",
+            InvalidOutputType(..) => "BPFLNK-0006",
+            InvalidAsmDialect(..) => "BPFLNK-0007",
+            InvalidInstrumentFunctions(..) => "BPFLNK-0008",
+            InvalidBpfTrap(..) => "BPFLNK-0009",
+            InvalidCodeModel(..) => "BPFLNK-0010",
+            InvalidRelocModel(..) => "BPFLNK-0011",
+            InstrumentError(..) => "BPFLNK-0012",
+            InvalidLlvmArg(..) => "BPFLNK-0013",
+            InvalidDiagnosticCategory(..) => "BPFLNK-0014",
+            InvalidTarget(..) => "BPFLNK-0015",
+            IoError(..) => "BPFLNK-0016",
+            ReadInputError(..) => "BPFLNK-0017",
+            CorruptArchive { .. } => "BPFLNK-0018",
+            InvalidInputType(..) => "BPFLNK-0019",
+            LinkModuleError(..) => "BPFLNK-0020",
+            BitcodeVersionMismatch { .. } => "BPFLNK-0021",
+            LinkArchiveModuleError { .. } => "BPFLNK-0022",
+            OptimizeError(..) => "BPFLNK-0023",
+            EmitCodeError(..) => "BPFLNK-0024",
+            WriteBitcodeError => "BPFLNK-0025",
+            WriteIRError(..) => "BPFLNK-0026",
+            EmbeddedBitcodeError(..) => "BPFLNK-0027",
+            MissingBitcodeSection(..) => "BPFLNK-0028",
+            UndefinedSymbols(..) => "BPFLNK-0029",
+            StructOpsError(..) => "BPFLNK-0030",
+            ValidationScriptError(..) => "BPFLNK-0031",
+            DeniedDiagnostics(..) => "BPFLNK-0032",
+            MergeObjectsError(..) => "BPFLNK-0033",
+            InvalidOutput(..) => "BPFLNK-0034",
+            ValidationFailed(..) => "BPFLNK-0035",
+            CpuFeaturesError => "BPFLNK-0036",
+            MissingFuncInfo(..) => "BPFLNK-0037",
+            InvalidPath(..) => "BPFLNK-0038",
+            ModuleVerification(..) => "BPFLNK-0039",
+            InvalidKernelBtf(..) => "BPFLNK-0040",
+            KfuncMismatch(..) => "BPFLNK-0041",
+            NonBpfConstructs(..) => "BPFLNK-0042",
+            InvalidSymbolSection(..) => "BPFLNK-0043",
+            RenameSymbolError(..) => "BPFLNK-0044",
+            ConflictingEndianness { .. } => "BPFLNK-0045",
+            TooManyArguments(..) => "BPFLNK-0046",
+            NativeCpuError(..) => "BPFLNK-0047",
+            ElfHeaderError(..) => "BPFLNK-0048",
+            LlvmInitError(..) => "BPFLNK-0049",
+            CommandLineError(..) => "BPFLNK-0050",
+            AyaObjError(..) => "BPFLNK-0051",
+            TargetEndianness { .. } => "BPFLNK-0052",
+            PooledLlvmArgs(..) => "BPFLNK-0053",
+        }
+    }
 }
 
 /// BPF Cpu type
@@ -87,6 +393,9 @@ pub enum Cpu {
     V1,
     V2,
     V3,
+    V4,
+    /// The newest version supported by the kernel the linker runs on, see [`Cpu::resolve`].
+    Native,
 }
 
 impl Cpu {
@@ -98,6 +407,21 @@ impl Cpu {
             V1 => "v1",
             V2 => "v2",
             V3 => "v3",
+            V4 => "v4",
+            Native => "native",
+        }
+    }
+
+    /// Returns the CPU to generate code for: [`Cpu::Native`] is replaced by the version the
+    /// running kernel supports, other CPUs are returned as is.
+    pub fn resolve(self) -> Result<Self, LinkerError> {
+        match self {
+            Cpu::Native => {
+                let cpu = probe::native_cpu().map_err(LinkerError::NativeCpuError)?;
+                info!("detected native CPU {cpu}");
+                Ok(cpu)
+            }
+            cpu => Ok(cpu),
         }
     }
 }
@@ -119,15 +443,352 @@ impl FromStr for Cpu {
             "v1" => V1,
             "v2" => V2,
             "v3" => V3,
+            "v4" => V4,
+            "native" => Native,
             _ => return Err(LinkerError::InvalidCpu(s.to_string())),
         })
     }
 }
 
+/// A feature of the BPF processor, enabled with `+name` or disabled with `-name`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuFeature {
+    pub name: String,
+    pub description: String,
+}
+
+/// What to do with the symbols which are still undefined after linking and optimization
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UndefinedSymbols {
+    /// Move them to the `.ksyms` section, so the loader resolves them against kernel symbols.
+    KsymsSection,
+    /// Leave them as plain undefined symbols, with a warning.
+    Keep,
+    /// Fail the link.
+    Error,
+}
+
+impl FromStr for UndefinedSymbols {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use UndefinedSymbols::*;
+        Ok(match s {
+            "ksyms" => KsymsSection,
+            "keep" => Keep,
+            "error" => Error,
+            _ => return Err(LinkerError::InvalidUndefinedSymbols(s.to_string())),
+        })
+    }
+}
+
+/// The assembler the emitted assembly is written for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AsmDialect {
+    /// The LLVM integrated assembler, eg `llvm-mc` or `clang -c`.
+    Llvm,
+    /// GNU as, which only accepts the pseudo-C BPF syntax from binutils 2.41 on. Directives GNU as
+    /// doesn't support are left out.
+    Gas,
+}
+
+impl FromStr for AsmDialect {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use AsmDialect::*;
+        Ok(match s {
+            "llvm" => Llvm,
+            "gas" => Gas,
+            _ => return Err(LinkerError::InvalidAsmDialect(s.to_string())),
+        })
+    }
+}
+
+/// What to do with traps, eg the calls to `llvm.trap` emitted for Rust panics, see
+/// [`LinkerOptions::bpf_trap`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BpfTrap {
+    /// Leave them to the backend. Backends from LLVM 21 on lower them to calls to the
+    /// `__bpf_trap` kfunc, which the loader must resolve from the `.ksyms` section, see
+    /// [`UndefinedSymbols::KsymsSection`].
+    Keep,
+    /// Rewrite the calls to `llvm.trap` and `__bpf_trap` into returns of -1, or of null for
+    /// functions which don't return integers, before optimization. The output is then trap free
+    /// whatever the backend does with traps.
+    Return,
+}
+
+impl FromStr for BpfTrap {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use BpfTrap::*;
+        Ok(match s {
+            "keep" => Keep,
+            "return" => Return,
+            _ => return Err(LinkerError::InvalidBpfTrap(s.to_string())),
+        })
+    }
+}
+
+/// The code model of the generated code, see [`LinkerOptions::code_model`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodeModel {
+    /// The default of the target.
+    Default,
+    Tiny,
+    Small,
+    Kernel,
+    Medium,
+    Large,
+}
+
+impl FromStr for CodeModel {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use CodeModel::*;
+        Ok(match s {
+            "default" => Default,
+            "tiny" => Tiny,
+            "small" => Small,
+            "kernel" => Kernel,
+            "medium" => Medium,
+            "large" => Large,
+            _ => return Err(LinkerError::InvalidCodeModel(s.to_string())),
+        })
+    }
+}
+
+/// The relocation model of the generated code, see [`LinkerOptions::reloc_model`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocModel {
+    /// The default of the target.
+    Default,
+    Static,
+    Pic,
+    DynamicNoPic,
+}
+
+impl FromStr for RelocModel {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use RelocModel::*;
+        Ok(match s {
+            "default" => Default,
+            "static" => Static,
+            "pic" => Pic,
+            "dynamic-no-pic" => DynamicNoPic,
+            _ => return Err(LinkerError::InvalidRelocModel(s.to_string())),
+        })
+    }
+}
+
+/// How functions are instrumented for profiling, see [`LinkerOptions::instrument_functions`].
+///
+/// Each instrumented function is given an id, its index in
+/// [`LinkerStats::instrumented_functions`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstrumentFunctions {
+    /// Call `void __bpf_profile_enter(u32 id)` on entry and `void __bpf_profile_exit(u32 id)`
+    /// before returning. The hooks are provided by the inputs.
+    Hooks,
+    /// Atomically increment `__bpf_profile_counters[id]`, an `u64` array added to `.bss`, on
+    /// entry.
+    Counters,
+}
+
+impl FromStr for InstrumentFunctions {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use InstrumentFunctions::*;
+        Ok(match s {
+            "hooks" => Hooks,
+            "counters" => Counters,
+            _ => return Err(LinkerError::InvalidInstrumentFunctions(s.to_string())),
+        })
+    }
+}
+
+/// Tuning of the transformations run by the optimization pipeline, see
+/// [`LinkerOptions::pass_options`].
+///
+/// Some of them turn loops into code the kernel verifier can't follow, so they can be disabled
+/// without resorting to LLVM command line options.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PassOptions {
+    /// Interleave loop iterations, ie unroll loops to run several iterations at once.
+    pub loop_interleaving: bool,
+    /// Vectorize loops.
+    pub loop_vectorization: bool,
+    /// Vectorize straight-line code.
+    pub slp_vectorization: bool,
+    /// Let LICM promote the memory accessed in loops to registers, hoisting the loads and sinking
+    /// the stores out of the loops.
+    pub licm_promotion: bool,
+}
+
+impl Default for PassOptions {
+    /// The defaults of the LLVM pass builder.
+    fn default() -> Self {
+        PassOptions {
+            loop_interleaving: true,
+            loop_vectorization: true,
+            slp_vectorization: false,
+            licm_promotion: true,
+        }
+    }
+}
+
+/// A category of diagnostics whose level can be configured with
+/// [`LinkerOptions::diagnostic_levels`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DiagnosticCategory {
+    /// Calls to memory builtins that the BPF backend can't expand. Allowed by default.
+    MemoryBuiltins,
+    /// Functions marked `#[inline(never)]`, which become BPF-to-BPF calls and need kernel 5.8 or
+    /// later. Allowed by default.
+    InlineNever,
+    /// Data-carrying enums whose debug info is skipped when emitting BTF.
+    DataCarryingEnum,
+    /// Inputs built with -O0, whose functions can't be optimized.
+    Optnone,
+    /// Inputs built without debug info while emitting BTF.
+    MissingDebugInfo,
+    /// Object files that don't contain any bitcode.
+    NoEmbeddedBitcode,
+    /// Symbols left undefined with [`UndefinedSymbols::Keep`].
+    UndefinedSymbols,
+    /// Globals whose BTF doesn't match their size when fixing up DATASEC entries, which get a
+    /// byte array type instead.
+    BtfDatasec,
+    /// Warnings issued by LLVM.
+    Llvm,
+    /// Functions using more stack than the verifier allows. Only checked when
+    /// [`LinkerOptions::stack_usage`] is set.
+    StackUsage,
+    /// Exported names which no input defines, typically typos or definitions configured out.
+    UnmatchedExports,
+    /// Objects which aya-obj fails to parse. Only checked when [`LinkerOptions::check_aya_obj`]
+    /// is set.
+    AyaObj,
+}
+
+impl DiagnosticCategory {
+    fn default_level(self) -> DiagnosticLevel {
+        match self {
+            Self::MemoryBuiltins | Self::InlineNever => DiagnosticLevel::Allow,
+            _ => DiagnosticLevel::Warn,
+        }
+    }
+}
+
+impl FromStr for DiagnosticCategory {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use DiagnosticCategory::*;
+        Ok(match s {
+            "memory-builtins" => MemoryBuiltins,
+            "inline-never" => InlineNever,
+            "data-carrying-enum" => DataCarryingEnum,
+            "optnone" => Optnone,
+            "missing-debug-info" => MissingDebugInfo,
+            "no-embedded-bitcode" => NoEmbeddedBitcode,
+            "undefined-symbols" => UndefinedSymbols,
+            "btf-datasec" => BtfDatasec,
+            "llvm" => Llvm,
+            "stack-usage" => StackUsage,
+            "unmatched-exports" => UnmatchedExports,
+            "aya-obj" => AyaObj,
+            _ => return Err(LinkerError::InvalidDiagnosticCategory(s.to_string())),
+        })
+    }
+}
+
+impl std::fmt::Display for DiagnosticCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use DiagnosticCategory::*;
+        f.write_str(match self {
+            MemoryBuiltins => "memory-builtins",
+            InlineNever => "inline-never",
+            DataCarryingEnum => "data-carrying-enum",
+            Optnone => "optnone",
+            MissingDebugInfo => "missing-debug-info",
+            NoEmbeddedBitcode => "no-embedded-bitcode",
+            UndefinedSymbols => "undefined-symbols",
+            BtfDatasec => "btf-datasec",
+            Llvm => "llvm",
+            StackUsage => "stack-usage",
+            UnmatchedExports => "unmatched-exports",
+            AyaObj => "aya-obj",
+        })
+    }
+}
+
+/// How diagnostics of a [`DiagnosticCategory`] are reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticLevel {
+    /// Only logged at debug level.
+    Allow,
+    /// Logged as warnings.
+    Warn,
+    /// Logged as errors, and fail the link once it completes.
+    Deny,
+}
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The link carries on and succeeds.
+    Warning,
+    /// Errors of a category fail the link once it completes, LLVM errors only make
+    /// [`Linker::has_errors`] return true.
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A warning or an error reported while linking, see [`Linker::diagnostics`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The category of the diagnostic, `None` for the errors LLVM reports.
+    pub category: Option<DiagnosticCategory>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            severity,
+            category,
+            message,
+        } = self;
+        match category {
+            Some(category) => write!(f, "{severity}: {category}: {message}"),
+            None => write!(f, "{severity}: llvm: {message}"),
+        }
+    }
+}
+
 /// Optimization level
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum OptLevel {
-    /// No optimizations. Equivalent to -O0.
+    /// No optimizations. Equivalent to -O0, except that the functions marked `alwaysinline` are
+    /// still inlined and the unreferenced code is still removed, as BPF requires. Useful to debug
+    /// the unoptimized IR, but much of what compiles at higher levels fails the verifier or
+    /// codegen at this one, eg for using too much stack.
     No,
     /// Less than the default optimizations. Equivalent to -O1.
     Less,
@@ -141,9 +802,41 @@ pub enum OptLevel {
     SizeMin,
 }
 
+impl FromStr for OptLevel {
+    type Err = LinkerError;
+
+    /// Parses the levels accepted by `-O`: `0` to `3`, `s` and `z`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use OptLevel::*;
+        Ok(match s {
+            "0" => No,
+            "1" => Less,
+            "2" => Default,
+            "3" => Aggressive,
+            "s" => Size,
+            "z" => SizeMin,
+            _ => return Err(LinkerError::InvalidOptLevel(s.to_string())),
+        })
+    }
+}
+
+impl std::fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use OptLevel::*;
+        f.write_str(match self {
+            No => "0",
+            Less => "1",
+            Default => "2",
+            Aggressive => "3",
+            Size => "s",
+            SizeMin => "z",
+        })
+    }
+}
+
 /// Linker input type
 #[derive(Clone, Copy, Debug, PartialEq)]
-enum InputType {
+pub(crate) enum InputType {
     /// LLVM bitcode.
     Bitcode,
     /// ELF object file.
@@ -152,6 +845,13 @@ enum InputType {
     MachO,
     /// Archive file. (.a)
     Archive,
+    /// Thin archive, whose members are files referenced by path.
+    ThinArchive,
+    /// Compressed file, detected again once decompressed.
+    Compressed(Compression),
+    /// Rust crate metadata, either raw as written by `--emit=metadata` or wrapped in an object
+    /// file like the `lib.rmeta` member of rlibs. It has no code, so it's skipped.
+    RustMetadata,
 }
 
 impl std::fmt::Display for InputType {
@@ -165,13 +865,89 @@ impl std::fmt::Display for InputType {
                 Elf => "elf",
                 MachO => "Mach-O",
                 Archive => "archive",
+                ThinArchive => "thin archive",
+                Compressed(compression) => compression.name(),
+                RustMetadata => "Rust metadata",
             }
         )
     }
 }
 
+/// Linker input
+#[derive(Clone, Debug)]
+pub enum LinkerInput {
+    /// A file on disk.
+    File(PathBuf),
+    /// An in-memory buffer. The name is only used to identify the input in logs and errors.
+    Buffer { name: String, bytes: Vec<u8> },
+}
+
+impl LinkerInput {
+    /// Returns the identity of the input used in logs and errors.
+    pub fn id(&self) -> InputId {
+        match self {
+            LinkerInput::File(path) => InputId::File(path.clone()),
+            LinkerInput::Buffer { name, .. } => InputId::Buffer(name.clone()),
+        }
+    }
+}
+
+impl From<PathBuf> for LinkerInput {
+    fn from(path: PathBuf) -> Self {
+        LinkerInput::File(path)
+    }
+}
+
+/// Identifies where a linked module came from.
+///
+/// Buffer names are never interpreted as paths, so a buffer named like a file on disk is still
+/// reported as a buffer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InputId {
+    /// A file on disk.
+    File(PathBuf),
+    /// An in-memory buffer.
+    Buffer(String),
+    /// A member of an archive input.
+    ArchiveMember {
+        archive: Box<InputId>,
+        member: String,
+    },
+}
+
+impl std::fmt::Display for InputId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputId::File(path) => write!(f, "`{}`", path.display()),
+            InputId::Buffer(name) => write!(f, "buffer `{name}`"),
+            InputId::ArchiveMember { archive, member } => write!(f, "`{member}` in {archive}"),
+        }
+    }
+}
+
+// Names the IR file of a dumped input after it: the file name of files, the archive file name
+// followed by the member name for archive members.
+fn input_file_name(id: &InputId) -> String {
+    let name = match id {
+        InputId::File(path) => path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        InputId::Buffer(name) => name.clone(),
+        InputId::ArchiveMember { archive, member } => {
+            format!("{}.{member}", input_file_name(archive))
+        }
+    };
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect()
+}
+
 /// Output type
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum OutputType {
     /// LLVM bitcode.
     Bitcode,
@@ -181,37 +957,149 @@ pub enum OutputType {
     LlvmAssembly,
     /// ELF object file.
     Object,
+    /// C header embedding the object file, with accessors for its maps and programs built on
+    /// the skeleton API of libbpf, like the ones `bpftool gen skeleton` generates.
+    Skeleton,
+    /// Rust module to `include!` in aya applications, with constants naming the programs and
+    /// maps of the object file and Rust definitions of the structs of its BTF.
+    RustSkeleton,
+    /// Include file mapping the exported functions of the object file to their BTF type ids, eg
+    /// to register struct_ops. A C header if the output ends with `.h`, Rust constants otherwise.
+    BtfIds,
+    /// Disassembly of the object file, annotated with the BTF functions and source lines of the
+    /// instructions and the symbols they're relocated against.
+    Disassembly,
+}
+
+impl FromStr for OutputType {
+    type Err = LinkerError;
+
+    /// Parses the types accepted by `--emit`: `llvm-bc`, `asm`, `llvm-ir`, `obj`, `skel`,
+    /// `rust-skel`, `btf-ids` and `disasm`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use OutputType::*;
+        Ok(match s {
+            "llvm-bc" => Bitcode,
+            "asm" => Assembly,
+            "llvm-ir" => LlvmAssembly,
+            "obj" => Object,
+            "skel" => Skeleton,
+            "rust-skel" => RustSkeleton,
+            "btf-ids" => BtfIds,
+            "disasm" => Disassembly,
+            _ => return Err(LinkerError::InvalidOutputType(s.to_string())),
+        })
+    }
+}
+
+impl std::fmt::Display for OutputType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use OutputType::*;
+        f.write_str(match self {
+            Bitcode => "llvm-bc",
+            Assembly => "asm",
+            LlvmAssembly => "llvm-ir",
+            Object => "obj",
+            Skeleton => "skel",
+            RustSkeleton => "rust-skel",
+            BtfIds => "btf-ids",
+            Disassembly => "disasm",
+        })
+    }
+}
+
+/// An output written in addition to the main one, from the same linked module.
+#[derive(Clone, Debug)]
+pub struct ExtraOutput {
+    /// The format of the output.
+    pub output_type: OutputType,
+    /// Where to save the output.
+    pub path: PathBuf,
+    /// The optimization level of the output, the one of the main output when not set. Lets eg
+    /// dump unoptimized IR next to an optimized object.
+    pub optimize: Option<OptLevel>,
 }
 
 /// Options to configure the linker
 #[derive(Debug)]
 pub struct LinkerOptions {
     /// The LLVM target to generate code for. If None, the target will be inferred from the input
-    /// modules.
+    /// modules: the endianness of the inputs compiled for `bpfel` or `bpfeb`, which must agree,
+    /// and of the host otherwise.
     pub target: Option<String>,
+    /// Targets to generate code for from the same linked module, eg `bpfel` and `bpfeb`. When not
+    /// empty, `target` is ignored and one output is written per target, named after `output` with
+    /// the target endianness inserted before the extension (`<output>.el.o`, `<output>.eb.o`).
+    ///
+    /// The module is only retargeted, so the layouts and constants the compiler already lowered
+    /// keep the endianness of the inputs. Inputs compiled for `bpfel` or `bpfeb` are rejected
+    /// with [`LinkerError::TargetEndianness`] when a target has the other endianness.
+    pub targets: Vec<String>,
     /// Cpu type.
     pub cpu: Cpu,
-    /// Cpu features.
+    /// Cpu features. LLVM ignores the features it doesn't know, which
+    /// [`check_llvm_options`](crate::check_llvm_options) checks.
     pub cpu_features: String,
-    /// Input files. Can be bitcode, object files with embedded bitcode or archive files.
-    pub inputs: Vec<PathBuf>,
+    /// Inputs. Can be bitcode, object files with embedded bitcode or archive files.
+    pub inputs: Vec<LinkerInput>,
     /// Where to save the output.
     pub output: PathBuf,
     /// The format to output.
     pub output_type: OutputType,
+    /// Outputs to write in addition to `output`, each optimized and generated from a fresh copy
+    /// of the linked module. With multiple `targets`, one is written per target as for `output`.
+    pub extra_outputs: Vec<ExtraOutput>,
+    /// Glob patterns of the archive members to link, matched against the member names. All the
+    /// members are linked when empty.
+    pub archive_member_filters: Vec<String>,
+    /// Library search path.
     pub libs: Vec<PathBuf>,
+    /// Library archives. Unlike inputs, their members are only linked when they define a symbol
+    /// which is otherwise undefined.
+    pub libraries: Vec<PathBuf>,
     /// Optimization level.
     pub optimize: OptLevel,
+    /// Optimization level of code generation, `optimize` when not set. Code generation has no
+    /// size levels, `Size` and `SizeMin` generate code as `Default` does.
+    pub codegen_optimize: Option<OptLevel>,
     /// Set of symbol names to export.
     pub export_symbols: HashSet<Cow<'static, str>>,
+    /// Glob patterns of definitions added to `llvm.used`, so that optimizations don't remove them
+    /// even when nothing appears to use them, eg `freplace` targets.
+    pub keep_symbols: Vec<String>,
+    /// Sections the definitions of some symbols are moved to before optimization, as (symbol,
+    /// section), eg to name the section of a program from the build script.
+    pub symbol_sections: Vec<(String, String)>,
+    /// Bitcode module the inputs are linked into, instead of an empty module, eg a large common
+    /// runtime linked and optimized once and shared by many programs.
+    pub base_module: Option<PathBuf>,
+    /// Symbols whose fate through internalization and optimization is traced, to tell why they
+    /// are missing from the output. The traces are in [`LinkerStats::symbol_explanations`].
+    pub why_internalized: Vec<String>,
     /// Whether to aggressively unroll loops. Useful for older kernels that don't support loops.
+    /// Only the loops of the functions matching `unroll_functions` are unrolled.
     pub unroll_loops: bool,
+    /// Glob patterns of the functions whose loops, and the loops of the functions they call, are
+    /// unrolled when `unroll_loops` is set, by marking them like `#pragma unroll` does. When
+    /// empty, the unroll thresholds of all loops are raised instead.
+    pub unroll_functions: Vec<String>,
+    /// Convert the counted loops too big to be unrolled into calls to the `bpf_loop` helper,
+    /// available starting from kernel 5.17. Experimental. The loops are converted before
+    /// optimization, so they aren't unrolled even with [`unroll_loops`](Self::unroll_loops).
+    pub convert_loops_to_bpf_loop: bool,
     /// Remove `noinline` attributes from functions. Useful for kernels before 5.8 that don't
     /// support function calls.
     pub ignore_inline_never: bool,
+    /// Glob patterns of the functions which keep their `noinline` attribute when
+    /// `ignore_inline_never` is set, so they remain BPF-to-BPF calls.
+    pub keep_inline_never: Vec<String>,
     /// Write the linked module IR before and after optimization.
     pub dump_module: Option<PathBuf>,
-    /// Extra command line args to pass to LLVM.
+    /// Also write the IR of each input module to `dump_module`, as parsed and before linking, as
+    /// `<input name>.ll`. Archive members are named after the archive and the member.
+    pub dump_inputs: bool,
+    /// Extra command line args to pass to LLVM. LLVM exits the process on invalid ones, which
+    /// [`check_llvm_options`](crate::check_llvm_options) checks.
     pub llvm_args: Vec<String>,
     /// Disable passing --bpf-expand-memcpy-in-order to LLVM.
     pub disable_expand_memcpy_in_order: bool,
@@ -221,6 +1109,195 @@ pub struct LinkerOptions {
     pub disable_memory_builtins: bool,
     /// Emit BTF information
     pub btf: bool,
+    /// Kernel BTF, eg `/sys/kernel/btf/vmlinux`, which the kfuncs the program calls are checked
+    /// against. Requires `btf`.
+    pub kernel_btf: Option<PathBuf>,
+    /// Rhai script run against the linked module before code generation. The link fails if the
+    /// script reports violations. See the `validate` module for the available variables.
+    pub validation_script: Option<PathBuf>,
+    /// Prefix prepended to the names of all defined symbols that are not exported. Useful to link
+    /// the same library code into several objects loaded into the same kernel.
+    pub prefix_symbols: Option<String>,
+    /// Symbols to rename after optimization, as (old name, new name), eg to give the entry points
+    /// of each build variant of a program a different name. The debug info is renamed along, so
+    /// BTF matches the symbol table.
+    pub rename_symbols: Vec<(String, String)>,
+    /// Write the optimization remarks emitted by LLVM to this file as YAML, with the function and
+    /// debug location of each, even when the link fails.
+    pub remarks_file: Option<PathBuf>,
+    /// Regex matched against pass names to select which remarks are written to `remarks_file`.
+    /// Remarks from all passes are written if None. The filter is an LLVM option, global to the
+    /// process, so the links of a process writing remarks must use the same one.
+    pub remarks_filter: Option<String>,
+    /// Synthesize BTF for the globals that don't have debug info, or whose debug info type doesn't
+    /// match their size, so that every data section global is described by a valid DATASEC
+    /// entry. Only used when `btf` is set.
+    pub btf_datasec_fixup: bool,
+    /// Glob patterns of the BTF-defined maps given the libbpf `pinning` attribute, so that loaders
+    /// pin them by name under their pin root path, eg `/sys/fs/bpf/<map>`. Only used when `btf`
+    /// is set.
+    pub pin_maps: Vec<String>,
+    /// Glob patterns of the structs, unions and enums kept in BTF even when no program or map
+    /// references them, eg for other programs to relocate against with CO-RE. The patterns are
+    /// matched against the path of the types, eg `my_crate::module::Type`, so `my_crate::*` keeps
+    /// all the types of a crate found in the debug info of the inputs. Only used when `btf` is
+    /// set.
+    pub btf_keep_types: Vec<String>,
+    /// What to do with the symbols which are still undefined after linking and optimization.
+    pub undefined_symbols: UndefinedSymbols,
+    /// What to do with traps.
+    pub bpf_trap: BpfTrap,
+    /// The code model of the generated code.
+    pub code_model: CodeModel,
+    /// The relocation model of the generated code.
+    pub reloc_model: RelocModel,
+    /// Levels of the diagnostic categories which don't use their default level.
+    pub diagnostic_levels: HashMap<DiagnosticCategory, DiagnosticLevel>,
+    /// Fail the link if any diagnostic is reported at the warning level.
+    pub fatal_warnings: bool,
+    /// Estimate the stack used by each function from the generated code, see
+    /// [`LinkerStats::stack_usage`]. Functions over the verifier limit are reported as
+    /// [`DiagnosticCategory::StackUsage`].
+    pub stack_usage: bool,
+    /// List the functions removed by optimization with their size, see
+    /// [`LinkerStats::removed_functions`].
+    pub removed_functions: bool,
+    /// Write a `.bpf.linker.meta` section to the output recording the bpf-linker and LLVM
+    /// versions, a hash of the options and a hash of each input module, to tell what produced a
+    /// deployed object. The options which only affect diagnostics or the files written besides
+    /// the output, eg [`verify`](Self::verify), aren't hashed.
+    pub linker_metadata: bool,
+    /// Write the output to a temporary file renamed to `output` once complete, so that a failed
+    /// link never leaves a truncated output behind.
+    pub atomic_output: bool,
+    /// The assembler to write the assembly for. Only used when emitting assembly.
+    pub asm_dialect: AsmDialect,
+    /// Leave the `.BTF` and `.BTF.ext` sections out of the emitted assembly, as they are long and
+    /// meaningless to read.
+    pub asm_without_btf: bool,
+    /// Annotate the emitted assembly with the source lines it was generated from, read from the
+    /// files named in the debug info. Requires debug info.
+    pub asm_with_source: bool,
+    /// Accept BPF object files without embedded bitcode, eg built by clang, and merge their
+    /// sections, symbols and relocations into the output. Only supported for object file output.
+    pub allow_prelinked_objects: bool,
+    /// Remove the maps which no program references after dead code elimination, along with their
+    /// BTF. The removed maps are listed in [`LinkerStats::removed_maps`].
+    pub gc_maps: bool,
+    /// Instrument every function which isn't marked `alwaysinline` for profiling, before
+    /// optimization.
+    pub instrument_functions: Option<InstrumentFunctions>,
+    /// Write a Make-style dependency file listing the files read by the link, for build systems
+    /// to know when to link again.
+    pub dep_file: Option<PathBuf>,
+    /// Files read before linking which the output depends on, eg the list of symbols to export.
+    /// Only used to write `dep_file`.
+    pub dependencies: Vec<PathBuf>,
+    /// Merge identical read-only globals once optimized, shrinking `.rodata`. When unset, the
+    /// optimization pipeline may still merge some of them.
+    pub merge_constants: bool,
+    /// Skip the inputs and archive members whose bitcode is identical to that of one already
+    /// linked, eg an rlib passed twice by rustc.
+    pub dedup_inputs: bool,
+    /// Tuning of the optimization pipeline.
+    pub pass_options: PassOptions,
+    /// Files whose contents are appended to the module level asm before optimization, like
+    /// [`Linker::append_module_asm`] does.
+    pub module_asm: Vec<PathBuf>,
+    /// Write the [`LinkerOutput::content_hash`] of the output to this path, or one
+    /// `<hash> <output>` line per output with multiple targets. Outputs which aren't object
+    /// files are hashed whole.
+    pub emit_hash: Option<PathBuf>,
+    /// Run the LLVM verifier on the module after linking and after optimization, to catch
+    /// invalid IR before it reaches codegen.
+    pub verify: bool,
+    /// Overwrite the OSABI byte of the ELF header of the emitted object, for loaders which check
+    /// it. LLVM always emits `ELFOSABI_NONE`.
+    pub elf_osabi: Option<u8>,
+    /// Overwrite the `e_flags` of the ELF header of the emitted object, for loaders which check
+    /// them.
+    pub elf_flags: Option<u32>,
+    /// Parse the emitted object with aya-obj, like the aya loader does, reporting failures as
+    /// [`DiagnosticCategory::AyaObj`]. Requires the `aya-obj` feature.
+    pub check_aya_obj: bool,
+    /// Measure the peak RSS of each stage of the link, see [`LinkerStats::stage_peak_rss`]. This
+    /// resets the peak RSS Linux keeps for the whole process before each stage.
+    pub measure_stage_memory: bool,
+}
+
+/// The defaults of the `bpf-linker` command line, which the [builder](LinkerOptions::builder)
+/// starts from. The output has to be set.
+impl Default for LinkerOptions {
+    fn default() -> Self {
+        Self {
+            target: None,
+            targets: Vec::new(),
+            cpu: Cpu::Generic,
+            cpu_features: String::new(),
+            inputs: Vec::new(),
+            output: PathBuf::new(),
+            output_type: OutputType::Object,
+            extra_outputs: Vec::new(),
+            archive_member_filters: Vec::new(),
+            libs: Vec::new(),
+            libraries: Vec::new(),
+            optimize: OptLevel::Default,
+            codegen_optimize: None,
+            export_symbols: Default::default(),
+            keep_symbols: Vec::new(),
+            symbol_sections: Vec::new(),
+            base_module: None,
+            why_internalized: Vec::new(),
+            unroll_loops: false,
+            unroll_functions: Vec::new(),
+            convert_loops_to_bpf_loop: false,
+            ignore_inline_never: false,
+            keep_inline_never: Vec::new(),
+            dump_module: None,
+            dump_inputs: false,
+            llvm_args: Vec::new(),
+            disable_expand_memcpy_in_order: false,
+            disable_memory_builtins: false,
+            btf: false,
+            kernel_btf: None,
+            validation_script: None,
+            prefix_symbols: None,
+            rename_symbols: Vec::new(),
+            remarks_file: None,
+            remarks_filter: None,
+            btf_datasec_fixup: false,
+            pin_maps: Vec::new(),
+            btf_keep_types: Vec::new(),
+            undefined_symbols: UndefinedSymbols::Keep,
+            bpf_trap: BpfTrap::Keep,
+            code_model: CodeModel::Default,
+            reloc_model: RelocModel::Default,
+            diagnostic_levels: HashMap::new(),
+            fatal_warnings: false,
+            stack_usage: false,
+            removed_functions: false,
+            linker_metadata: false,
+            atomic_output: true,
+            asm_dialect: AsmDialect::Llvm,
+            asm_without_btf: false,
+            asm_with_source: false,
+            allow_prelinked_objects: false,
+            gc_maps: false,
+            instrument_functions: None,
+            dep_file: None,
+            dependencies: Vec::new(),
+            merge_constants: true,
+            dedup_inputs: true,
+            pass_options: PassOptions::default(),
+            module_asm: Vec::new(),
+            emit_hash: None,
+            verify: cfg!(debug_assertions),
+            elf_osabi: None,
+            elf_flags: None,
+            check_aya_obj: false,
+            measure_stage_memory: false,
+        }
+    }
 }
 
 /// BPF Linker
@@ -230,102 +1307,897 @@ pub struct Linker {
     module: LLVMModuleRef,
     target_machine: LLVMTargetMachineRef,
     diagnostic_handler: DiagnosticHandler,
+    stats: LinkerStats,
+    input_hashes: Vec<(InputId, u64)>,
+    // the SHA-256 of the bitcode linked so far, to skip duplicate inputs
+    linked_bitcode: HashMap<[u8; 32], InputId>,
+    // the struct_ops maps and the programs they point to, which must survive optimization
+    struct_ops: Vec<String>,
+    // the names of the input IR files written to `dump_module`
+    dumped_inputs: HashSet<String>,
+    // the input defining each function, when it was compiled for a non-BPF target
+    function_inputs: HashMap<String, Option<InputId>>,
+    prelinked_objects: Vec<(InputId, Vec<u8>)>,
+    // the global symbols the prelinked objects define, and the ones they reference without
+    // defining them
+    prelinked_defined: HashSet<String>,
+    prelinked_undefined: HashSet<String>,
+    // the files read by the link, for the dependency file
+    files_read: Vec<PathBuf>,
+    module_asm: Vec<String>,
+    symbol_policy: Option<Box<dyn SymbolPolicy>>,
+    // the first input compiled for bpfel or bpfeb and its target, when no target is set
+    input_target: Option<(InputId, String)>,
+    // where the context and target machines come from and go back to when linking in a pool
+    pool: Option<PoolState>,
+    target_machine_key: Option<TargetMachineKey>,
+    // the hash of the options as given, before the link changes them, for the linker metadata
+    options_hash: u64,
+    // the object generated from the module, shared by the checks of the generated code and the
+    // outputs derived from it so that the backend only runs once
+    object: Option<Rc<[u8]>>,
 }
 
 impl Linker {
     /// Create a new linker instance with the given options.
-    pub fn new(options: LinkerOptions) -> Self {
+    ///
+    /// This sets LLVM up for the link, which fails if the LLVM arguments are invalid or conflict
+    /// with the ones of a previous link in the process, if `--cpu native` can't be detected or if
+    /// the CPU features aren't supported.
+    pub fn new(options: LinkerOptions) -> Result<Self, LinkerError> {
+        let mut linker = Self::uninit(options);
+        linker.init()?;
+        Ok(linker)
+    }
+
+    fn uninit(options: LinkerOptions) -> Self {
+        let options_hash = Fnv1a64::hash(&canonical_options(&options));
+        let mut diagnostic_handler = DiagnosticHandler::new();
+        diagnostic_handler.levels = options.diagnostic_levels.clone();
+        diagnostic_handler.fatal_warnings = options.fatal_warnings;
         Linker {
             options,
             context: ptr::null_mut(),
             module: ptr::null_mut(),
             target_machine: ptr::null_mut(),
-            diagnostic_handler: DiagnosticHandler::new(),
+            diagnostic_handler,
+            stats: LinkerStats::default(),
+            input_hashes: Vec::new(),
+            linked_bitcode: HashMap::new(),
+            struct_ops: Vec::new(),
+            dumped_inputs: HashSet::new(),
+            function_inputs: HashMap::new(),
+            prelinked_objects: Vec::new(),
+            prelinked_defined: HashSet::new(),
+            prelinked_undefined: HashSet::new(),
+            files_read: Vec::new(),
+            module_asm: Vec::new(),
+            symbol_policy: None,
+            input_target: None,
+            pool: None,
+            target_machine_key: None,
+            options_hash,
+            object: None,
         }
     }
 
-    /// Link and generate the output code.
-    pub fn link(&mut self) -> Result<(), LinkerError> {
-        self.llvm_init();
-        self.link_modules()?;
-        self.create_target_machine()?;
-        if let Some(path) = &self.options.dump_module {
-            std::fs::create_dir_all(path).map_err(|err| LinkerError::IoError(path.clone(), err))?;
+    // Creates a linker taking its context and target machines from `pool`, which
+    // `release_pool` gives back. The pool is also given back when the linker can't be created.
+    pub(crate) fn pooled(
+        options: LinkerOptions,
+        pool: PoolState,
+    ) -> Result<Self, (LinkerError, PoolState)> {
+        let mut linker = Self::uninit(options);
+        linker.pool = Some(pool);
+        match linker.init() {
+            Ok(()) => Ok(linker),
+            Err(err) => Err((err, linker.release_pool())),
         }
-        if let Some(path) = &self.options.dump_module {
-            // dump IR before optimization
-            let path = path.join("pre-opt.ll");
-            let path = CString::new(path.as_os_str().as_bytes()).unwrap();
-            self.write_ir(&path)?;
-        };
-        self.optimize()?;
-        if let Some(path) = &self.options.dump_module {
-            // dump IR before optimization
-            let path = path.join("post-opt.ll");
-            let path = CString::new(path.as_os_str().as_bytes()).unwrap();
-            self.write_ir(&path)?;
-        };
-        self.codegen()?;
+    }
+
+    // Does the fallible setup of LLVM, so that linking only fails because of the inputs.
+    fn init(&mut self) -> Result<(), LinkerError> {
+        self.llvm_init()?;
+        self.options.cpu = self.options.cpu.resolve()?;
         Ok(())
     }
 
-    pub fn has_errors(&self) -> bool {
-        self.diagnostic_handler.has_errors
+    pub(crate) fn release_pool(&mut self) -> PoolState {
+        self.release_target_machine();
+        let mut pool = self.pool.take().unwrap_or_default();
+        unsafe {
+            if !self.module.is_null() {
+                LLVMDisposeModule(self.module);
+                self.module = ptr::null_mut();
+            }
+        }
+        if !self.context.is_null() {
+            pool.put_context(mem::replace(&mut self.context, ptr::null_mut()));
+        }
+        pool
     }
 
-    fn link_modules(&mut self) -> Result<(), LinkerError> {
-        // buffer used to perform file type detection
+    // Gives the target machine back to the pool, or disposes of it when not linking in a pool.
+    fn release_target_machine(&mut self) {
+        if self.target_machine.is_null() {
+            return;
+        }
+        let target_machine = mem::replace(&mut self.target_machine, ptr::null_mut());
+        match (&mut self.pool, self.target_machine_key.take()) {
+            (Some(pool), Some(key)) => pool.put_target_machine(key, target_machine),
+            _ => unsafe { LLVMDisposeTargetMachine(target_machine) },
+        }
+    }
+
+    /// Create a new linker instance from the command line of the current process, parsed the
+    /// same way the `bpf-linker` binary parses it when invoked by rustc.
+    pub fn from_env() -> Result<Self, LinkerError> {
+        let invocation = Invocation::from_rustc_args(std::env::args_os())?;
+        for arg in &invocation.ignored_args {
+            info!("ignoring `{arg}`, it has no effect when linking BPF");
+        }
+        Self::new(invocation.into_linker_options()?)
+    }
+
+    /// Appends `asm` to the module level asm of the linked module, before optimization. Used to
+    /// define sections or symbols which can't be expressed in the inputs, eg with
+    /// `.pushsection`.
+    pub fn append_module_asm(&mut self, asm: &str) {
+        self.module_asm.push(asm.to_owned());
+    }
+
+    /// Sets the policy deciding the exports, sections and linkage of the symbols of the linked
+    /// module, on top of the options. Replaces the policy set before if any.
+    pub fn set_symbol_policy(&mut self, policy: impl SymbolPolicy + 'static) {
+        self.symbol_policy = Some(Box::new(policy));
+    }
+
+    /// Link and generate the output code.
+    pub fn link(&mut self) -> Result<(), LinkerError> {
+        let ret = self.link_outputs();
+        // written when the link fails too, as the remarks often tell why, eg a loop not unrolled
+        let written = match &self.options.remarks_file {
+            Some(path) => self.write_remarks(path),
+            None => Ok(()),
+        };
+        ret.and(written)?;
+        let denied = mem::take(&mut self.diagnostic_handler.denied);
+        if !denied.is_empty() {
+            return Err(LinkerError::DeniedDiagnostics(denied));
+        }
+        if let Some(path) = &self.options.dep_file {
+            self.write_dep_file(path)?;
+        }
+        if let Some(path) = &self.options.emit_hash {
+            self.write_hash(path)?;
+        }
+        Ok(())
+    }
+
+    /// Links the inputs and generates each of `output_types` in memory, from the same optimized
+    /// module, eg both the object and the IR a build server returns to its clients. The module is
+    /// only optimized once, at [`LinkerOptions::optimize`].
+    ///
+    /// Code is generated for [`LinkerOptions::target`] only. `targets`, `output_type`,
+    /// `extra_outputs` and the options naming output files are ignored, except `output` which
+    /// still names skeletons.
+    pub fn link_to_buffers(
+        &mut self,
+        output_types: &[OutputType],
+    ) -> Result<HashMap<OutputType, Vec<u8>>, LinkerError> {
+        let targets = mem::take(&mut self.options.targets);
+        let ret = self.link_buffers(output_types);
+        self.options.targets = targets;
+        let buffers = ret?;
+        let denied = mem::take(&mut self.diagnostic_handler.denied);
+        if !denied.is_empty() {
+            return Err(LinkerError::DeniedDiagnostics(denied));
+        }
+        Ok(buffers)
+    }
+
+    fn link_buffers(
+        &mut self,
+        output_types: &[OutputType],
+    ) -> Result<HashMap<OutputType, Vec<u8>>, LinkerError> {
+        self.link_module()?;
+        self.prepare_codegen()?;
+        let start = Instant::now();
+        let buffers = self.stage("codegen", |linker| linker.codegen_to_buffers(output_types))?;
+        self.stats.codegen_time = start.elapsed();
+        if let Some(object) = buffers.get(&OutputType::Object) {
+            match unsafe { llvm::section_sizes(object) } {
+                Ok(sizes) => self.stats.section_sizes = sizes,
+                Err(e) => warn!("failed to read the sections of the object: {}", e),
+            }
+        }
+        Ok(buffers)
+    }
+
+    // Generates `output_types` from the optimized module. The object is only generated once,
+    // for all the outputs derived from it.
+    fn codegen_to_buffers(
+        &mut self,
+        output_types: &[OutputType],
+    ) -> Result<HashMap<OutputType, Vec<u8>>, LinkerError> {
+        let mut buffers = HashMap::new();
+        let mut object = None;
+        for &output_type in output_types {
+            if buffers.contains_key(&output_type) {
+                continue;
+            }
+            let buffer = match output_type {
+                OutputType::Bitcode => unsafe { llvm::bitcode_to_memory(self.module) },
+                OutputType::LlvmAssembly => unsafe { llvm::ir_to_string(self.module) }.into_bytes(),
+                OutputType::Assembly => self.asm_to_memory()?,
+                OutputType::Object
+                | OutputType::Skeleton
+                | OutputType::RustSkeleton
+                | OutputType::BtfIds
+                | OutputType::Disassembly => {
+                    let object = match &object {
+                        Some(object) => object,
+                        None => object.insert(self.object_to_memory()?),
+                    };
+                    match output_type {
+                        OutputType::Object => object.clone(),
+                        OutputType::Disassembly => disasm::disassemble(object)?.into_bytes(),
+                        _ => self.skeleton(output_type, object)?.into_bytes(),
+                    }
+                }
+            };
+            let _: Option<_> = buffers.insert(output_type, buffer);
+        }
+        Ok(buffers)
+    }
+
+    fn output_paths(&self) -> Vec<PathBuf> {
+        let LinkerOptions {
+            output, targets, ..
+        } = &self.options;
+        if targets.is_empty() {
+            vec![output.clone()]
+        } else {
+            targets
+                .iter()
+                .map(|target| target_output_path(output, target_suffix(target)))
+                .collect()
+        }
+    }
+
+    fn write_hash(&self, path: &Path) -> Result<(), LinkerError> {
+        let outputs = self.output_paths();
+        let mut hashes = String::new();
+        for output in &outputs {
+            let hash = if matches!(self.options.output_type, OutputType::Object) {
+                LinkerOutput::read(output)?.content_hash()?
+            } else {
+                let data =
+                    fs::read(output).map_err(|e| LinkerError::IoError(output.to_owned(), e))?;
+                to_hex(&Sha256::digest(data))
+            };
+            info!("content hash of {}: {hash}", output.display());
+            if outputs.len() == 1 {
+                hashes = hash;
+            } else {
+                writeln!(hashes, "{hash} {}", output.display()).unwrap();
+            }
+        }
+        if outputs.len() == 1 {
+            hashes.push('\n');
+        }
+        fs::write(path, hashes).map_err(|e| LinkerError::IoError(path.to_owned(), e))
+    }
+
+    // Writes the outputs as depending on every file read, escaped like gcc -MD does.
+    fn write_dep_file(&self, path: &Path) -> Result<(), LinkerError> {
+        let escape = |path: &Path| {
+            let mut escaped = String::new();
+            for c in path.to_string_lossy().chars() {
+                match c {
+                    ' ' | '#' => escaped.push('\\'),
+                    '$' => escaped.push('$'),
+                    _ => {}
+                }
+                escaped.push(c);
+            }
+            escaped
+        };
+        let outputs = self.output_paths();
+        let dependencies = &self.options.dependencies;
+        let mut files = Vec::new();
+        for file in dependencies.iter().chain(&self.files_read) {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        let mut rule = outputs
+            .iter()
+            .map(|output| escape(output))
+            .collect::<Vec<_>>()
+            .join(" ");
+        rule.push(':');
+        for file in files {
+            rule.push_str(" \\\n  ");
+            rule.push_str(&escape(file));
+        }
+        rule.push('\n');
+        fs::write(path, rule).map_err(|e| LinkerError::IoError(path.to_owned(), e))
+    }
+
+    // Links the inputs and libraries into the module, up to optimization.
+    fn link_module(&mut self) -> Result<(), LinkerError> {
+        // the linker may have moved since it was created, so the handler is only registered now
+        unsafe {
+            LLVMContextSetDiagnosticHandler(
+                self.context,
+                Some(llvm::diagnostic_handler::<DiagnosticHandler>),
+                &mut self.diagnostic_handler as *mut _ as _,
+            )
+        };
+        self.stage("load base module", Self::load_base_module)?;
+        self.stage("link inputs", Self::link_modules)?;
+        self.stage("link libraries", Self::link_libraries)?;
+        self.export_prelinked_references();
+        self.check_exports();
+        self.verify_module("linking")
+    }
+
+    fn link_outputs(&mut self) -> Result<(), LinkerError> {
+        self.link_module()?;
+        if self.options.targets.is_empty() && self.options.extra_outputs.is_empty() {
+            return self.link_target();
+        }
+
+        // Everything up to here is target independent, so keep a copy of the linked module and
+        // run optimization and codegen on a fresh clone for each target and output.
+        let linked = unsafe { LLVMCloneModule(self.module) };
+        let ret = self.link_main_outputs(linked).and_then(|()| {
+            // the stats describe the main output
+            let stats = self.stats.clone();
+            let ret = self.link_extra_outputs(linked);
+            self.stats = stats;
+            ret
+        });
+        unsafe { LLVMDisposeModule(linked) };
+        ret
+    }
+
+    fn link_main_outputs(&mut self, linked: LLVMModuleRef) -> Result<(), LinkerError> {
+        if self.options.targets.is_empty() {
+            return self.link_target();
+        }
+        let targets = self.options.targets.clone();
+        let output = self.options.output.clone();
+        let dump_module = self.options.dump_module.clone();
+        let ret = targets.iter().try_for_each(|target| {
+            let suffix = target_suffix(target);
+            info!("generating {target} output");
+            self.reset_module(linked);
+            self.options.target = Some(target.clone());
+            self.options.output = target_output_path(&output, suffix);
+            self.options.dump_module = dump_module.as_ref().map(|path| path.join(suffix));
+            self.link_target()
+        });
+        self.options.output = output;
+        self.options.dump_module = dump_module;
+        ret
+    }
+
+    // Writes the extra outputs, swapping in their type, path and optimization level. The IR is
+    // only dumped for the main output.
+    fn link_extra_outputs(&mut self, linked: LLVMModuleRef) -> Result<(), LinkerError> {
+        let LinkerOptions {
+            output,
+            output_type,
+            optimize,
+            dump_module,
+            ..
+        } = &self.options;
+        let saved = (output.clone(), *output_type, *optimize, dump_module.clone());
+        self.options.dump_module = None;
+        let extra_outputs = self.options.extra_outputs.clone();
+        let targets = self.options.targets.clone();
+        let ret = extra_outputs.iter().try_for_each(|extra| {
+            info!(
+                "generating {} output {}",
+                extra.output_type,
+                extra.path.display()
+            );
+            self.options.output_type = extra.output_type;
+            self.options.optimize = extra.optimize.unwrap_or(saved.2);
+            if targets.is_empty() {
+                self.reset_module(linked);
+                self.options.output = extra.path.clone();
+                return self.link_target();
+            }
+            targets.iter().try_for_each(|target| {
+                self.reset_module(linked);
+                self.options.target = Some(target.clone());
+                self.options.output = target_output_path(&extra.path, target_suffix(target));
+                self.link_target()
+            })
+        });
+        (
+            self.options.output,
+            self.options.output_type,
+            self.options.optimize,
+            self.options.dump_module,
+        ) = saved;
+        ret
+    }
+
+    // Replaces the module with a fresh copy of `linked`, for a new target or output.
+    fn reset_module(&mut self, linked: LLVMModuleRef) {
+        self.release_target_machine();
+        unsafe {
+            LLVMDisposeModule(self.module);
+            self.module = LLVMCloneModule(linked);
+        }
+    }
+
+    // Optimizes the linked module and generates code for a single target.
+    fn link_target(&mut self) -> Result<(), LinkerError> {
+        self.prepare_codegen()?;
+        let start = Instant::now();
+        self.stage("codegen", Self::codegen)?;
+        self.stats.codegen_time = start.elapsed();
+        self.collect_section_sizes()
+    }
+
+    // Optimizes the linked module and runs the checks of the optimized module, up to codegen.
+    fn prepare_codegen(&mut self) -> Result<(), LinkerError> {
+        // the object of a previous target or output was generated from another module
+        self.object = None;
+        self.create_target_machine()?;
+        if !self.options.targets.is_empty() {
+            // The inputs were compiled for one endianness only, make the module match the target.
+            unsafe { llvm::set_module_target(self.module, self.target_machine) };
+        }
+        if let Some(path) = &self.options.dump_module {
+            std::fs::create_dir_all(path).map_err(|err| LinkerError::IoError(path.clone(), err))?;
+        }
+        if let Some(path) = &self.options.dump_module {
+            // dump IR before optimization
+            let path = path.join("pre-opt.ll");
+            let path = path_to_cstring(&path)?;
+            self.write_ir(&path)?;
+        };
+        let start = Instant::now();
+        self.optimize()?;
+        self.stats.optimize_time = start.elapsed();
+        self.verify_module("optimization")?;
+        if let Some(path) = &self.options.dump_module {
+            // dump IR before optimization
+            let path = path.join("post-opt.ll");
+            let path = path_to_cstring(&path)?;
+            self.write_ir(&path)?;
+        };
+        if self.function_inputs.values().any(Option::is_some) {
+            self.stage("check non-BPF constructs", Self::check_non_bpf_constructs)?;
+        }
+        self.check_undefined_symbols()?;
+        self.check_arguments()?;
+        if let Some(path) = &self.options.validation_script {
+            let symbols = unsafe { llvm::module_symbols(self.module) };
+            validate::run_script(path, &symbols)?;
+            self.files_read.push(path.clone());
+        }
+        if self.options.linker_metadata {
+            self.add_linker_metadata();
+        }
+        if self.options.stack_usage {
+            self.stage("stack usage", Self::collect_stack_usage)?;
+        }
+        if self.options.btf && !self.struct_ops.is_empty() {
+            self.stage("check struct_ops", Self::check_struct_ops)?;
+        }
+        if self.options.kernel_btf.is_some() {
+            self.stage("check kfuncs", Self::check_kfuncs)?;
+        }
+        Ok(())
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostic_handler.has_errors
+    }
+
+    /// The warnings and errors reported so far, in the order they were reported. Diagnostics
+    /// whose category is allowed aren't included.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostic_handler.diagnostics
+    }
+
+    // Runs a link stage in its own span and records how long it took.
+    fn stage<T>(&mut self, name: &'static str, f: impl FnOnce(&mut Self) -> T) -> T {
+        let span = info_span!("stage", name, elapsed = field::Empty).entered();
+        // the peak is process wide, so it's reset for each stage to tell them apart
+        let measure_memory = self.options.measure_stage_memory && stats::reset_peak_rss();
+        let start = Instant::now();
+        let ret = f(self);
+        let elapsed = start.elapsed();
+        let _: &Span = span.record("elapsed", field::debug(elapsed));
+        debug!("{name} took {elapsed:?}");
+        self.stats.stage_times.push((name, elapsed));
+        if let Some(peak_rss) = measure_memory.then(stats::peak_rss).flatten() {
+            self.stats.stage_peak_rss.push((name, peak_rss));
+        }
+        ret
+    }
+
+    /// Statistics about the last link.
+    pub fn stats(&self) -> &LinkerStats {
+        &self.stats
+    }
+
+    /// Reads back the object file written by the last link, to inspect its programs and maps.
+    pub fn output(&self) -> Result<LinkerOutput, LinkerError> {
+        LinkerOutput::read(&self.options.output)
+    }
+
+    // The prelinked objects are only merged with the generated object, so what they reference
+    // from the bitcode must survive internalization and dead code elimination like exports do.
+    fn export_prelinked_references(&mut self) {
+        if self.prelinked_undefined.is_empty() {
+            return;
+        }
+        let defined = unsafe { llvm::defined_symbols(self.module) };
+        for name in &self.prelinked_undefined {
+            if defined.contains(name) && !self.options.export_symbols.contains(name.as_str()) {
+                debug!("exporting {name}, referenced by a prelinked object");
+                let _: bool = self.options.export_symbols.insert(Cow::Owned(name.clone()));
+            }
+        }
+    }
+
+    // Reports the exported names which neither the linked module nor the prelinked objects
+    // define, suggesting the closest defined name.
+    fn check_exports(&mut self) {
+        let defined = unsafe { llvm::defined_symbols(self.module) };
+        let mut unmatched: Vec<String> = self
+            .options
+            .export_symbols
+            .iter()
+            .filter(|name| {
+                !defined.contains(name.as_ref()) && !self.prelinked_defined.contains(name.as_ref())
+            })
+            .map(|name| name.to_string())
+            .collect();
+        if unmatched.is_empty() {
+            return;
+        }
+        unmatched.sort();
+        let names = unmatched
+            .iter()
+            .map(|name| match closest_name(name, &defined) {
+                Some(closest) => format!("{name} (did you mean {closest}?)"),
+                None => name.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.diagnostic_handler.report(
+            DiagnosticCategory::UnmatchedExports,
+            format!(
+                "exported symbols not defined by any input: {}",
+                names.join(", ")
+            ),
+        );
+        self.stats.unmatched_exports = unmatched;
+    }
+
+    // Runs the LLVM verifier when enabled, `stage` saying what the module just went through.
+    fn verify_module(&self, stage: &str) -> Result<(), LinkerError> {
+        if !self.options.verify {
+            return Ok(());
+        }
+        debug!("verifying the module after {stage}");
+        unsafe { llvm::verify_module(self.module) }
+            .map_err(|message| LinkerError::ModuleVerification(format!("after {stage}: {message}")))
+    }
+
+    // Symbols which are still undefined at this point make the object fail to load, with errors
+    // much less obvious than a link error, unless the loader resolves them as kernel symbols.
+    fn check_undefined_symbols(&mut self) -> Result<(), LinkerError> {
+        let mut undefined = Vec::from_iter(self.unresolved_symbols());
+        if undefined.is_empty() {
+            return Ok(());
+        }
+        undefined.sort();
+        match self.options.undefined_symbols {
+            UndefinedSymbols::KsymsSection => {
+                info!(
+                    "moving undefined symbols to .ksyms: {}",
+                    undefined.join(", ")
+                );
+                // the references of the prelinked objects are left to the loader
+                unsafe { llvm::move_to_ksyms(self.module, &undefined) };
+            }
+            UndefinedSymbols::Keep => self.diagnostic_handler.report(
+                DiagnosticCategory::UndefinedSymbols,
+                format!("undefined symbols: {}", undefined.join(", ")),
+            ),
+            UndefinedSymbols::Error => return Err(LinkerError::UndefinedSymbols(undefined)),
+        }
+        Ok(())
+    }
+
+    // Returns the symbols which the module or the prelinked objects reference and which neither
+    // defines. The ones the prelinked objects define are resolved when the objects are merged.
+    fn unresolved_symbols(&self) -> HashSet<String> {
+        let mut undefined = unsafe { llvm::undefined_symbols(self.module) };
+        if !self.prelinked_undefined.is_empty() {
+            let defined = unsafe { llvm::defined_symbols(self.module) };
+            undefined.extend(
+                self.prelinked_undefined
+                    .iter()
+                    .filter(|name| !defined.contains(*name))
+                    .cloned(),
+            );
+        }
+        undefined.retain(|name| !self.prelinked_defined.contains(name));
+        undefined
+    }
+
+    // Fails on the calls BPF can't make, rather than on a backend error in the middle of codegen.
+    fn check_arguments(&mut self) -> Result<(), LinkerError> {
+        let functions = unsafe { llvm::functions_with_too_many_args(self.module) };
+        if functions.is_empty() {
+            return Ok(());
+        }
+        Err(LinkerError::TooManyArguments(
+            functions
+                .into_iter()
+                .map(|(function, args)| format!("`{function}` takes {args} arguments"))
+                .collect(),
+        ))
+    }
+
+    fn collect_section_sizes(&mut self) -> Result<(), LinkerError> {
+        if !matches!(self.options.output_type, OutputType::Object) {
+            return Ok(());
+        }
+        let path = &self.options.output;
+        let data = std::fs::read(path).map_err(|e| LinkerError::IoError(path.clone(), e))?;
+        match unsafe { llvm::section_sizes(&data) } {
+            Ok(sizes) => self.stats.section_sizes = sizes,
+            Err(e) => warn!("failed to read the sections of {:?}: {}", path, e),
+        }
+        Ok(())
+    }
+
+    // Records what produced the output, similar to the build id of other linkers.
+    fn add_linker_metadata(&mut self) {
+        let (major, minor, patch) = llvm::version();
+        let mut meta = format!(
+            "bpf-linker={}\nllvm={major}.{minor}.{patch}\noptions=fnv1a64:{:016x}\n",
+            env!("CARGO_PKG_VERSION"),
+            self.options_hash
+        );
+        for (id, hash) in &self.input_hashes {
+            writeln!(meta, "input=fnv1a64:{hash:016x} {id}").unwrap();
+        }
+        unsafe {
+            llvm::add_section_data(
+                self.context,
+                self.module,
+                "bpf_linker_meta",
+                ".bpf.linker.meta",
+                meta.as_bytes(),
+            )
+        };
+    }
+
+    fn collect_stack_usage(&mut self) -> Result<(), LinkerError> {
+        let object = self.object()?;
+        let functions = unsafe { llvm::defined_functions(self.module) };
+        let big_endian = unsafe { llvm::is_big_endian(self.target_machine) };
+        let mut stack_usage = unsafe { llvm::function_code(self.context, &object, &functions) }
+            .map_err(LinkerError::EmitCodeError)?
+            .into_iter()
+            .map(|(name, code)| (name, stack::stack_usage(&code, big_endian)))
+            .collect::<Vec<_>>();
+        stack_usage.sort();
+        for (name, size) in &stack_usage {
+            if *size > stack::MAX_STACK_SIZE {
+                self.diagnostic_handler.report(
+                    DiagnosticCategory::StackUsage,
+                    format!(
+                        "{name} uses {size} bytes of stack, more than the {} bytes the verifier allows",
+                        stack::MAX_STACK_SIZE
+                    ),
+                );
+            }
+        }
+        self.stats.stack_usage = stack_usage;
+        Ok(())
+    }
+
+    // Checks the members of the struct_ops maps against the functions they're initialized with.
+    fn check_struct_ops(&mut self) -> Result<(), LinkerError> {
+        let object = self.object()?;
+        let Some(data) = unsafe { llvm::section_contents(&object, ".BTF") }
+            .map_err(LinkerError::InvalidOutput)?
+        else {
+            return Ok(());
+        };
+        let btf = Btf::parse(&data).map_err(|e| LinkerError::InvalidOutput(e.to_string()))?;
+        let mut errors = Vec::new();
+        for section in llvm::STRUCT_OPS_MAP_SECTIONS {
+            let relocations = elf::relocations(&object, section)
+                .map_err(|e| LinkerError::InvalidOutput(e.to_string()))?;
+            errors.extend(btf::check_struct_ops(&btf, section, &relocations));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(LinkerError::StructOpsError(errors.join("; ")))
+        }
+    }
+
+    // Checks the kfuncs in the BTF of the program against the kernel BTF.
+    fn check_kfuncs(&mut self) -> Result<(), LinkerError> {
+        let Some(path) = self.options.kernel_btf.clone() else {
+            return Ok(());
+        };
+        let data = fs::read(&path).map_err(|e| LinkerError::IoError(path.clone(), e))?;
+        self.files_read.push(path.clone());
+        let kernel =
+            Btf::parse(&data).map_err(|e| LinkerError::InvalidKernelBtf(path, e.to_string()))?;
+
+        let object = self.object()?;
+        let Some(data) = unsafe { llvm::section_contents(&object, ".BTF") }
+            .map_err(LinkerError::InvalidOutput)?
+        else {
+            warn!("no BTF generated, kfuncs not checked against the kernel BTF");
+            return Ok(());
+        };
+        let program = Btf::parse(&data).map_err(|e| LinkerError::InvalidOutput(e.to_string()))?;
+        let errors = btf::check_kfuncs(&program, &kernel);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(LinkerError::KfuncMismatch(errors))
+        }
+    }
+
+    // Replaces the empty module created by llvm_init with the base module, which the inputs are
+    // then linked into.
+    fn load_base_module(&mut self) -> Result<(), LinkerError> {
+        let Some(path) = self.options.base_module.clone() else {
+            return Ok(());
+        };
+        let id = InputId::File(path.clone());
+        info!("loading base module {id}");
+        let data = fs::read(&path).map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+        self.files_read.push(path);
+        let bitcode = match detect_input_type(&data) {
+            Some(InputType::Bitcode) => llvm::bitcode::unwrap(data),
+            _ => None,
+        }
+        .ok_or_else(|| LinkerError::InvalidInputType(id.clone()))?;
+        if self.options.linker_metadata {
+            self.input_hashes
+                .push((id.clone(), Fnv1a64::hash(&bitcode)));
+        }
+        self.check_input_target(&id, &bitcode)?;
+        let module = unsafe { llvm::parse_bitcode(self.context, &bitcode) }
+            .map_err(|message| link_module_error(id, &bitcode, message))?;
+        unsafe { LLVMDisposeModule(self.module) };
+        self.module = module;
+        Ok(())
+    }
+
+    fn link_modules(&mut self) -> Result<(), LinkerError> {
+        let inputs = mem::take(&mut self.options.inputs);
+        let result = inputs.iter().try_for_each(|input| self.link_input(input));
+        self.options.inputs = inputs;
+        result
+    }
+
+    fn link_input(&mut self, input: &LinkerInput) -> Result<(), LinkerError> {
+        let id = input.id();
+        match input {
+            LinkerInput::File(path) => {
+                let file =
+                    File::open(path).map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+                self.files_read.push(path.clone());
+                self.link_input_reader(id, file)
+            }
+            LinkerInput::Buffer { bytes, .. } => {
+                self.link_input_reader(id, io::Cursor::new(bytes.as_slice()))
+            }
+        }
+    }
+
+    fn link_input_reader(
+        &mut self,
+        id: InputId,
+        mut reader: impl Read + Seek,
+    ) -> Result<(), LinkerError> {
+        // buffer used to perform file type detection
         let mut buf = [0u8; 8];
-        for path in self.options.inputs.clone() {
-            let mut file = File::open(&path).map_err(|e| LinkerError::IoError(path.clone(), e))?;
-
-            // determine whether the input is bitcode, ELF with embedded bitcode, an archive file
-            // or an invalid file
-            file.read_exact(&mut buf)
-                .map_err(|e| LinkerError::IoError(path.clone(), e))?;
-            file.rewind()
-                .map_err(|e| LinkerError::IoError(path.clone(), e))?;
-            let in_type = detect_input_type(&buf)
-                .ok_or_else(|| LinkerError::InvalidInputType(path.clone()))?;
-
-            match in_type {
-                InputType::Archive => {
-                    info!("linking archive {:?}", path);
-
-                    // Extract the archive and call link_reader() for each item.
-                    let mut archive = Archive::new(file);
-                    while let Some(Ok(item)) = archive.next_entry() {
-                        let name =
-                            PathBuf::from(str::from_utf8(item.header().identifier()).unwrap());
-                        info!("linking archive item {:?}", name);
-
-                        match self.link_reader(&name, item, None) {
-                            Ok(_) => continue,
-                            Err(LinkerError::InvalidInputType(_)) => {
-                                info!("ignoring archive item {:?}: invalid type", name);
-                                continue;
-                            }
-                            Err(LinkerError::MissingBitcodeSection(_)) => {
-                                warn!("ignoring archive item {:?}: no embedded bitcode", name);
-                                continue;
-                            }
-                            Err(_) => return Err(LinkerError::LinkArchiveModuleError(path, name)),
-                        };
+
+        // determine whether the input is bitcode, ELF with embedded bitcode, an archive file
+        // or an invalid file
+        reader
+            .read_exact(&mut buf)
+            .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+        reader
+            .rewind()
+            .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+        let in_type =
+            detect_input_type(&buf).ok_or_else(|| LinkerError::InvalidInputType(id.clone()))?;
+
+        match in_type {
+            InputType::Compressed(compression) => {
+                let mut data = Vec::new();
+                let _: usize = reader
+                    .read_to_end(&mut data)
+                    .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+                let data = compression
+                    .decompress(&data)
+                    .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+                info!("decompressed {id} ({})", compression.name());
+                return self.link_input_reader(id, io::Cursor::new(data));
+            }
+            InputType::Archive => {
+                info!("linking archive {id}");
+
+                let mut data = Vec::new();
+                let _: usize = reader
+                    .read_to_end(&mut data)
+                    .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+                // Extract the archive and call link_reader() for each item.
+                for_each_archive_member(&id, &data, |member, item| {
+                    if !self.includes_archive_member(&member) {
+                        return Ok(());
                     }
+                    self.link_archive_member(&id, member, item)
+                })?;
+            }
+            InputType::ThinArchive => {
+                info!("linking thin archive {id}");
+
+                let mut data = Vec::new();
+                let _: usize = reader
+                    .read_to_end(&mut data)
+                    .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+                let names = thin_archive::members(&data)
+                    .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+                // member paths are relative to the archive
+                let dir = match &id {
+                    InputId::File(path) => path.parent().unwrap_or(Path::new("")),
+                    _ => Path::new(""),
                 }
-                ty => {
-                    info!("linking file {:?} type {}", path, ty);
-                    match self.link_reader(&path, file, Some(ty)) {
-                        Ok(_) => {}
-                        Err(LinkerError::InvalidInputType(_)) => {
-                            info!("ignoring file {:?}: invalid type", path);
-                            continue;
-                        }
-                        Err(LinkerError::MissingBitcodeSection(_)) => {
-                            warn!("ignoring file {:?}: no embedded bitcode", path);
-                        }
-                        err => return err,
+                .to_owned();
+                for name in names {
+                    let member = InputId::ArchiveMember {
+                        archive: Box::new(id.clone()),
+                        member: name.clone(),
+                    };
+                    if !self.includes_archive_member(&member) {
+                        continue;
                     }
+                    let path = dir.join(&name);
+                    let file = File::open(&path)
+                        .map_err(|e| LinkerError::ReadInputError(member.clone(), e))?;
+                    self.files_read.push(path);
+                    self.link_archive_member(&id, member, file)?;
+                }
+            }
+            ty => {
+                info!("linking {id} type {ty}");
+                match self.link_reader(&id, reader, Some(ty)) {
+                    Ok(_) => {}
+                    Err(LinkerError::InvalidInputType(_)) => {
+                        info!("ignoring {id}: invalid type");
+                    }
+                    Err(LinkerError::MissingBitcodeSection(_)) => {
+                        self.diagnostic_handler.report(
+                            DiagnosticCategory::NoEmbeddedBitcode,
+                            format!("ignoring {id}: no embedded bitcode"),
+                        );
+                    }
+                    err => return err,
                 }
             }
         }
@@ -333,45 +2205,383 @@ impl Linker {
         Ok(())
     }
 
-    // link in a `Read`-er, which can be a file or an archive item
+    // Returns whether the archive `member` passes the `archive_member_filters`.
+    fn includes_archive_member(&self, member: &InputId) -> bool {
+        let filters = &self.options.archive_member_filters;
+        let InputId::ArchiveMember { member: name, .. } = member else {
+            return true;
+        };
+        if filters.is_empty() || filters.iter().any(|pattern| glob::matches(pattern, name)) {
+            return true;
+        }
+        info!("ignoring archive item {member}: filtered out");
+        false
+    }
+
+    // link in a `Read`-er, which can be a file, a buffer or an archive item
+    fn link_archive_member(
+        &mut self,
+        archive: &InputId,
+        member: InputId,
+        reader: impl Read,
+    ) -> Result<(), LinkerError> {
+        info!("linking archive item {member}");
+
+        match self.link_reader(&member, reader, None) {
+            Ok(()) => Ok(()),
+            Err(LinkerError::InvalidInputType(_)) => {
+                info!("ignoring archive item {member}: invalid type");
+                Ok(())
+            }
+            Err(LinkerError::MissingBitcodeSection(_)) => {
+                self.diagnostic_handler.report(
+                    DiagnosticCategory::NoEmbeddedBitcode,
+                    format!("ignoring archive item {member}: no embedded bitcode"),
+                );
+                Ok(())
+            }
+            Err(source) => {
+                let InputId::ArchiveMember { member, .. } = member else {
+                    unreachable!("archive members are identified as such")
+                };
+                Err(LinkerError::LinkArchiveModuleError {
+                    archive: archive.clone(),
+                    member,
+                    source: Box::new(source),
+                })
+            }
+        }
+    }
+
     fn link_reader(
         &mut self,
-        path: &Path,
-        mut reader: impl Read,
+        id: &InputId,
+        reader: impl Read,
         in_type: Option<InputType>,
     ) -> Result<(), LinkerError> {
+        let span = info_span!("link_module", module = %id, bitcode_size = field::Empty).entered();
+        let (in_type, data) = self.read_input(id, reader, in_type)?;
+        if in_type == InputType::RustMetadata {
+            debug!("ignoring {id}: Rust metadata");
+            return Ok(());
+        }
+        if self.options.allow_prelinked_objects && elf::is_prelinked_object(&data) {
+            info!("{id} has no embedded bitcode, merging its machine code into the output");
+            let (defined, undefined) = elf::global_symbols(&data)
+                .map_err(|e| LinkerError::MergeObjectsError(format!("{id}: {e}")))?;
+            self.prelinked_defined.extend(defined);
+            self.prelinked_undefined.extend(undefined);
+            self.prelinked_objects.push((id.clone(), data));
+            return Ok(());
+        }
+        let bitcode = self.extract_bitcode(id, in_type, data)?;
+        let _: &Span = span.record("bitcode_size", bitcode.len());
+        if self.is_duplicate(id, &bitcode) {
+            return Ok(());
+        }
+        self.dump_input(id, &bitcode)?;
+
+        if self.options.linker_metadata {
+            self.input_hashes
+                .push((id.clone(), Fnv1a64::hash(&bitcode)));
+        }
+        self.check_input_target(id, &bitcode)?;
+        unsafe { llvm::link_bitcode_buffer(self.context, self.module, &bitcode) }
+            .map_err(|message| link_module_error(id.clone(), &bitcode, message))?;
+        self.record_function_inputs(id, &bitcode);
+        self.stats.input_modules += 1;
+
+        Ok(())
+    }
+
+    // Writes the IR of the input `id` to the dump directory, when inputs are dumped.
+    fn dump_input(&mut self, id: &InputId, bitcode: &[u8]) -> Result<(), LinkerError> {
+        let Some(dir) = self
+            .options
+            .dump_module
+            .as_ref()
+            .filter(|_| self.options.dump_inputs)
+        else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir).map_err(|e| LinkerError::IoError(dir.clone(), e))?;
+        // inputs from different directories or archives can have the same name
+        let name = input_file_name(id);
+        let mut file_name = format!("{name}.ll");
+        let mut n = 1;
+        while !self.dumped_inputs.insert(file_name.clone()) {
+            n += 1;
+            file_name = format!("{name}.{n}.ll");
+        }
+        let path = path_to_cstring(&dir.join(file_name))?;
+        let module = unsafe { llvm::parse_bitcode(self.context, bitcode) }
+            .map_err(|message| link_module_error(id.clone(), bitcode, message))?;
+        let ret = unsafe { llvm::write_ir(module, &path) }.map_err(LinkerError::WriteIRError);
+        unsafe { LLVMDisposeModule(module) };
+        ret
+    }
+
+    // Records the input `id`, which was just linked, as the input defining the functions it
+    // added, when `bitcode` was compiled for a non-BPF target. The functions of BPF inputs are
+    // recorded too, with no input, so that they aren't attributed to later inputs.
+    fn record_function_inputs(&mut self, id: &InputId, bitcode: &[u8]) {
+        let host = unsafe { llvm::bitcode_target(self.context, bitcode) }
+            .is_some_and(|target| !target.starts_with("bpf"));
+        for function in unsafe { llvm::defined_functions(self.module) } {
+            let _: &mut Option<InputId> = self
+                .function_inputs
+                .entry(function)
+                .or_insert_with(|| host.then(|| id.clone()));
+        }
+    }
+
+    // Checks that the functions which survived optimization and come from inputs compiled for a
+    // non-BPF target don't use constructs the BPF backend can't generate code for, which it
+    // would otherwise fail on with errors that don't say where they come from.
+    fn check_non_bpf_constructs(&mut self) -> Result<(), LinkerError> {
+        let errors: Vec<_> = unsafe { llvm::non_bpf_constructs(self.module) }
+            .into_iter()
+            .filter_map(
+                |(function, construct)| match self.function_inputs.get(&function) {
+                    Some(Some(input)) => {
+                        Some(format!("`{function}` from {input} uses {construct}"))
+                    }
+                    Some(None) => None,
+                    None => Some(format!("`{function}` uses {construct}")),
+                },
+            )
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(LinkerError::NonBpfConstructs(errors))
+        }
+    }
+
+    // Returns whether the same bitcode as `bitcode` was already linked, recording it otherwise.
+    fn is_duplicate(&mut self, id: &InputId, bitcode: &[u8]) -> bool {
+        if !self.options.dedup_inputs {
+            return false;
+        }
+        match self.linked_bitcode.entry(Sha256::digest(bitcode).into()) {
+            Entry::Occupied(entry) => {
+                debug!("ignoring {id}: same bitcode as {}", entry.get());
+                true
+            }
+            Entry::Vacant(entry) => {
+                let _: &mut InputId = entry.insert(id.clone());
+                false
+            }
+        }
+    }
+
+    // read the bitcode of a bitcode file or of an object file with embedded bitcode, None for
+    // Rust metadata
+    fn read_bitcode(
+        &mut self,
+        id: &InputId,
+        reader: impl Read,
+        in_type: Option<InputType>,
+    ) -> Result<Option<Vec<u8>>, LinkerError> {
+        let (in_type, data) = self.read_input(id, reader, in_type)?;
+        if in_type == InputType::RustMetadata {
+            debug!("ignoring {id}: Rust metadata");
+            return Ok(None);
+        }
+        self.extract_bitcode(id, in_type, data).map(Some)
+    }
+
+    // read an input, decompressing it if needed
+    fn read_input(
+        &mut self,
+        id: &InputId,
+        mut reader: impl Read,
+        in_type: Option<InputType>,
+    ) -> Result<(InputType, Vec<u8>), LinkerError> {
         let mut data = Vec::new();
         let _: usize = reader
             .read_to_end(&mut data)
-            .map_err(|e| LinkerError::IoError(path.to_owned(), e))?;
+            .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
         // in_type is unknown when we're linking an item from an archive file
-        let in_type = in_type
+        let mut in_type = in_type
             .or_else(|| detect_input_type(&data))
-            .ok_or_else(|| LinkerError::InvalidInputType(path.to_owned()))?;
+            .ok_or_else(|| LinkerError::InvalidInputType(id.clone()))?;
+        if let InputType::Compressed(compression) = in_type {
+            data = compression
+                .decompress(&data)
+                .map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+            in_type = detect_input_type(&data)
+                .ok_or_else(|| LinkerError::InvalidInputType(id.clone()))?;
+        }
+        if matches!(in_type, InputType::Elf | InputType::MachO)
+            && unsafe { llvm::is_rust_metadata(&data) }
+        {
+            in_type = InputType::RustMetadata;
+        }
+        Ok((in_type, data))
+    }
 
+    fn extract_bitcode(
+        &mut self,
+        id: &InputId,
+        in_type: InputType,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, LinkerError> {
         use InputType::*;
         let bitcode = match in_type {
             Bitcode => data,
-            Elf => match unsafe { llvm::find_embedded_bitcode(self.context, &data) } {
-                Ok(Some(bitcode)) => bitcode,
-                Ok(None) => return Err(LinkerError::MissingBitcodeSection(path.to_owned())),
+            Elf => match unsafe { llvm::find_embedded_bitcode(&data) } {
+                Ok(Some(llvm::EmbeddedBitcode { bitcode, cmdline })) => {
+                    if let Some(cmdline) = cmdline {
+                        self.check_embedded_cmdline(id, &EmbeddedCmdline::parse(&cmdline));
+                    }
+                    bitcode
+                }
+                Ok(None) => return Err(LinkerError::MissingBitcodeSection(id.clone())),
                 Err(e) => return Err(LinkerError::EmbeddedBitcodeError(e)),
             },
-            // we need to handle this here since archive files could contain
-            // mach-o files, eg somecrate.rlib containing lib.rmeta which is
-            // mach-o on macos
-            InputType::MachO => return Err(LinkerError::InvalidInputType(path.to_owned())),
-            // this can't really happen
-            Archive => panic!("nested archives not supported duh"),
+            // Mach-O objects have no bitcode unless built with -fembed-bitcode
+            MachO => match unsafe { llvm::find_embedded_bitcode(&data) } {
+                Ok(Some(llvm::EmbeddedBitcode { bitcode, .. })) => bitcode,
+                Ok(None) | Err(_) => return Err(LinkerError::InvalidInputType(id.clone())),
+            },
+            // the members of thin archives are files which may well be archives themselves, but
+            // nested archives aren't supported. Compressed twice, or no code at all
+            Archive | ThinArchive | Compressed(_) | RustMetadata => {
+                return Err(LinkerError::InvalidInputType(id.clone()))
+            }
         };
 
-        if unsafe { !llvm::link_bitcode_buffer(self.context, self.module, &bitcode) } {
-            return Err(LinkerError::LinkModuleError(path.to_owned()));
+        llvm::bitcode::unwrap(bitcode).ok_or_else(|| LinkerError::InvalidInputType(id.clone()))
+    }
+
+    // Link the members of the library archives which define symbols that are still undefined,
+    // until no member resolves anything new. Like static libraries in ld, members are pulled in
+    // regardless of the order of the libraries.
+    fn link_libraries(&mut self) -> Result<(), LinkerError> {
+        let mut members = Vec::new();
+        for path in self.options.libraries.clone() {
+            let id = InputId::File(path.clone());
+            info!("reading library {id}");
+            let data = fs::read(&path).map_err(|e| LinkerError::ReadInputError(id.clone(), e))?;
+            self.files_read.push(path.clone());
+            for_each_archive_member(&id, &data, |member, item| {
+                if !self.includes_archive_member(&member) {
+                    return Ok(());
+                }
+                let bitcode = match self.read_bitcode(&member, item, None) {
+                    Ok(Some(bitcode)) => bitcode,
+                    Ok(None) => return Ok(()),
+                    Err(
+                        LinkerError::InvalidInputType(_) | LinkerError::MissingBitcodeSection(_),
+                    ) => {
+                        info!("ignoring library member {member}: no bitcode");
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+                let symbols = unsafe { llvm::bitcode_defined_symbols(self.context, &bitcode) }
+                    .map_err(|message| LinkerError::LinkModuleError(member.clone(), message))?;
+                members.push((member, bitcode, symbols));
+                Ok(())
+            })?;
+        }
+
+        loop {
+            let undefined = self.unresolved_symbols();
+            let Some(index) = members
+                .iter()
+                .position(|(_, _, symbols)| symbols.iter().any(|s| undefined.contains(s)))
+            else {
+                break;
+            };
+            let (member, bitcode, _) = members.swap_remove(index);
+            if self.is_duplicate(&member, &bitcode) {
+                continue;
+            }
+            info!("linking library member {member}");
+            self.dump_input(&member, &bitcode)?;
+            if self.options.linker_metadata {
+                self.input_hashes
+                    .push((member.clone(), Fnv1a64::hash(&bitcode)));
+            }
+            self.check_input_target(&member, &bitcode)?;
+            unsafe { llvm::link_bitcode_buffer(self.context, self.module, &bitcode) }
+                .map_err(|message| link_module_error(member.clone(), &bitcode, message))?;
+            self.record_function_inputs(&member, &bitcode);
+            self.stats.input_modules += 1;
         }
 
         Ok(())
     }
 
+    // Inputs compiled for the host (cases 2 and 3 in create_target_machine) can be linked with
+    // inputs compiled for bpfel or bpfeb, eg a rust crate built for BPF with its dependencies
+    // built for the host. Without a target set, the output must then have the endianness of the
+    // latter instead of the host's. When generating code for several targets, the inputs compiled
+    // for bpfel or bpfeb must have the endianness of all of them.
+    fn check_input_target(&mut self, id: &InputId, bitcode: &[u8]) -> Result<(), LinkerError> {
+        if self.options.target.is_some() {
+            return Ok(());
+        }
+        let Some(target) = (unsafe { llvm::bitcode_target(self.context, bitcode) }) else {
+            return Ok(());
+        };
+        let Some(endianness) = bpf_endianness(&target) else {
+            return Ok(());
+        };
+        if !self.options.targets.is_empty() {
+            return match self
+                .options
+                .targets
+                .iter()
+                .find(|t| bpf_endianness(t).is_some_and(|e| e != endianness))
+            {
+                Some(other) => Err(LinkerError::TargetEndianness {
+                    input: id.clone(),
+                    input_target: target,
+                    target: other.clone(),
+                }),
+                None => Ok(()),
+            };
+        }
+        match &self.input_target {
+            None => self.input_target = Some((id.clone(), target)),
+            Some((first, first_target)) => {
+                if bpf_endianness(first_target) != bpf_endianness(&target) {
+                    return Err(LinkerError::ConflictingEndianness {
+                        first: first.clone(),
+                        first_target: first_target.clone(),
+                        input: id.clone(),
+                        target,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // warn about inputs built with options that produce subtly broken output
+    fn check_embedded_cmdline(&mut self, id: &InputId, cmdline: &EmbeddedCmdline) {
+        debug!("{id} codegen options: {cmdline:?}");
+        if cmdline.opt_level.as_deref() == Some("0") {
+            self.diagnostic_handler.report(
+                DiagnosticCategory::Optnone,
+                format!(
+                    "{id} was built with -O0, its functions are marked `optnone` and won't be optimized"
+                ),
+            );
+        }
+        if self.options.btf && cmdline.debug_info == Some(false) {
+            self.diagnostic_handler.report(
+                DiagnosticCategory::MissingDebugInfo,
+                format!(
+                    "{id} was built without debug info, the emitted BTF won't describe its types"
+                ),
+            );
+        }
+    }
+
     fn create_target_machine(&mut self) -> Result<(), LinkerError> {
         let Self {
             options:
@@ -379,10 +2589,17 @@ impl Linker {
                     target,
                     cpu,
                     cpu_features,
+                    code_model,
+                    reloc_model,
+                    optimize,
+                    codegen_optimize,
                     ..
                 },
             module,
             target_machine,
+            input_target,
+            pool,
+            target_machine_key,
             ..
         } = self;
         // Here's how the output target is selected:
@@ -398,6 +2615,9 @@ impl Linker {
         //      the input modules are configured for the *host* target, the output target isn't
         //      set via `--target`, so default to `bpf` (bpfel or bpfeb depending on the host
         //      endianness)
+        //
+        // In cases 2 and 3, inputs compiled for bpfel or bpfeb may be linked too, eg when only
+        // some crates are built for BPF. Their endianness is then used instead of the host's.
         let (triple, target) = match target {
             // case 1
             Some(triple) => {
@@ -409,7 +2629,16 @@ impl Linker {
             None => {
                 let c_triple = unsafe { LLVMGetTarget(*module) };
                 let triple = unsafe { CStr::from_ptr(c_triple) }.to_str().unwrap();
-                if triple.starts_with("bpf") {
+                if let Some((id, input_triple)) = input_target
+                    .as_ref()
+                    .filter(|_| bpf_endianness(triple).is_none())
+                {
+                    info!("inferred target {input_triple} from {id}");
+                    let c_triple = CString::new(input_triple.as_str()).unwrap();
+                    (input_triple.as_str(), unsafe {
+                        llvm::target_from_triple(&c_triple)
+                    })
+                } else if triple.starts_with("bpf") {
                     // case 2
                     (triple, unsafe { llvm::target_from_module(*module) })
                 } else {
@@ -423,26 +2652,185 @@ impl Linker {
         };
         let target = target.map_err(|_msg| LinkerError::InvalidTarget(triple.to_owned()))?;
 
+        let codegen_optimize = codegen_optimize.unwrap_or(*optimize);
         debug!(
-            "creating target machine: triple: {} cpu: {} features: {}",
-            triple, cpu, cpu_features,
+            "creating target machine: triple: {} cpu: {} features: {} code model: {:?} \
+             relocation model: {:?} optimization: {:?}",
+            triple, cpu, cpu_features, code_model, reloc_model, codegen_optimize,
         );
 
-        *target_machine =
-            unsafe { llvm::create_target_machine(target, triple, cpu.to_str(), cpu_features) }
-                .ok_or_else(|| LinkerError::InvalidTarget(triple.to_owned()))?;
+        let key = TargetMachineKey {
+            triple: triple.to_owned(),
+            cpu: cpu.to_str().to_owned(),
+            features: cpu_features.clone(),
+            code_model: *code_model,
+            reloc_model: *reloc_model,
+            optimize: codegen_optimize,
+        };
+        *target_machine = match pool
+            .as_mut()
+            .and_then(|pool| pool.take_target_machine(&key))
+        {
+            Some(pooled) => {
+                debug!("reusing pooled target machine");
+                pooled
+            }
+            None => unsafe {
+                llvm::create_target_machine(
+                    target,
+                    triple,
+                    cpu.to_str(),
+                    cpu_features,
+                    *code_model,
+                    *reloc_model,
+                    codegen_optimize,
+                )
+            }
+            .ok_or_else(|| LinkerError::InvalidTarget(triple.to_owned()))?,
+        };
+        *target_machine_key = Some(key);
 
         Ok(())
     }
 
+    // Asks the symbol policy, if any, about each symbol the module defines. The sections and
+    // exports it decides are applied right away, the symbols to keep and to make weak are
+    // returned.
+    fn apply_symbol_policy(&mut self) -> Result<(HashSet<String>, HashSet<String>), LinkerError> {
+        let mut kept = HashSet::new();
+        let mut weak = HashSet::new();
+        let Some(policy) = &self.symbol_policy else {
+            return Ok((kept, weak));
+        };
+        let mut sections = Vec::new();
+        for symbol in unsafe { llvm::module_symbols(self.module) } {
+            if symbol.declaration {
+                continue;
+            }
+            let policy_symbol = PolicySymbol {
+                name: &symbol.name,
+                kind: symbol.kind,
+                section: symbol.section.as_deref(),
+                exported: self.options.export_symbols.contains(symbol.name.as_str()),
+            };
+            let section = policy.decide_section(&policy_symbol);
+            let export = policy.decide_export(&policy_symbol);
+            let linkage = policy.decide_linkage(&policy_symbol);
+            if let Some(section) = section {
+                if section.contains('\0') {
+                    return Err(LinkerError::InvalidSymbolSection(symbol.name, section));
+                }
+                sections.push((symbol.name.clone(), section));
+            }
+            match export {
+                Some(true) => {
+                    let _: bool = self
+                        .options
+                        .export_symbols
+                        .insert(symbol.name.clone().into());
+                }
+                Some(false) => {
+                    let _: bool = self.options.export_symbols.remove(symbol.name.as_str());
+                }
+                None => {}
+            }
+            match linkage {
+                Some(SymbolLinkage::Keep) => {
+                    let _: bool = kept.insert(symbol.name);
+                }
+                Some(SymbolLinkage::Weak) => {
+                    let _: bool = weak.insert(symbol.name);
+                }
+                Some(SymbolLinkage::Default) | None => {}
+            }
+        }
+        if !sections.is_empty() {
+            let _: Vec<String> = unsafe { llvm::set_symbol_sections(self.module, &sections) };
+        }
+        Ok((kept, weak))
+    }
+
     fn optimize(&mut self) -> Result<(), LinkerError> {
+        // first, so that the checks of the program and map sections see them
+        if !self.options.symbol_sections.is_empty() {
+            if let Some((symbol, section)) = self
+                .options
+                .symbol_sections
+                .iter()
+                .find(|(_, section)| section.contains('\0'))
+            {
+                return Err(LinkerError::InvalidSymbolSection(
+                    symbol.clone(),
+                    section.clone(),
+                ));
+            }
+            let missing =
+                unsafe { llvm::set_symbol_sections(self.module, &self.options.symbol_sections) };
+            if !missing.is_empty() {
+                warn!("can't set the section of symbols no input defines: {missing:?}");
+            }
+        }
+        let (mut keep, mut policy_weak) = self.apply_symbol_policy()?;
+        // the exports of what a query asks about, before they're merged with the others
+        let requested: Vec<bool> = self
+            .options
+            .why_internalized
+            .iter()
+            .map(|name| self.options.export_symbols.contains(name.as_str()))
+            .collect();
         if !self.options.disable_memory_builtins {
-            self.options.export_symbols.extend(
-                ["memcpy", "memmove", "memset", "memcmp", "bcmp"]
-                    .into_iter()
-                    .map(Into::into),
-            );
+            self.options
+                .export_symbols
+                .extend(MEMORY_BUILTINS.into_iter().map(Into::into));
         };
+        // struct_ops maps and their programs are looked up by libbpf even when nothing exports
+        // them.
+        let struct_ops = unsafe { llvm::struct_ops_symbols(self.module) }
+            .map_err(LinkerError::StructOpsError)?;
+        let export_reasons: Vec<Option<ExportReason>> = self
+            .options
+            .why_internalized
+            .iter()
+            .zip(requested)
+            .map(|(name, requested)| {
+                if requested {
+                    Some(ExportReason::Requested)
+                } else if !self.options.disable_memory_builtins
+                    && MEMORY_BUILTINS.contains(&name.as_str())
+                {
+                    Some(ExportReason::MemoryBuiltin)
+                } else if struct_ops.contains(name) {
+                    Some(ExportReason::StructOps)
+                } else if name == llvm::PROFILE_COUNTERS
+                    && self.options.instrument_functions == Some(InstrumentFunctions::Counters)
+                {
+                    Some(ExportReason::ProfileCounters)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.options
+            .export_symbols
+            .extend(struct_ops.iter().cloned().map(Into::into));
+        self.struct_ops = struct_ops;
+        if self.options.bpf_trap == BpfTrap::Return {
+            let rewritten = unsafe { llvm::rewrite_traps(self.context, self.module) };
+            debug!("rewrote {rewritten} traps into returns");
+        }
+        if let Some(mode) = self.options.instrument_functions {
+            let instrumented =
+                unsafe { llvm::instrument_functions(self.context, self.module, mode) }
+                    .map_err(LinkerError::InstrumentError)?;
+            debug!("instrumented functions {instrumented:?}");
+            if mode == InstrumentFunctions::Counters && !instrumented.is_empty() {
+                let _: bool = self
+                    .options
+                    .export_symbols
+                    .insert(llvm::PROFILE_COUNTERS.into());
+            }
+            self.stats.instrumented_functions = instrumented;
+        }
         debug!(
             "linking exporting symbols {:?}, opt level {:?}",
             self.options.export_symbols, self.options.optimize
@@ -450,39 +2838,437 @@ impl Linker {
         // run optimizations. Will optionally remove noinline attributes, intern all non exported
         // programs and maps and remove dead code.
 
+        let mut btf_kept_types = Vec::new();
         if self.options.btf {
             // if we want to emit BTF, we need to sanitize the debug information
-            llvm::DISanitizer::new(self.context, self.module).run(&self.options.export_symbols);
+            let llvm::SanitizedDebugInfo {
+                skipped_types,
+                kept_types,
+            } = self.stage("sanitize debug info", |linker| {
+                llvm::DISanitizer::new(linker.context, linker.module)
+                    .keep_types(&linker.options.btf_keep_types)
+                    .run(&linker.options.export_symbols)
+            });
+            btf_kept_types = kept_types;
+            let missing = unsafe {
+                llvm::exported_functions_without_debug_info(
+                    self.module,
+                    &self.options.export_symbols,
+                )
+            };
+            if !missing.is_empty() {
+                return Err(LinkerError::MissingFuncInfo(missing));
+            }
+            for llvm::SkippedType {
+                name,
+                location,
+                referenced_by,
+            } in skipped_types
+            {
+                let referenced_by = if referenced_by.is_empty() {
+                    String::new()
+                } else {
+                    format!(", referenced by {}", referenced_by.join(", "))
+                };
+                self.diagnostic_handler.report(
+                    DiagnosticCategory::DataCarryingEnum,
+                    format!(
+                        "BTF can't describe the data-carrying enum {name} ({location})\
+                         {referenced_by}, so its debug info was not emitted. Use a #[repr(C)] \
+                         struct holding a tag and a union of the variants' data if it must \
+                         appear in BTF"
+                    ),
+                );
+            }
         } else {
             // if we don't need BTF emission, we can strip DI
             let ok = unsafe { llvm::strip_debug_info(self.module) };
             debug!("Stripping DI, changed={}", ok);
         }
 
-        unsafe {
+        // exporting an alias keeps its aliasee, which would otherwise be internalized and could be
+        // removed from under it
+        let aliasees =
+            unsafe { llvm::exported_aliasees(self.module, &self.options.export_symbols) };
+        if !aliasees.is_empty() {
+            debug!("keeping the aliasees of exported aliases {aliasees:?}");
+            keep.extend(aliasees);
+        }
+
+        let mut kept = Vec::new();
+        if !self.options.keep_symbols.is_empty() || !keep.is_empty() {
+            let patterns = &self.options.keep_symbols;
+            kept = unsafe {
+                llvm::keep_symbols(self.context, self.module, |name| {
+                    keep.contains(name)
+                        || patterns.iter().any(|pattern| glob::matches(pattern, name))
+                })
+            };
+            debug!("keeping symbols {kept:?}");
+        }
+
+        if unsafe { llvm::remove_probestack_asm(self.module) } {
+            debug!("removed the __rust_probestack module asm");
+        }
+        for path in &self.options.module_asm {
+            let asm =
+                fs::read_to_string(path).map_err(|e| LinkerError::IoError(path.clone(), e))?;
+            unsafe { llvm::append_module_asm(self.module, &asm) };
+            self.files_read.push(path.clone());
+        }
+        for asm in &self.module_asm {
+            unsafe { llvm::append_module_asm(self.module, asm) };
+        }
+
+        if let OptLevel::No = self.options.optimize {
+            info!(
+                "optimizations disabled, only inlining alwaysinline functions and removing \
+                 unreferenced code"
+            );
+        }
+        if self.options.convert_loops_to_bpf_loop {
+            // before optimization, which would unroll the loops, eg with --unroll-loops, and then
+            // optimizes the callbacks and the functions calling bpf_loop
+            let converted = self
+                .stage("convert loops", |linker| unsafe {
+                    llvm::run_pipeline(
+                        linker.target_machine,
+                        linker.module,
+                        llvm::LOOP_CANONICALIZATION_PASSES,
+                    )
+                    .map(|()| llvm::convert_loops_to_bpf_loop(linker.context, linker.module))
+                })
+                .map_err(LinkerError::OptimizeError)?;
+            info!("converted {converted} loops into bpf_loop calls");
+        }
+        if self.options.unroll_loops && !self.options.unroll_functions.is_empty() {
+            let marked = unsafe {
+                llvm::mark_loops_for_unrolling(
+                    self.context,
+                    self.module,
+                    &self.options.unroll_functions,
+                )
+            };
+            debug!("marked {marked} loops for unrolling");
+        }
+        (self.stats.functions_before_internalize, _) =
+            unsafe { llvm::count_defined_functions(self.module) };
+        let function_sizes = if self.options.removed_functions {
+            unsafe { llvm::function_sizes(self.module) }
+        } else {
+            HashMap::new()
+        };
+        let mut explanations: Vec<_> = self
+            .options
+            .why_internalized
+            .iter()
+            .zip(export_reasons)
+            .map(|(name, export)| {
+                let state = unsafe { llvm::symbol_state(self.module, name) };
+                let explanation =
+                    SymbolExplanation::before(name, state, export, kept.contains(name));
+                (explanation, state)
+            })
+            .collect();
+        self.stage("optimize", |linker| unsafe {
             llvm::optimize(
-                self.target_machine,
-                self.module,
-                self.options.optimize,
-                self.options.ignore_inline_never,
-                &self.options.export_symbols,
+                linker.target_machine,
+                linker.module,
+                linker.options.optimize,
+                linker.options.ignore_inline_never,
+                &linker.options.keep_inline_never,
+                &linker.options.export_symbols,
+                linker.options.merge_constants,
+                linker.options.pass_options,
             )
-        }
+        })
         .map_err(LinkerError::OptimizeError)?;
+        (
+            self.stats.functions_after_optimize,
+            self.stats.functions_exported,
+        ) = unsafe { llvm::count_defined_functions(self.module) };
+        if self.options.removed_functions {
+            let remaining = unsafe { llvm::defined_functions(self.module) };
+            let mut removed: Vec<_> = function_sizes
+                .into_iter()
+                .filter(|(name, _)| !remaining.contains(name))
+                .collect();
+            removed.sort();
+            self.stats.removed_functions = removed;
+        }
+        policy_weak.retain(|name| self.options.export_symbols.contains(name.as_str()));
+        if !policy_weak.is_empty() {
+            let missing = unsafe { llvm::make_weak(self.module, &policy_weak) };
+            if !missing.is_empty() {
+                debug!("can't make symbols removed by optimization weak: {missing:?}");
+            }
+        }
+
+        if self.options.gc_maps {
+            let mut removed = unsafe { llvm::gc_maps(self.module) };
+            removed.sort();
+            if !removed.is_empty() {
+                info!("removed unreferenced maps {removed:?}");
+            }
+            self.stats.removed_maps = removed;
+        }
+        for name in &self.struct_ops {
+            if !unsafe { llvm::symbol_state(self.module, name) }.is_some_and(|state| state.defined)
+            {
+                return Err(LinkerError::StructOpsError(format!(
+                    "`{name}` was removed by optimization, but libbpf needs it for a struct_ops \
+                     map"
+                )));
+            }
+        }
+        let ksyms = self.options.undefined_symbols == UndefinedSymbols::KsymsSection;
+        for (explanation, before) in &mut explanations {
+            if let Some(before) = *before {
+                let state = unsafe { llvm::symbol_state(self.module, &explanation.name) };
+                let map_removed = self.stats.removed_maps.contains(&explanation.name);
+                explanation.after(before, state, map_removed, ksyms);
+            }
+        }
+        self.stats.symbol_explanations = explanations
+            .into_iter()
+            .map(|(explanation, _)| explanation)
+            .collect();
+
+        let noinline = unsafe { llvm::noinline_functions(self.module) };
+        if !noinline.is_empty() {
+            self.diagnostic_handler.report(
+                DiagnosticCategory::InlineNever,
+                format!(
+                    "functions marked #[inline(never)] are emitted as BPF-to-BPF calls: {}",
+                    noinline.join(", ")
+                ),
+            );
+        }
+
+        if self.options.btf && self.options.btf_datasec_fixup {
+            for mismatch in unsafe { llvm::fixup_btf_datasec(self.context, self.module) } {
+                self.diagnostic_handler
+                    .report(DiagnosticCategory::BtfDatasec, mismatch);
+            }
+        }
+
+        if self.options.btf && !self.options.pin_maps.is_empty() {
+            let (pinned, unpinned) =
+                unsafe { llvm::pin_maps(self.context, self.module, &self.options.pin_maps) };
+            debug!("pinning maps {pinned:?}");
+            if !unpinned.is_empty() {
+                warn!(
+                    "maps without debug info can't be pinned: {}",
+                    unpinned.join(", ")
+                );
+            }
+            for pattern in &self.options.pin_maps {
+                if !pinned
+                    .iter()
+                    .chain(&unpinned)
+                    .any(|name| glob::matches(pattern, name))
+                {
+                    warn!("no map matches the pinning pattern `{pattern}`");
+                }
+            }
+        }
+
+        if !self.options.rename_symbols.is_empty() {
+            unsafe {
+                llvm::rename_symbols(self.context, self.module, &self.options.rename_symbols)
+            }
+            .map_err(LinkerError::RenameSymbolError)?;
+        }
+
+        if let Some(prefix) = &self.options.prefix_symbols {
+            unsafe { llvm::prefix_symbols(self.context, self.module, prefix) };
+        }
+
+        if !btf_kept_types.is_empty() {
+            debug!("keeping the BTF of {} types", btf_kept_types.len());
+            unsafe { llvm::keep_btf_types(self.context, self.module, &btf_kept_types) };
+        } else if self.options.btf && !self.options.btf_keep_types.is_empty() {
+            warn!(
+                "no type matches the BTF keep patterns {:?}",
+                self.options.btf_keep_types
+            );
+        }
 
         Ok(())
     }
 
     fn codegen(&mut self) -> Result<(), LinkerError> {
-        let output = CString::new(self.options.output.as_os_str().to_str().unwrap()).unwrap();
+        let output = self.options.output.clone();
+        if !self.options.atomic_output {
+            return self.codegen_to(&output);
+        }
+
+        // Write to a temporary file next to the output and rename it once complete, so that a
+        // failed or interrupted link never leaves a truncated output behind.
+        let tmp = temp_output_path(&output);
+        let ret = self.codegen_to(&tmp).and_then(|()| {
+            File::open(&tmp)
+                .and_then(|file| file.sync_all())
+                .and_then(|()| fs::rename(&tmp, &output))
+                .map_err(|err| LinkerError::IoError(output.clone(), err))
+        });
+        if ret.is_err() {
+            let _: Result<(), io::Error> = fs::remove_file(&tmp);
+            return ret;
+        }
+        // make the rename itself durable
+        let dir = match output.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if let Err(err) = File::open(dir).and_then(|dir| dir.sync_all()) {
+            debug!("failed to sync {dir:?}: {err}");
+        }
+        Ok(())
+    }
+
+    fn codegen_to(&mut self, output: &Path) -> Result<(), LinkerError> {
+        if let OutputType::Skeleton | OutputType::RustSkeleton | OutputType::BtfIds =
+            self.options.output_type
+        {
+            return self.write_skeleton(output);
+        }
+        if let OutputType::Disassembly = self.options.output_type {
+            return self.write_disassembly(output);
+        }
+        if !self.prelinked_objects.is_empty() {
+            return self.write_merged_object(output);
+        }
+        if let OutputType::Object = self.options.output_type {
+            // The object is generated in memory, where the checks of the generated code may
+            // already have generated it and where LLVM can't set the header fields.
+            info!("writing object to {:?}", output);
+            let object = self.object_to_memory()?;
+            return fs::write(output, object)
+                .map_err(|e| LinkerError::IoError(output.to_owned(), e));
+        }
+        let output = path_to_cstring(output)?;
         match self.options.output_type {
             OutputType::Bitcode => self.write_bitcode(&output),
             OutputType::LlvmAssembly => self.write_ir(&output),
-            OutputType::Assembly => self.emit(&output, LLVMCodeGenFileType::LLVMAssemblyFile),
-            OutputType::Object => self.emit(&output, LLVMCodeGenFileType::LLVMObjectFile),
+            OutputType::Assembly => self.write_asm(&output),
+            OutputType::Object => unreachable!("objects are written by object_to_memory"),
+            OutputType::Skeleton | OutputType::RustSkeleton | OutputType::BtfIds => {
+                unreachable!("skeletons are written by write_skeleton")
+            }
+            OutputType::Disassembly => {
+                unreachable!("the disassembly is written by write_disassembly")
+            }
         }
     }
 
+    // Generates the object and merges the prelinked objects into it.
+    fn write_merged_object(&mut self, output: &Path) -> Result<(), LinkerError> {
+        if !matches!(self.options.output_type, OutputType::Object) {
+            return Err(LinkerError::MergeObjectsError(
+                "prelinked objects can only be merged into an object file".to_owned(),
+            ));
+        }
+        info!(
+            "merging {} prelinked objects into {:?}",
+            self.prelinked_objects.len(),
+            output
+        );
+        let merged = self.object_to_memory()?;
+        fs::write(output, merged).map_err(|e| LinkerError::IoError(output.to_owned(), e))
+    }
+
+    // Generates the object of the module, once per module: the checks of the generated code and
+    // the outputs all use this one, so that a backend diagnostic is only reported once.
+    fn object(&mut self) -> Result<Rc<[u8]>, LinkerError> {
+        if let Some(object) = &self.object {
+            return Ok(Rc::clone(object));
+        }
+        let object: Rc<[u8]> = unsafe {
+            llvm::codegen_to_memory(
+                self.target_machine,
+                self.module,
+                LLVMCodeGenFileType::LLVMObjectFile,
+            )
+        }
+        .map_err(LinkerError::EmitCodeError)?
+        .into();
+        self.object = Some(Rc::clone(&object));
+        Ok(object)
+    }
+
+    // Generates the object in memory, merging the prelinked objects into it if any, sets the
+    // ELF header fields of the options and checks the object with aya-obj if enabled.
+    fn object_to_memory(&mut self) -> Result<Vec<u8>, LinkerError> {
+        let mut object = self.object()?.to_vec();
+        if !self.prelinked_objects.is_empty() {
+            let objects: Vec<&[u8]> = [object.as_slice()]
+                .into_iter()
+                .chain(
+                    self.prelinked_objects
+                        .iter()
+                        .map(|(_, data)| data.as_slice()),
+                )
+                .collect();
+            object =
+                elf::merge(&objects).map_err(|e| LinkerError::MergeObjectsError(e.to_string()))?;
+        }
+        let LinkerOptions {
+            elf_osabi,
+            elf_flags,
+            check_aya_obj,
+            ..
+        } = self.options;
+        if elf_osabi.is_some() || elf_flags.is_some() {
+            elf::set_header(&mut object, elf_osabi, elf_flags)
+                .map_err(|e| LinkerError::ElfHeaderError(e.to_string()))?;
+        }
+        if check_aya_obj {
+            if let Some(message) = validate::check_aya_obj(&object)? {
+                self.diagnostic_handler.report(
+                    DiagnosticCategory::AyaObj,
+                    format!("aya can't load the object: {message}"),
+                );
+            }
+        }
+        Ok(object)
+    }
+
+    fn write_skeleton(&mut self, output: &Path) -> Result<(), LinkerError> {
+        // the temporary file of atomic outputs isn't named after the output
+        let name = skel::skeleton_name(&self.options.output);
+        info!("writing skeleton {name} to {:?}", output);
+
+        let object = self.object_to_memory()?;
+        let skel = self.skeleton(self.options.output_type, &object)?;
+        fs::write(output, skel).map_err(|e| LinkerError::IoError(output.to_owned(), e))
+    }
+
+    // Generates the skeleton of type `output_type` for `object`, named after the output.
+    fn skeleton(&self, output_type: OutputType, object: &[u8]) -> Result<String, LinkerError> {
+        let name = skel::skeleton_name(&self.options.output);
+        match output_type {
+            OutputType::RustSkeleton => skel::rust_skeleton(object),
+            OutputType::BtfIds => skel::btf_ids(
+                &name,
+                object,
+                self.options
+                    .output
+                    .extension()
+                    .is_some_and(|ext| ext == "h"),
+            ),
+            _ => skel::c_skeleton(&name, object),
+        }
+    }
+
+    fn write_disassembly(&mut self, output: &Path) -> Result<(), LinkerError> {
+        info!("writing disassembly to {:?}", output);
+
+        let object = self.object_to_memory()?;
+        let disassembly = disasm::disassemble(&object)?;
+        fs::write(output, disassembly).map_err(|e| LinkerError::IoError(output.to_owned(), e))
+    }
+
     fn write_bitcode(&mut self, output: &CStr) -> Result<(), LinkerError> {
         info!("writing bitcode to {:?}", output);
 
@@ -499,6 +3285,32 @@ impl Linker {
         unsafe { llvm::write_ir(self.module, output) }.map_err(LinkerError::WriteIRError)
     }
 
+    // Writes the remarks to `path`, one YAML document each. The C API only exposes the rendered
+    // remarks, `<file>:<line>:<column>: <message>`, so the function of a remark is found from its
+    // location.
+    fn write_remarks(&self, path: &Path) -> Result<(), LinkerError> {
+        let remarks = &self.diagnostic_handler.remarks;
+        info!("writing {} remarks to {:?}", remarks.len(), path);
+
+        let functions = unsafe { llvm::functions_by_debug_loc(self.module) };
+        let mut yaml = String::new();
+        for remark in remarks {
+            let (location, message) = parse_remark(remark.trim_end());
+            yaml.push_str("--- !Remark\n");
+            if let Some((file, line, column)) = location {
+                if let Some(function) = functions.get(&(file.to_owned(), line, column)) {
+                    yaml.push_str(&format!("Function: {}\n", yaml_string(function)));
+                }
+                yaml.push_str(&format!(
+                    "DebugLoc: {{ File: {}, Line: {line}, Column: {column} }}\n",
+                    yaml_string(file)
+                ));
+            }
+            yaml.push_str(&format!("Message: {}\n...\n", yaml_string(message)));
+        }
+        std::fs::write(path, yaml).map_err(|e| LinkerError::IoError(path.to_owned(), e))
+    }
+
     fn emit(&mut self, output: &CStr, output_type: LLVMCodeGenFileType) -> Result<(), LinkerError> {
         info!("emitting {:?} to {:?}", output_type, output);
 
@@ -506,7 +3318,54 @@ impl Linker {
             .map_err(LinkerError::EmitCodeError)
     }
 
-    fn llvm_init(&mut self) {
+    fn write_asm(&mut self, output: &CStr) -> Result<(), LinkerError> {
+        let LinkerOptions {
+            asm_dialect,
+            asm_without_btf,
+            asm_with_source,
+            ..
+        } = self.options;
+        if asm_dialect == AsmDialect::Llvm && !asm_without_btf && !asm_with_source {
+            return self.emit(output, LLVMCodeGenFileType::LLVMAssemblyFile);
+        }
+
+        info!("emitting assembly to {:?}", output);
+        let asm = self.asm_to_memory()?;
+        let path = Path::new(OsStr::from_bytes(output.to_bytes()));
+        fs::write(path, asm).map_err(|e| LinkerError::IoError(path.to_owned(), e))
+    }
+
+    // Generates the assembly in memory, rewritten for the asm options.
+    fn asm_to_memory(&mut self) -> Result<Vec<u8>, LinkerError> {
+        let LinkerOptions {
+            asm_dialect,
+            asm_without_btf,
+            asm_with_source,
+            ..
+        } = self.options;
+        let asm = unsafe {
+            llvm::codegen_to_memory(
+                self.target_machine,
+                self.module,
+                LLVMCodeGenFileType::LLVMAssemblyFile,
+            )
+        }
+        .map_err(LinkerError::EmitCodeError)?;
+        if asm_dialect == AsmDialect::Llvm && !asm_without_btf && !asm_with_source {
+            return Ok(asm);
+        }
+        Ok(asm::rewrite(
+            &String::from_utf8_lossy(&asm),
+            &AsmOptions {
+                dialect: asm_dialect,
+                with_btf: !asm_without_btf,
+                with_source: asm_with_source,
+            },
+        )
+        .into_bytes())
+    }
+
+    fn llvm_init(&mut self) -> Result<(), LinkerError> {
         let mut args = Vec::<Cow<str>>::new();
         args.push("bpf-linker".into());
         // Disable cold call site detection. Many accessors in aya-ebpf return Result<T, E>
@@ -516,7 +3375,7 @@ impl Linker {
         // as cold though - and they often are starting from LLVM17 - #[inline(always)]
         // is ignored and the BPF target fails codegen.
         args.push("--cold-callsite-rel-freq=0".into());
-        if self.options.unroll_loops {
+        if self.options.unroll_loops && self.options.unroll_functions.is_empty() {
             // setting cmdline arguments is the only way to customize the unroll pass with the
             // C API.
             args.extend([
@@ -525,38 +3384,240 @@ impl Linker {
                 format!("--unroll-max-upperbound={}", u32::MAX).into(),
                 format!("--unroll-threshold={}", u32::MAX).into(),
             ]);
+        } else if self.options.unroll_loops {
+            // the loops to unroll are marked like `#pragma unroll` does, this lifts the size limit
+            // of those only.
+            args.push(format!("--pragma-unroll-threshold={}", u32::MAX).into());
         }
         if !self.options.disable_expand_memcpy_in_order {
             args.push("--bpf-expand-memcpy-in-order".into());
         }
+        if self.options.remarks_file.is_some() {
+            // remarks are only reported to the diagnostic handler for the passes matching these
+            let filter = self.options.remarks_filter.as_deref().unwrap_or(".*");
+            args.extend([
+                format!("--pass-remarks={filter}").into(),
+                format!("--pass-remarks-missed={filter}").into(),
+                format!("--pass-remarks-analysis={filter}").into(),
+            ]);
+        }
         args.extend(self.options.llvm_args.iter().map(Into::into));
         info!("LLVM command line: {:?}", args);
+        if self.pool.is_some() {
+            // LLVM options can't be unset, so pooled links, which are meant to be independent,
+            // only run with the options they set themselves in effect
+            let stale = llvm::command_line()
+                .into_iter()
+                .filter(|arg| !args.iter().any(|a| a.as_ref() == arg))
+                .collect::<Vec<_>>();
+            if !stale.is_empty() {
+                return Err(LinkerError::PooledLlvmArgs(stale));
+            }
+        }
         unsafe {
-            llvm::init(&args, "BPF linker");
+            if let Err(message) = llvm::init(&args, "BPF linker") {
+                error!("LLVM rejected its command line: {message}");
+                return Err(LinkerError::InvalidLlvmArg(invalid_llvm_arg(
+                    &self.options.llvm_args,
+                    &message,
+                )));
+            }
 
-            self.context = LLVMContextCreate();
-            LLVMContextSetDiagnosticHandler(
-                self.context,
-                Some(llvm::diagnostic_handler::<DiagnosticHandler>),
-                &mut self.diagnostic_handler as *mut _ as _,
-            );
+            self.context = match self.pool.as_mut().and_then(PoolState::take_context) {
+                Some(context) => context,
+                None => LLVMContextCreate(),
+            };
             LLVMInstallFatalErrorHandler(Some(llvm::fatal_error));
             LLVMEnablePrettyStackTrace();
-            self.module = llvm::create_module(
-                self.options.output.file_stem().unwrap().to_str().unwrap(),
-                self.context,
-            )
-            .unwrap();
+            let name = self
+                .options
+                .output
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .replace('\0', "_");
+            self.module = llvm::create_module(&name, self.context).ok_or_else(|| {
+                LinkerError::LlvmInitError(format!("can't create the module `{name}`"))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the argument of `llvm_args` that LLVM complained about in `message`, or the first line
+// of `message` if none of them is mentioned.
+pub(crate) fn invalid_llvm_arg(llvm_args: &[String], message: &str) -> String {
+    llvm_args
+        .iter()
+        .find(|arg| {
+            let name = arg
+                .split('=')
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches('-');
+            message.contains(&format!("'{arg}'"))
+                || (!name.is_empty() && message.contains(&format!("-{name}")))
+        })
+        .cloned()
+        .unwrap_or_else(|| message.lines().next().unwrap_or_default().to_owned())
+}
+
+// Calls `f` with each member of the archive `data`, identified as a member of `id`. A corrupt
+// archive is an error pointing at the first member which can't be read.
+pub(crate) fn for_each_archive_member(
+    id: &InputId,
+    data: &[u8],
+    mut f: impl FnMut(InputId, &[u8]) -> Result<(), LinkerError>,
+) -> Result<(), LinkerError> {
+    let corrupt = |index, offset, err: object::read::Error| LinkerError::CorruptArchive {
+        archive: id.clone(),
+        index,
+        offset,
+        source: io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    };
+    let archive = ArchiveFile::parse(data).map_err(|e| corrupt(0, AR_GLOBAL_HEADER_LEN, e))?;
+    // where the previous member ends, members being aligned on 2 bytes
+    let mut offset = AR_GLOBAL_HEADER_LEN;
+    for (index, member) in archive.members().enumerate() {
+        let member = member.map_err(|e| corrupt(index, offset, e))?;
+        let contents = member.data(data).map_err(|e| corrupt(index, offset, e))?;
+        let (data_offset, size) = member.file_range();
+        offset = (data_offset + size).next_multiple_of(2);
+        let member = InputId::ArchiveMember {
+            archive: Box::new(id.clone()),
+            member: String::from_utf8_lossy(member.name()).into_owned(),
+        };
+        f(member, contents)?;
+    }
+    Ok(())
+}
+
+const AR_GLOBAL_HEADER_LEN: u64 = 8;
+
+// Explains why `bitcode` failed to link when it comes from a newer LLVM, since LLVM itself only
+// reports it as invalid. Otherwise the error carries `message`, what LLVM reported.
+fn link_module_error(input: InputId, bitcode: &[u8], message: String) -> LinkerError {
+    let (major, minor, patch) = llvm::version();
+    match llvm::bitcode::producer(bitcode) {
+        Some(producer)
+            if llvm::bitcode::producer_llvm_major(&producer).is_some_and(|v| v > major) =>
+        {
+            LinkerError::BitcodeVersionMismatch {
+                input,
+                producer,
+                ours: format!("{major}.{minor}.{patch}"),
+            }
+        }
+        _ => LinkerError::LinkModuleError(input, message),
+    }
+}
+
+pub(crate) fn path_to_cstring(path: &Path) -> Result<CString, LinkerError> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| LinkerError::InvalidPath(path.to_owned()))
+}
+
+// Returns a path in the same directory as `output`, so it can be renamed to it atomically.
+fn temp_output_path(output: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(output.file_name().unwrap_or_default());
+    file_name.push(format!(".{}.tmp", std::process::id()));
+    output.with_file_name(file_name)
+}
+
+// Returns the name of `names` closest to `name`, if any is close enough to be a typo of it.
+fn closest_name<'a>(name: &str, names: impl IntoIterator<Item = &'a String>) -> Option<&'a str> {
+    // Levenshtein distance
+    let distance = |a: &str, b: &str| {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, ca) in a.chars().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let substitution = diagonal + usize::from(ca != *cb);
+                diagonal = row[j + 1];
+                row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+            }
+        }
+        row[b.len()]
+    };
+    let max = name.chars().count().div_ceil(3);
+    names
+        .into_iter()
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max)
+        .min()
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+// Returns the architecture of `target` if it's a BPF target of explicit endianness, `bpfel` or
+// `bpfeb`.
+fn bpf_endianness(target: &str) -> Option<&str> {
+    target
+        .split('-')
+        .next()
+        .filter(|arch| matches!(*arch, "bpfel" | "bpfeb"))
+}
+
+// Returns the name given to the `target` output when generating code for multiple targets: the
+// endianness for BPF targets (`el` or `eb`), the architecture otherwise.
+fn target_suffix(target: &str) -> &str {
+    let arch = target.split('-').next().unwrap_or(target);
+    arch.strip_prefix("bpf")
+        .filter(|suffix| !suffix.is_empty())
+        .unwrap_or(arch)
+}
+
+// Splits a remark rendered by LLVM into its `(file, line, column)` location, `None` when it's
+// `<unknown>:0:0`, and its message.
+fn parse_remark(remark: &str) -> (Option<(&str, u32, u32)>, &str) {
+    let Some((location, message)) = remark.split_once(": ") else {
+        return (None, remark);
+    };
+    let mut parts = location.rsplitn(3, ':');
+    let (Some(column), Some(line), Some(file)) = (parts.next(), parts.next(), parts.next()) else {
+        return (None, remark);
+    };
+    match (line.parse(), column.parse()) {
+        (Ok(line), Ok(column)) if file != "<unknown>" => (Some((file, line, column)), message),
+        (Ok(_), Ok(_)) => (None, message),
+        _ => (None, remark),
+    }
+}
+
+// Quotes `s` as a double-quoted YAML string.
+fn yaml_string(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
         }
     }
+    quoted.push('"');
+    quoted
+}
+
+// Inserts `suffix` before the extension of `output`, eg `prog.o` becomes `prog.el.o`.
+fn target_output_path(output: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = output.file_stem().unwrap_or_default().to_owned();
+    file_name.push(".");
+    file_name.push(suffix);
+    if let Some(extension) = output.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    output.with_file_name(file_name)
 }
 
 impl Drop for Linker {
     fn drop(&mut self) {
+        self.release_target_machine();
         unsafe {
-            if !self.target_machine.is_null() {
-                LLVMDisposeTargetMachine(self.target_machine);
-            }
             if !self.module.is_null() {
                 LLVMDisposeModule(self.module);
             }
@@ -569,6 +3630,11 @@ impl Drop for Linker {
 
 pub struct DiagnosticHandler {
     pub(crate) has_errors: bool,
+    pub(crate) remarks: Vec<String>,
+    pub(crate) levels: HashMap<DiagnosticCategory, DiagnosticLevel>,
+    pub(crate) fatal_warnings: bool,
+    pub(crate) denied: Vec<String>,
+    pub(crate) diagnostics: Vec<Diagnostic>,
 }
 
 impl Default for DiagnosticHandler {
@@ -579,7 +3645,42 @@ impl Default for DiagnosticHandler {
 
 impl DiagnosticHandler {
     pub fn new() -> Self {
-        Self { has_errors: false }
+        Self {
+            has_errors: false,
+            remarks: Vec::new(),
+            levels: HashMap::new(),
+            fatal_warnings: false,
+            denied: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub(crate) fn report(&mut self, category: DiagnosticCategory, message: String) {
+        let level = self
+            .levels
+            .get(&category)
+            .copied()
+            .unwrap_or_else(|| category.default_level());
+        let severity = match level {
+            DiagnosticLevel::Allow => {
+                debug!("{category}: {message}");
+                return;
+            }
+            DiagnosticLevel::Warn if !self.fatal_warnings => {
+                warn!("{category}: {message}");
+                Severity::Warning
+            }
+            DiagnosticLevel::Warn | DiagnosticLevel::Deny => {
+                error!("{category}: {message}");
+                self.denied.push(format!("{category}: {message}"));
+                Severity::Error
+            }
+        };
+        self.diagnostics.push(Diagnostic {
+            severity,
+            category: Some(category),
+            message,
+        });
     }
 }
 
@@ -601,20 +3702,41 @@ impl llvm::LLVMDiagnosticHandler for DiagnosticHandler {
         match severity {
             llvm_sys::LLVMDiagnosticSeverity::LLVMDSError => {
                 if MATCHERS.iter().any(|matcher| message.ends_with(matcher)) {
+                    self.report(
+                        DiagnosticCategory::MemoryBuiltins,
+                        message.trim_end().to_owned(),
+                    );
                     return;
                 }
                 self.has_errors = true;
 
-                error!("llvm: {}", message)
+                error!("llvm: {}", message);
+                self.diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    category: None,
+                    message: message.trim_end().to_owned(),
+                });
+            }
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSWarning => {
+                self.report(DiagnosticCategory::Llvm, message.to_owned())
+            }
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSRemark => {
+                debug!("remark: {}", message);
+                self.remarks.push(message.to_owned());
             }
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSWarning => warn!("llvm: {}", message),
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSRemark => debug!("remark: {}", message),
             llvm_sys::LLVMDiagnosticSeverity::LLVMDSNote => debug!("note: {}", message),
         }
     }
 }
 
-fn detect_input_type(data: &[u8]) -> Option<InputType> {
+// Exported unless memory builtins are disabled, as the backend lowers memory intrinsics to calls
+// to them.
+const MEMORY_BUILTINS: [&str; 5] = ["memcpy", "memmove", "memset", "memcmp", "bcmp"];
+
+// The start of the files written by rustc `--emit=metadata`, followed by the format version.
+const RUST_METADATA_MAGIC: &[u8; 7] = b"rust\0\0\0";
+
+pub(crate) fn detect_input_type(data: &[u8]) -> Option<InputType> {
     if data.len() < 8 {
         return None;
     }
@@ -625,11 +3747,1052 @@ fn detect_input_type(data: &[u8]) -> Option<InputType> {
         b"\x7FELF" => Some(Elf),
         b"\xcf\xfa\xed\xfe" => Some(MachO),
         _ => {
-            if &data[..8] == b"!<arch>\x0A" {
+            if data[..7] == *RUST_METADATA_MAGIC {
+                Some(RustMetadata)
+            } else if &data[..8] == b"!<arch>\x0A" {
                 Some(Archive)
+            } else if &data[..8] == thin_archive::MAGIC {
+                Some(ThinArchive)
             } else {
-                None
+                Compression::detect(data).map(Compressed)
+            }
+        }
+    }
+}
+
+/// A value of [`LinkerOptions`] in the form hashed into the linker metadata. Unlike the `Debug`
+/// output, it doesn't depend on the Rust version nor on the iteration order of sets, and it's
+/// unambiguous: strings are prefixed with their length, lists with their number of items.
+trait Canonical {
+    fn write_canonical(&self, out: &mut Vec<u8>);
+}
+
+fn write_canonical_str(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(s.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(s);
+}
+
+impl Canonical for bool {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        out.push(if *self { b'1' } else { b'0' });
+    }
+}
+
+impl Canonical for u8 {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        u32::from(*self).write_canonical(out)
+    }
+}
+
+impl Canonical for u32 {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        write_canonical_str(out, self.to_string().as_bytes())
+    }
+}
+
+impl Canonical for String {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        write_canonical_str(out, self.as_bytes())
+    }
+}
+
+impl Canonical for Cow<'_, str> {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        write_canonical_str(out, self.as_bytes())
+    }
+}
+
+impl Canonical for PathBuf {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        write_canonical_str(out, self.as_os_str().as_bytes())
+    }
+}
+
+impl<T: Canonical> Canonical for Option<T> {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(b'-'),
+            Some(value) => {
+                out.push(b'+');
+                value.write_canonical(out);
+            }
+        }
+    }
+}
+
+impl<T: Canonical> Canonical for [T] {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.len().to_string().as_bytes());
+        out.push(b'[');
+        for item in self {
+            item.write_canonical(out);
+        }
+        out.push(b']');
+    }
+}
+
+impl<T: Canonical> Canonical for Vec<T> {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        self.as_slice().write_canonical(out)
+    }
+}
+
+impl<A: Canonical, B: Canonical> Canonical for (A, B) {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        self.0.write_canonical(out);
+        self.1.write_canonical(out);
+    }
+}
+
+impl Canonical for HashSet<Cow<'static, str>> {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        let mut items = Vec::from_iter(self.iter().cloned());
+        items.sort();
+        items.write_canonical(out)
+    }
+}
+
+impl Canonical for LinkerInput {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        // the contents of the inputs are hashed separately
+        match self {
+            LinkerInput::File(path) => {
+                out.extend_from_slice(b"file");
+                path.write_canonical(out);
+            }
+            LinkerInput::Buffer { name, .. } => {
+                out.extend_from_slice(b"buffer");
+                name.write_canonical(out);
+            }
+        }
+    }
+}
+
+impl Canonical for PassOptions {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        let PassOptions {
+            loop_interleaving,
+            loop_vectorization,
+            slp_vectorization,
+            licm_promotion,
+        } = self;
+        for option in [
+            loop_interleaving,
+            loop_vectorization,
+            slp_vectorization,
+            licm_promotion,
+        ] {
+            option.write_canonical(out);
+        }
+    }
+}
+
+// The enums are written as they're named on the command line, which is also how the ones
+// implementing `Display` display.
+macro_rules! canonical_display {
+    ($($ty:ident),*) => {$(
+        impl Canonical for $ty {
+            fn write_canonical(&self, out: &mut Vec<u8>) {
+                write_canonical_str(out, self.to_string().as_bytes())
+            }
+        }
+    )*};
+}
+
+canonical_display!(Cpu, OptLevel, OutputType);
+
+macro_rules! canonical_names {
+    ($($ty:ident { $($variant:ident => $name:literal),* $(,)? })*) => {$(
+        impl Canonical for $ty {
+            fn write_canonical(&self, out: &mut Vec<u8>) {
+                write_canonical_str(out, match self {
+                    $($ty::$variant => $name.as_bytes(),)*
+                })
+            }
+        }
+    )*};
+}
+
+canonical_names! {
+    UndefinedSymbols {
+        KsymsSection => "ksyms",
+        Keep => "keep",
+        Error => "error",
+    }
+    BpfTrap {
+        Keep => "keep",
+        Return => "return",
+    }
+    CodeModel {
+        Default => "default",
+        Tiny => "tiny",
+        Small => "small",
+        Kernel => "kernel",
+        Medium => "medium",
+        Large => "large",
+    }
+    RelocModel {
+        Default => "default",
+        Static => "static",
+        Pic => "pic",
+        DynamicNoPic => "dynamic-no-pic",
+    }
+    AsmDialect {
+        Llvm => "llvm",
+        Gas => "gas",
+    }
+    InstrumentFunctions {
+        Hooks => "hooks",
+        Counters => "counters",
+    }
+}
+
+// Writes every option as a `<name>=<value>` line. Destructuring `LinkerOptions` makes adding an
+// option without hashing it a compile error.
+fn canonical_options(options: &LinkerOptions) -> Vec<u8> {
+    let LinkerOptions {
+        target,
+        targets,
+        cpu,
+        cpu_features,
+        inputs,
+        output,
+        output_type,
+        archive_member_filters,
+        libs,
+        libraries,
+        optimize,
+        codegen_optimize,
+        export_symbols,
+        keep_symbols,
+        symbol_sections,
+        base_module,
+        unroll_loops,
+        unroll_functions,
+        convert_loops_to_bpf_loop,
+        ignore_inline_never,
+        keep_inline_never,
+        llvm_args,
+        disable_expand_memcpy_in_order,
+        disable_memory_builtins,
+        btf,
+        kernel_btf,
+        prefix_symbols,
+        rename_symbols,
+        btf_datasec_fixup,
+        pin_maps,
+        btf_keep_types,
+        undefined_symbols,
+        bpf_trap,
+        code_model,
+        reloc_model,
+        atomic_output,
+        asm_dialect,
+        asm_without_btf,
+        asm_with_source,
+        allow_prelinked_objects,
+        gc_maps,
+        instrument_functions,
+        dependencies,
+        merge_constants,
+        dedup_inputs,
+        pass_options,
+        module_asm,
+        elf_osabi,
+        elf_flags,
+        // only affect diagnostics, checks or files written besides the output
+        extra_outputs: _,
+        why_internalized: _,
+        dump_module: _,
+        dump_inputs: _,
+        validation_script: _,
+        remarks_file: _,
+        remarks_filter: _,
+        diagnostic_levels: _,
+        fatal_warnings: _,
+        stack_usage: _,
+        removed_functions: _,
+        linker_metadata: _,
+        dep_file: _,
+        emit_hash: _,
+        verify: _,
+        check_aya_obj: _,
+        measure_stage_memory: _,
+    } = options;
+    let fields: [(&str, &dyn Canonical); 49] = [
+        ("target", target),
+        ("targets", targets),
+        ("cpu", cpu),
+        ("cpu_features", cpu_features),
+        ("inputs", inputs),
+        ("output", output),
+        ("output_type", output_type),
+        ("archive_member_filters", archive_member_filters),
+        ("libs", libs),
+        ("libraries", libraries),
+        ("optimize", optimize),
+        ("codegen_optimize", codegen_optimize),
+        ("export_symbols", export_symbols),
+        ("keep_symbols", keep_symbols),
+        ("symbol_sections", symbol_sections),
+        ("base_module", base_module),
+        ("unroll_loops", unroll_loops),
+        ("unroll_functions", unroll_functions),
+        ("convert_loops_to_bpf_loop", convert_loops_to_bpf_loop),
+        ("ignore_inline_never", ignore_inline_never),
+        ("keep_inline_never", keep_inline_never),
+        ("llvm_args", llvm_args),
+        (
+            "disable_expand_memcpy_in_order",
+            disable_expand_memcpy_in_order,
+        ),
+        ("disable_memory_builtins", disable_memory_builtins),
+        ("btf", btf),
+        ("kernel_btf", kernel_btf),
+        ("prefix_symbols", prefix_symbols),
+        ("rename_symbols", rename_symbols),
+        ("btf_datasec_fixup", btf_datasec_fixup),
+        ("pin_maps", pin_maps),
+        ("btf_keep_types", btf_keep_types),
+        ("undefined_symbols", undefined_symbols),
+        ("bpf_trap", bpf_trap),
+        ("code_model", code_model),
+        ("reloc_model", reloc_model),
+        ("atomic_output", atomic_output),
+        ("asm_dialect", asm_dialect),
+        ("asm_without_btf", asm_without_btf),
+        ("asm_with_source", asm_with_source),
+        ("allow_prelinked_objects", allow_prelinked_objects),
+        ("gc_maps", gc_maps),
+        ("instrument_functions", instrument_functions),
+        ("dependencies", dependencies),
+        ("merge_constants", merge_constants),
+        ("dedup_inputs", dedup_inputs),
+        ("pass_options", pass_options),
+        ("module_asm", module_asm),
+        ("elf_osabi", elf_osabi),
+        ("elf_flags", elf_flags),
+    ];
+    let mut out = Vec::new();
+    for (name, value) in fields {
+        out.extend_from_slice(name.as_bytes());
+        out.push(b'=');
+        value.write_canonical(&mut out);
+        out.push(b'\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use llvm_sys::core::{LLVMContextCreate, LLVMContextDispose};
+
+    use super::*;
+
+    fn ir_to_bitcode(ir: &str) -> Vec<u8> {
+        unsafe {
+            let context = LLVMContextCreate();
+            let bitcode = llvm::ir_to_bitcode(context, ir).unwrap();
+            LLVMContextDispose(context);
+            bitcode
+        }
+    }
+
+    #[test]
+    fn test_prelinked_object_references() {
+        let dir = tempfile::tempdir().unwrap();
+        // the prelinked object defines `shared`, which calls `helper` from the bitcode
+        let prelinked = dir.path().join("prelinked.o");
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+declare i32 @helper(i32)
+
+define i32 @shared(i32 %x) {
+  %y = call i32 @helper(i32 %x)
+  ret i32 %y
+}
+"#,
+        );
+        let options = LinkerOptions::builder()
+            .input_buffer("prelinked.ll", bitcode)
+            .export("shared")
+            .output(&prelinked)
+            .build()
+            .unwrap();
+        Linker::new(options).unwrap().link().unwrap();
+
+        // `helper` isn't exported but must be kept, and `shared` isn't undefined
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+declare i32 @shared(i32)
+
+define i32 @helper(i32 %x) {
+  %y = add i32 %x, 1
+  ret i32 %y
+}
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  %x = call i32 @shared(i32 1)
+  ret i32 %x
+}
+"#,
+        );
+        let output = dir.path().join("prog.o");
+        let mut options = LinkerOptions::builder()
+            .input(&prelinked)
+            .input_buffer("prog.ll", bitcode)
+            .export("prog")
+            .undefined_symbols(UndefinedSymbols::Error)
+            .output(&output)
+            .build()
+            .unwrap();
+        options.allow_prelinked_objects = true;
+        Linker::new(options).unwrap().link().unwrap();
+
+        let (mut defined, undefined) = elf::global_symbols(&fs::read(&output).unwrap()).unwrap();
+        defined.sort();
+        assert_eq!(defined, ["helper", "prog", "shared"]);
+        assert_eq!(undefined, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_targets_endianness() {
+        let dir = tempfile::tempdir().unwrap();
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  ret i32 2
+}
+"#,
+        );
+        let mut options = LinkerOptions::builder()
+            .input_buffer("prog.ll", bitcode)
+            .export("prog")
+            .output(dir.path().join("prog.o"))
+            .build()
+            .unwrap();
+        options.targets = vec!["bpfel".to_owned(), "bpfeb".to_owned()];
+        let err = Linker::new(options).unwrap().link().unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                LinkerError::TargetEndianness { input_target, target, .. }
+                    if input_target == "bpfel" && target == "bpfeb"
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_missing_func_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = |exports: &[&str]| {
+            let bitcode = ir_to_bitcode(
+                r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) section "xdp" !dbg !3 {
+  ret i32 2
+}
+
+define i32 @other(ptr %ctx) section "xdp" {
+  ret i32 2
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !1, producer: "rustc", emissionKind: FullDebug)
+!1 = !DIFile(filename: "prog.rs", directory: "/src")
+!2 = !{i32 2, !"Debug Info Version", i32 3}
+!3 = distinct !DISubprogram(name: "prog", scope: !1, file: !1, line: 1, type: !4, spFlags: DISPFlagDefinition, unit: !0)
+!4 = !DISubroutineType(types: !{})
+"#,
+            );
+            let options = exports
+                .iter()
+                .fold(
+                    LinkerOptions::builder()
+                        .input_buffer("prog.ll", bitcode)
+                        .output(dir.path().join("prog.o"))
+                        .btf(true),
+                    |options, export| options.export(*export),
+                )
+                .build()
+                .unwrap();
+            Linker::new(options).unwrap().link()
+        };
+
+        link(&["prog"]).unwrap();
+        assert!(matches!(
+            link(&["prog", "other"]),
+            Err(LinkerError::MissingFuncInfo(names)) if names == ["other"]
+        ));
+    }
+
+    #[test]
+    fn test_btf_dropped_members() {
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+@ODD = global [16 x i8] zeroinitializer, align 4, !dbg !3
+@PACKED = global [5 x i8] zeroinitializer, align 1, !dbg !5
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !1, producer: "rustc", emissionKind: FullDebug)
+!1 = !DIFile(filename: "prog.rs", directory: "/src")
+!2 = !{i32 2, !"Debug Info Version", i32 3}
+!3 = !DIGlobalVariableExpression(var: !4, expr: !DIExpression())
+!4 = distinct !DIGlobalVariable(name: "ODD", scope: !0, file: !1, line: 1, type: !7, isLocal: false, isDefinition: true)
+!5 = !DIGlobalVariableExpression(var: !6, expr: !DIExpression())
+!6 = distinct !DIGlobalVariable(name: "PACKED", scope: !0, file: !1, line: 2, type: !17, isLocal: false, isDefinition: true)
+!7 = !DICompositeType(tag: DW_TAG_structure_type, name: "Odd", scope: !1, file: !1, size: 128, align: 32, elements: !8, identifier: "Odd")
+!8 = !{!9, !10, !11, !12, !13, !14}
+!9 = !DIDerivedType(tag: DW_TAG_member, name: "a", scope: !7, file: !1, baseType: !15, size: 32, align: 32, offset: 0)
+!10 = !DIDerivedType(tag: DW_TAG_member, name: "marker", scope: !7, file: !1, baseType: !18, align: 8, offset: 32)
+!11 = !DIDerivedType(tag: DW_TAG_member, name: "unaligned", scope: !7, file: !1, baseType: !16, size: 8, align: 8, offset: 33)
+!12 = !DIDerivedType(tag: DW_TAG_member, name: "misaligned", scope: !7, file: !1, baseType: !15, size: 32, align: 32, offset: 40)
+!13 = !DIDerivedType(tag: DW_TAG_member, name: "ok", scope: !7, file: !1, baseType: !15, size: 32, align: 32, offset: 64)
+!14 = !DIDerivedType(tag: DW_TAG_member, name: "past_end", scope: !7, file: !1, baseType: !15, size: 32, align: 32, offset: 112)
+!15 = !DIBasicType(name: "u32", size: 32, encoding: DW_ATE_unsigned)
+!16 = !DIBasicType(name: "u8", size: 8, encoding: DW_ATE_unsigned)
+!17 = !DICompositeType(tag: DW_TAG_structure_type, name: "Packed", scope: !1, file: !1, size: 40, align: 8, elements: !19, identifier: "Packed")
+!18 = !DICompositeType(tag: DW_TAG_structure_type, name: "Marker", scope: !1, file: !1, align: 8, elements: !{}, identifier: "Marker")
+!19 = !{!20, !21}
+!20 = !DIDerivedType(tag: DW_TAG_member, name: "b", scope: !17, file: !1, baseType: !16, size: 8, align: 8, offset: 0)
+!21 = !DIDerivedType(tag: DW_TAG_member, name: "c", scope: !17, file: !1, baseType: !15, size: 32, align: 32, offset: 8)
+"#,
+        );
+        let options = LinkerOptions::builder()
+            .input_buffer("prog.ll", bitcode)
+            .export("ODD")
+            .export("PACKED")
+            .output("prog.o")
+            .btf(true)
+            .build()
+            .unwrap();
+        let buffers = Linker::new(options)
+            .unwrap()
+            .link_to_buffers(&[OutputType::Object])
+            .unwrap();
+        let data = unsafe { llvm::section_contents(&buffers[&OutputType::Object], ".BTF") }
+            .unwrap()
+            .unwrap();
+        let btf = Btf::parse(&data).unwrap();
+        let members = |name: &str| {
+            let id = btf.find(btf::BtfKind::Struct, name).unwrap();
+            btf.get(id)
+                .unwrap()
+                .members()
+                .map(|(name, _, offset)| (btf.string(name).to_owned(), offset))
+                .collect::<Vec<_>>()
+        };
+
+        // the zero-sized, unaligned, misaligned and out of bounds members are dropped
+        assert_eq!(members("Odd"), [("a".to_owned(), 0), ("ok".to_owned(), 64)]);
+        // members of packed structs only need to be byte aligned
+        assert_eq!(
+            members("Packed"),
+            [("b".to_owned(), 0), ("c".to_owned(), 8)]
+        );
+    }
+
+    #[test]
+    fn test_remarks_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // `helper` is inlined into `prog`, then the link fails as `missing` is undefined
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+declare i32 @missing(i32)
+
+define internal i32 @helper(i32 %x) !dbg !5 {
+  %y = add i32 %x, 1, !dbg !7
+  ret i32 %y, !dbg !7
+}
+
+define i32 @prog(ptr %ctx) section "xdp" !dbg !3 {
+  %x = call i32 @helper(i32 1), !dbg !6
+  %y = call i32 @missing(i32 %x), !dbg !6
+  ret i32 %y, !dbg !6
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !1, producer: "rustc", emissionKind: FullDebug)
+!1 = !DIFile(filename: "prog.rs", directory: "/src")
+!2 = !{i32 2, !"Debug Info Version", i32 3}
+!3 = distinct !DISubprogram(name: "prog", scope: !1, file: !1, line: 1, type: !4, spFlags: DISPFlagDefinition, unit: !0)
+!4 = !DISubroutineType(types: !{})
+!5 = distinct !DISubprogram(name: "helper", scope: !1, file: !1, line: 10, type: !4, spFlags: DISPFlagDefinition, unit: !0)
+!6 = !DILocation(line: 2, column: 5, scope: !3)
+!7 = !DILocation(line: 11, column: 5, scope: !5)
+"#,
+        );
+        let remarks = dir.path().join("remarks.yaml");
+        let mut options = LinkerOptions::builder()
+            .input_buffer("prog.ll", bitcode)
+            .export("prog")
+            .undefined_symbols(UndefinedSymbols::Error)
+            .output(dir.path().join("prog.o"))
+            .btf(true)
+            .build()
+            .unwrap();
+        options.remarks_file = Some(remarks.clone());
+        assert!(matches!(
+            Linker::new(options).unwrap().link(),
+            Err(LinkerError::UndefinedSymbols(names)) if names == ["missing"]
+        ));
+
+        let yaml = fs::read_to_string(&remarks).unwrap();
+        let inlined = yaml
+            .split("--- !Remark\n")
+            .find(|remark| remark.contains("'helper' inlined into 'prog'"))
+            .unwrap_or_else(|| panic!("{yaml}"));
+        assert!(
+            inlined.starts_with(
+                "Function: \"prog\"\nDebugLoc: { File: \"prog.rs\", Line: 2, Column: 5 }\n\
+                 Message: \""
+            ),
+            "{inlined}"
+        );
+    }
+
+    #[test]
+    fn test_parse_remark() {
+        assert_eq!(
+            parse_remark("src/prog.rs:12:9: loop not unrolled: unknown trip count"),
+            (
+                Some(("src/prog.rs", 12, 9)),
+                "loop not unrolled: unknown trip count"
+            )
+        );
+        assert_eq!(
+            parse_remark("<unknown>:0:0: 'helper' inlined into 'prog'"),
+            (None, "'helper' inlined into 'prog'")
+        );
+        assert_eq!(parse_remark("no location"), (None, "no location"));
+        assert_eq!(yaml_string("a \"b\"\n\\"), r#""a \"b\"\n\\""#);
+    }
+
+    #[test]
+    fn test_link_to_buffers() {
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  ret i32 2
+}
+"#,
+        );
+        let options = LinkerOptions::builder()
+            .input_buffer("prog.ll", bitcode)
+            .export("prog")
+            .output("prog.o")
+            .build()
+            .unwrap();
+        let buffers = Linker::new(options)
+            .unwrap()
+            .link_to_buffers(&[
+                OutputType::LlvmAssembly,
+                OutputType::Object,
+                OutputType::Disassembly,
+            ])
+            .unwrap();
+        assert_eq!(buffers.len(), 3);
+        let ir = String::from_utf8_lossy(&buffers[&OutputType::LlvmAssembly]);
+        assert!(ir.contains("define i32 @prog"), "{ir}");
+        // generated from the same object
+        let object = &buffers[&OutputType::Object];
+        assert_eq!(
+            String::from_utf8_lossy(&buffers[&OutputType::Disassembly]),
+            disasm::disassemble(object).unwrap()
+        );
+    }
+
+    #[cfg(feature = "compressed-inputs")]
+    #[test]
+    fn test_compressed_input() {
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  ret i32 2
+}
+"#,
+        );
+        let link = |input: Vec<u8>| {
+            let options = LinkerOptions::builder()
+                .input_buffer("prog.bc.zst", input)
+                .export("prog")
+                .output("prog.o")
+                .build()
+                .unwrap();
+            Linker::new(options)
+                .unwrap()
+                .link_to_buffers(&[OutputType::LlvmAssembly])
+        };
+
+        let buffers = link(zstd::encode_all(bitcode.as_slice(), 0).unwrap()).unwrap();
+        let ir = String::from_utf8_lossy(&buffers[&OutputType::LlvmAssembly]);
+        assert!(ir.contains("define i32 @prog"), "{ir}");
+        assert!(matches!(
+            link(b"\x28\xb5\x2f\xfdgarbage".to_vec()),
+            Err(LinkerError::ReadInputError(..))
+        ));
+    }
+
+    #[test]
+    fn test_convert_loops_to_bpf_loop() {
+        // --unroll-loops raises the unroll thresholds of the whole process, so the link runs in a
+        // process of its own
+        if std::env::var_os("BPF_LINKER_TEST_UNROLL_PROCESS").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "linker::tests::test_convert_loops_to_bpf_loop"])
+                .env("BPF_LINKER_TEST_UNROLL_PROCESS", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+@array = global [1024 x i64] zeroinitializer
+
+define i32 @prog(ptr %ctx) section "xdp" {
+entry:
+  br label %loop
+
+loop:
+  %i = phi i64 [ 0, %entry ], [ %next, %loop ]
+  %slot = getelementptr [1024 x i64], ptr @array, i64 0, i64 %i
+  %value = load i64, ptr %slot
+  %sum = add i64 %value, %i
+  store i64 %sum, ptr %slot
+  %next = add i64 %i, 1
+  %done = icmp eq i64 %next, 1024
+  br i1 %done, label %exit, label %loop
+
+exit:
+  ret i32 2
+}
+"#,
+        );
+        let mut options = LinkerOptions::builder()
+            .input_buffer("prog.ll", bitcode)
+            .export("prog")
+            .output("prog.o")
+            .build()
+            .unwrap();
+        options.convert_loops_to_bpf_loop = true;
+        options.unroll_loops = true;
+        let buffers = Linker::new(options)
+            .unwrap()
+            .link_to_buffers(&[OutputType::LlvmAssembly])
+            .unwrap();
+        let ir = String::from_utf8_lossy(&buffers[&OutputType::LlvmAssembly]);
+        // the loop became a bpf_loop call instead of being unrolled into 1024 stores
+        assert!(ir.contains("inttoptr (i64 181 to ptr)"), "{ir}");
+        assert!(ir.contains("ptr @prog_bpf_loop_1"), "{ir}");
+        assert_eq!(ir.matches("store i64").count(), 1, "{ir}");
+    }
+
+    #[test]
+    fn test_checks_share_the_object() {
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  %buf = alloca [1024 x i8], align 8
+  store volatile i8 1, ptr %buf
+  ret i32 2
+}
+"#,
+        );
+        // the errors LLVM reports when generating the object
+        let llvm_errors = |stack_usage: bool| {
+            let mut options = LinkerOptions::builder()
+                .input_buffer("prog.ll", bitcode.clone())
+                .export("prog")
+                .output("prog.o")
+                .build()
+                .unwrap();
+            options.stack_usage = stack_usage;
+            let mut linker = Linker::new(options).unwrap();
+            let _: Result<HashMap<OutputType, Vec<u8>>, LinkerError> =
+                linker.link_to_buffers(&[OutputType::Object]);
+            linker
+                .diagnostics()
+                .iter()
+                .filter(|diagnostic| diagnostic.category.is_none())
+                .map(|diagnostic| diagnostic.message.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let errors = llvm_errors(false);
+        assert!(
+            errors.iter().any(|error| error.contains("stack limit")),
+            "{errors:?}"
+        );
+        // the stack usage is collected from the object of the output instead of generating
+        // another one, which would report the errors again
+        assert_eq!(llvm_errors(true), errors);
+    }
+
+    #[test]
+    fn test_struct_ops_exports() {
+        let link = |section: &str| {
+            let bitcode = ir_to_bitcode(&format!(
+                r#"
+target triple = "bpfel"
+
+%ops = type {{ ptr, ptr }}
+
+@OPS = global %ops {{ ptr @init, ptr @dispatch }}, section ".struct_ops.link"
+
+define i32 @init() section "struct_ops/init" {{
+  ret i32 0
+}}
+
+define void @dispatch(ptr %ctx) section "{section}" {{
+  ret void
+}}
+
+define i32 @prog(ptr %ctx) section "xdp" {{
+  ret i32 2
+}}
+"#
+            ));
+            let options = LinkerOptions::builder()
+                .input_buffer("prog.ll", bitcode)
+                .export("prog")
+                .output("prog.o")
+                .build()
+                .unwrap();
+            Linker::new(options)
+                .unwrap()
+                .link_to_buffers(&[OutputType::LlvmAssembly])
+        };
+
+        // the map and its programs are kept global without being exported
+        let buffers = link("struct_ops.s/dispatch").unwrap();
+        let ir = String::from_utf8_lossy(&buffers[&OutputType::LlvmAssembly]);
+        assert!(ir.contains("@OPS = "), "{ir}");
+        assert!(ir.contains("@init()"), "{ir}");
+        assert!(ir.contains("@dispatch(ptr"), "{ir}");
+        assert!(!ir.contains("internal"), "{ir}");
+        assert!(matches!(
+            link("xdp"),
+            Err(LinkerError::StructOpsError(message))
+                if message.contains("`OPS` points to `dispatch` in section `xdp`")
+        ));
+    }
+
+    #[test]
+    fn test_too_many_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        // `sum` is a BPF-to-BPF call, which can only pass 5 arguments in registers
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  %p = getelementptr i32, ptr %ctx, i32 1
+  %a = load i32, ptr %ctx
+  %b = load i32, ptr %p
+  %r = call i32 @sum(i32 %a, i32 %b, i32 %a, i32 %b, i32 %a, i32 %b)
+  ret i32 %r
+}
+
+define internal i32 @sum(i32 %a, i32 %b, i32 %c, i32 %d, i32 %e, i32 %f) noinline {
+  %ab = add i32 %a, %b
+  %cd = mul i32 %c, %d
+  %ef = sub i32 %e, %f
+  %abcd = add i32 %ab, %cd
+  %r = add i32 %abcd, %ef
+  ret i32 %r
+}
+"#,
+        );
+        let options = LinkerOptions::builder()
+            .input_buffer("prog.ll", bitcode)
+            .export("prog")
+            .output(dir.path().join("prog.o"))
+            .build()
+            .unwrap();
+        let err = Linker::new(options).unwrap().link().unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                LinkerError::TooManyArguments(functions)
+                    if functions == &["`sum` takes 6 arguments"]
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_symbol_policy() {
+        struct Policy;
+
+        impl SymbolPolicy for Policy {
+            fn decide_export(&self, symbol: &PolicySymbol<'_>) -> Option<bool> {
+                match symbol.name {
+                    "prog" => Some(true),
+                    "unexported" => Some(false),
+                    _ => None,
+                }
+            }
+
+            fn decide_section(&self, symbol: &PolicySymbol<'_>) -> Option<String> {
+                (symbol.name == "prog").then(|| "xdp/policy".to_owned())
+            }
+
+            fn decide_linkage(&self, symbol: &PolicySymbol<'_>) -> Option<SymbolLinkage> {
+                match symbol.name {
+                    "prog" => Some(SymbolLinkage::Weak),
+                    "kept" => Some(SymbolLinkage::Keep),
+                    _ => None,
+                }
             }
         }
+
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) {
+  ret i32 2
+}
+
+define i32 @unexported(ptr %ctx) section "xdp" {
+  ret i32 1
+}
+
+define i32 @kept(ptr %ctx) {
+  ret i32 0
+}
+"#,
+        );
+        let options = LinkerOptions::builder()
+            .input_buffer("prog.ll", bitcode)
+            .export("unexported")
+            .output("prog.o")
+            .build()
+            .unwrap();
+        let mut linker = Linker::new(options).unwrap();
+        linker.set_symbol_policy(Policy);
+        let buffers = linker.link_to_buffers(&[OutputType::LlvmAssembly]).unwrap();
+        let ir = String::from_utf8_lossy(&buffers[&OutputType::LlvmAssembly]);
+        let definition = |name: &str| {
+            ir.lines()
+                .find(|line| line.starts_with("define") && line.contains(name))
+                .unwrap_or_else(|| panic!("{name} isn't defined: {ir}"))
+        };
+        let prog = definition("@prog(");
+        assert!(prog.starts_with("define weak "), "{prog}");
+        assert!(prog.contains(r#"section "xdp/policy""#), "{prog}");
+        assert!(!ir.contains("@unexported"), "{ir}");
+        let kept = definition("@kept(");
+        assert!(kept.starts_with("define internal "), "{kept}");
+    }
+
+    #[test]
+    fn test_canonical_options() {
+        let options = |exports: &[&'static str]| {
+            exports
+                .iter()
+                .fold(
+                    LinkerOptions::builder().input("prog.o").output("out.o"),
+                    |options, export| options.export(*export),
+                )
+                .build()
+                .unwrap()
+        };
+
+        let a = canonical_options(&options(&["a", "b", "c", "d"]));
+        let b = canonical_options(&options(&["d", "c", "b", "a"]));
+        assert_eq!(a, b);
+        let a = str::from_utf8(&a).unwrap();
+        assert!(a.contains("export_symbols=4[1:a1:b1:c1:d]\n"), "{a}");
+        assert!(a.contains("inputs=1[file6:prog.o]\n"), "{a}");
+
+        let mut changed = options(&["a", "b", "c", "d"]);
+        changed.btf = !changed.btf;
+        assert_ne!(a.as_bytes(), canonical_options(&changed));
+
+        // options which only affect diagnostics or side files don't change the output
+        let mut diagnostics = options(&["a", "b", "c", "d"]);
+        diagnostics.measure_stage_memory = !diagnostics.measure_stage_memory;
+        diagnostics.verify = !diagnostics.verify;
+        diagnostics.stack_usage = !diagnostics.stack_usage;
+        diagnostics.dump_module = Some("dump".into());
+        diagnostics.remarks_file = Some("remarks.yaml".into());
+        let _: Option<DiagnosticLevel> = diagnostics
+            .diagnostic_levels
+            .insert(DiagnosticCategory::StackUsage, DiagnosticLevel::Deny);
+        assert_eq!(a.as_bytes(), canonical_options(&diagnostics));
+    }
+
+    #[test]
+    fn test_linker_metadata_options_hash() {
+        let bitcode = ir_to_bitcode(
+            r#"
+target triple = "bpfel"
+
+%ops = type { ptr }
+
+@OPS = global %ops { ptr @init }, section ".struct_ops.link"
+
+define i32 @init() section "struct_ops/init" {
+  ret i32 0
+}
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  ret i32 2
+}
+"#,
+        );
+        let link = |measure_stage_memory: bool, verify: bool| {
+            let mut options = LinkerOptions::builder()
+                .input_buffer("prog.ll", bitcode.clone())
+                .export("prog")
+                .output("prog.o")
+                .measure_stage_memory(measure_stage_memory)
+                .verify(verify)
+                .build()
+                .unwrap();
+            options.linker_metadata = true;
+            let hash = Fnv1a64::hash(&canonical_options(&options));
+            let buffers = Linker::new(options)
+                .unwrap()
+                .link_to_buffers(&[OutputType::LlvmAssembly])
+                .unwrap();
+            let ir = String::from_utf8_lossy(&buffers[&OutputType::LlvmAssembly]).into_owned();
+            // the hash is the one of the options as given, not of the exports the link adds for
+            // the struct_ops map
+            assert!(ir.contains(&format!("options=fnv1a64:{hash:016x}")), "{ir}");
+            hash
+        };
+
+        assert_eq!(link(false, false), link(true, true));
     }
 }
@@ -0,0 +1,273 @@
+//! Minimal reader for the LLVM bitstream container, just enough to get the producer of a bitcode
+//! module out of its IDENTIFICATION block without handing the bitcode to LLVM, and to unwrap
+//! wrapped bitcode.
+//!
+//! See https://llvm.org/docs/BitCodeFormat.html for the format.
+
+const WRAPPER_MAGIC: [u8; 4] = [0xde, 0xc0, 0x17, 0x0b];
+const BITCODE_MAGIC: [u8; 4] = *b"BC\xc0\xde";
+
+const END_BLOCK: u64 = 0;
+const ENTER_SUBBLOCK: u64 = 1;
+const DEFINE_ABBREV: u64 = 2;
+const UNABBREV_RECORD: u64 = 3;
+
+const IDENTIFICATION_BLOCK_ID: u64 = 13;
+const IDENTIFICATION_CODE_STRING: u64 = 1;
+
+/// Returns the producer recorded in `bitcode`, eg `LLVM19.1.7-rust-1.85.0-stable`. `None` if the
+/// bitcode is malformed or doesn't start with an IDENTIFICATION block, which is the case for
+/// bitcode written by LLVM older than 3.8.
+pub(crate) fn producer(bitcode: &[u8]) -> Option<String> {
+    let bitcode = strip_wrapper(bitcode)?;
+    let bitcode = bitcode.strip_prefix(&BITCODE_MAGIC)?;
+    let mut reader = BitReader::new(bitcode);
+
+    // the top level abbreviation width is 2
+    if reader.read(2)? != ENTER_SUBBLOCK {
+        return None;
+    }
+    let block_id = reader.read_vbr(8)?;
+    let abbrev_width = reader.read_vbr(4)? as u32;
+    reader.align32();
+    let _block_words = reader.read(32)?;
+    if block_id != IDENTIFICATION_BLOCK_ID {
+        return None;
+    }
+
+    let mut abbrevs = Vec::new();
+    loop {
+        let (code, ops) = match reader.read(abbrev_width)? {
+            END_BLOCK | ENTER_SUBBLOCK => return None,
+            DEFINE_ABBREV => {
+                abbrevs.push(reader.read_abbrev()?);
+                continue;
+            }
+            UNABBREV_RECORD => {
+                let code = reader.read_vbr(6)?;
+                let len = reader.read_vbr(6)?;
+                let ops = (0..len)
+                    .map(|_| reader.read_vbr(6))
+                    .collect::<Option<Vec<_>>>()?;
+                (code, ops)
+            }
+            id => {
+                let abbrev = abbrevs.get(usize::try_from(id - 4).ok()?)?;
+                let mut fields = reader.read_abbreviated_record(abbrev)?;
+                if fields.is_empty() {
+                    return None;
+                }
+                let code = fields.remove(0);
+                (code, fields)
+            }
+        };
+        if code == IDENTIFICATION_CODE_STRING {
+            return ops
+                .into_iter()
+                .map(|c| u8::try_from(c).ok().map(char::from))
+                .collect();
+        }
+    }
+}
+
+/// Returns the major LLVM version of a bitcode `producer`, eg `19` for
+/// `LLVM19.1.7-rust-1.85.0-stable`.
+pub(crate) fn producer_llvm_major(producer: &str) -> Option<u32> {
+    let version = producer.strip_prefix("LLVM")?;
+    let end = version
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(version.len());
+    version[..end].parse().ok()
+}
+
+/// Returns the bitcode wrapped in `data` if it starts with a bitcode wrapper header, `data`
+/// otherwise. `None` if the header points outside of `data`.
+///
+/// LLVM skips the header itself, but then requires the wrapped bitcode to be 4 byte aligned,
+/// which nothing guarantees, so the bitcode is copied out instead.
+pub(crate) fn unwrap(data: Vec<u8>) -> Option<Vec<u8>> {
+    if !data.starts_with(&WRAPPER_MAGIC) {
+        return Some(data);
+    }
+    strip_wrapper(&data).map(<[u8]>::to_vec)
+}
+
+// Apple's tools wrap bitcode in a header pointing at the actual bitcode.
+fn strip_wrapper(data: &[u8]) -> Option<&[u8]> {
+    if !data.starts_with(&WRAPPER_MAGIC) {
+        return Some(data);
+    }
+    let field = |at: usize| -> Option<usize> {
+        let bytes = data.get(at..at + 4)?.try_into().ok()?;
+        usize::try_from(u32::from_le_bytes(bytes)).ok()
+    };
+    let offset = field(8)?;
+    let size = field(12)?;
+    data.get(offset..offset.checked_add(size)?)
+}
+
+enum AbbrevOp {
+    Literal(u64),
+    Fixed(u32),
+    Vbr(u32),
+    Array,
+    Char6,
+    Blob,
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read(&mut self, width: u32) -> Option<u64> {
+        if width > 64 {
+            return None;
+        }
+        let mut value = 0;
+        for bit in 0..width {
+            let byte = self.data.get(self.pos / 8)?;
+            value |= u64::from((byte >> (self.pos % 8)) & 1) << bit;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn read_vbr(&mut self, width: u32) -> Option<u64> {
+        if width < 2 {
+            return None;
+        }
+        let continuation = 1 << (width - 1);
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let chunk = self.read(width)?;
+            value |= (chunk & (continuation - 1)).checked_shl(shift)?;
+            if chunk & continuation == 0 {
+                return Some(value);
+            }
+            shift += width - 1;
+            if shift >= 64 {
+                return None;
+            }
+        }
+    }
+
+    fn align32(&mut self) {
+        self.pos = self.pos.next_multiple_of(32);
+    }
+
+    fn read_abbrev(&mut self) -> Option<Vec<AbbrevOp>> {
+        let len = self.read_vbr(5)?;
+        (0..len)
+            .map(|_| {
+                if self.read(1)? == 1 {
+                    return Some(AbbrevOp::Literal(self.read_vbr(8)?));
+                }
+                Some(match self.read(3)? {
+                    1 => AbbrevOp::Fixed(self.read_vbr(5)? as u32),
+                    2 => AbbrevOp::Vbr(self.read_vbr(5)? as u32),
+                    3 => AbbrevOp::Array,
+                    4 => AbbrevOp::Char6,
+                    5 => AbbrevOp::Blob,
+                    _ => return None,
+                })
+            })
+            .collect()
+    }
+
+    fn read_scalar(&mut self, op: &AbbrevOp) -> Option<u64> {
+        match op {
+            AbbrevOp::Literal(value) => Some(*value),
+            AbbrevOp::Fixed(width) => self.read(*width),
+            AbbrevOp::Vbr(width) => self.read_vbr(*width),
+            AbbrevOp::Char6 => {
+                let c = match self.read(6)? as u8 {
+                    c @ 0..=25 => b'a' + c,
+                    c @ 26..=51 => b'A' + c - 26,
+                    c @ 52..=61 => b'0' + c - 52,
+                    62 => b'.',
+                    _ => b'_',
+                };
+                Some(u64::from(c))
+            }
+            AbbrevOp::Array | AbbrevOp::Blob => None,
+        }
+    }
+
+    fn read_abbreviated_record(&mut self, abbrev: &[AbbrevOp]) -> Option<Vec<u64>> {
+        let mut fields = Vec::new();
+        let mut ops = abbrev.iter();
+        while let Some(op) = ops.next() {
+            match op {
+                AbbrevOp::Array => {
+                    // the element encoding is the last operand of the abbreviation
+                    let element = ops.next()?;
+                    let len = self.read_vbr(6)?;
+                    for _ in 0..len {
+                        fields.push(self.read_scalar(element)?);
+                    }
+                }
+                AbbrevOp::Blob => {
+                    let len = usize::try_from(self.read_vbr(6)?).ok()?;
+                    self.align32();
+                    for _ in 0..len {
+                        fields.push(self.read(8)?);
+                    }
+                    self.align32();
+                }
+                op => fields.push(self.read_scalar(op)?),
+            }
+        }
+        Some(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_producer() {
+        // IDENTIFICATION block written by LLVM 14: a char6 array abbreviation for the producer
+        // string followed by the epoch record.
+        let bitcode = [
+            0x42, 0x43, 0xc0, 0xde, 0x35, 0x14, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x62, 0x0c,
+            0x30, 0x24, 0x4a, 0x59, 0xbe, 0x66, 0x8d, 0xfb, 0xb4, 0xaf, 0x0b, 0x51, 0x80, 0x4c,
+            0x01, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(producer(&bitcode).as_deref(), Some("LLVM14.0.6"));
+        assert_eq!(producer(b"BC\xc0\xde"), None);
+        assert_eq!(producer(b"\x7fELF"), None);
+    }
+
+    #[test]
+    fn test_unwrap() {
+        let mut wrapped = Vec::new();
+        for field in [0x0b17c0de, 0, 21, 4, 0x01000007u32] {
+            wrapped.extend(field.to_le_bytes());
+        }
+        wrapped.push(0);
+        wrapped.extend(BITCODE_MAGIC);
+        wrapped.extend([0; 3]);
+        assert_eq!(unwrap(wrapped.clone()), Some(BITCODE_MAGIC.to_vec()));
+        wrapped.truncate(24);
+        assert_eq!(unwrap(wrapped), None);
+        assert_eq!(unwrap(BITCODE_MAGIC.to_vec()), Some(BITCODE_MAGIC.to_vec()));
+    }
+
+    #[test]
+    fn test_producer_llvm_major() {
+        assert_eq!(
+            producer_llvm_major("LLVM19.1.7-rust-1.85.0-stable"),
+            Some(19)
+        );
+        assert_eq!(producer_llvm_major("LLVM14.0.0"), Some(14));
+        assert_eq!(producer_llvm_major("APPLE_1_1500.3.9.4_0"), None);
+    }
+}
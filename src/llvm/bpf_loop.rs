@@ -0,0 +1,728 @@
+//! Conversion of big counted loops into calls to the `bpf_loop` helper, whose callback runs the
+//! body of the loop, so that the verifier checks the body once instead of every iteration.
+
+use std::{collections::HashMap, ptr};
+
+use gimli::{DW_ATE_signed, DW_ATE_unsigned};
+use llvm_sys::{
+    core::{
+        LLVMAddFunction, LLVMAppendBasicBlockInContext, LLVMBuildAdd, LLVMBuildAlloca,
+        LLVMBuildBitCast, LLVMBuildBr, LLVMBuildCall2, LLVMBuildLoad2, LLVMBuildRet,
+        LLVMBuildStore, LLVMBuildStructGEP2, LLVMBuildTrunc, LLVMConstInt,
+        LLVMConstIntGetSExtValue, LLVMConstIntToPtr, LLVMCountIncoming, LLVMCreateBuilderInContext,
+        LLVMDisposeBuilder, LLVMFunctionType, LLVMGetBasicBlockTerminator, LLVMGetCalledValue,
+        LLVMGetEntryBasicBlock, LLVMGetFirstInstruction, LLVMGetFirstUse, LLVMGetICmpPredicate,
+        LLVMGetIncomingBlock, LLVMGetIncomingValue, LLVMGetInstructionOpcode,
+        LLVMGetInstructionParent, LLVMGetIntTypeWidth, LLVMGetNextUse, LLVMGetNumOperands,
+        LLVMGetOperand, LLVMGetParam, LLVMGetSuccessor, LLVMGetTypeKind, LLVMGetUndef, LLVMGetUser,
+        LLVMInsertIntoBuilder, LLVMInstructionEraseFromParent, LLVMInstructionRemoveFromParent,
+        LLVMInt32TypeInContext, LLVMInt64TypeInContext, LLVMInt8TypeInContext, LLVMIsAAllocaInst,
+        LLVMIsAArgument, LLVMIsACallInst, LLVMIsAConstantInt, LLVMIsAFunction, LLVMIsAInstruction,
+        LLVMIsConditional, LLVMIsDeclaration, LLVMPointerType, LLVMPositionBuilderAtEnd,
+        LLVMPositionBuilderBefore, LLVMReplaceAllUsesWith, LLVMSetCurrentDebugLocation2,
+        LLVMSetLinkage, LLVMSetOperand, LLVMStructTypeInContext, LLVMTypeOf,
+    },
+    debuginfo::{
+        LLVMCreateDIBuilder, LLVMDIBuilderCreateBasicType, LLVMDIBuilderCreateCompileUnit,
+        LLVMDIBuilderCreateDebugLocation, LLVMDIBuilderCreateFunction,
+        LLVMDIBuilderCreatePointerType, LLVMDIBuilderCreateSubroutineType, LLVMDIBuilderFinalize,
+        LLVMDIFlagZero, LLVMDIScopeGetFile, LLVMDISubprogramGetLine, LLVMDWARFEmissionKind,
+        LLVMDWARFSourceLanguage, LLVMDisposeDIBuilder, LLVMGetSubprogram,
+        LLVMInstructionSetDebugLoc, LLVMSetSubprogram,
+    },
+    prelude::{
+        LLVMBasicBlockRef, LLVMBuilderRef, LLVMContextRef, LLVMDIBuilderRef, LLVMMetadataRef,
+        LLVMModuleRef, LLVMTypeRef, LLVMValueRef,
+    },
+    LLVMIntPredicate, LLVMLinkage, LLVMOpcode, LLVMTypeKind,
+};
+use tracing::debug;
+
+use super::{
+    iter::{IterBasicBlocks as _, IterInstructions as _, IterModuleFunctions as _},
+    symbol_name,
+};
+
+/// The ID of the `bpf_loop` helper.
+const BPF_LOOP: u64 = 181;
+
+/// The most iterations `bpf_loop` runs.
+const BPF_LOOP_MAX_ITERATIONS: u64 = 1 << 23;
+
+/// The cost, in instructions, above which LLVM doesn't fully unroll a loop by default. Loops
+/// cheaper than that are left to the optimizer.
+const UNROLL_THRESHOLD: u64 = 150;
+
+/// The passes putting the loops of a module not optimized yet in the shape
+/// [`convert_loops_to_bpf_loop`] converts: their variables promoted to registers, rotated so that
+/// a simple loop is a single block and counting with a canonical induction variable.
+pub const LOOP_CANONICALIZATION_PASSES: &str =
+    "function(sroa,early-cse,simplifycfg,instcombine,loop(loop-rotate,indvars),simplifycfg)";
+
+/// Converts the counted loops of `module` whose trip count times the size of their body exceeds
+/// the unroll threshold into `bpf_loop` calls, the body of each loop being outlined into the
+/// callback. Meant to run before optimization, which would otherwise unroll the loops, and which
+/// then optimizes the callbacks along with the rest of the module. Only loops of a single block, counting up by one from a constant to a constant and
+/// not computing values used after the loop, the induction variable included, are converted, the
+/// others are left as they are.
+///
+/// Returns the number of loops converted.
+pub unsafe fn convert_loops_to_bpf_loop(context: LLVMContextRef, module: LLVMModuleRef) -> usize {
+    let mut loops = Vec::new();
+    for function in module.functions_iter() {
+        if LLVMIsDeclaration(function) != 0 {
+            continue;
+        }
+        for block in function.basic_blocks_iter() {
+            match CountedLoop::new(block) {
+                Ok(counted) => loops.push((function, counted)),
+                Err(Some(reason)) => {
+                    debug!(
+                        "not converting a loop of {}: {reason}",
+                        symbol_name(function)
+                    );
+                }
+                Err(None) => {}
+            }
+        }
+    }
+    if loops.is_empty() {
+        return 0;
+    }
+
+    let mut converter = Converter::new(context, module);
+    let mut callbacks = HashMap::<LLVMValueRef, usize>::new();
+    for (function, counted) in &loops {
+        let n = callbacks.entry(*function).or_default();
+        *n += 1;
+        converter.convert(*function, counted, *n);
+    }
+    converter.finish();
+    loops.len()
+}
+
+/// A loop of a single block, counting from `start` for `trip_count` iterations.
+struct CountedLoop {
+    block: LLVMBasicBlockRef,
+    // the induction variable and the exit
+    phi: LLVMValueRef,
+    exit: LLVMBasicBlockRef,
+    start: u64,
+    trip_count: u64,
+    // the instructions making up the body, ie not counting
+    body: Vec<LLVMValueRef>,
+}
+
+impl CountedLoop {
+    /// Matches the loop `block` branches back to itself, if it does. Returns why a loop doesn't
+    /// have a shape which can be converted, or `None` if `block` isn't a loop or is too small to
+    /// be worth converting.
+    unsafe fn new(block: LLVMBasicBlockRef) -> Result<Self, Option<&'static str>> {
+        let branch = LLVMGetBasicBlockTerminator(block);
+        if branch.is_null()
+            || LLVMGetInstructionOpcode(branch) != LLVMOpcode::LLVMBr
+            || LLVMIsConditional(branch) == 0
+        {
+            return Err(None);
+        }
+        let (continue_on_true, exit) =
+            match (LLVMGetSuccessor(branch, 0), LLVMGetSuccessor(branch, 1)) {
+                (taken, exit) if taken == block && exit != block => (true, exit),
+                (exit, taken) if taken == block && exit != block => (false, exit),
+                _ => return Err(None),
+            };
+
+        let instructions: Vec<_> = block.instructions_iter().collect();
+        let phis: Vec<_> = instructions
+            .iter()
+            .copied()
+            .filter(|&instruction| LLVMGetInstructionOpcode(instruction) == LLVMOpcode::LLVMPHI)
+            .collect();
+        let [phi] = phis[..] else {
+            return Err(Some("it doesn't have exactly one induction variable"));
+        };
+        let phi_type = LLVMTypeOf(phi);
+        if LLVMGetTypeKind(phi_type) != LLVMTypeKind::LLVMIntegerTypeKind
+            || LLVMGetIntTypeWidth(phi_type) > 64
+        {
+            return Err(Some("its induction variable isn't an integer"));
+        }
+        // the induction variable is replaced once the loop is converted, so it can't have uses
+        // after the loop, eg in the phis LCSSA puts in the exit block
+        if used_outside(phi, block) {
+            return Err(Some("its induction variable is used after it"));
+        }
+        if LLVMCountIncoming(phi) != 2 {
+            return Err(Some("it has several entries"));
+        }
+        let (mut start, mut next) = (None, None);
+        for i in 0..2 {
+            let value = LLVMGetIncomingValue(phi, i);
+            if LLVMGetIncomingBlock(phi, i) == block {
+                next = Some(value);
+            } else {
+                start = Some(value);
+            }
+        }
+        let (Some(start), Some(next)) = (start, next) else {
+            return Err(Some("its induction variable isn't counted"));
+        };
+        if LLVMIsAConstantInt(start).is_null() {
+            return Err(Some("it doesn't start from a constant"));
+        }
+        let start = LLVMConstIntGetSExtValue(start);
+        if LLVMIsAInstruction(next).is_null()
+            || LLVMGetInstructionParent(next) != block
+            || LLVMGetInstructionOpcode(next) != LLVMOpcode::LLVMAdd
+            || LLVMGetOperand(next, 0) != phi
+            || !is_constant(LLVMGetOperand(next, 1), 1)
+        {
+            return Err(Some("it doesn't count up by one"));
+        }
+
+        let condition = LLVMGetOperand(branch, 0);
+        if LLVMIsAInstruction(condition).is_null()
+            || LLVMGetInstructionOpcode(condition) != LLVMOpcode::LLVMICmp
+            || LLVMGetOperand(condition, 0) != next
+            || LLVMIsAConstantInt(LLVMGetOperand(condition, 1)).is_null()
+        {
+            return Err(Some("it doesn't count up to a constant"));
+        }
+        let end = LLVMConstIntGetSExtValue(LLVMGetOperand(condition, 1));
+        let counts_up = match LLVMGetICmpPredicate(condition) {
+            LLVMIntPredicate::LLVMIntNE
+            | LLVMIntPredicate::LLVMIntULT
+            | LLVMIntPredicate::LLVMIntSLT => continue_on_true,
+            LLVMIntPredicate::LLVMIntEQ => !continue_on_true,
+            _ => false,
+        };
+        if !counts_up || end <= start {
+            return Err(Some("it doesn't count up to a constant"));
+        }
+        let trip_count = end.abs_diff(start);
+
+        let counter = [phi, next, condition, branch];
+        let mut body = Vec::new();
+        for &instruction in &instructions {
+            if counter.contains(&instruction) {
+                continue;
+            }
+            if !LLVMIsAAllocaInst(instruction).is_null() {
+                return Err(Some("it allocates stack memory"));
+            }
+            if used_outside(instruction, block) {
+                return Err(Some("its body computes values used after it"));
+            }
+            // the counter is only allowed to feed the body through the induction variable
+            for operand in
+                (0..LLVMGetNumOperands(instruction)).map(|i| LLVMGetOperand(instruction, i as u32))
+            {
+                if operand == next || operand == condition {
+                    return Err(Some(
+                        "its body uses the next value of the induction variable",
+                    ));
+                }
+            }
+            body.push(instruction);
+        }
+        for value in [next, condition] {
+            let mut used = LLVMGetFirstUse(value);
+            while !used.is_null() {
+                let user = LLVMGetUser(used);
+                if !counter.contains(&user) {
+                    return Err(Some("its counter is used outside of it"));
+                }
+                used = LLVMGetNextUse(used);
+            }
+        }
+
+        if trip_count > BPF_LOOP_MAX_ITERATIONS {
+            return Err(Some("it runs more iterations than bpf_loop supports"));
+        }
+        if trip_count.saturating_mul(body.len() as u64) <= UNROLL_THRESHOLD {
+            return Err(None);
+        }
+        Ok(Self {
+            block,
+            phi,
+            exit,
+            start: start as u64,
+            trip_count,
+            body,
+        })
+    }
+}
+
+unsafe fn is_constant(value: LLVMValueRef, n: i64) -> bool {
+    !LLVMIsAConstantInt(value).is_null() && LLVMConstIntGetSExtValue(value) == n
+}
+
+// Returns whether `instruction` is used outside of `block`.
+unsafe fn used_outside(instruction: LLVMValueRef, block: LLVMBasicBlockRef) -> bool {
+    let mut used = LLVMGetFirstUse(instruction);
+    while !used.is_null() {
+        let user = LLVMGetUser(used);
+        if LLVMIsAInstruction(user).is_null() || LLVMGetInstructionParent(user) != block {
+            return true;
+        }
+        used = LLVMGetNextUse(used);
+    }
+    false
+}
+
+// Returns whether `value` is defined by the function outside of `block`, so that it must be
+// passed to the callback.
+unsafe fn is_live_in(value: LLVMValueRef, block: LLVMBasicBlockRef) -> bool {
+    !LLVMIsAArgument(value).is_null()
+        || (!LLVMIsAInstruction(value).is_null() && LLVMGetInstructionParent(value) != block)
+}
+
+struct Converter {
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    builder: LLVMBuilderRef,
+    i64_type: LLVMTypeRef,
+    ptr_type: LLVMTypeRef,
+    // the type of the callbacks, and the type of `bpf_loop`
+    callback_type: LLVMTypeRef,
+    helper_type: LLVMTypeRef,
+    // created when the first callback needing debug info is
+    di: Option<CallbackDebugInfo>,
+}
+
+// The debug info of the callbacks, which have a compile unit of their own.
+struct CallbackDebugInfo {
+    builder: LLVMDIBuilderRef,
+    callback_type: LLVMMetadataRef,
+}
+
+impl Converter {
+    unsafe fn new(context: LLVMContextRef, module: LLVMModuleRef) -> Self {
+        let i64_type = LLVMInt64TypeInContext(context);
+        let ptr_type = LLVMPointerType(LLVMInt8TypeInContext(context), 0);
+        let mut callback_params = [i64_type, ptr_type];
+        let callback_type = LLVMFunctionType(
+            i64_type,
+            callback_params.as_mut_ptr(),
+            callback_params.len() as u32,
+            0,
+        );
+        let mut helper_params = [
+            LLVMInt32TypeInContext(context),
+            ptr_type,
+            ptr_type,
+            i64_type,
+        ];
+        let helper_type = LLVMFunctionType(
+            i64_type,
+            helper_params.as_mut_ptr(),
+            helper_params.len() as u32,
+            0,
+        );
+        Self {
+            context,
+            module,
+            builder: LLVMCreateBuilderInContext(context),
+            i64_type,
+            ptr_type,
+            callback_type,
+            helper_type,
+            di: None,
+        }
+    }
+
+    unsafe fn convert(&mut self, function: LLVMValueRef, counted: &CountedLoop, n: usize) {
+        let CountedLoop {
+            block,
+            phi,
+            exit,
+            start,
+            trip_count,
+            ref body,
+        } = *counted;
+        let name = format!(
+            "{}_bpf_loop_{n}",
+            symbol_name(function).replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+        );
+        debug!("converting a loop of {} into {name}", symbol_name(function));
+
+        // the values the body uses from the function are passed through a struct on the stack
+        let mut live_ins = Vec::new();
+        for &instruction in body {
+            for i in 0..LLVMGetNumOperands(instruction) as u32 {
+                let operand = LLVMGetOperand(instruction, i);
+                if is_live_in(operand, block) && !live_ins.contains(&operand) {
+                    live_ins.push(operand);
+                }
+            }
+        }
+        let mut live_in_types: Vec<_> = live_ins.iter().map(|&value| LLVMTypeOf(value)).collect();
+        let context_type = LLVMStructTypeInContext(
+            self.context,
+            live_in_types.as_mut_ptr(),
+            live_in_types.len() as u32,
+            0,
+        );
+
+        let callback = LLVMAddFunction(
+            self.module,
+            format!("{name}\0").as_ptr().cast(),
+            self.callback_type,
+        );
+        LLVMSetLinkage(callback, LLVMLinkage::LLVMInternalLinkage);
+        let location = self.subprogram(function, callback, &name);
+        let entry = LLVMAppendBasicBlockInContext(self.context, callback, c"entry".as_ptr());
+        LLVMPositionBuilderAtEnd(self.builder, entry);
+        // the builder keeps the location of the function of the previous loop otherwise
+        LLVMSetCurrentDebugLocation2(self.builder, location.unwrap_or(ptr::null_mut()));
+        let callback_context = LLVMBuildBitCast(
+            self.builder,
+            LLVMGetParam(callback, 1),
+            LLVMPointerType(context_type, 0),
+            c"".as_ptr(),
+        );
+        let mut replacements = Vec::new();
+        for (i, &value) in live_ins.iter().enumerate() {
+            let field = LLVMBuildStructGEP2(
+                self.builder,
+                context_type,
+                callback_context,
+                i as u32,
+                c"".as_ptr(),
+            );
+            replacements.push((
+                value,
+                LLVMBuildLoad2(self.builder, LLVMTypeOf(value), field, c"".as_ptr()),
+            ));
+        }
+        let mut index = LLVMGetParam(callback, 0);
+        let index_type = LLVMTypeOf(phi);
+        if LLVMGetIntTypeWidth(index_type) < 64 {
+            index = LLVMBuildTrunc(self.builder, index, index_type, c"".as_ptr());
+        }
+        if start != 0 {
+            index = LLVMBuildAdd(
+                self.builder,
+                index,
+                LLVMConstInt(index_type, start, 0),
+                c"".as_ptr(),
+            );
+        }
+        replacements.push((phi, index));
+
+        for &instruction in body {
+            // the variables of the debug info are scoped to the function the loop was in
+            if is_debug_intrinsic(instruction) {
+                LLVMInstructionEraseFromParent(instruction);
+                continue;
+            }
+            LLVMInstructionRemoveFromParent(instruction);
+            LLVMInsertIntoBuilder(self.builder, instruction);
+            for i in 0..LLVMGetNumOperands(instruction) as u32 {
+                let operand = LLVMGetOperand(instruction, i);
+                if let Some(&(_, replacement)) =
+                    replacements.iter().find(|(value, _)| *value == operand)
+                {
+                    LLVMSetOperand(instruction, i, replacement);
+                }
+            }
+            if let Some(location) = location {
+                LLVMInstructionSetDebugLoc(instruction, location);
+            }
+        }
+        let _: LLVMValueRef = LLVMBuildRet(self.builder, LLVMConstInt(self.i64_type, 0, 0));
+
+        // store the live-ins at the start of the function, not to grow the stack in a loop
+        let first = LLVMGetFirstInstruction(LLVMGetEntryBasicBlock(function));
+        LLVMPositionBuilderBefore(self.builder, first);
+        let loop_context = LLVMBuildAlloca(self.builder, context_type, c"".as_ptr());
+
+        // the counting instructions are left at the end of the block, replace them with the call
+        let counting: Vec<_> = block.instructions_iter().collect();
+        LLVMPositionBuilderBefore(self.builder, LLVMGetBasicBlockTerminator(block));
+        for (i, &value) in live_ins.iter().enumerate() {
+            let field = LLVMBuildStructGEP2(
+                self.builder,
+                context_type,
+                loop_context,
+                i as u32,
+                c"".as_ptr(),
+            );
+            let _: LLVMValueRef = LLVMBuildStore(self.builder, value, field);
+        }
+        let helper = LLVMConstIntToPtr(
+            LLVMConstInt(self.i64_type, BPF_LOOP, 0),
+            LLVMPointerType(self.helper_type, 0),
+        );
+        let mut args = [
+            LLVMConstInt(LLVMInt32TypeInContext(self.context), trip_count, 0),
+            LLVMBuildBitCast(self.builder, callback, self.ptr_type, c"".as_ptr()),
+            LLVMBuildBitCast(self.builder, loop_context, self.ptr_type, c"".as_ptr()),
+            LLVMConstInt(self.i64_type, 0, 0),
+        ];
+        let _: LLVMValueRef = LLVMBuildCall2(
+            self.builder,
+            self.helper_type,
+            helper,
+            args.as_mut_ptr(),
+            args.len() as u32,
+            c"".as_ptr(),
+        );
+        let _: LLVMValueRef = LLVMBuildBr(self.builder, exit);
+        // the induction variable and its next value use each other, break the cycle to erase them
+        // users first
+        LLVMReplaceAllUsesWith(phi, LLVMGetUndef(index_type));
+        for instruction in counting.into_iter().rev() {
+            LLVMInstructionEraseFromParent(instruction);
+        }
+    }
+
+    // Gives `callback` a subprogram when `function` has one, since BTF needs one for every
+    // function, and returns the location of the instructions of the callback.
+    unsafe fn subprogram(
+        &mut self,
+        function: LLVMValueRef,
+        callback: LLVMValueRef,
+        name: &str,
+    ) -> Option<LLVMMetadataRef> {
+        let subprogram = LLVMGetSubprogram(function);
+        if subprogram.is_null() {
+            return None;
+        }
+        let file = LLVMDIScopeGetFile(subprogram);
+        let line = LLVMDISubprogramGetLine(subprogram);
+        let module = self.module;
+        let di = self
+            .di
+            .get_or_insert_with(|| CallbackDebugInfo::new(module, file));
+        let callback_subprogram = LLVMDIBuilderCreateFunction(
+            di.builder,
+            file,
+            name.as_ptr().cast(),
+            name.len(),
+            name.as_ptr().cast(),
+            name.len(),
+            file,
+            line,
+            di.callback_type,
+            1,
+            1,
+            line,
+            LLVMDIFlagZero,
+            1,
+        );
+        LLVMSetSubprogram(callback, callback_subprogram);
+        Some(LLVMDIBuilderCreateDebugLocation(
+            self.context,
+            line,
+            0,
+            callback_subprogram,
+            ptr::null_mut(),
+        ))
+    }
+
+    unsafe fn finish(self) {
+        if let Some(di) = self.di {
+            LLVMDIBuilderFinalize(di.builder);
+            LLVMDisposeDIBuilder(di.builder);
+        }
+        LLVMDisposeBuilder(self.builder);
+    }
+}
+
+impl CallbackDebugInfo {
+    unsafe fn new(module: LLVMModuleRef, file: LLVMMetadataRef) -> Self {
+        let builder = LLVMCreateDIBuilder(module);
+        let producer = "bpf-linker";
+        let _: LLVMMetadataRef = LLVMDIBuilderCreateCompileUnit(
+            builder,
+            LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageRust,
+            file,
+            producer.as_ptr().cast(),
+            producer.len(),
+            1,
+            ptr::null(),
+            0,
+            0,
+            ptr::null(),
+            0,
+            LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull,
+            0,
+            0,
+            0,
+            ptr::null(),
+            0,
+            ptr::null(),
+            0,
+        );
+        // long (*)(u64 index, void *ctx)
+        let mut types = [
+            LLVMDIBuilderCreateBasicType(
+                builder,
+                c"i64".as_ptr(),
+                3,
+                64,
+                DW_ATE_signed.0.into(),
+                LLVMDIFlagZero,
+            ),
+            LLVMDIBuilderCreateBasicType(
+                builder,
+                c"u64".as_ptr(),
+                3,
+                64,
+                DW_ATE_unsigned.0.into(),
+                LLVMDIFlagZero,
+            ),
+            LLVMDIBuilderCreatePointerType(builder, ptr::null_mut(), 64, 0, 0, ptr::null(), 0),
+        ];
+        let callback_type = LLVMDIBuilderCreateSubroutineType(
+            builder,
+            file,
+            types.as_mut_ptr(),
+            types.len() as u32,
+            LLVMDIFlagZero,
+        );
+        Self {
+            builder,
+            callback_type,
+        }
+    }
+}
+
+unsafe fn is_debug_intrinsic(instruction: LLVMValueRef) -> bool {
+    if LLVMIsACallInst(instruction).is_null() {
+        return false;
+    }
+    let callee = LLVMGetCalledValue(instruction);
+    !LLVMIsAFunction(callee).is_null() && symbol_name(callee).starts_with("llvm.dbg.")
+}
+
+#[cfg(test)]
+mod tests {
+    use llvm_sys::core::{LLVMContextCreate, LLVMContextDispose, LLVMDisposeModule};
+
+    use super::*;
+    use crate::llvm::{ir_to_string, parse_ir, verify_module};
+
+    // A loop of 1024 iterations incrementing the elements of an array, `{exit}` being the exit
+    // block and `{body}` extra instructions of the loop.
+    fn ir(body: &str, exit: &str) -> String {
+        format!(
+            r#"
+target triple = "bpfel"
+
+@array = global [1024 x i64] zeroinitializer
+
+define i64 @prog() {{
+entry:
+  br label %loop
+
+loop:
+  %i = phi i64 [ 0, %entry ], [ %next, %loop ]
+  %slot = getelementptr [1024 x i64], ptr @array, i64 0, i64 %i
+  %value = load i64, ptr %slot
+  %sum = add i64 %value, %i
+  store i64 %sum, ptr %slot
+{body}
+  %next = add i64 %i, 1
+  %done = icmp eq i64 %next, 1024
+  br i1 %done, label %exit, label %loop
+
+exit:
+{exit}
+}}
+"#
+        )
+    }
+
+    // Converts the loops of `ir`, returning how many were and the resulting IR.
+    fn convert(ir: &str) -> (usize, String) {
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, ir).unwrap();
+            let converted = convert_loops_to_bpf_loop(context, module);
+            verify_module(module).unwrap();
+            let ir = ir_to_string(module);
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+            (converted, ir)
+        }
+    }
+
+    #[test]
+    fn test_convert() {
+        let (converted, ir) = convert(&ir("", "  ret i64 0"));
+        assert_eq!(converted, 1);
+        assert!(ir.contains("define internal i64 @prog_bpf_loop_1(i64 %0, ptr %1)"));
+        assert!(ir.contains("call i64 inttoptr (i64 181 to ptr)(i32 1024, ptr @prog_bpf_loop_1"));
+        assert!(!ir.contains("phi"));
+    }
+
+    #[test]
+    fn test_not_converted() {
+        for (body, exit) in [
+            // LCSSA phi of the induction variable
+            ("", "  %last = phi i64 [ %i, %loop ]\n  ret i64 %last"),
+            // value of the body used after the loop
+            ("", "  %last = phi i64 [ %sum, %loop ]\n  ret i64 %last"),
+            // stack allocation
+            (
+                "  %tmp = alloca i64\n  store i64 %sum, ptr %tmp",
+                "  ret i64 0",
+            ),
+            // the next value of the induction variable used by the body
+            ("  store i64 %next, ptr @array", "  ret i64 0"),
+        ] {
+            let ir = ir(body, exit);
+            assert_eq!(convert(&ir).0, 0, "{ir}");
+        }
+
+        // too small to be worth converting
+        let ir = ir("", "  ret i64 0").replace("1024\n", "4\n");
+        assert_eq!(convert(&ir).0, 0, "{ir}");
+    }
+
+    #[test]
+    fn test_convert_debug_info() {
+        let ir = ir("", "  ret i64 0")
+            .replace("define i64 @prog() {", "define i64 @prog() !dbg !3 {")
+            .replace(
+                "store i64 %sum, ptr %slot",
+                "store i64 %sum, ptr %slot, !dbg !5",
+            )
+            + r#"
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !1, producer: "rustc", emissionKind: FullDebug)
+!1 = !DIFile(filename: "prog.rs", directory: "/src")
+!2 = !{i32 2, !"Debug Info Version", i32 3}
+!3 = distinct !DISubprogram(name: "prog", scope: !1, file: !1, line: 7, type: !4, spFlags: DISPFlagDefinition, unit: !0)
+!4 = !DISubroutineType(types: !{})
+!5 = !DILocation(line: 9, scope: !3)
+"#;
+        let (converted, ir) = convert(&ir);
+        assert_eq!(converted, 1);
+        // the callback is described for BTF, at the line of the function of the loop, and its
+        // instructions are located in it
+        assert!(
+            ir.contains("define internal i64 @prog_bpf_loop_1(i64 %0, ptr %1) !dbg"),
+            "{ir}"
+        );
+        let subprogram = ir
+            .lines()
+            .find(|line| line.contains(r#"!DISubprogram(name: "prog_bpf_loop_1""#))
+            .unwrap();
+        assert!(subprogram.contains("line: 7,"), "{subprogram}");
+        assert!(ir.contains(r#"producer: "bpf-linker""#), "{ir}");
+        let callback = &ir[ir.find("@prog_bpf_loop_1(i64").unwrap()..];
+        let callback = &callback[..callback.find("\n}").unwrap()];
+        assert!(
+            callback
+                .lines()
+                .filter(|line| line.contains("store i64"))
+                .all(|line| line.contains("!dbg")),
+            "{callback}"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use std::ptr;
+
+use gimli::DW_ATE_unsigned;
+use llvm_sys::{
+    core::{
+        LLVMGetLinkage, LLVMGetMDKindIDInContext, LLVMGetNamedMetadataNumOperands,
+        LLVMGetNamedMetadataOperands, LLVMGlobalGetValueType, LLVMGlobalSetMetadata,
+        LLVMIsDeclaration, LLVMValueAsMetadata,
+    },
+    debuginfo::{
+        LLVMCreateDIBuilder, LLVMDIBuilderCreateArrayType, LLVMDIBuilderCreateBasicType,
+        LLVMDIBuilderCreateExpression, LLVMDIBuilderCreateGlobalVariableExpression,
+        LLVMDIBuilderFinalize, LLVMDIBuilderGetOrCreateSubrange, LLVMDIFlagZero,
+        LLVMDIScopeGetFile, LLVMDisposeDIBuilder,
+    },
+    prelude::{LLVMContextRef, LLVMDIBuilderRef, LLVMMetadataRef, LLVMModuleRef, LLVMValueRef},
+    target::{LLVMABISizeOfType, LLVMGetModuleDataLayout},
+    LLVMLinkage,
+};
+use tracing::debug;
+
+use super::{
+    iter::IterModuleGlobals as _, section_name, symbol_name, types::ir::global_variable_debug_info,
+};
+
+/// Sections whose globals are either not described by DATASEC entries or need their real type:
+/// maps, struct_ops maps and the metadata libbpf reads itself.
+const SKIPPED_SECTIONS: &[&str] = &[
+    ".maps",
+    "maps",
+    ".struct_ops",
+    ".struct_ops.link",
+    "license",
+    "version",
+];
+
+/// Makes sure every global that ends up in a data section is described in BTF.
+///
+/// The BPF backend only emits a DATASEC entry for globals with debug info attached, so globals
+/// without it (typically statics moved to a custom section with `#[link_section]`) are missing
+/// from BTF and their sections can't be loaded. Such globals get an `u8` array type of their size
+/// synthesized. So do the globals whose debug info type doesn't match their size, as the loader
+/// would reject the resulting DATASEC; their types are replaced, and described in the messages
+/// returned.
+///
+/// Does nothing if the module has no debug info.
+pub unsafe fn fixup_btf_datasec(context: LLVMContextRef, module: LLVMModuleRef) -> Vec<String> {
+    let Some(unit) = compile_unit(module) else {
+        return vec!["no debug info found, not fixing up BTF DATASEC entries".to_owned()];
+    };
+    let file = LLVMDIScopeGetFile(unit);
+    let data_layout = LLVMGetModuleDataLayout(module);
+    let dbg_kind = LLVMGetMDKindIDInContext(context, c"dbg".as_ptr(), 3);
+
+    let builder = LLVMCreateDIBuilder(module);
+    let u8_type = LLVMDIBuilderCreateBasicType(
+        builder,
+        c"u8".as_ptr(),
+        2,
+        8,
+        DW_ATE_unsigned.0.into(),
+        LLVMDIFlagZero,
+    );
+
+    let mut mismatches = Vec::new();
+    for global in module.globals_iter() {
+        let name = symbol_name(global);
+        let linkage = LLVMGetLinkage(global);
+        if name.starts_with("llvm.")
+            || LLVMIsDeclaration(global) != 0
+            // private globals are anonymous constants, eg string literals
+            || linkage == LLVMLinkage::LLVMPrivateLinkage
+            || !is_btf_identifier(name)
+            || section_name(global).is_some_and(|section| SKIPPED_SECTIONS.contains(&section))
+        {
+            continue;
+        }
+
+        let size = LLVMABISizeOfType(data_layout, LLVMGlobalGetValueType(global));
+        let variables = global_variable_debug_info(context, global);
+        if variables.is_empty() {
+            debug!("synthesizing BTF for {name}: u8[{size}]");
+            add_byte_array_debug_info(builder, (unit, file), global, name, size, u8_type, dbg_kind);
+            continue;
+        }
+        for mut variable in variables {
+            if let Some(bits) = variable.type_size_in_bits() {
+                if bits != size * 8 {
+                    mismatches.push(format!(
+                        "the BTF type of {name} is {} bytes but the variable is {size} bytes, \
+                         replacing it with u8[{size}]",
+                        bits / 8
+                    ));
+                    variable.replace_type(byte_array_type(builder, size, u8_type));
+                }
+            }
+        }
+    }
+
+    LLVMDIBuilderFinalize(builder);
+    LLVMDisposeDIBuilder(builder);
+    mismatches
+}
+
+unsafe fn add_byte_array_debug_info(
+    builder: LLVMDIBuilderRef,
+    (unit, file): (LLVMMetadataRef, LLVMMetadataRef),
+    global: LLVMValueRef,
+    name: &str,
+    size: u64,
+    u8_type: LLVMMetadataRef,
+    dbg_kind: u32,
+) {
+    let ty = byte_array_type(builder, size, u8_type);
+    let expression = LLVMDIBuilderCreateExpression(builder, ptr::null_mut(), 0);
+    let local_to_unit = LLVMGetLinkage(global) == LLVMLinkage::LLVMInternalLinkage;
+    let variable = LLVMDIBuilderCreateGlobalVariableExpression(
+        builder,
+        unit,
+        name.as_ptr().cast(),
+        name.len(),
+        name.as_ptr().cast(),
+        name.len(),
+        file,
+        0,
+        ty,
+        local_to_unit.into(),
+        expression,
+        ptr::null_mut(),
+        0,
+    );
+    LLVMGlobalSetMetadata(global, dbg_kind, variable);
+}
+
+unsafe fn byte_array_type(
+    builder: LLVMDIBuilderRef,
+    size: u64,
+    u8_type: LLVMMetadataRef,
+) -> LLVMMetadataRef {
+    let mut subscripts = [LLVMDIBuilderGetOrCreateSubrange(builder, 0, size as i64)];
+    LLVMDIBuilderCreateArrayType(
+        builder,
+        size * 8,
+        8,
+        u8_type,
+        subscripts.as_mut_ptr(),
+        subscripts.len() as u32,
+    )
+}
+
+/// Returns the first compile unit of `module`, if it has debug info.
+pub(super) unsafe fn compile_unit(module: LLVMModuleRef) -> Option<LLVMMetadataRef> {
+    let name = c"llvm.dbg.cu";
+    let count = LLVMGetNamedMetadataNumOperands(module, name.as_ptr());
+    if count == 0 {
+        return None;
+    }
+    let mut units = vec![ptr::null_mut(); count as usize];
+    LLVMGetNamedMetadataOperands(module, name.as_ptr(), units.as_mut_ptr());
+    units.first().map(|unit| LLVMValueAsMetadata(*unit))
+}
+
+/// BTF variable names must be valid C identifiers.
+fn is_btf_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use llvm_sys::core::{
+        LLVMContextCreate, LLVMContextDispose, LLVMDisposeModule, LLVMGetNamedGlobal,
+    };
+
+    use super::*;
+    use crate::llvm::{parse_ir, verify_module};
+
+    #[test]
+    fn test_fixup_btf_datasec() {
+        const IR: &str = r#"
+target triple = "bpfel"
+
+@BAD = global i64 0, align 8, !dbg !3
+@RAW = global [3 x i8] zeroinitializer, section ".data.raw"
+@MAP = global [4 x i32] zeroinitializer, section ".maps"
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !1, producer: "rustc", emissionKind: FullDebug)
+!1 = !DIFile(filename: "prog.rs", directory: "/src")
+!2 = !{i32 2, !"Debug Info Version", i32 3}
+!3 = !DIGlobalVariableExpression(var: !4, expr: !DIExpression())
+!4 = distinct !DIGlobalVariable(name: "BAD", scope: !0, file: !1, line: 1, type: !5, isLocal: false, isDefinition: true)
+!5 = !DIBasicType(name: "u32", size: 32, encoding: DW_ATE_unsigned)
+"#;
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, IR).unwrap();
+            assert_eq!(
+                fixup_btf_datasec(context, module),
+                ["the BTF type of BAD is 4 bytes but the variable is 8 bytes, replacing it with \
+                  u8[8]"]
+            );
+            verify_module(module).unwrap();
+            let type_sizes = |name: &std::ffi::CStr| {
+                let global = LLVMGetNamedGlobal(module, name.as_ptr());
+                global_variable_debug_info(context, global)
+                    .iter()
+                    .map(|variable| variable.type_size_in_bits())
+                    .collect::<Vec<_>>()
+            };
+            // the mismatched type is replaced, the missing one synthesized, and maps are left
+            // alone
+            assert_eq!(type_sizes(c"BAD"), [Some(64)]);
+            assert_eq!(type_sizes(c"RAW"), [Some(24)]);
+            assert!(type_sizes(c"MAP").is_empty());
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+}
@@ -1,25 +1,26 @@
 use std::{
     borrow::Cow,
-    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    collections::{HashMap, HashSet},
     ffi::c_char,
-    hash::Hasher,
     ptr,
 };
 
-use gimli::{DW_TAG_pointer_type, DW_TAG_structure_type, DW_TAG_variant_part};
+use gimli::{
+    DW_TAG_enumeration_type, DW_TAG_pointer_type, DW_TAG_structure_type, DW_TAG_union_type,
+    DW_TAG_variant_part,
+};
 use llvm_sys::{core::*, debuginfo::*, prelude::*};
-use tracing::{span, trace, warn, Level};
+use tracing::trace;
 
 use super::types::{
     di::DIType,
     ir::{Function, MDNode, Metadata, Value},
 };
-use crate::llvm::{iter::*, types::di::DISubprogram};
-
-// KSYM_NAME_LEN from linux kernel intentionally set
-// to lower value found accross kernel versions to ensure
-// backward compatibility
-const MAX_KSYM_NAME_LEN: usize = 128;
+use crate::{
+    btf::{sanitize_type_name, strip_flavor},
+    glob,
+    llvm::{iter::*, symbol_name, types::di::DISubprogram},
+};
 
 pub struct DISanitizer {
     context: LLVMContextRef,
@@ -27,35 +28,93 @@ pub struct DISanitizer {
     builder: LLVMDIBuilderRef,
     visited_nodes: HashSet<u64>,
     replace_operands: HashMap<u64, LLVMMetadataRef>,
-    skipped_types: Vec<String>,
+    skipped_types: Vec<SkippedType>,
+    // the index in `skipped_types` of the type of each skipped node
+    skipped_nodes: HashMap<u64, usize>,
+    keep_types: Vec<String>,
+    kept_types: Vec<LLVMMetadataRef>,
 }
 
-// Sanitize Rust type names to be valid C type names.
-fn sanitize_type_name<T: AsRef<str>>(name: T) -> String {
-    let n: String = name
-        .as_ref()
-        .chars()
-        .map(|ch| {
-            // Characters which are valid in C type names (alphanumeric and `_`).
-            if matches!(ch, '0'..='9' | 'A'..='Z' | 'a'..='z' | '_') {
-                ch.to_string()
-            } else {
-                format!("_{:X}_", ch as u32)
-            }
-        })
-        .collect();
-
-    // we trim type name if it is too long
-    if n.len() > MAX_KSYM_NAME_LEN {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(n.as_bytes());
-        let hash = format!("{:x}", hasher.finish());
-        // leave space for underscore
-        let trim = MAX_KSYM_NAME_LEN - hash.len() - 1;
-        return format!("{}_{hash}", &n[..trim]);
-    }
+/// The result of [`DISanitizer::run`].
+pub struct SanitizedDebugInfo {
+    /// The types whose debug info had to be skipped.
+    pub skipped_types: Vec<SkippedType>,
+    /// The composite types matching [`DISanitizer::keep_types`], to be passed to
+    /// [`super::keep_btf_types`].
+    pub kept_types: Vec<LLVMMetadataRef>,
+}
 
-    n
+/// A type whose debug info was skipped because BTF can't describe it, eg a data-carrying enum.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkippedType {
+    /// The name of the type, eg `core::option::Option<u32>`.
+    pub name: String,
+    /// Where the type is defined, as `file:line`.
+    pub location: String,
+    /// The exported programs and globals whose debug info references the type.
+    pub referenced_by: Vec<String>,
+}
+
+// BPF can't load or store anything aligned to more than 8 bytes.
+const MAX_ALIGN_IN_BITS: u32 = 64;
+
+// Returns whether the member `member` of the struct `type_name` of `size` bits aligned to `align`
+// bits can be kept in BTF. Zero-sized members, such as Rust ZSTs like `PhantomData`, take no space
+// and are dropped. Members which aren't byte aligned without being bitfields or which extend past
+// the end of the struct are rejected by the kernel, so they're dropped too. So are members placed
+// at an offset their alignment doesn't allow, as the verifier would reject the accesses to them.
+// The alignment required is capped by the one of the struct, eg when it's packed, and by the
+// widest access BPF can make.
+fn keep_member(type_name: &str, size: u64, align: u32, member: &DIType) -> bool {
+    let name = member
+        .name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or(Cow::Borrowed("(anon)"));
+    let offset = member.offset_in_bits() as u64;
+    let member_size = member.size_in_bits();
+    let bitfield = member.flags() & LLVMDIFlagBitField != 0;
+    if member_size == 0 {
+        trace!("dropping zero-sized member {name} of {type_name}");
+        return false;
+    }
+    if !bitfield && !offset.is_multiple_of(8) {
+        trace!(
+            "dropping member {name} of {type_name}, its offset of {offset} bits isn't byte aligned"
+        );
+        return false;
+    }
+    if size != 0 && offset + member_size > size {
+        trace!(
+            "dropping member {name} of {type_name}, it ends at bit {} past the struct size of {size} bits",
+            offset + member_size
+        );
+        return false;
+    }
+    let member_align = member.align_in_bits();
+    if !bitfield && member_align != 0 {
+        let required = match align {
+            0 => member_align.min(MAX_ALIGN_IN_BITS),
+            align => member_align.min(align).min(MAX_ALIGN_IN_BITS),
+        };
+        if !offset.is_multiple_of(required.into()) {
+            trace!(
+                "dropping member {name} of {type_name}, its offset of {} bytes isn't aligned to {} \
+                 bytes",
+                offset / 8,
+                required / 8
+            );
+            return false;
+        }
+    }
+    if member_align > MAX_ALIGN_IN_BITS {
+        // BTF doesn't record alignment, so the member stays at its offset and only the accesses
+        // to it may need to be split
+        trace!(
+            "member {name} of {type_name} is aligned to {} bytes",
+            member_align / 8
+        );
+    }
+    true
 }
 
 impl DISanitizer {
@@ -67,10 +126,41 @@ impl DISanitizer {
             visited_nodes: HashSet::new(),
             replace_operands: HashMap::new(),
             skipped_types: Vec::new(),
+            skipped_nodes: HashMap::new(),
+            keep_types: Vec::new(),
+            kept_types: Vec::new(),
+        }
+    }
+
+    /// Sets the glob patterns of the structs, unions and enums to keep in BTF even if nothing
+    /// references them by the time the code is generated. The patterns are matched against the
+    /// path of the types, eg `my_crate::module::Type`.
+    pub fn keep_types(mut self, patterns: &[String]) -> Self {
+        self.keep_types = patterns.to_vec();
+        self
+    }
+
+    // Records the composite type `value_id` if it matches the types to keep. Must be called before
+    // the type is renamed.
+    fn keep_if_matching(&mut self, value_id: u64, di_composite_type: &DICompositeType) {
+        if self.keep_types.is_empty() || di_composite_type.flags() & LLVMDIFlagFwdDecl != 0 {
+            return;
+        }
+        let Some(name) = di_composite_type.qualified_name() else {
+            return;
+        };
+        if self
+            .keep_types
+            .iter()
+            .any(|pattern| glob::matches(pattern, &name))
+        {
+            trace!("keeping the BTF of {name}");
+            self.kept_types
+                .push(unsafe { LLVMValueAsMetadata(value_id as LLVMValueRef) });
         }
     }
 
-    fn visit_mdnode(&mut self, mdnode: MDNode) {
+    fn visit_mdnode(&mut self, value_id: u64, mdnode: MDNode) {
         match mdnode.try_into().expect("MDNode is not Metadata") {
             Metadata::DICompositeType(mut di_composite_type) => {
                 #[allow(clippy::single_match)]
@@ -80,7 +170,10 @@ impl DISanitizer {
                         let names = match di_composite_type.name() {
                             Some(name) => {
                                 let original_name = name.to_string_lossy().to_string();
-                                let sanitized_name = sanitize_type_name(&original_name);
+                                // flavored shadow types keep their own layout, but are named
+                                // after the kernel type they shadow
+                                let sanitized_name =
+                                    sanitize_type_name(strip_flavor(&original_name));
 
                                 Some((original_name, sanitized_name))
                             }
@@ -127,7 +220,25 @@ impl DISanitizer {
                                             trace!(
                                                 "found data carrying enum {name} ({filename}:{line}), not emitting the debug info for it"
                                             );
-                                            self.skipped_types.push(name);
+                                            // generic enums instantiated by several crates
+                                            // have one node per crate
+                                            let location = format!("{filename}:{line}");
+                                            let index =
+                                                match self.skipped_types.iter().position(|t| {
+                                                    t.name == name && t.location == location
+                                                }) {
+                                                    Some(index) => index,
+                                                    None => {
+                                                        self.skipped_types.push(SkippedType {
+                                                            name,
+                                                            location,
+                                                            referenced_by: Vec::new(),
+                                                        });
+                                                        self.skipped_types.len() - 1
+                                                    }
+                                                };
+                                            let _: Option<usize> =
+                                                self.skipped_nodes.insert(value_id, index);
 
                                             is_data_carrying_enum = true;
                                             break;
@@ -167,9 +278,20 @@ impl DISanitizer {
                                 _ => {}
                             }
                         }
+                        if !is_data_carrying_enum {
+                            self.keep_if_matching(value_id, &di_composite_type);
+                        }
+                        let type_name = names
+                            .as_ref()
+                            .map_or("(anon)", |(original_name, _)| original_name.as_str());
+                        let size = di_composite_type.size_in_bits();
+                        let align = di_composite_type.align_in_bits();
+                        let count = members.len();
+                        members.retain(|member| keep_member(type_name, size, align, member));
+                        let dropped_members = members.len() != count;
                         if is_data_carrying_enum {
                             di_composite_type.replace_elements(MDNode::empty(self.context));
-                        } else if !members.is_empty() {
+                        } else if !members.is_empty() || dropped_members {
                             members.sort_by_cached_key(|di_type| di_type.offset_in_bits());
                             let sorted_elements =
                                 MDNode::with_elements(self.context, members.as_mut_slice());
@@ -187,6 +309,9 @@ impl DISanitizer {
                                 .unwrap();
                         }
                     }
+                    DW_TAG_union_type | DW_TAG_enumeration_type => {
+                        self.keep_if_matching(value_id, &di_composite_type)
+                    }
                     _ => (),
                 }
             }
@@ -214,76 +339,78 @@ impl DISanitizer {
         }
     }
 
-    // navigate the tree of LLVMValueRefs (DFS-pre-order)
-    fn visit_item(&mut self, mut item: Item) {
-        let value_ref = item.value_ref();
-        let value_id = item.value_id();
-
-        let item_span = span!(Level::TRACE, "item", value_id);
-        let _enter = item_span.enter();
-        trace!(?item, value = ?value_ref, "visiting item");
-
-        let value = match (value_ref, &item) {
-            // An operand with no value is valid and means that the operand is
-            // not set
-            (v, Item::Operand { .. }) if v.is_null() => return,
-            (v, _) if !v.is_null() => Value::new(v),
-            // All other items should have values
-            (_, item) => panic!("{item:?} has no value"),
-        };
-
-        if let Item::Operand(operand) = &mut item {
-            // When we have an operand to replace, we must do so regardless of whether we've already
-            // seen its value or not, since the same value can appear as an operand in multiple
-            // nodes in the tree.
-            if let Some(new_metadata) = self.replace_operands.get(&value_id) {
-                operand.replace(unsafe { LLVMMetadataAsValue(self.context, *new_metadata) })
-            }
-        }
-
-        let first_visit = self.visited_nodes.insert(value_id);
-        if !first_visit {
-            trace!("already visited");
-            return;
-        }
-
-        if let Value::MDNode(mdnode) = value.clone() {
-            self.visit_mdnode(mdnode)
-        }
+    // Navigates the debug info reachable from `root`, depth first in pre-order. The walk uses an
+    // explicit stack, as the metadata graphs of big modules are too deep to recurse.
+    //
+    // Only metadata nodes can be reached more than once, so they're the only items remembered as
+    // visited. Globals and functions are all visited as roots by `run`, and instructions through
+    // the function they belong to, so the operands of instructions which aren't metadata, the
+    // initializers of globals and the values referenced by metadata are skipped.
+    fn visit_item(&mut self, root: Item) {
+        let mut stack = vec![root];
+        while let Some(mut item) = stack.pop() {
+            let value_ref = item.value_ref();
+            let value_id = item.value_id();
+            trace!(?item, value = ?value_ref, "visiting item");
+
+            let value = match (value_ref, &item) {
+                // An operand with no value is valid and means that the operand is
+                // not set
+                (v, Item::Operand { .. }) if v.is_null() => continue,
+                (v, _) if !v.is_null() => Value::new(v),
+                // All other items should have values
+                (_, item) => panic!("{item:?} has no value"),
+            };
 
-        if let Some(operands) = value.operands() {
-            for (index, operand) in operands.enumerate() {
-                self.visit_item(Item::Operand(Operand {
-                    parent: value_ref,
-                    value: operand,
-                    index: index as u32,
-                }))
+            if let Item::Operand(operand) = &mut item {
+                // When we have an operand to replace, we must do so regardless of whether we've
+                // already seen its value or not, since the same value can appear as an operand in
+                // multiple nodes in the tree.
+                if let Some(new_metadata) = self.replace_operands.get(&value_id) {
+                    operand.replace(unsafe { LLVMMetadataAsValue(self.context, *new_metadata) })
+                }
             }
-        }
 
-        if let Some(entries) = value.metadata_entries() {
-            for (index, (metadata, kind)) in entries.iter().enumerate() {
-                let metadata_value = unsafe { LLVMMetadataAsValue(self.context, metadata) };
-                self.visit_item(Item::MetadataEntry(metadata_value, kind, index));
+            let first_child = stack.len();
+            match (&value, &item) {
+                (Value::MDNode(mdnode), _) => {
+                    if !self.visited_nodes.insert(value_id) {
+                        trace!(value_id, "already visited");
+                        continue;
+                    }
+                    self.visit_mdnode(value_id, mdnode.clone());
+                    push_operands(&mut stack, value_ref, &value, |_| true);
+                }
+                (_, Item::Operand(_)) => continue,
+                (_, Item::Instruction(_)) => {
+                    // the metadata arguments of the debug intrinsics
+                    push_operands(&mut stack, value_ref, &value, |operand| unsafe {
+                        !LLVMIsAMetadataAsValue(operand).is_null()
+                    });
+                }
+                _ => {}
             }
-        }
 
-        // If an item has sub items that are not operands nor metadata entries, we need to visit
-        // those too.
-        if let Value::Function(fun) = value {
-            for param in fun.params() {
-                self.visit_item(Item::FunctionParam(param));
+            if let Some(entries) = value.metadata_entries() {
+                for (index, (metadata, kind)) in entries.iter().enumerate() {
+                    let metadata_value = unsafe { LLVMMetadataAsValue(self.context, metadata) };
+                    stack.push(Item::MetadataEntry(metadata_value, kind, index));
+                }
             }
 
-            for basic_block in fun.basic_blocks() {
-                for instruction in basic_block.instructions_iter() {
-                    self.visit_item(Item::Instruction(instruction));
+            if let Value::Function(fun) = &value {
+                for basic_block in fun.basic_blocks() {
+                    stack.extend(basic_block.instructions_iter().map(Item::Instruction));
                 }
             }
+
+            // pop the children in order
+            stack[first_child..].reverse();
         }
     }
 
-    pub fn run(mut self, exported_symbols: &HashSet<Cow<'static, str>>) {
+    /// Sanitizes the debug info of the module.
+    pub fn run(mut self, exported_symbols: &HashSet<Cow<'static, str>>) -> SanitizedDebugInfo {
         let module = self.module;
 
         self.replace_operands = self.fix_subprogram_linkage(exported_symbols);
@@ -299,14 +426,61 @@ impl DISanitizer {
             self.visit_item(Item::Function(function));
         }
 
-        if !self.skipped_types.is_empty() {
-            warn!(
-                "debug info was not emitted for the following types: {}",
-                self.skipped_types.join(", ")
-            );
+        unsafe { LLVMDisposeDIBuilder(self.builder) };
+        if !self.skipped_nodes.is_empty() {
+            self.find_references(exported_symbols);
+        }
+        SanitizedDebugInfo {
+            skipped_types: self.skipped_types,
+            kept_types: self.kept_types,
         }
+    }
 
-        unsafe { LLVMDisposeDIBuilder(self.builder) };
+    // Fills `referenced_by` of the skipped types. The sanitizing visit only reaches each node
+    // once, so the nodes reachable from every exported symbol are walked again. This only happens
+    // when some types were skipped.
+    fn find_references(&mut self, exported_symbols: &HashSet<Cow<'static, str>>) {
+        let module = self.module;
+        for root in module.functions_iter().chain(module.globals_iter()) {
+            let name = symbol_name(root);
+            if !exported_symbols.contains(name) || unsafe { LLVMIsDeclaration(root) } != 0 {
+                continue;
+            }
+            let mut visited = HashSet::new();
+            let mut referenced = HashSet::new();
+            let mut stack = vec![root];
+            while let Some(value_ref) = stack.pop() {
+                if value_ref.is_null() || !visited.insert(value_ref as u64) {
+                    continue;
+                }
+                if let Some(index) = self.skipped_nodes.get(&(value_ref as u64)) {
+                    let _: bool = referenced.insert(*index);
+                }
+                let value = Value::new(value_ref);
+                if let Some(operands) = value.operands() {
+                    stack.extend(operands);
+                }
+                if let Some(entries) = value.metadata_entries() {
+                    stack.extend(entries.iter().map(|(metadata, _kind)| unsafe {
+                        LLVMMetadataAsValue(self.context, metadata)
+                    }));
+                }
+                if let Value::Function(fun) = value {
+                    stack.extend(fun.params());
+                    for basic_block in fun.basic_blocks() {
+                        stack.extend(basic_block.instructions_iter());
+                    }
+                }
+            }
+            for index in referenced {
+                self.skipped_types[index]
+                    .referenced_by
+                    .push(name.to_owned());
+            }
+        }
+        for skipped in &mut self.skipped_types {
+            skipped.referenced_by.sort();
+        }
     }
 
     // Make it so that only exported symbols (programs marked as #[no_mangle]) get BTF
@@ -333,7 +507,11 @@ impl DISanitizer {
             .functions_iter()
             .map(|value| unsafe { Function::from_value_ref(value) })
         {
-            if export_symbols.contains(function.name()) {
+            // declarations, eg of kfuncs, keep their subprogram so the BTF backend emits them
+            // with linkage=extern
+            if export_symbols.contains(function.name())
+                || unsafe { LLVMIsDeclaration(function.value_ref) } != 0
+            {
                 continue;
             }
 
@@ -411,7 +589,6 @@ enum Item {
     GlobalVariable(LLVMValueRef),
     GlobalAlias(LLVMValueRef),
     Function(LLVMValueRef),
-    FunctionParam(LLVMValueRef),
     Instruction(LLVMValueRef),
     Operand(Operand),
     MetadataEntry(LLVMValueRef, u32, usize),
@@ -437,13 +614,33 @@ impl Operand {
     }
 }
 
+// Pushes the operands of `value` accepted by `filter` on `stack`.
+fn push_operands(
+    stack: &mut Vec<Item>,
+    parent: LLVMValueRef,
+    value: &Value,
+    filter: impl Fn(LLVMValueRef) -> bool,
+) {
+    let Some(operands) = value.operands() else {
+        return;
+    };
+    for (index, operand) in operands.enumerate() {
+        if operand.is_null() || filter(operand) {
+            stack.push(Item::Operand(Operand {
+                parent,
+                value: operand,
+                index: index as u32,
+            }));
+        }
+    }
+}
+
 impl Item {
     fn value_ref(&self) -> LLVMValueRef {
         match self {
             Item::GlobalVariable(value)
             | Item::GlobalAlias(value)
             | Item::Function(value)
-            | Item::FunctionParam(value)
             | Item::Instruction(value)
             | Item::Operand(Operand { value, .. })
             | Item::MetadataEntry(value, _, _) => *value,
@@ -454,44 +651,3 @@ impl Item {
         self.value_ref() as u64
     }
 }
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn test_strip_generics() {
-        let name = "MyStruct<u64>";
-        assert_eq!(sanitize_type_name(name), "MyStruct_3C_u64_3E_");
-
-        let name = "MyStruct<u64, u64>";
-        assert_eq!(sanitize_type_name(name), "MyStruct_3C_u64_2C__20_u64_3E_");
-
-        let name = "my_function<aya_bpf::BpfContext>";
-        assert_eq!(
-            sanitize_type_name(name),
-            "my_function_3C_aya_bpf_3A__3A_BpfContext_3E_"
-        );
-
-        let name = "my_function<aya_bpf::BpfContext, aya_log_ebpf::WriteToBuf>";
-        assert_eq!(
-            sanitize_type_name(name),
-            "my_function_3C_aya_bpf_3A__3A_BpfContext_2C__20_aya_log_ebpf_3A__3A_WriteToBuf_3E_"
-        );
-
-        let name = "PerfEventArray<[u8; 32]>";
-        assert_eq!(
-            sanitize_type_name(name),
-            "PerfEventArray_3C__5B_u8_3B__20_32_5D__3E_"
-        );
-
-        let name = "my_function<aya_bpf::this::is::a::very::long::namespace::BpfContext, aya_log_ebpf::this::is::a::very::long::namespace::WriteToBuf>";
-        let san = sanitize_type_name(name);
-
-        assert_eq!(san.len(), 128);
-        assert_eq!(
-            san,
-            "my_function_3C_aya_bpf_3A__3A_this_3A__3A_is_3A__3A_a_3A__3A_very_3A__3A_long_3A__3A_namespace_3A__3A_BpfContex_94e4085604b3142f"
-        );
-    }
-}
@@ -0,0 +1,105 @@
+//! Detection of the constructs of code compiled for a non-BPF target which the BPF backend can't
+//! generate code for.
+
+use llvm_sys::{
+    core::{
+        LLVMGetCalledValue, LLVMIsACallInst, LLVMIsAFunction, LLVMIsAInlineAsm, LLVMIsDeclaration,
+    },
+    prelude::{LLVMModuleRef, LLVMValueRef},
+};
+
+use super::{
+    iter::{IterBasicBlocks as _, IterInstructions as _, IterModuleFunctions as _},
+    symbol_name,
+};
+
+/// The prefixes of the intrinsics specific to a target other than BPF, eg `llvm.x86.`.
+const TARGET_INTRINSIC_PREFIXES: &[&str] = &[
+    "aarch64",
+    "amdgcn",
+    "arm",
+    "hexagon",
+    "loongarch",
+    "mips",
+    "nvvm",
+    "ppc",
+    "r600",
+    "riscv",
+    "s390",
+    "spv",
+    "ve",
+    "wasm",
+    "x86",
+    "xcore",
+];
+
+/// The stack probe of rustc, defined with inline asm on some hosts and never called by BPF code.
+const PROBESTACK: &str = "__rust_probestack";
+
+/// Returns the defined functions of `module` which use a construct the BPF backend can't generate
+/// code for, with a description of the construct: inline asm, or a call to an intrinsic of
+/// another target.
+pub unsafe fn non_bpf_constructs(module: LLVMModuleRef) -> Vec<(String, String)> {
+    let mut constructs = Vec::new();
+    for function in module.functions_iter() {
+        if LLVMIsDeclaration(function) != 0 {
+            continue;
+        }
+        let name = symbol_name(function);
+        if name == PROBESTACK {
+            continue;
+        }
+        let mut found = Vec::new();
+        for block in function.basic_blocks_iter() {
+            for instruction in block.instructions_iter() {
+                if let Some(construct) = non_bpf_construct(instruction) {
+                    if !found.contains(&construct) {
+                        found.push(construct);
+                    }
+                }
+            }
+        }
+        constructs.extend(
+            found
+                .into_iter()
+                .map(|construct| (name.to_owned(), construct)),
+        );
+    }
+    constructs
+}
+
+unsafe fn non_bpf_construct(instruction: LLVMValueRef) -> Option<String> {
+    if LLVMIsACallInst(instruction).is_null() {
+        return None;
+    }
+    let callee = LLVMGetCalledValue(instruction);
+    if !LLVMIsAInlineAsm(callee).is_null() {
+        return Some("inline asm".to_owned());
+    }
+    if LLVMIsAFunction(callee).is_null() {
+        return None;
+    }
+    let callee = symbol_name(callee);
+    is_target_intrinsic(callee).then(|| format!("the target intrinsic `{callee}`"))
+}
+
+// Returns whether `name` is an intrinsic specific to a target other than BPF.
+fn is_target_intrinsic(name: &str) -> bool {
+    name.strip_prefix("llvm.")
+        .and_then(|name| name.split_once('.'))
+        .is_some_and(|(target, _)| TARGET_INTRINSIC_PREFIXES.contains(&target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_target_intrinsic() {
+        assert!(is_target_intrinsic("llvm.x86.sse2.pause"));
+        assert!(is_target_intrinsic("llvm.aarch64.isb"));
+        assert!(!is_target_intrinsic("llvm.memcpy.p0i8.p0i8.i64"));
+        assert!(!is_target_intrinsic("llvm.bpf.pseudo"));
+        assert!(!is_target_intrinsic("x86.helper"));
+    }
+}
@@ -0,0 +1,353 @@
+use std::{ffi::CString, ptr};
+
+use llvm_sys::{
+    core::{
+        LLVMAddFunction, LLVMAddGlobal, LLVMArrayType, LLVMBuildAtomicRMW, LLVMBuildCall2,
+        LLVMBuildInBoundsGEP2, LLVMConstInt, LLVMConstNull, LLVMCreateBuilderInContext,
+        LLVMDisposeBuilder, LLVMFunctionType, LLVMGetBasicBlockTerminator,
+        LLVMGetCurrentDebugLocation2, LLVMGetEntryBasicBlock, LLVMGetEnumAttributeAtIndex,
+        LLVMGetEnumAttributeKindForName, LLVMGetInstructionOpcode, LLVMGetNamedFunction,
+        LLVMGetNamedGlobal, LLVMGlobalGetValueType, LLVMInt32TypeInContext, LLVMInt64TypeInContext,
+        LLVMIsAAllocaInst, LLVMIsDeclaration, LLVMPositionBuilderAtEnd, LLVMPositionBuilderBefore,
+        LLVMSetAlignment, LLVMSetCurrentDebugLocation2, LLVMSetInitializer, LLVMSetSection,
+        LLVMVoidTypeInContext,
+    },
+    debuginfo::{LLVMDIBuilderCreateDebugLocation, LLVMGetSubprogram},
+    prelude::{LLVMBuilderRef, LLVMContextRef, LLVMModuleRef, LLVMTypeRef, LLVMValueRef},
+    LLVMAtomicOrdering, LLVMAtomicRMWBinOp, LLVMAttributeFunctionIndex, LLVMOpcode,
+};
+
+use super::{
+    iter::{IterBasicBlocks as _, IterInstructions as _, IterModuleFunctions as _},
+    symbol_name,
+};
+use crate::InstrumentFunctions;
+
+/// Called with the id of the function on entry, in [`InstrumentFunctions::Hooks`] mode.
+pub const PROFILE_ENTER: &str = "__bpf_profile_enter";
+/// Called with the id of the function before it returns, in [`InstrumentFunctions::Hooks`] mode.
+pub const PROFILE_EXIT: &str = "__bpf_profile_exit";
+/// The `u64` array in `.bss` holding the number of calls of each function, in
+/// [`InstrumentFunctions::Counters`] mode.
+pub const PROFILE_COUNTERS: &str = "__bpf_profile_counters";
+
+/// Instruments the functions defined in `module` as `mode` says. Functions marked
+/// `alwaysinline` are left alone, as they're meant to disappear into their callers.
+///
+/// Returns the names of the functions instrumented. The id each function is instrumented with is
+/// its index.
+pub unsafe fn instrument_functions(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    mode: InstrumentFunctions,
+) -> Result<Vec<String>, String> {
+    let always_inline = LLVMGetEnumAttributeKindForName(c"alwaysinline".as_ptr(), 12);
+    let functions: Vec<_> = module
+        .functions_iter()
+        .filter(|&function| {
+            let name = symbol_name(function);
+            LLVMIsDeclaration(function) == 0
+                && !name.starts_with("llvm.")
+                && name != PROFILE_ENTER
+                && name != PROFILE_EXIT
+                && LLVMGetEnumAttributeAtIndex(function, LLVMAttributeFunctionIndex, always_inline)
+                    .is_null()
+        })
+        .collect();
+    if functions.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let i32_type = LLVMInt32TypeInContext(context);
+    let i64_type = LLVMInt64TypeInContext(context);
+    let builder = Builder(LLVMCreateBuilderInContext(context));
+    match mode {
+        InstrumentFunctions::Hooks => {
+            let mut params = [i32_type];
+            let hook_type = LLVMFunctionType(
+                LLVMVoidTypeInContext(context),
+                params.as_mut_ptr(),
+                params.len() as u32,
+                0,
+            );
+            let enter = hook(module, PROFILE_ENTER, hook_type)?;
+            let exit = hook(module, PROFILE_EXIT, hook_type)?;
+            for (id, &function) in functions.iter().enumerate() {
+                let mut args = [LLVMConstInt(i32_type, id as u64, 0)];
+                position_at_entry(context, builder.0, function);
+                let _: LLVMValueRef = LLVMBuildCall2(
+                    builder.0,
+                    hook_type,
+                    enter,
+                    args.as_mut_ptr(),
+                    args.len() as u32,
+                    c"".as_ptr(),
+                );
+                for block in function.basic_blocks_iter() {
+                    let terminator = LLVMGetBasicBlockTerminator(block);
+                    if terminator.is_null()
+                        || LLVMGetInstructionOpcode(terminator) != LLVMOpcode::LLVMRet
+                    {
+                        continue;
+                    }
+                    LLVMPositionBuilderBefore(builder.0, terminator);
+                    set_location(context, builder.0, function);
+                    let _: LLVMValueRef = LLVMBuildCall2(
+                        builder.0,
+                        hook_type,
+                        exit,
+                        args.as_mut_ptr(),
+                        args.len() as u32,
+                        c"".as_ptr(),
+                    );
+                }
+            }
+        }
+        InstrumentFunctions::Counters => {
+            let name = CString::new(PROFILE_COUNTERS).unwrap();
+            if !LLVMGetNamedGlobal(module, name.as_ptr()).is_null() {
+                return Err(format!("`{PROFILE_COUNTERS}` is already defined"));
+            }
+            let counters_type = LLVMArrayType(i64_type, functions.len() as u32);
+            let counters = LLVMAddGlobal(module, counters_type, name.as_ptr());
+            LLVMSetInitializer(counters, LLVMConstNull(counters_type));
+            LLVMSetSection(counters, c".bss".as_ptr());
+            LLVMSetAlignment(counters, 8);
+            for (id, &function) in functions.iter().enumerate() {
+                position_at_entry(context, builder.0, function);
+                let mut indices = [
+                    LLVMConstInt(i32_type, 0, 0),
+                    LLVMConstInt(i32_type, id as u64, 0),
+                ];
+                let counter = LLVMBuildInBoundsGEP2(
+                    builder.0,
+                    counters_type,
+                    counters,
+                    indices.as_mut_ptr(),
+                    indices.len() as u32,
+                    c"".as_ptr(),
+                );
+                let _: LLVMValueRef = LLVMBuildAtomicRMW(
+                    builder.0,
+                    LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpAdd,
+                    counter,
+                    LLVMConstInt(i64_type, 1, 0),
+                    LLVMAtomicOrdering::LLVMAtomicOrderingMonotonic,
+                    0,
+                );
+            }
+        }
+    }
+
+    Ok(functions
+        .into_iter()
+        .map(|function| symbol_name(function).to_owned())
+        .collect())
+}
+
+struct Builder(LLVMBuilderRef);
+
+impl Drop for Builder {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeBuilder(self.0) }
+    }
+}
+
+// Returns the hook `name`, declaring it if the module doesn't define it.
+unsafe fn hook(
+    module: LLVMModuleRef,
+    name: &str,
+    hook_type: LLVMTypeRef,
+) -> Result<LLVMValueRef, String> {
+    let c_name = CString::new(name).unwrap();
+    let function = LLVMGetNamedFunction(module, c_name.as_ptr());
+    if function.is_null() {
+        return Ok(LLVMAddFunction(module, c_name.as_ptr(), hook_type));
+    }
+    if LLVMGlobalGetValueType(function) != hook_type {
+        return Err(format!(
+            "`{name}` must be declared as `void {name}(u32 id)`"
+        ));
+    }
+    Ok(function)
+}
+
+// Positions `builder` after the allocas at the start of `function`.
+unsafe fn position_at_entry(
+    context: LLVMContextRef,
+    builder: LLVMBuilderRef,
+    function: LLVMValueRef,
+) {
+    let entry = LLVMGetEntryBasicBlock(function);
+    LLVMSetCurrentDebugLocation2(builder, ptr::null_mut());
+    match entry
+        .instructions_iter()
+        .find(|&instruction| LLVMIsAAllocaInst(instruction).is_null())
+    {
+        Some(instruction) => LLVMPositionBuilderBefore(builder, instruction),
+        None => LLVMPositionBuilderAtEnd(builder, entry),
+    }
+    set_location(context, builder, function);
+}
+
+// Calls in functions with debug info must have a location. Positioning the builder takes the
+// location of the instruction it's positioned at, so only fall back to a location in the
+// subprogram of `function` when that instruction has none.
+unsafe fn set_location(context: LLVMContextRef, builder: LLVMBuilderRef, function: LLVMValueRef) {
+    let subprogram = LLVMGetSubprogram(function);
+    if !subprogram.is_null() && LLVMGetCurrentDebugLocation2(builder).is_null() {
+        let location = LLVMDIBuilderCreateDebugLocation(context, 0, 0, subprogram, ptr::null_mut());
+        LLVMSetCurrentDebugLocation2(builder, location);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use llvm_sys::core::{LLVMContextCreate, LLVMContextDispose, LLVMDisposeModule};
+
+    use super::*;
+    use crate::llvm::{ir_to_string, parse_ir, verify_module};
+
+    const IR: &str = r#"
+target triple = "bpfel"
+
+declare i64 @helper(i64)
+
+define internal i64 @inlined(i64 %x) alwaysinline {
+  ret i64 %x
+}
+
+define internal i64 @callee(i64 %x) {
+entry:
+  %slot = alloca i64
+  store i64 %x, ptr %slot
+  %bad = icmp eq i64 %x, 0
+  br i1 %bad, label %error, label %ok
+
+error:
+  ret i64 -1
+
+ok:
+  %y = call i64 @helper(i64 %x)
+  ret i64 %y
+}
+
+define i64 @prog(i64 %x) {
+  %y = call i64 @inlined(i64 %x)
+  %z = call i64 @callee(i64 %y)
+  ret i64 %z
+}
+"#;
+
+    #[test]
+    fn test_instrument_functions_hooks() {
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, IR).unwrap();
+            let instrumented =
+                instrument_functions(context, module, InstrumentFunctions::Hooks).unwrap();
+            assert_eq!(instrumented, ["callee", "prog"]);
+            verify_module(module).unwrap();
+            let ir = ir_to_string(module);
+            assert!(
+                ir.contains(&format!("declare void @{PROFILE_ENTER}(i32)")),
+                "{ir}"
+            );
+            assert!(
+                ir.contains(&format!("declare void @{PROFILE_EXIT}(i32)")),
+                "{ir}"
+            );
+            // the entry hook goes after the allocas, the exit hook before every return
+            assert!(
+                ir.contains(&format!(
+                    "%slot = alloca i64, align 8\n  call void @{PROFILE_ENTER}(i32 0)\n"
+                )),
+                "{ir}"
+            );
+            assert!(
+                ir.contains(&format!(
+                    "error:\n  call void @{PROFILE_EXIT}(i32 0)\n  ret i64 -1\n"
+                )),
+                "{ir}"
+            );
+            assert!(
+                ir.contains(&format!("call void @{PROFILE_EXIT}(i32 0)\n  ret i64 %y\n")),
+                "{ir}"
+            );
+            assert_eq!(
+                ir.matches(&format!("call void @{PROFILE_ENTER}(i32 1)"))
+                    .count(),
+                1
+            );
+            assert_eq!(
+                ir.matches(&format!("call void @{PROFILE_EXIT}(i32 1)"))
+                    .count(),
+                1
+            );
+            // neither the alwaysinline function nor the declaration is instrumented
+            assert_eq!(
+                ir.matches(&format!("call void @{PROFILE_ENTER}")).count(),
+                2
+            );
+            assert_eq!(ir.matches(&format!("call void @{PROFILE_EXIT}")).count(), 3);
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+
+    #[test]
+    fn test_instrument_functions_counters() {
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, IR).unwrap();
+            let instrumented =
+                instrument_functions(context, module, InstrumentFunctions::Counters).unwrap();
+            assert_eq!(instrumented, ["callee", "prog"]);
+            verify_module(module).unwrap();
+            let ir = ir_to_string(module);
+            assert!(
+                ir.contains(&format!(
+                    "@{PROFILE_COUNTERS} = global [2 x i64] zeroinitializer, section \".bss\", \
+                     align 8"
+                )),
+                "{ir}"
+            );
+            assert_eq!(ir.matches("atomicrmw add").count(), 2, "{ir}");
+            assert_eq!(ir.matches("i64 1 monotonic").count(), 2, "{ir}");
+            assert!(!ir.contains(PROFILE_ENTER), "{ir}");
+
+            // instrumenting again would clobber the counters
+            assert_eq!(
+                instrument_functions(context, module, InstrumentFunctions::Counters),
+                Err(format!("`{PROFILE_COUNTERS}` is already defined"))
+            );
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+
+    #[test]
+    fn test_instrument_functions_hook_signature() {
+        const IR: &str = r#"
+target triple = "bpfel"
+
+declare void @__bpf_profile_enter(i64)
+
+define i64 @prog(i64 %x) {
+  ret i64 %x
+}
+"#;
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, IR).unwrap();
+            assert_eq!(
+                instrument_functions(context, module, InstrumentFunctions::Hooks),
+                Err(format!(
+                    "`{PROFILE_ENTER}` must be declared as `void {PROFILE_ENTER}(u32 id)`"
+                ))
+            );
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::ptr;
+
+use llvm_sys::{
+    core::{
+        LLVMAddGlobal, LLVMConstNull, LLVMGetMDKindIDInContext, LLVMGlobalSetMetadata,
+        LLVMInt8TypeInContext, LLVMSetGlobalConstant, LLVMSetInitializer, LLVMSetLinkage,
+    },
+    debuginfo::{
+        LLVMCreateDIBuilder, LLVMDIBuilderCreateExpression,
+        LLVMDIBuilderCreateGlobalVariableExpression, LLVMDIBuilderFinalize, LLVMDIScopeGetFile,
+        LLVMDisposeDIBuilder,
+    },
+    prelude::{LLVMContextRef, LLVMMetadataRef, LLVMModuleRef},
+    LLVMLinkage,
+};
+
+use super::datasec::compile_unit;
+
+/// The prefix of the globals anchoring the types kept by [`keep_btf_types`]. The dot keeps them
+/// from clashing with Rust or C symbols.
+const ANCHOR_PREFIX: &str = "__bpf_linker_btf_keep.";
+
+/// Makes the BPF backend emit BTF for the debug info types `types`, even if nothing references
+/// them anymore.
+///
+/// The backend emits the type of every global variable with debug info, but only emits a VAR and a
+/// DATASEC entry for the globals with a linkage BTF can describe. Each type gets an
+/// `available_externally` global of its type: such globals are never emitted in the object, so
+/// only their type ends up in BTF. They must be added once optimization is done, as it removes
+/// them.
+///
+/// Does nothing if the module has no debug info.
+pub unsafe fn keep_btf_types(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    types: &[LLVMMetadataRef],
+) {
+    let Some(unit) = compile_unit(module) else {
+        return;
+    };
+    let file = LLVMDIScopeGetFile(unit);
+    let dbg_kind = LLVMGetMDKindIDInContext(context, c"dbg".as_ptr(), 3);
+    let i8_type = LLVMInt8TypeInContext(context);
+
+    let builder = LLVMCreateDIBuilder(module);
+    for (index, ty) in types.iter().enumerate() {
+        let name = format!("{ANCHOR_PREFIX}{index}\0");
+        let global = LLVMAddGlobal(module, i8_type, name.as_ptr().cast());
+        LLVMSetInitializer(global, LLVMConstNull(i8_type));
+        LLVMSetGlobalConstant(global, 1);
+        LLVMSetLinkage(global, LLVMLinkage::LLVMAvailableExternallyLinkage);
+
+        let name = &name[..name.len() - 1];
+        let expression = LLVMDIBuilderCreateExpression(builder, ptr::null_mut(), 0);
+        let variable = LLVMDIBuilderCreateGlobalVariableExpression(
+            builder,
+            unit,
+            name.as_ptr().cast(),
+            name.len(),
+            name.as_ptr().cast(),
+            name.len(),
+            file,
+            0,
+            *ty,
+            0,
+            expression,
+            ptr::null_mut(),
+            0,
+        );
+        LLVMGlobalSetMetadata(global, dbg_kind, variable);
+    }
+    LLVMDIBuilderFinalize(builder);
+    LLVMDisposeDIBuilder(builder);
+}
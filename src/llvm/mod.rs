@@ -1,69 +1,150 @@
+pub(crate) mod bitcode;
+mod bpf_loop;
+mod datasec;
 mod di;
+mod host;
+mod instrument;
 mod iter;
+mod keep_types;
+mod pinning;
+mod trap;
 mod types;
+mod unroll;
 
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ffi::{c_uchar, c_void, CStr, CString},
     os::raw::c_char,
     ptr, slice, str,
+    sync::Mutex,
 };
 
-pub use di::DISanitizer;
-use iter::{IterModuleFunctions, IterModuleGlobalAliases, IterModuleGlobals};
+pub use bpf_loop::{convert_loops_to_bpf_loop, LOOP_CANONICALIZATION_PASSES};
+pub use datasec::fixup_btf_datasec;
+pub use di::{DISanitizer, SanitizedDebugInfo, SkippedType};
+pub use host::non_bpf_constructs;
+pub use instrument::{instrument_functions, PROFILE_COUNTERS};
+use iter::{
+    IterBasicBlocks, IterInstructions, IterModuleFunctions, IterModuleGlobalAliases,
+    IterModuleGlobals,
+};
+pub use keep_types::keep_btf_types;
 use libc::c_char as libc_char;
 use llvm_sys::{
-    bit_reader::LLVMParseBitcodeInContext2,
+    analysis::{LLVMVerifierFailureAction, LLVMVerifyModule},
+    bit_reader::{LLVMGetBitcodeModuleInContext2, LLVMParseBitcodeInContext2},
+    bit_writer::LLVMWriteBitcodeToMemoryBuffer,
     core::{
-        LLVMCreateMemoryBufferWithMemoryRange, LLVMDisposeMemoryBuffer, LLVMDisposeMessage,
-        LLVMGetDiagInfoDescription, LLVMGetDiagInfoSeverity, LLVMGetEnumAttributeKindForName,
-        LLVMGetMDString, LLVMGetModuleInlineAsm, LLVMGetTarget, LLVMGetValueName2,
-        LLVMModuleCreateWithNameInContext, LLVMPrintModuleToFile, LLVMRemoveEnumAttributeAtIndex,
-        LLVMSetLinkage, LLVMSetModuleInlineAsm2, LLVMSetVisibility,
+        LLVMAddGlobal, LLVMAliasGetAliasee, LLVMAppendModuleInlineAsm, LLVMCloneModule,
+        LLVMConstArray, LLVMConstPointerCast, LLVMConstStringInContext,
+        LLVMContextGetDiagnosticContext, LLVMContextGetDiagnosticHandler,
+        LLVMContextSetDiagnosticHandler, LLVMCountParams, LLVMCreateMemoryBufferWithMemoryRange,
+        LLVMDeleteGlobal, LLVMDisposeMemoryBuffer, LLVMDisposeMessage, LLVMDisposeModule,
+        LLVMGetBufferSize, LLVMGetBufferStart, LLVMGetDebugLocColumn, LLVMGetDebugLocFilename,
+        LLVMGetDebugLocLine, LLVMGetDiagInfoDescription, LLVMGetDiagInfoSeverity,
+        LLVMGetEnumAttributeAtIndex, LLVMGetEnumAttributeKindForName, LLVMGetFirstUse,
+        LLVMGetInitializer, LLVMGetLinkage, LLVMGetMDString, LLVMGetModuleInlineAsm,
+        LLVMGetNamedGlobal, LLVMGetNumOperands, LLVMGetOperand, LLVMGetSection, LLVMGetTarget,
+        LLVMGetValueName2, LLVMGetVersion, LLVMInt8TypeInContext, LLVMIsAConstant,
+        LLVMIsAConstantExpr, LLVMIsAFunction, LLVMIsAGlobalValue, LLVMIsAGlobalVariable,
+        LLVMIsDeclaration, LLVMModuleCreateWithNameInContext, LLVMPointerType,
+        LLVMPrintModuleToFile, LLVMPrintModuleToString, LLVMPrintTypeToString,
+        LLVMRemoveEnumAttributeAtIndex, LLVMSetGlobalConstant, LLVMSetInitializer, LLVMSetLinkage,
+        LLVMSetModuleInlineAsm2, LLVMSetSection, LLVMSetTarget, LLVMSetValueName2,
+        LLVMSetVisibility, LLVMTypeOf,
     },
-    debuginfo::LLVMStripModuleDebugInfo,
+    debuginfo::{LLVMGetSubprogram, LLVMStripModuleDebugInfo},
+    disassembler::{LLVMCreateDisasm, LLVMDisasmDispose, LLVMDisasmInstruction},
     error::{
         LLVMDisposeErrorMessage, LLVMGetErrorMessage, LLVMGetErrorTypeId, LLVMGetStringErrorTypeId,
     },
     linker::LLVMLinkModules2,
     object::{
-        LLVMCreateBinary, LLVMDisposeBinary, LLVMDisposeSectionIterator, LLVMGetSectionContents,
-        LLVMGetSectionName, LLVMGetSectionSize, LLVMMoveToNextSection,
-        LLVMObjectFileCopySectionIterator, LLVMObjectFileIsSectionIteratorAtEnd,
+        LLVMCreateBinary, LLVMDisposeBinary, LLVMDisposeSectionIterator, LLVMDisposeSymbolIterator,
+        LLVMGetSectionContents, LLVMGetSectionName, LLVMGetSectionSize, LLVMGetSymbolAddress,
+        LLVMGetSymbolName, LLVMGetSymbolSize, LLVMMoveToContainingSection, LLVMMoveToNextSection,
+        LLVMMoveToNextSymbol, LLVMObjectFileCopySectionIterator, LLVMObjectFileCopySymbolIterator,
+        LLVMObjectFileIsSectionIteratorAtEnd, LLVMObjectFileIsSymbolIteratorAtEnd,
     },
-    prelude::{LLVMContextRef, LLVMDiagnosticInfoRef, LLVMModuleRef, LLVMValueRef},
+    prelude::{LLVMContextRef, LLVMDiagnosticInfoRef, LLVMModuleRef, LLVMTypeRef, LLVMValueRef},
     support::LLVMParseCommandLineOptions,
     target::{
-        LLVMInitializeBPFAsmParser, LLVMInitializeBPFAsmPrinter, LLVMInitializeBPFDisassembler,
-        LLVMInitializeBPFTarget, LLVMInitializeBPFTargetInfo, LLVMInitializeBPFTargetMC,
+        LLVMByteOrder, LLVMByteOrdering, LLVMDisposeTargetData, LLVMInitializeBPFAsmParser,
+        LLVMInitializeBPFAsmPrinter, LLVMInitializeBPFDisassembler, LLVMInitializeBPFTarget,
+        LLVMInitializeBPFTargetInfo, LLVMInitializeBPFTargetMC, LLVMSetModuleDataLayout,
     },
     target_machine::{
-        LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetMachine,
-        LLVMGetTargetFromTriple, LLVMRelocMode, LLVMTargetMachineEmitToFile, LLVMTargetMachineRef,
-        LLVMTargetRef,
+        LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetDataLayout,
+        LLVMCreateTargetMachine, LLVMDisposeTargetMachine, LLVMGetTargetFromTriple,
+        LLVMGetTargetMachineTriple, LLVMRelocMode, LLVMTargetMachineEmitToFile,
+        LLVMTargetMachineEmitToMemoryBuffer, LLVMTargetMachineRef, LLVMTargetRef,
     },
     transforms::pass_builder::{
-        LLVMCreatePassBuilderOptions, LLVMDisposePassBuilderOptions, LLVMRunPasses,
+        LLVMCreatePassBuilderOptions, LLVMDisposePassBuilderOptions, LLVMPassBuilderOptionsRef,
+        LLVMPassBuilderOptionsSetLicmMssaNoAccForPromotionCap,
+        LLVMPassBuilderOptionsSetLoopInterleaving, LLVMPassBuilderOptionsSetLoopVectorization,
+        LLVMPassBuilderOptionsSetSLPVectorization, LLVMRunPasses,
     },
     LLVMAttributeFunctionIndex, LLVMLinkage, LLVMVisibility,
 };
+pub use pinning::pin_maps;
 use tracing::{debug, error};
+pub use trap::rewrite_traps;
+use types::ir::{global_variable_debug_info, Function};
+pub use unroll::mark_loops_for_unrolling;
 
-use crate::OptLevel;
+use crate::{glob, CodeModel, OptLevel, PassOptions, RelocModel};
 
-pub unsafe fn init<T: AsRef<str>>(args: &[T], overview: &str) {
-    LLVMInitializeBPFTarget();
-    LLVMInitializeBPFTargetMC();
-    LLVMInitializeBPFTargetInfo();
-    LLVMInitializeBPFAsmPrinter();
-    LLVMInitializeBPFAsmParser();
-    LLVMInitializeBPFDisassembler();
+/// Initializes the BPF target and parses the LLVM command line `args`, whose first element is the
+/// program name. Returns an error naming the argument if one sets an option already set to
+/// another value.
+///
+/// LLVM options are global to the process and each can only be given once, so the arguments
+/// parsed by earlier calls are skipped, and stay in effect. LLVM exits the process on invalid
+/// arguments, which [`check_llvm_options`](crate::check_llvm_options) checks beforehand.
+pub unsafe fn init<T: AsRef<str>>(args: &[T], overview: &str) -> Result<(), String> {
+    init_target();
+
+    let mut parsed = PARSED.lock().unwrap();
+    let mut new = Vec::new();
+    for arg in args.iter().skip(1).map(AsRef::as_ref) {
+        if parsed.iter().chain(&new).any(|parsed| parsed == arg) {
+            continue;
+        }
+        if let Some(previous) = parsed
+            .iter()
+            .find(|parsed| option_name(parsed) == option_name(arg))
+        {
+            return Err(format!(
+                "'{arg}' conflicts with '{previous}', which the LLVM command line of this process \
+                 was already set to"
+            ));
+        }
+        new.push(arg.to_owned());
+    }
+    if new.is_empty() {
+        return Ok(());
+    }
+    let mut command_line = vec![args.first().map_or("bpf-linker", AsRef::as_ref)];
+    command_line.extend(new.iter().map(String::as_str));
+    parse_command_line(&command_line, overview);
+    parsed.extend(new);
+    Ok(())
+}
+
+// The arguments parsed by `init` so far, without the program name.
+static PARSED: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
-    parse_command_line_options(args, overview);
+/// Returns the arguments of the LLVM command line in effect in the process, as parsed by
+/// [`init`], without the program name.
+pub fn command_line() -> Vec<String> {
+    PARSED.lock().unwrap().clone()
 }
 
-unsafe fn parse_command_line_options<T: AsRef<str>>(args: &[T], overview: &str) {
+/// Parses the LLVM command line `args`, whose first element is the program name. LLVM exits the
+/// process if they are invalid.
+pub unsafe fn parse_command_line<T: AsRef<str>>(args: &[T], overview: &str) {
     let c_args = args
         .iter()
         .map(|s| CString::new(s.as_ref()).unwrap())
@@ -73,6 +154,54 @@ unsafe fn parse_command_line_options<T: AsRef<str>>(args: &[T], overview: &str)
     LLVMParseCommandLineOptions(c_ptrs.len() as i32, c_ptrs.as_ptr(), overview.as_ptr());
 }
 
+/// Initializes the BPF target.
+pub unsafe fn init_target() {
+    LLVMInitializeBPFTarget();
+    LLVMInitializeBPFTargetMC();
+    LLVMInitializeBPFTargetInfo();
+    LLVMInitializeBPFAsmPrinter();
+    LLVMInitializeBPFAsmParser();
+    LLVMInitializeBPFDisassembler();
+}
+
+/// Returns the names of the options in the output of `--help-list-hidden`, whose lines look like
+/// `  --unroll-threshold=<uint>  - The cost threshold for loop unrolling`.
+pub fn option_names(listing: &str) -> HashSet<String> {
+    listing
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix('-'))
+        .filter_map(|option| {
+            let name = option.trim_start_matches('-');
+            let end = name
+                .find(|c: char| c == '=' || c == '<' || c.is_whitespace())
+                .unwrap_or(name.len());
+            (end > 0).then(|| name[..end].to_owned())
+        })
+        .collect()
+}
+
+/// Returns whether the command line argument `arg` is one of the `known` options.
+pub fn is_known_option(known: &HashSet<String>, arg: &str) -> bool {
+    let Some(option) = arg.strip_prefix('-') else {
+        // LLVM doesn't take positional arguments
+        return false;
+    };
+    let option = option.trim_start_matches('-');
+    let name = option.split('=').next().unwrap_or_default();
+    // single letter options such as -O take their value without `=`
+    known.contains(name)
+        || known
+            .iter()
+            .any(|known| known.len() == 1 && option.starts_with(known.as_str()))
+}
+
+// Returns the name of the option set by the command line argument `arg`, eg `unroll-threshold`
+// for `--unroll-threshold=5`.
+fn option_name(arg: &str) -> &str {
+    let option = arg.trim_start_matches('-');
+    option.split('=').next().unwrap_or(option)
+}
+
 pub unsafe fn create_module(name: &str, context: LLVMContextRef) -> Option<LLVMModuleRef> {
     let c_name = CString::new(name).unwrap();
     let module = LLVMModuleCreateWithNameInContext(c_name.as_ptr(), context);
@@ -84,10 +213,163 @@ pub unsafe fn create_module(name: &str, context: LLVMContextRef) -> Option<LLVMM
     Some(module)
 }
 
-pub unsafe fn find_embedded_bitcode(
+/// The sections bitcode is embedded in, by order of preference: `.llvmbc` in ELF and COFF objects
+/// built with `-fembed-bitcode` or `-C embed-bitcode`, `__LLVM,__bitcode` in Mach-O ones, and
+/// `.llvm.lto` in fat LTO objects.
+pub const BITCODE_SECTIONS: [&str; 3] = [".llvmbc", "__bitcode", ".llvm.lto"];
+
+/// The sections the codegen options of embedded bitcode are in, in ELF and COFF then Mach-O
+/// objects.
+const CMDLINE_SECTIONS: [&str; 2] = [".llvmcmd", "__cmdline"];
+
+/// Bitcode embedded in an object file.
+pub struct EmbeddedBitcode {
+    /// Contents of the first of [`BITCODE_SECTIONS`] found.
+    pub bitcode: Vec<u8>,
+    /// Contents of the `.llvmcmd` or `__LLVM,__cmdline` section, ie the NUL separated codegen
+    /// options the bitcode was produced with.
+    pub cmdline: Option<Vec<u8>>,
+}
+
+pub unsafe fn find_embedded_bitcode(data: &[u8]) -> Result<Option<EmbeddedBitcode>, String> {
+    // the index in BITCODE_SECTIONS of the section found
+    let mut bitcode: Option<(usize, Vec<u8>)> = None;
+    let mut cmdline = None;
+    let _: Option<()> = find_section(data, |name, _size, contents| {
+        if let Some(index) = BITCODE_SECTIONS.iter().position(|section| *section == name) {
+            if bitcode.as_ref().is_none_or(|(found, _)| index < *found) {
+                bitcode = Some((index, contents()));
+            }
+        } else if cmdline.is_none() && CMDLINE_SECTIONS.contains(&name) {
+            cmdline = Some(contents());
+        }
+        (bitcode.as_ref().is_some_and(|(index, _)| *index == 0) && cmdline.is_some()).then_some(())
+    })?;
+    Ok(bitcode.map(|(_, bitcode)| EmbeddedBitcode { bitcode, cmdline }))
+}
+
+/// The section rustc wraps crate metadata in, eg in the `lib.rmeta` member of rlibs.
+const RUST_METADATA_SECTION: &str = ".rmeta";
+
+/// Returns whether the object file in `data` is a wrapper around Rust crate metadata, which has
+/// no code to link.
+pub unsafe fn is_rust_metadata(data: &[u8]) -> bool {
+    find_section(data, |name, _size, _contents| {
+        (name == RUST_METADATA_SECTION).then_some(())
+    })
+    .is_ok_and(|found| found.is_some())
+}
+
+/// Returns the name and size of every section of the object file in `data`.
+pub unsafe fn section_sizes(data: &[u8]) -> Result<Vec<(String, u64)>, String> {
+    let mut sizes = Vec::new();
+    let _: Option<()> = find_section(data, |name, size, _contents| {
+        sizes.push((name.to_owned(), size));
+        None
+    })?;
+    Ok(sizes)
+}
+
+/// Returns the machine code of the functions named `functions` in the object file in `data`.
+pub unsafe fn function_code(
+    context: LLVMContextRef,
+    data: &[u8],
+    functions: &HashSet<String>,
+) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let buffer_name = CString::new("mem_buffer").unwrap();
+    let buffer = LLVMCreateMemoryBufferWithMemoryRange(
+        data.as_ptr() as *const libc_char,
+        data.len(),
+        buffer_name.as_ptr(),
+        0,
+    );
+
+    let (bin, message) = Message::with(|message| LLVMCreateBinary(buffer, context, message));
+    if bin.is_null() {
+        return Err(message.to_string_lossy());
+    }
+
+    let mut code = Vec::new();
+    let sections = LLVMObjectFileCopySectionIterator(bin);
+    let symbols = LLVMObjectFileCopySymbolIterator(bin);
+    while LLVMObjectFileIsSymbolIteratorAtEnd(bin, symbols) == 0 {
+        let name = LLVMGetSymbolName(symbols);
+        if !name.is_null() {
+            let name = CStr::from_ptr(name).to_string_lossy();
+            if functions.contains(name.as_ref()) {
+                // in relocatable objects the address is the offset in the section
+                let address = LLVMGetSymbolAddress(symbols) as usize;
+                let size = LLVMGetSymbolSize(symbols) as usize;
+                LLVMMoveToContainingSection(sections, symbols);
+                let section_size = LLVMGetSectionSize(sections) as usize;
+                if address + size <= section_size {
+                    let contents = LLVMGetSectionContents(sections) as *const c_uchar;
+                    let contents = slice::from_raw_parts(contents.add(address), size);
+                    code.push((name.into_owned(), contents.to_vec()));
+                }
+            }
+        }
+        LLVMMoveToNextSymbol(symbols);
+    }
+    LLVMDisposeSymbolIterator(symbols);
+    LLVMDisposeSectionIterator(sections);
+    LLVMDisposeBinary(bin);
+    LLVMDisposeMemoryBuffer(buffer);
+
+    Ok(code)
+}
+
+/// Disassembles the BPF machine `code`, returning the size and the text of each instruction.
+/// Instructions which can't be decoded are returned without text, as 8 bytes.
+pub unsafe fn disassemble(
+    big_endian: bool,
+    code: &[u8],
+) -> Result<Vec<(usize, Option<String>)>, String> {
+    let triple = if big_endian { c"bpfeb" } else { c"bpfel" };
+    let disasm = LLVMCreateDisasm(triple.as_ptr(), ptr::null_mut(), 0, None, None);
+    if disasm.is_null() {
+        return Err("the BPF disassembler is not available".to_owned());
+    }
+    let mut instructions = Vec::new();
+    let mut text = [0 as libc_char; 256];
+    let mut offset = 0;
+    while offset < code.len() {
+        let size = LLVMDisasmInstruction(
+            disasm,
+            code.as_ptr().add(offset).cast_mut(),
+            (code.len() - offset) as u64,
+            offset as u64,
+            text.as_mut_ptr(),
+            text.len(),
+        );
+        if size == 0 {
+            let size = 8.min(code.len() - offset);
+            instructions.push((size, None));
+            offset += size;
+        } else {
+            let text = CStr::from_ptr(text.as_ptr()).to_string_lossy();
+            instructions.push((size, Some(text.trim().to_owned())));
+            offset += size;
+        }
+    }
+    LLVMDisasmDispose(disasm);
+    Ok(instructions)
+}
+
+/// A symbol defined in an object file.
+pub struct ObjectSymbol {
+    pub name: String,
+    pub section: String,
+    /// Offset of the symbol in its section.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Returns the named symbols defined in a section of the object file in `data`.
+pub unsafe fn object_symbols(
     context: LLVMContextRef,
     data: &[u8],
-) -> Result<Option<Vec<u8>>, String> {
+) -> Result<Vec<ObjectSymbol>, String> {
     let buffer_name = CString::new("mem_buffer").unwrap();
     let buffer = LLVMCreateMemoryBufferWithMemoryRange(
         data.as_ptr() as *const libc_char,
@@ -98,7 +380,77 @@ pub unsafe fn find_embedded_bitcode(
 
     let (bin, message) = Message::with(|message| LLVMCreateBinary(buffer, context, message));
     if bin.is_null() {
-        return Err(message.as_c_str().unwrap().to_str().unwrap().to_string());
+        return Err(message.to_string_lossy());
+    }
+
+    let mut object_symbols = Vec::new();
+    let sections = LLVMObjectFileCopySectionIterator(bin);
+    let symbols = LLVMObjectFileCopySymbolIterator(bin);
+    while LLVMObjectFileIsSymbolIteratorAtEnd(bin, symbols) == 0 {
+        let name = LLVMGetSymbolName(symbols);
+        if !name.is_null() && *name != 0 {
+            LLVMMoveToContainingSection(sections, symbols);
+            // undefined symbols have no section
+            if LLVMObjectFileIsSectionIteratorAtEnd(bin, sections) == 0 {
+                let section = LLVMGetSectionName(sections);
+                if !section.is_null() {
+                    object_symbols.push(ObjectSymbol {
+                        name: CStr::from_ptr(name).to_string_lossy().into_owned(),
+                        section: CStr::from_ptr(section).to_string_lossy().into_owned(),
+                        offset: LLVMGetSymbolAddress(symbols),
+                        size: LLVMGetSymbolSize(symbols),
+                    });
+                }
+            }
+        }
+        LLVMMoveToNextSymbol(symbols);
+    }
+    LLVMDisposeSymbolIterator(symbols);
+    LLVMDisposeSectionIterator(sections);
+    LLVMDisposeBinary(bin);
+    LLVMDisposeMemoryBuffer(buffer);
+
+    Ok(object_symbols)
+}
+
+/// Returns the contents of the section `name` of the object file in `data`.
+pub unsafe fn section_contents(data: &[u8], name: &str) -> Result<Option<Vec<u8>>, String> {
+    find_section(data, |section, _size, contents| {
+        (section == name).then(contents)
+    })
+}
+
+/// Returns the name and the contents of every section of the object file in `data`, in the order
+/// of the section headers.
+pub unsafe fn object_sections(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut sections = Vec::new();
+    let _: Option<()> = find_section(data, |name, _size, contents| {
+        sections.push((name.to_owned(), contents()));
+        None
+    })?;
+    Ok(sections)
+}
+
+/// Calls `f` with the name, size and a function returning the contents of the sections of the
+/// object file in `data` until it returns `Some`.
+unsafe fn find_section<T>(
+    data: &[u8],
+    mut f: impl FnMut(&str, u64, &dyn Fn() -> Vec<u8>) -> Option<T>,
+) -> Result<Option<T>, String> {
+    let buffer_name = CString::new("mem_buffer").unwrap();
+    let buffer = LLVMCreateMemoryBufferWithMemoryRange(
+        data.as_ptr() as *const libc_char,
+        data.len(),
+        buffer_name.as_ptr(),
+        0,
+    );
+
+    // Given a context, LLVM returns the module of objects embedding bitcode instead of the object
+    // file, whose sections then can't be iterated.
+    let (bin, message) =
+        Message::with(|message| LLVMCreateBinary(buffer, ptr::null_mut(), message));
+    if bin.is_null() {
+        return Err(message.to_string_lossy());
     }
 
     let mut ret = None;
@@ -107,10 +459,13 @@ pub unsafe fn find_embedded_bitcode(
         let name = LLVMGetSectionName(iter);
         if !name.is_null() {
             let name = CStr::from_ptr(name);
-            if name.to_str().unwrap() == ".llvmbc" {
+            let size = LLVMGetSectionSize(iter);
+            let contents = || {
                 let buf = LLVMGetSectionContents(iter);
-                let size = LLVMGetSectionSize(iter) as usize;
-                ret = Some(slice::from_raw_parts(buf as *const c_uchar, size).to_vec());
+                slice::from_raw_parts(buf as *const c_uchar, size as usize).to_vec()
+            };
+            ret = f(&name.to_string_lossy(), size, &contents);
+            if ret.is_some() {
                 break;
             }
         }
@@ -123,13 +478,25 @@ pub unsafe fn find_embedded_bitcode(
     Ok(ret)
 }
 
-#[must_use]
+/// Returns the version of LLVM bpf-linker uses, eg `19.1.7`.
+pub fn version() -> (u32, u32, u32) {
+    let (mut major, mut minor, mut patch) = (0, 0, 0);
+    unsafe { LLVMGetVersion(&mut major, &mut minor, &mut patch) };
+    (major, minor, patch)
+}
+
+/// Links the bitcode in `buffer` into `module`, or returns the errors LLVM reported if the
+/// bitcode can't be read or linked.
+///
+/// The bitcode is loaded lazily: function bodies are only materialized when the IR linker moves
+/// them into `module`. Local and linkonce definitions which nothing references, as well as
+/// linkonce definitions already present in `module` (think generic code instantiated by many
+/// crates), are never materialized, which keeps peak memory usage down when linking large rlibs.
 pub unsafe fn link_bitcode_buffer(
     context: LLVMContextRef,
     module: LLVMModuleRef,
     buffer: &[u8],
-) -> bool {
-    let mut linked = false;
+) -> Result<(), String> {
     let buffer_name = CString::new("mem_buffer").unwrap();
     let buffer = LLVMCreateMemoryBufferWithMemoryRange(
         buffer.as_ptr() as *const libc_char,
@@ -138,15 +505,360 @@ pub unsafe fn link_bitcode_buffer(
         0,
     );
 
-    let mut temp_module = ptr::null_mut();
+    let (linked, errors) = capture_errors(context, || {
+        let mut temp_module = ptr::null_mut();
 
-    if LLVMParseBitcodeInContext2(context, buffer, &mut temp_module) == 0 {
-        linked = LLVMLinkModules2(module, temp_module) == 0;
+        // NB: the lazy module takes ownership of the memory buffer only if it could be read. On
+        // failure LLVM releases the buffer without freeing it, so we must dispose it ourselves.
+        if LLVMGetBitcodeModuleInContext2(context, buffer, &mut temp_module) != 0 {
+            LLVMDisposeMemoryBuffer(buffer);
+            return false;
+        }
+
+        // LLVMLinkModules2 takes ownership of temp_module (and so of the buffer) and disposes it.
+        LLVMLinkModules2(module, temp_module) == 0
+    });
+    if linked {
+        Ok(())
+    } else {
+        Err(errors_message(errors))
     }
+}
+
+/// Parses the bitcode in `buffer` into a fully materialized module, or returns the errors LLVM
+/// reported if the bitcode can't be read.
+pub unsafe fn parse_bitcode(
+    context: LLVMContextRef,
+    buffer: &[u8],
+) -> Result<LLVMModuleRef, String> {
+    let buffer_name = CString::new("mem_buffer").unwrap();
+    let buffer = LLVMCreateMemoryBufferWithMemoryRange(
+        buffer.as_ptr() as *const libc_char,
+        buffer.len(),
+        buffer_name.as_ptr(),
+        0,
+    );
 
+    let mut module = ptr::null_mut();
+    // unlike the lazy readers, the parser doesn't take ownership of the buffer
+    let (ret, errors) = capture_errors(context, || {
+        LLVMParseBitcodeInContext2(context, buffer, &mut module)
+    });
     LLVMDisposeMemoryBuffer(buffer);
+    if ret == 0 {
+        Ok(module)
+    } else {
+        Err(errors_message(errors))
+    }
+}
+
+/// Returns the names of the symbols defined with external linkage by the bitcode in `buffer`, or
+/// the errors LLVM reported if the bitcode can't be read. Function bodies are not materialized.
+pub unsafe fn bitcode_defined_symbols(
+    context: LLVMContextRef,
+    buffer: &[u8],
+) -> Result<Vec<String>, String> {
+    let buffer_name = CString::new("mem_buffer").unwrap();
+    let buffer = LLVMCreateMemoryBufferWithMemoryRange(
+        buffer.as_ptr() as *const libc_char,
+        buffer.len(),
+        buffer_name.as_ptr(),
+        0,
+    );
+
+    let mut module = ptr::null_mut();
+    // NB: as in link_bitcode_buffer, the lazy module owns the buffer only if it could be read.
+    let (ret, errors) = capture_errors(context, || {
+        LLVMGetBitcodeModuleInContext2(context, buffer, &mut module)
+    });
+    if ret != 0 {
+        LLVMDisposeMemoryBuffer(buffer);
+        return Err(errors_message(errors));
+    }
+    let symbols = module
+        .functions_iter()
+        .chain(module.globals_iter())
+        .chain(module.global_aliases_iter())
+        .filter(|value| {
+            LLVMIsDeclaration(*value) == 0
+                && !matches!(
+                    LLVMGetLinkage(*value),
+                    LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+                )
+        })
+        .map(|value| symbol_name(value).to_owned())
+        .collect();
+    LLVMDisposeModule(module);
+    Ok(symbols)
+}
+
+// The errors reported through the diagnostic handler while `capture_errors` runs, and the handler
+// the other diagnostics are forwarded to.
+struct CapturedErrors {
+    errors: Vec<String>,
+    handler: llvm_sys::LLVMDiagnosticHandler,
+    handler_context: *mut c_void,
+}
+
+extern "C" fn capture_error(info: LLVMDiagnosticInfoRef, captured: *mut c_void) {
+    let captured = unsafe { &mut *(captured as *mut CapturedErrors) };
+    match unsafe { LLVMGetDiagInfoSeverity(info) } {
+        llvm_sys::LLVMDiagnosticSeverity::LLVMDSError => {
+            let message = Message {
+                ptr: unsafe { LLVMGetDiagInfoDescription(info) },
+            };
+            captured
+                .errors
+                .push(message.to_string_lossy().trim_end().to_owned());
+        }
+        _ => {
+            if let Some(handler) = captured.handler {
+                handler(info, captured.handler_context)
+            }
+        }
+    }
+}
 
-    linked
+// Runs `f` with the errors LLVM reports through the diagnostic handler of `context` captured
+// rather than handled, since the bitcode reader and the IR linker report why they failed there
+// instead of returning it. Returns the result of `f` along with the errors.
+unsafe fn capture_errors<T>(context: LLVMContextRef, f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    let mut captured = CapturedErrors {
+        errors: Vec::new(),
+        handler: LLVMContextGetDiagnosticHandler(context),
+        handler_context: LLVMContextGetDiagnosticContext(context),
+    };
+    LLVMContextSetDiagnosticHandler(
+        context,
+        Some(capture_error),
+        &mut captured as *mut _ as *mut c_void,
+    );
+    let ret = f();
+    LLVMContextSetDiagnosticHandler(context, captured.handler, captured.handler_context);
+    (ret, captured.errors)
+}
+
+fn errors_message(errors: Vec<String>) -> String {
+    if errors.is_empty() {
+        "unknown error".to_owned()
+    } else {
+        errors.join("; ")
+    }
+}
+
+/// Returns the target triple of the bitcode in `buffer`, or `None` if the bitcode can't be read.
+/// Function bodies are not materialized.
+pub unsafe fn bitcode_target(context: LLVMContextRef, buffer: &[u8]) -> Option<String> {
+    let buffer_name = CString::new("mem_buffer").unwrap();
+    let buffer = LLVMCreateMemoryBufferWithMemoryRange(
+        buffer.as_ptr() as *const libc_char,
+        buffer.len(),
+        buffer_name.as_ptr(),
+        0,
+    );
+
+    let mut module = ptr::null_mut();
+    // NB: as in link_bitcode_buffer, the lazy module owns the buffer only if it could be read.
+    // The errors are left for the linking of the bitcode to report.
+    let (ret, _errors) = capture_errors(context, || {
+        LLVMGetBitcodeModuleInContext2(context, buffer, &mut module)
+    });
+    if ret != 0 {
+        LLVMDisposeMemoryBuffer(buffer);
+        return None;
+    }
+    let target = CStr::from_ptr(LLVMGetTarget(module))
+        .to_string_lossy()
+        .into_owned();
+    LLVMDisposeModule(module);
+    Some(target)
+}
+
+/// Sections of the externs resolved against kernel symbols by the loader.
+const KSYMS_SECTION: &str = ".ksyms";
+
+/// Returns the names of the functions and globals which are used but not defined in `module`.
+///
+/// LLVM intrinsics and externs placed in `.ksyms` are skipped. BPF helpers don't show up here
+/// since they're called by ID rather than through a declaration.
+pub unsafe fn undefined_symbols(module: LLVMModuleRef) -> HashSet<String> {
+    module
+        .functions_iter()
+        .chain(module.globals_iter())
+        .filter(|value| {
+            LLVMIsDeclaration(*value) != 0
+                && !LLVMGetFirstUse(*value).is_null()
+                && section_name(*value) != Some(KSYMS_SECTION)
+        })
+        .map(symbol_name)
+        .filter(|name| !name.starts_with("llvm."))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The number of arguments BPF passes in registers, `r1` to `r5`. There's no stack passing.
+pub const MAX_BPF_ARGS: u32 = 5;
+
+/// Returns the functions of `module` which take more than [`MAX_BPF_ARGS`] arguments, with their
+/// number of arguments: the functions defined, which inlining didn't remove, and the functions
+/// declared and called, eg kfuncs. LLVM intrinsics are skipped as they never become calls.
+pub unsafe fn functions_with_too_many_args(module: LLVMModuleRef) -> Vec<(String, u32)> {
+    module
+        .functions_iter()
+        .filter(|&function| {
+            LLVMIsDeclaration(function) == 0 || !LLVMGetFirstUse(function).is_null()
+        })
+        .filter_map(|function| {
+            let name = symbol_name(function);
+            let args = LLVMCountParams(function);
+            (args > MAX_BPF_ARGS && !name.starts_with("llvm.")).then(|| (name.to_owned(), args))
+        })
+        .collect()
+}
+
+/// Places the declarations named `names` in the `.ksyms` section.
+pub unsafe fn move_to_ksyms(module: LLVMModuleRef, names: &[String]) {
+    let section = CString::new(KSYMS_SECTION).unwrap();
+    for value in module.functions_iter().chain(module.globals_iter()) {
+        if LLVMIsDeclaration(value) != 0 && names.iter().any(|name| name == symbol_name(value)) {
+            LLVMSetSection(value, section.as_ptr());
+        }
+    }
+}
+
+/// Moves the definitions named in `sections` to their section, given as (symbol, section).
+/// Returns the names of the symbols which `module` doesn't define.
+///
+/// Panics if a section contains a NUL byte.
+pub unsafe fn set_symbol_sections(
+    module: LLVMModuleRef,
+    sections: &[(String, String)],
+) -> Vec<String> {
+    let mut missing = Vec::new();
+    for (name, section) in sections {
+        let Some(value) = module
+            .functions_iter()
+            .chain(module.globals_iter())
+            .find(|&value| LLVMIsDeclaration(value) == 0 && symbol_name(value) == name)
+        else {
+            missing.push(name.clone());
+            continue;
+        };
+        let section = CString::new(section.as_str()).unwrap();
+        LLVMSetSection(value, section.as_ptr());
+    }
+    missing
+}
+
+/// Adds the definitions whose name `keep` returns true for to `llvm.used`, so optimizations
+/// never remove them. Returns the names of the symbols added.
+pub unsafe fn keep_symbols(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    keep: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut used = Vec::new();
+    let ptr_type = LLVMPointerType(LLVMInt8TypeInContext(context), 0);
+    for value in module
+        .functions_iter()
+        .chain(module.globals_iter())
+        .chain(module.global_aliases_iter())
+    {
+        let name = symbol_name(value);
+        if !name.starts_with("llvm.") && LLVMIsDeclaration(value) == 0 && keep(name) {
+            kept.push(name.to_owned());
+            used.push(LLVMConstPointerCast(value, ptr_type));
+        }
+    }
+    if used.is_empty() {
+        return kept;
+    }
+
+    // keep what's already in llvm.used
+    let llvm_used = c"llvm.used";
+    let old = LLVMGetNamedGlobal(module, llvm_used.as_ptr());
+    if !old.is_null() {
+        let init = LLVMGetInitializer(old);
+        if !init.is_null() {
+            for i in 0..LLVMGetNumOperands(init) {
+                used.push(LLVMGetOperand(init, i as u32));
+            }
+        }
+        LLVMDeleteGlobal(old);
+    }
+
+    let array = LLVMConstArray(ptr_type, used.as_mut_ptr(), used.len() as u32);
+    let global = LLVMAddGlobal(module, LLVMTypeOf(array), llvm_used.as_ptr());
+    LLVMSetInitializer(global, array);
+    LLVMSetLinkage(global, LLVMLinkage::LLVMAppendingLinkage);
+    LLVMSetSection(global, c"llvm.metadata".as_ptr());
+    kept
+}
+
+/// Returns the names of the definitions aliased by the aliases named in `export_symbols`, looking
+/// through the casts and offsets of the aliasee expressions. The aliasees are internalized like
+/// any other symbol which isn't exported, so they must be kept for the aliases to stay defined.
+pub unsafe fn exported_aliasees(
+    module: LLVMModuleRef,
+    export_symbols: &HashSet<Cow<'static, str>>,
+) -> HashSet<String> {
+    let mut aliasees = HashSet::new();
+    for alias in module.global_aliases_iter() {
+        if !export_symbols.contains(symbol_name(alias)) {
+            continue;
+        }
+        let mut aliasee = LLVMAliasGetAliasee(alias);
+        while !LLVMIsAConstantExpr(aliasee).is_null() {
+            aliasee = LLVMGetOperand(aliasee, 0);
+        }
+        if !LLVMIsAGlobalValue(aliasee).is_null() && LLVMIsDeclaration(aliasee) == 0 {
+            let _: bool = aliasees.insert(symbol_name(aliasee).to_owned());
+        }
+    }
+    aliasees
+}
+
+/// Gives weak linkage to the global definitions named in `names`. Returns the names of the
+/// symbols which `module` doesn't define with external linkage, eg because optimization removed
+/// them.
+pub unsafe fn make_weak(module: LLVMModuleRef, names: &HashSet<String>) -> Vec<String> {
+    let mut missing: HashSet<&str> = names.iter().map(String::as_str).collect();
+    for value in module.functions_iter().chain(module.globals_iter()) {
+        let name = symbol_name(value);
+        if LLVMIsDeclaration(value) == 0
+            && matches!(LLVMGetLinkage(value), LLVMLinkage::LLVMExternalLinkage)
+            && missing.remove(name)
+        {
+            LLVMSetLinkage(value, LLVMLinkage::LLVMWeakAnyLinkage);
+        }
+    }
+    let mut missing: Vec<String> = missing.into_iter().map(str::to_owned).collect();
+    missing.sort();
+    missing
+}
+
+/// Adds a private constant holding `data` to `module`, placed in `section`.
+pub unsafe fn add_section_data(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    name: &str,
+    section: &str,
+    data: &[u8],
+) {
+    let name = CString::new(name).unwrap();
+    let section = CString::new(section).unwrap();
+    let init = LLVMConstStringInContext(
+        context,
+        data.as_ptr() as *const c_char,
+        data.len() as u32,
+        1, // don't null terminate
+    );
+    let global = LLVMAddGlobal(module, LLVMTypeOf(init), name.as_ptr());
+    LLVMSetInitializer(global, init);
+    LLVMSetGlobalConstant(global, 1);
+    LLVMSetLinkage(global, LLVMLinkage::LLVMPrivateLinkage);
+    LLVMSetSection(global, section.as_ptr());
 }
 
 pub unsafe fn target_from_triple(triple: &CStr) -> Result<LLVMTargetRef, String> {
@@ -156,7 +868,7 @@ pub unsafe fn target_from_triple(triple: &CStr) -> Result<LLVMTargetRef, String>
     if ret == 0 {
         Ok(target)
     } else {
-        Err(message.as_c_str().unwrap().to_str().unwrap().to_string())
+        Err(message.to_string_lossy())
     }
 }
 
@@ -170,18 +882,44 @@ pub unsafe fn create_target_machine(
     triple: &str,
     cpu: &str,
     features: &str,
+    code_model: CodeModel,
+    reloc_model: RelocModel,
+    optimize: OptLevel,
 ) -> Option<LLVMTargetMachineRef> {
     let triple = CString::new(triple).unwrap();
     let cpu = CString::new(cpu).unwrap();
     let features = CString::new(features).unwrap();
+    let code_model = match code_model {
+        CodeModel::Default => LLVMCodeModel::LLVMCodeModelDefault,
+        CodeModel::Tiny => LLVMCodeModel::LLVMCodeModelTiny,
+        CodeModel::Small => LLVMCodeModel::LLVMCodeModelSmall,
+        CodeModel::Kernel => LLVMCodeModel::LLVMCodeModelKernel,
+        CodeModel::Medium => LLVMCodeModel::LLVMCodeModelMedium,
+        CodeModel::Large => LLVMCodeModel::LLVMCodeModelLarge,
+    };
+    let reloc_model = match reloc_model {
+        RelocModel::Default => LLVMRelocMode::LLVMRelocDefault,
+        RelocModel::Static => LLVMRelocMode::LLVMRelocStatic,
+        RelocModel::Pic => LLVMRelocMode::LLVMRelocPIC,
+        RelocModel::DynamicNoPic => LLVMRelocMode::LLVMRelocDynamicNoPic,
+    };
+    // like clang, which leaves optimizing for size to the optsize and minsize attributes
+    let optimize = match optimize {
+        OptLevel::No => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+        OptLevel::Less => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+        OptLevel::Default | OptLevel::Size | OptLevel::SizeMin => {
+            LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault
+        }
+        OptLevel::Aggressive => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+    };
     let tm = LLVMCreateTargetMachine(
         target,
         triple.as_ptr(),
         cpu.as_ptr(),
         features.as_ptr(),
-        LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
-        LLVMRelocMode::LLVMRelocDefault,
-        LLVMCodeModel::LLVMCodeModelDefault,
+        optimize,
+        reloc_model,
+        code_model,
     );
     if tm.is_null() {
         None
@@ -190,17 +928,58 @@ pub unsafe fn create_target_machine(
     }
 }
 
+/// Prints the name and description of the features `target` supports for `cpu` to stdout, which
+/// LLVM does when asked for the `help` feature.
+pub unsafe fn print_cpu_features(target: LLVMTargetRef, triple: &str, cpu: &str) {
+    if let Some(tm) = create_target_machine(
+        target,
+        triple,
+        cpu,
+        "+help",
+        CodeModel::Default,
+        RelocModel::Default,
+        OptLevel::Default,
+    ) {
+        LLVMDisposeTargetMachine(tm);
+    }
+}
+
+/// Parses the features printed by [`print_cpu_features`], eg
+/// `  alu32    - Enable ALU32 instructions.`, which come after
+/// `Available features for this target:` and before `Use +feature to enable a feature`.
+pub fn parse_cpu_features(help: &str) -> Vec<(String, String)> {
+    help.lines()
+        .skip_while(|line| !line.starts_with("Available features"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("Use +feature"))
+        .filter_map(|line| {
+            let (name, description) = line.split_once(" - ")?;
+            Some((name.trim().to_owned(), description.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// Sets the triple and data layout of `module` to the ones of `tm`.
+pub unsafe fn set_module_target(module: LLVMModuleRef, tm: LLVMTargetMachineRef) {
+    let triple = LLVMGetTargetMachineTriple(tm);
+    LLVMSetTarget(module, triple);
+    LLVMDisposeMessage(triple);
+    let data_layout = LLVMCreateTargetDataLayout(tm);
+    LLVMSetModuleDataLayout(module, data_layout);
+    LLVMDisposeTargetData(data_layout);
+}
+
+#[allow(clippy::too_many_arguments)]
 pub unsafe fn optimize(
     tm: LLVMTargetMachineRef,
     module: LLVMModuleRef,
     opt_level: OptLevel,
     ignore_inline_never: bool,
+    keep_inline_never: &[String],
     export_symbols: &HashSet<Cow<'static, str>>,
+    merge_constants: bool,
+    pass_options: PassOptions,
 ) -> Result<(), String> {
-    if module_asm_is_probestack(module) {
-        LLVMSetModuleInlineAsm2(module, ptr::null_mut(), 0);
-    }
-
     for sym in module.globals_iter() {
         internalize(sym, symbol_name(sym), export_symbols);
     }
@@ -211,18 +990,23 @@ pub unsafe fn optimize(
     for function in module.functions_iter() {
         let name = symbol_name(function);
         if !name.starts_with("llvm.") {
-            if ignore_inline_never {
+            if ignore_inline_never
+                && !keep_inline_never
+                    .iter()
+                    .any(|pattern| glob::matches(pattern, name))
+            {
                 remove_attribute(function, "noinline");
             }
             internalize(function, name, export_symbols);
         }
     }
 
-    let passes = [
+    let mut passes = vec![
         // NB: "default<_>" must be the first pass in the list, otherwise it will be ignored.
         match opt_level {
-            // Pretty much nothing compiles with -O0 so make it an alias for -O1.
-            OptLevel::No | OptLevel::Less => "default<O1>",
+            // Only inlines the always-inline functions.
+            OptLevel::No => "default<O0>",
+            OptLevel::Less => "default<O1>",
             OptLevel::Default => "default<O2>",
             OptLevel::Aggressive => "default<O3>",
             OptLevel::Size => "default<Os>",
@@ -234,20 +1018,69 @@ pub unsafe fn optimize(
         // for a case which includes DCE only conditionally. Better safe than sorry; include it always.
         "dce",
     ];
+    if let OptLevel::No = opt_level {
+        // The O0 pipeline doesn't remove anything, so drop the internalized functions and globals
+        // which nothing references. Those are typically generic code which doesn't even compile
+        // for BPF.
+        passes.push("globaldce");
+    }
+    if merge_constants {
+        // Merge the constants duplicated across inputs, eg formatting strings, once everything
+        // else ran rather than relying on where the default pipeline schedules constmerge, which
+        // varies with the optimization level and the LLVM version.
+        passes.push("constmerge");
+    }
 
     let passes = passes.join(",");
     debug!("running passes: {passes}");
     let passes = CString::new(passes).unwrap();
+    let PassOptions {
+        loop_interleaving,
+        loop_vectorization,
+        slp_vectorization,
+        licm_promotion,
+    } = pass_options;
     let options = LLVMCreatePassBuilderOptions();
-    let error = LLVMRunPasses(module, passes.as_ptr(), tm, options);
+    LLVMPassBuilderOptionsSetLoopInterleaving(options, loop_interleaving.into());
+    LLVMPassBuilderOptionsSetLoopVectorization(options, loop_vectorization.into());
+    LLVMPassBuilderOptionsSetSLPVectorization(options, slp_vectorization.into());
+    if !licm_promotion {
+        // LICM doesn't promote in loops with more memory accesses than the cap
+        LLVMPassBuilderOptionsSetLicmMssaNoAccForPromotionCap(options, 0);
+    }
+    let ret = run_passes(tm, module, &passes, options);
+    LLVMDisposePassBuilderOptions(options);
+    ret
+}
+
+/// Runs the pass pipeline `passes`, eg [`LOOP_CANONICALIZATION_PASSES`], on `module`.
+pub unsafe fn run_pipeline(
+    tm: LLVMTargetMachineRef,
+    module: LLVMModuleRef,
+    passes: &str,
+) -> Result<(), String> {
+    debug!("running passes: {passes}");
+    let passes = CString::new(passes).unwrap();
+    let options = LLVMCreatePassBuilderOptions();
+    let ret = run_passes(tm, module, &passes, options);
     LLVMDisposePassBuilderOptions(options);
+    ret
+}
+
+unsafe fn run_passes(
+    tm: LLVMTargetMachineRef,
+    module: LLVMModuleRef,
+    passes: &CStr,
+    options: LLVMPassBuilderOptionsRef,
+) -> Result<(), String> {
+    let error = LLVMRunPasses(module, passes.as_ptr(), tm, options);
     // Handle the error and print it to stderr.
     if !error.is_null() {
         let error_type_id = LLVMGetErrorTypeId(error);
         // This is the only error type that exists currently, but there might be more in the future.
         assert_eq!(error_type_id, LLVMGetStringErrorTypeId());
         let error_message = LLVMGetErrorMessage(error);
-        let error_string = CStr::from_ptr(error_message).to_str().unwrap().to_owned();
+        let error_string = CStr::from_ptr(error_message).to_string_lossy().into_owned();
         LLVMDisposeErrorMessage(error_message);
         return Err(error_string);
     }
@@ -260,6 +1093,21 @@ pub unsafe fn strip_debug_info(module: LLVMModuleRef) -> bool {
     LLVMStripModuleDebugInfo(module) != 0
 }
 
+/// Removes the module level asm of `module` if it's the `__rust_probestack` definition, which
+/// doesn't assemble for BPF. Returns whether it was removed.
+pub unsafe fn remove_probestack_asm(module: LLVMModuleRef) -> bool {
+    let probestack = module_asm_is_probestack(module);
+    if probestack {
+        LLVMSetModuleInlineAsm2(module, ptr::null_mut(), 0);
+    }
+    probestack
+}
+
+/// Appends `asm` to the module level asm of `module`.
+pub unsafe fn append_module_asm(module: LLVMModuleRef, asm: &str) {
+    LLVMAppendModuleInlineAsm(module, asm.as_ptr() as *const libc_char, asm.len());
+}
+
 unsafe fn module_asm_is_probestack(module: LLVMModuleRef) -> bool {
     let mut len = 0;
     let ptr = LLVMGetModuleInlineAsm(module, &mut len);
@@ -277,6 +1125,22 @@ fn symbol_name<'a>(value: *mut llvm_sys::LLVMValue) -> &'a str {
     unsafe { str::from_utf8(slice::from_raw_parts(ptr as *const c_uchar, name_len)).unwrap() }
 }
 
+/// Returns the names of the functions defined in `module` that are marked `noinline`, which are
+/// emitted as BPF-to-BPF calls.
+pub unsafe fn noinline_functions(module: LLVMModuleRef) -> Vec<String> {
+    let name = "noinline";
+    let attr_kind = LLVMGetEnumAttributeKindForName(name.as_ptr() as *const c_char, name.len());
+    module
+        .functions_iter()
+        .filter(|function| {
+            LLVMIsDeclaration(*function) == 0
+                && !LLVMGetEnumAttributeAtIndex(*function, LLVMAttributeFunctionIndex, attr_kind)
+                    .is_null()
+        })
+        .map(|function| symbol_name(function).to_owned())
+        .collect()
+}
+
 unsafe fn remove_attribute(function: *mut llvm_sys::LLVMValue, name: &str) {
     let attr_kind = LLVMGetEnumAttributeKindForName(name.as_ptr() as *const c_char, name.len());
     LLVMRemoveEnumAttributeAtIndex(function, LLVMAttributeFunctionIndex, attr_kind);
@@ -288,7 +1152,43 @@ pub unsafe fn write_ir(module: LLVMModuleRef, output: &CStr) -> Result<(), Strin
     if ret == 0 {
         Ok(())
     } else {
-        Err(message.as_c_str().unwrap().to_str().unwrap().to_string())
+        Err(message.to_string_lossy())
+    }
+}
+
+/// Returns the textual IR of `module`.
+pub unsafe fn ir_to_string(module: LLVMModuleRef) -> String {
+    Message {
+        ptr: LLVMPrintModuleToString(module),
+    }
+    .to_string_lossy()
+}
+
+/// Returns `module` as bitcode.
+pub unsafe fn bitcode_to_memory(module: LLVMModuleRef) -> Vec<u8> {
+    let buffer = LLVMWriteBitcodeToMemoryBuffer(module);
+    let data = slice::from_raw_parts(
+        LLVMGetBufferStart(buffer) as *const c_uchar,
+        LLVMGetBufferSize(buffer),
+    )
+    .to_vec();
+    LLVMDisposeMemoryBuffer(buffer);
+    data
+}
+
+/// Runs the LLVM verifier on `module`, returning what it reports if the module is invalid.
+pub unsafe fn verify_module(module: LLVMModuleRef) -> Result<(), String> {
+    let (ret, message) = Message::with(|message| {
+        LLVMVerifyModule(
+            module,
+            LLVMVerifierFailureAction::LLVMReturnStatusAction,
+            message,
+        )
+    });
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(message.to_string_lossy().trim_end().to_owned())
     }
 }
 
@@ -304,7 +1204,418 @@ pub unsafe fn codegen(
     if ret == 0 {
         Ok(())
     } else {
-        Err(message.as_c_str().unwrap().to_str().unwrap().to_string())
+        Err(message.to_string_lossy())
+    }
+}
+
+/// Generates an object file for a clone of `module` in memory, leaving `module` untouched.
+pub unsafe fn codegen_to_memory(
+    tm: LLVMTargetMachineRef,
+    module: LLVMModuleRef,
+    output_type: LLVMCodeGenFileType,
+) -> Result<Vec<u8>, String> {
+    // codegen mutates the module, so it can't be run twice on the same one
+    let module = LLVMCloneModule(module);
+    let mut buffer = ptr::null_mut();
+    let (ret, message) = Message::with(|message| {
+        LLVMTargetMachineEmitToMemoryBuffer(tm, module, output_type, message, &mut buffer)
+    });
+    LLVMDisposeModule(module);
+    if ret != 0 {
+        return Err(message.to_string_lossy());
+    }
+    let data = slice::from_raw_parts(
+        LLVMGetBufferStart(buffer) as *const c_uchar,
+        LLVMGetBufferSize(buffer),
+    )
+    .to_vec();
+    LLVMDisposeMemoryBuffer(buffer);
+    Ok(data)
+}
+
+/// Parses the textual IR `ir` into a module of `context`.
+#[cfg(any(test, feature = "testing"))]
+pub unsafe fn parse_ir(context: LLVMContextRef, ir: &str) -> Result<LLVMModuleRef, String> {
+    use llvm_sys::{
+        core::LLVMCreateMemoryBufferWithMemoryRangeCopy, ir_reader::LLVMParseIRInContext,
+    };
+
+    let buffer_name = CString::new("ir").unwrap();
+    // the buffer is owned by the parser
+    let buffer = LLVMCreateMemoryBufferWithMemoryRangeCopy(
+        ir.as_ptr() as *const libc_char,
+        ir.len(),
+        buffer_name.as_ptr(),
+    );
+    let mut module = ptr::null_mut();
+    let (ret, message) =
+        Message::with(|message| LLVMParseIRInContext(context, buffer, &mut module, message));
+    if ret != 0 {
+        return Err(message.to_string_lossy());
+    }
+    Ok(module)
+}
+
+/// Parses the textual IR `ir` and returns it as bitcode.
+#[cfg(any(test, feature = "testing"))]
+pub unsafe fn ir_to_bitcode(context: LLVMContextRef, ir: &str) -> Result<Vec<u8>, String> {
+    let module = parse_ir(context, ir)?;
+    let data = bitcode_to_memory(module);
+    LLVMDisposeModule(module);
+    Ok(data)
+}
+
+/// Returns whether `tm` generates big endian code.
+pub unsafe fn is_big_endian(tm: LLVMTargetMachineRef) -> bool {
+    let data_layout = LLVMCreateTargetDataLayout(tm);
+    let big_endian = LLVMByteOrder(data_layout) == LLVMByteOrdering::LLVMBigEndian;
+    LLVMDisposeTargetData(data_layout);
+    big_endian
+}
+
+/// Returns the names of the functions defined in `module`.
+pub unsafe fn defined_functions(module: LLVMModuleRef) -> HashSet<String> {
+    module
+        .functions_iter()
+        .filter(|function| LLVMIsDeclaration(*function) == 0)
+        .map(|function| symbol_name(function).to_owned())
+        .collect()
+}
+
+/// Kind of a [`Symbol`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// A function.
+    Function,
+    /// A global variable, eg a map.
+    Global,
+}
+
+/// A function or global variable of a module, as seen by checks and reports which run after
+/// linking.
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The section the symbol is placed in, if any was set explicitly.
+    pub section: Option<String>,
+    /// Whether the symbol has external linkage (ie it wasn't internalized).
+    pub global: bool,
+    /// Whether the symbol is only declared and not defined in the module.
+    pub declaration: bool,
+    /// The IR types of the parameters. Empty for global variables.
+    pub param_types: Vec<String>,
+}
+
+/// Returns the functions and global variables of `module`, skipping LLVM intrinsics.
+pub unsafe fn module_symbols(module: LLVMModuleRef) -> Vec<Symbol> {
+    let symbol = |value: LLVMValueRef, kind: SymbolKind| {
+        let section = section_name(value).map(str::to_owned);
+        let param_types = match kind {
+            SymbolKind::Function => Function::from_value_ref(value)
+                .params()
+                .map(|param| type_to_string(LLVMTypeOf(param)))
+                .collect(),
+            SymbolKind::Global => Vec::new(),
+        };
+        Symbol {
+            name: symbol_name(value).to_owned(),
+            kind,
+            section,
+            global: !matches!(
+                LLVMGetLinkage(value),
+                LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+            ),
+            declaration: LLVMIsDeclaration(value) != 0,
+            param_types,
+        }
+    };
+
+    module
+        .functions_iter()
+        .map(|value| symbol(value, SymbolKind::Function))
+        .chain(
+            module
+                .globals_iter()
+                .map(|value| symbol(value, SymbolKind::Global)),
+        )
+        .filter(|symbol| !symbol.name.starts_with("llvm."))
+        .collect()
+}
+
+/// Returns the names of the functions, global variables and aliases defined in `module`.
+pub unsafe fn defined_symbols(module: LLVMModuleRef) -> HashSet<String> {
+    module
+        .functions_iter()
+        .chain(module.globals_iter())
+        .filter(|&value| LLVMIsDeclaration(value) == 0)
+        .chain(module.global_aliases_iter())
+        .map(|value| symbol_name(value).to_owned())
+        .collect()
+}
+
+/// What the symbol named `name` is in a module, see [`symbol_state`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SymbolState {
+    pub kind: SymbolKind,
+    pub defined: bool,
+    pub internal: bool,
+    /// Whether anything in the module references the symbol.
+    pub used: bool,
+    /// Whether the symbol is a declaration placed in `.ksyms`.
+    pub ksyms: bool,
+}
+
+/// Returns what the function or global variable named `name` is in `module`, None if the module
+/// has no such symbol.
+pub unsafe fn symbol_state(module: LLVMModuleRef, name: &str) -> Option<SymbolState> {
+    let (kind, value) = module
+        .functions_iter()
+        .map(|value| (SymbolKind::Function, value))
+        .chain(
+            module
+                .globals_iter()
+                .map(|value| (SymbolKind::Global, value)),
+        )
+        .find(|&(_, value)| symbol_name(value) == name)?;
+    let defined = LLVMIsDeclaration(value) == 0;
+    Some(SymbolState {
+        kind,
+        defined,
+        internal: matches!(
+            LLVMGetLinkage(value),
+            LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+        ),
+        used: !LLVMGetFirstUse(value).is_null(),
+        ksyms: !defined && section_name(value) == Some(KSYMS_SECTION),
+    })
+}
+
+/// Returns the names of the functions defined in `module` and named in `export_symbols` which
+/// have no `DISubprogram`, the debug info BTF `func_info` is generated from. Modules without
+/// debug info have no BTF to check.
+pub unsafe fn exported_functions_without_debug_info(
+    module: LLVMModuleRef,
+    export_symbols: &HashSet<Cow<'static, str>>,
+) -> Vec<String> {
+    if datasec::compile_unit(module).is_none() {
+        return Vec::new();
+    }
+    let mut names: Vec<String> = module
+        .functions_iter()
+        .filter(|&function| {
+            LLVMIsDeclaration(function) == 0
+                && LLVMGetSubprogram(function).is_null()
+                && export_symbols.contains(symbol_name(function))
+        })
+        .map(|function| symbol_name(function).to_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Returns the number of functions defined in `module` and how many of them have external linkage.
+pub unsafe fn count_defined_functions(module: LLVMModuleRef) -> (usize, usize) {
+    let mut defined = 0;
+    let mut external = 0;
+    for function in module.functions_iter() {
+        if LLVMIsDeclaration(function) != 0 {
+            continue;
+        }
+        defined += 1;
+        if !matches!(
+            LLVMGetLinkage(function),
+            LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+        ) {
+            external += 1;
+        }
+    }
+    (defined, external)
+}
+
+/// Returns the defined functions of `module` with the number of IR instructions of each.
+pub unsafe fn function_sizes(module: LLVMModuleRef) -> HashMap<String, usize> {
+    module
+        .functions_iter()
+        .filter(|function| LLVMIsDeclaration(*function) == 0)
+        .map(|function| {
+            let instructions = function
+                .basic_blocks_iter()
+                .map(|block| block.instructions_iter().count())
+                .sum();
+            (symbol_name(function).to_owned(), instructions)
+        })
+        .collect()
+}
+
+/// Returns the names of the functions defined in `module` by the `(file, line, column)` debug
+/// locations of their instructions, and of their `DISubprogram` at column 0, which is how LLVM
+/// locates the remarks about them. Locations found in several functions, eg of inlined code, are
+/// left out.
+pub unsafe fn functions_by_debug_loc(module: LLVMModuleRef) -> HashMap<(String, u32, u32), String> {
+    let location = |value| {
+        let mut len = 0;
+        let file = LLVMGetDebugLocFilename(value, &mut len);
+        (!file.is_null() && len > 0).then(|| {
+            let file = slice::from_raw_parts(file as *const c_uchar, len as usize);
+            (
+                String::from_utf8_lossy(file).into_owned(),
+                LLVMGetDebugLocLine(value),
+            )
+        })
+    };
+    let mut functions: HashMap<_, Option<&str>> = HashMap::new();
+    for function in module.functions_iter() {
+        if LLVMIsDeclaration(function) != 0 {
+            continue;
+        }
+        let name = symbol_name(function);
+        let locations = location(function)
+            .map(|(file, line)| (file, line, 0))
+            .into_iter();
+        let locations = locations.chain(function.basic_blocks_iter().flat_map(|block| {
+            block.instructions_iter().filter_map(|instruction| {
+                location(instruction)
+                    .map(|(file, line)| (file, line, LLVMGetDebugLocColumn(instruction)))
+            })
+        }));
+        for location in locations {
+            let found = functions.entry(location).or_insert(Some(name));
+            if *found != Some(name) {
+                *found = None;
+            }
+        }
+    }
+    functions
+        .into_iter()
+        .filter_map(|(location, name)| Some((location, name?.to_owned())))
+        .collect()
+}
+
+/// Whether `section` holds map definitions: BTF-defined maps in `.maps`, or legacy maps in
+/// `maps` and `maps/<name>`.
+fn is_map_section(section: &str) -> bool {
+    section == ".maps" || section == "maps" || section.starts_with("maps/")
+}
+
+/// Removes the maps defined in `module` which nothing references anymore, so that loaders don't
+/// create them. Their BTF goes away with them, as BTF is only emitted for the globals left in the
+/// module. Maps in `llvm.used` are kept. Returns the names of the maps removed.
+pub unsafe fn gc_maps(module: LLVMModuleRef) -> Vec<String> {
+    let unused: Vec<_> = module
+        .globals_iter()
+        .filter(|&global| {
+            LLVMIsDeclaration(global) == 0
+                && section_name(global).is_some_and(is_map_section)
+                && LLVMGetFirstUse(global).is_null()
+        })
+        .collect();
+    unused
+        .into_iter()
+        .map(|global| {
+            let name = symbol_name(global).to_owned();
+            LLVMDeleteGlobal(global);
+            name
+        })
+        .collect()
+}
+
+/// Returns the section `value` is explicitly placed in, if any.
+unsafe fn section_name<'a>(value: LLVMValueRef) -> Option<&'a str> {
+    let section = LLVMGetSection(value);
+    (!section.is_null())
+        .then(|| CStr::from_ptr(section).to_str().ok())
+        .flatten()
+        .filter(|section| !section.is_empty())
+}
+
+unsafe fn type_to_string(ty: LLVMTypeRef) -> String {
+    let message = Message {
+        ptr: LLVMPrintTypeToString(ty),
+    };
+    message.to_string_lossy()
+}
+
+/// Prepends `prefix` to the name of every symbol defined in `module` that isn't exported, so that
+/// the same code can be linked into several objects loaded in the same kernel without clashes.
+pub unsafe fn prefix_symbols(context: LLVMContextRef, module: LLVMModuleRef, prefix: &str) {
+    let symbols = module
+        .functions_iter()
+        .chain(module.globals_iter())
+        .chain(module.global_aliases_iter());
+    for value in symbols {
+        let name = symbol_name(value);
+        if name.starts_with("llvm.")
+            || LLVMIsDeclaration(value) != 0
+            || !matches!(
+                LLVMGetLinkage(value),
+                LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+            )
+        {
+            continue;
+        }
+        let new_name = format!("{prefix}{name}");
+        debug!("renaming {name} to {new_name}");
+        rename_symbol(context, value, &new_name, |di_name| {
+            format!("{prefix}{di_name}")
+        });
+    }
+}
+
+/// Renames the symbols defined in `module` as `renames`, given as (old name, new name).
+pub unsafe fn rename_symbols(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    renames: &[(String, String)],
+) -> Result<(), String> {
+    let find = |name: &str| {
+        module
+            .functions_iter()
+            .chain(module.globals_iter())
+            .chain(module.global_aliases_iter())
+            .find(|&value| symbol_name(value) == name)
+    };
+    for (old_name, new_name) in renames {
+        let value = match find(old_name) {
+            Some(value) if LLVMIsDeclaration(value) == 0 => value,
+            _ => return Err(format!("`{old_name}` is not defined")),
+        };
+        if find(new_name).is_some() {
+            return Err(format!(
+                "can't rename `{old_name}` to `{new_name}`, `{new_name}` already exists"
+            ));
+        }
+        debug!("renaming {old_name} to {new_name}");
+        rename_symbol(context, value, new_name, |_| new_name.clone());
+    }
+    Ok(())
+}
+
+/// Renames the symbol `value` to `name`. The debug info of the symbol (the subprogram of a
+/// function or the variables of a global) is renamed with `di_name`, so that BTF stays consistent
+/// with the symbol table.
+unsafe fn rename_symbol(
+    context: LLVMContextRef,
+    value: LLVMValueRef,
+    name: &str,
+    di_name: impl Fn(&str) -> String,
+) {
+    LLVMSetValueName2(value, name.as_ptr() as *const c_char, name.len());
+
+    if !LLVMIsAFunction(value).is_null() {
+        if let Some(mut subprogram) = Function::from_value_ref(value).subprogram(context) {
+            if let Some(old_name) = subprogram.name() {
+                let new_name = di_name(old_name);
+                subprogram.replace_name(context, &new_name).unwrap();
+            }
+        }
+    } else if !LLVMIsAGlobalVariable(value).is_null() {
+        for mut variable in global_variable_debug_info(context, value) {
+            if let Some(old_name) = variable.name() {
+                let new_name = di_name(old_name);
+                variable.replace_name(context, &new_name).unwrap();
+            }
+        }
     }
 }
 
@@ -313,7 +1624,9 @@ pub unsafe fn internalize(
     name: &str,
     export_symbols: &HashSet<Cow<'static, str>>,
 ) {
-    if !name.starts_with("llvm.") && !export_symbols.contains(name) {
+    // declarations, eg of kfuncs, can't be internal: they're resolved by the loader
+    if !name.starts_with("llvm.") && !export_symbols.contains(name) && LLVMIsDeclaration(value) == 0
+    {
         LLVMSetLinkage(value, LLVMLinkage::LLVMInternalLinkage);
         LLVMSetVisibility(value, LLVMVisibility::LLVMDefaultVisibility);
     }
@@ -332,8 +1645,7 @@ pub extern "C" fn diagnostic_handler<T: LLVMDiagnosticHandler>(
         ptr: unsafe { LLVMGetDiagInfoDescription(info) },
     };
     let handler = handler as *mut T;
-    unsafe { &mut *handler }
-        .handle_diagnostic(severity, message.as_c_str().unwrap().to_str().unwrap());
+    unsafe { &mut *handler }.handle_diagnostic(severity, &message.to_string_lossy());
 }
 
 pub extern "C" fn fatal_error(reason: *const c_char) {
@@ -356,6 +1668,13 @@ impl Message {
         let ptr = *ptr;
         (!ptr.is_null()).then(|| unsafe { CStr::from_ptr(ptr) })
     }
+
+    // LLVM messages can quote paths and symbols which aren't valid UTF-8.
+    fn to_string_lossy(&self) -> String {
+        self.as_c_str()
+            .map(|message| message.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
 }
 
 impl Drop for Message {
@@ -375,3 +1694,240 @@ fn mdstring_to_str<'a>(mdstring: LLVMValueRef) -> &'a str {
     let ptr = unsafe { LLVMGetMDString(mdstring, &mut len) };
     unsafe { str::from_utf8(slice::from_raw_parts(ptr as *const c_uchar, len as usize)).unwrap() }
 }
+
+/// Sections holding struct_ops maps. libbpf finds these globals by name and creates a map for
+/// each of them.
+pub const STRUCT_OPS_MAP_SECTIONS: &[&str] = &[".struct_ops", ".struct_ops.link"];
+
+/// Section prefixes of the programs implementing struct_ops members.
+const STRUCT_OPS_PROG_SECTIONS: &[&str] = &["struct_ops/", "struct_ops.s/"];
+
+/// Returns the names of the struct_ops maps defined in `module` and of the programs their members
+/// point to. These must survive internalization even when they're not exported explicitly.
+///
+/// Fails if a member of a struct_ops map points to a function that isn't in a struct_ops program
+/// section, since libbpf would reject such a map at load time.
+pub unsafe fn struct_ops_symbols(module: LLVMModuleRef) -> Result<Vec<String>, String> {
+    let mut symbols = Vec::new();
+    for global in module.globals_iter() {
+        if LLVMIsDeclaration(global) != 0
+            || !section_name(global)
+                .is_some_and(|section| STRUCT_OPS_MAP_SECTIONS.contains(&section))
+        {
+            continue;
+        }
+        let map = symbol_name(global);
+        symbols.push(map.to_owned());
+
+        let mut members = Vec::new();
+        referenced_functions(LLVMGetInitializer(global), &mut members);
+        for function in members {
+            let name = symbol_name(function);
+            if LLVMIsDeclaration(function) != 0 {
+                return Err(format!(
+                    "`{map}` points to `{name}`, which no input defines"
+                ));
+            }
+            match section_name(function) {
+                Some(section)
+                    if STRUCT_OPS_PROG_SECTIONS
+                        .iter()
+                        .any(|prefix| section.starts_with(prefix)) =>
+                {
+                    symbols.push(name.to_owned())
+                }
+                section => {
+                    return Err(format!(
+                        "`{map}` points to `{name}` in section `{}`, expected a section starting \
+                         with one of {STRUCT_OPS_PROG_SECTIONS:?}",
+                        section.unwrap_or_default()
+                    ))
+                }
+            }
+        }
+    }
+    Ok(symbols)
+}
+
+/// Collects the functions referenced by the constant `value`, looking through aggregates and
+/// constant expressions such as casts.
+unsafe fn referenced_functions(value: LLVMValueRef, functions: &mut Vec<LLVMValueRef>) {
+    if value.is_null() {
+        return;
+    }
+    if !LLVMIsAFunction(value).is_null() {
+        functions.push(value);
+        return;
+    }
+    // other globals are referenced, not part of the initializer
+    if LLVMIsAConstant(value).is_null() || !LLVMIsAGlobalValue(value).is_null() {
+        return;
+    }
+    for i in 0..LLVMGetNumOperands(value) {
+        referenced_functions(LLVMGetOperand(value, i as u32), functions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use llvm_sys::core::{LLVMContextCreate, LLVMContextDispose};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_features() {
+        let help = "Available CPUs for this target:\n\n  generic - Select the generic \
+                    processor.\n\nAvailable features for this target:\n\n  alu32    - Enable \
+                    ALU32 instructions.\n  dwarfris - Disable MCAsmInfo \
+                    DwarfUsesRelocationsAcrossSections.\n\nUse +feature to enable a feature, or \
+                    -feature to disable it.\n";
+        assert_eq!(
+            parse_cpu_features(help),
+            [
+                ("alu32".to_owned(), "Enable ALU32 instructions.".to_owned()),
+                (
+                    "dwarfris".to_owned(),
+                    "Disable MCAsmInfo DwarfUsesRelocationsAcrossSections.".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_option_names() {
+        let listing = "OVERVIEW: BPF linker\n\nOPTIONS:\n  -O=<char>  - Optimization \
+                       level\n  --unroll-threshold=<uint>  - The cost threshold\n  \
+                       --x86-asm-syntax=<value>  - Assembly syntax\n    =att  - AT&T\n  \
+                       --bpf-expand-memcpy-in-order  - Expand memcpy\n";
+        let known = option_names(listing);
+        assert_eq!(known.len(), 4);
+        assert!(is_known_option(&known, "--unroll-threshold=5"));
+        assert!(is_known_option(&known, "-bpf-expand-memcpy-in-order"));
+        assert!(is_known_option(&known, "-O2"));
+        assert!(!is_known_option(&known, "--unroll"));
+        assert!(!is_known_option(&known, "unroll-threshold"));
+    }
+
+    #[test]
+    fn test_gc_maps() {
+        const IR: &str = r#"
+target triple = "bpfel"
+
+@USED = global [4 x i32] zeroinitializer, section ".maps", align 4
+@UNUSED = global [4 x i32] zeroinitializer, section ".maps", align 4
+@LEGACY = global [4 x i32] zeroinitializer, section "maps/legacy", align 4
+@KEPT = global [4 x i32] zeroinitializer, section ".maps", align 4
+@DATA = global i32 0, section ".data", align 4
+@llvm.used = appending global [1 x ptr] [ptr @KEPT], section "llvm.metadata"
+
+define i32 @prog() {
+  %v = load i32, ptr @USED
+  ret i32 %v
+}
+"#;
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, IR).unwrap();
+            let mut removed = gc_maps(module);
+            removed.sort();
+            assert_eq!(removed, ["LEGACY", "UNUSED"]);
+            verify_module(module).unwrap();
+            let ir = ir_to_string(module);
+            // maps which are referenced, kept in `llvm.used` or not maps at all stay
+            assert!(ir.contains("@USED = global"), "{ir}");
+            assert!(ir.contains("@KEPT = global"), "{ir}");
+            assert!(ir.contains("@DATA = global"), "{ir}");
+            assert!(!ir.contains("@UNUSED"), "{ir}");
+            assert!(!ir.contains("@LEGACY"), "{ir}");
+
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+
+    #[test]
+    fn test_prefix_symbols() {
+        const IR: &str = r#"
+target triple = "bpfel"
+
+@.str = private constant [3 x i8] c"hi\00"
+@STATE = internal global i32 0, !dbg !5
+@EXPORTED = global i32 0
+
+@alias = internal alias i32 (i32), ptr @helper
+
+declare i32 @ext(i32)
+
+define internal i32 @helper(i32 %x) !dbg !3 {
+  %y = call i32 @ext(i32 %x)
+  ret i32 %y
+}
+
+define i32 @prog(i32 %x) {
+  %y = call i32 @alias(i32 %x)
+  store i32 %y, ptr @STATE
+  ret i32 %y
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !1, producer: "rustc", emissionKind: FullDebug)
+!1 = !DIFile(filename: "prog.rs", directory: "/src")
+!2 = !{i32 2, !"Debug Info Version", i32 3}
+!3 = distinct !DISubprogram(name: "helper", scope: !1, file: !1, line: 1, type: !4, spFlags: DISPFlagDefinition, unit: !0)
+!4 = !DISubroutineType(types: !{})
+!5 = !DIGlobalVariableExpression(var: !6, expr: !DIExpression())
+!6 = distinct !DIGlobalVariable(name: "STATE", scope: !0, file: !1, line: 2, type: !7, isLocal: true, isDefinition: true)
+!7 = !DIBasicType(name: "u32", size: 32, encoding: DW_ATE_unsigned)
+"#;
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, IR).unwrap();
+            prefix_symbols(context, module, "obj1_");
+            verify_module(module).unwrap();
+            let ir = ir_to_string(module);
+            // the symbols defined locally are renamed along with their debug info
+            assert!(ir.contains("@obj1_.str = private"), "{ir}");
+            assert!(ir.contains("@obj1_STATE = internal global"), "{ir}");
+            assert!(ir.contains("@obj1_alias = internal alias"), "{ir}");
+            assert!(ir.contains("define internal i32 @obj1_helper("), "{ir}");
+            assert!(ir.contains("!DISubprogram(name: \"obj1_helper\""), "{ir}");
+            assert!(
+                ir.contains("!DIGlobalVariable(name: \"obj1_STATE\""),
+                "{ir}"
+            );
+            // exported and undefined symbols keep their names
+            assert!(ir.contains("@EXPORTED = global"), "{ir}");
+            assert!(ir.contains("define i32 @prog("), "{ir}");
+            assert!(ir.contains("declare i32 @ext("), "{ir}");
+
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_bitcode_errors() {
+        // the bitcode magic followed by garbage
+        let bitcode = b"BC\xc0\xde\x35\x14\x00\x00\x05\x00\x00\x00\x62\x0c\x30\x24garbage";
+        unsafe {
+            let context = LLVMContextCreate();
+            let name = CString::new("test").unwrap();
+            let module = LLVMModuleCreateWithNameInContext(name.as_ptr(), context);
+
+            let error = link_bitcode_buffer(context, module, bitcode).unwrap_err();
+            assert_ne!(error, "unknown error");
+            assert_eq!(parse_bitcode(context, bitcode).unwrap_err(), error);
+            assert_eq!(
+                bitcode_defined_symbols(context, bitcode).unwrap_err(),
+                error
+            );
+            // the handler of the context is restored
+            assert!(LLVMContextGetDiagnosticHandler(context).is_none());
+
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use std::ptr;
+
+use gimli::{DW_ATE_signed, DW_TAG_const_type, DW_TAG_typedef, DW_TAG_volatile_type};
+use llvm_sys::{
+    core::{
+        LLVMAddGlobal, LLVMConstNull, LLVMConstPointerCast, LLVMConstStructInContext,
+        LLVMDeleteGlobal, LLVMGetAlignment, LLVMGetInitializer, LLVMGetLinkage,
+        LLVMGetMDNodeNumOperands, LLVMGetMDNodeOperands, LLVMGetOperand, LLVMGetSection,
+        LLVMGetVisibility, LLVMGlobalGetValueType, LLVMGlobalSetMetadata, LLVMInt32TypeInContext,
+        LLVMIsDeclaration, LLVMIsGlobalConstant, LLVMMetadataAsValue, LLVMPointerType,
+        LLVMReplaceAllUsesWith, LLVMSetAlignment, LLVMSetGlobalConstant, LLVMSetInitializer,
+        LLVMSetLinkage, LLVMSetSection, LLVMSetValueName2, LLVMSetVisibility,
+        LLVMStructTypeInContext, LLVMTypeOf, LLVMValueAsMetadata,
+    },
+    debuginfo::{
+        LLVMCreateDIBuilder, LLVMDIBuilderCreateArrayType, LLVMDIBuilderCreateBasicType,
+        LLVMDIBuilderCreateMemberType, LLVMDIBuilderCreatePointerType,
+        LLVMDIBuilderCreateStructType, LLVMDIBuilderFinalize, LLVMDIBuilderGetOrCreateSubrange,
+        LLVMDIFlagZero, LLVMDIScopeGetFile, LLVMDITypeGetAlignInBits, LLVMDITypeGetFlags,
+        LLVMDITypeGetLine, LLVMDITypeGetName, LLVMDITypeGetOffsetInBits, LLVMDITypeGetSizeInBits,
+        LLVMDisposeDIBuilder, LLVMGetDINodeTag, LLVMGetMetadataKind, LLVMMetadataKind,
+    },
+    prelude::{LLVMContextRef, LLVMDIBuilderRef, LLVMMetadataRef, LLVMModuleRef, LLVMValueRef},
+    target::{
+        LLVMABIAlignmentOfType, LLVMABISizeOfType, LLVMGetModuleDataLayout, LLVMOffsetOfElement,
+    },
+};
+
+use super::{
+    iter::IterModuleGlobals as _,
+    section_name, symbol_name,
+    types::ir::{global_variable_debug_info, MetadataEntries},
+};
+use crate::glob;
+
+/// The value of the `pinning` attribute of map definitions which makes libbpf and aya pin a map
+/// by name, under the pin root path given to the loader.
+const LIBBPF_PIN_BY_NAME: i64 = 1;
+
+/// Pins the BTF-defined maps of `module` matching `patterns` by name: adds
+/// `__uint(pinning, LIBBPF_PIN_BY_NAME)` to their definitions, replacing the `pinning` attribute
+/// they may already have.
+///
+/// Returns the names of the maps pinned, and of the maps matching `patterns` which can't be since
+/// they have no debug info describing their definition.
+pub unsafe fn pin_maps(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    patterns: &[String],
+) -> (Vec<String>, Vec<String>) {
+    let maps: Vec<_> = module
+        .globals_iter()
+        .filter(|&global| {
+            LLVMIsDeclaration(global) == 0
+                && section_name(global) == Some(".maps")
+                && patterns
+                    .iter()
+                    .any(|pattern| glob::matches(pattern, symbol_name(global)))
+        })
+        .collect();
+
+    let builder = LLVMCreateDIBuilder(module);
+    let pinning_type = pinning_type(builder);
+    let (mut pinned, mut unpinned) = (Vec::new(), Vec::new());
+    for global in maps {
+        let name = symbol_name(global).to_owned();
+        let mut variables = global_variable_debug_info(context, global);
+        let Some(definition) = variables
+            .first()
+            .and_then(|variable| variable.ty())
+            .and_then(|ty| struct_type(context, ty))
+        else {
+            unpinned.push(name);
+            continue;
+        };
+
+        let mut elements = members(context, definition);
+        let existing = elements
+            .iter()
+            .position(|&member| type_name(member) == b"pinning");
+        let (offset, size) = match existing {
+            Some(i) => {
+                let member = elements.remove(i);
+                (
+                    LLVMDITypeGetOffsetInBits(member),
+                    LLVMDITypeGetSizeInBits(definition),
+                )
+            }
+            None => add_pinning_field(context, module, global),
+        };
+        let file = LLVMDIScopeGetFile(definition);
+        let line = LLVMDITypeGetLine(definition);
+        let member = "pinning";
+        elements.push(LLVMDIBuilderCreateMemberType(
+            builder,
+            file,
+            member.as_ptr().cast(),
+            member.len(),
+            file,
+            line,
+            64,
+            64,
+            offset,
+            LLVMDIFlagZero,
+            pinning_type,
+        ));
+        let mut name_len = 0;
+        let struct_name = LLVMDITypeGetName(definition, &mut name_len);
+        let ty = LLVMDIBuilderCreateStructType(
+            builder,
+            file,
+            struct_name,
+            name_len,
+            file,
+            line,
+            size,
+            LLVMDITypeGetAlignInBits(definition),
+            LLVMDITypeGetFlags(definition),
+            ptr::null_mut(),
+            elements.as_mut_ptr(),
+            elements.len() as u32,
+            0,
+            ptr::null_mut(),
+            ptr::null(),
+            0,
+        );
+        for variable in &mut variables {
+            variable.replace_type(ty);
+        }
+        pinned.push(name);
+    }
+
+    LLVMDIBuilderFinalize(builder);
+    LLVMDisposeDIBuilder(builder);
+    (pinned, unpinned)
+}
+
+// Returns the type of the `pinning` attribute, `int (*)[LIBBPF_PIN_BY_NAME]`: map definitions
+// encode integer attributes in the number of elements of the array pointed to.
+unsafe fn pinning_type(builder: LLVMDIBuilderRef) -> LLVMMetadataRef {
+    let int = LLVMDIBuilderCreateBasicType(
+        builder,
+        c"int".as_ptr(),
+        3,
+        32,
+        DW_ATE_signed.0.into(),
+        LLVMDIFlagZero,
+    );
+    let mut subscripts = [LLVMDIBuilderGetOrCreateSubrange(
+        builder,
+        0,
+        LIBBPF_PIN_BY_NAME,
+    )];
+    let array = LLVMDIBuilderCreateArrayType(
+        builder,
+        32 * LIBBPF_PIN_BY_NAME as u64,
+        32,
+        int,
+        subscripts.as_mut_ptr(),
+        subscripts.len() as u32,
+    );
+    LLVMDIBuilderCreatePointerType(builder, array, 64, 64, 0, ptr::null(), 0)
+}
+
+// Returns the struct `ty` stands for, following typedefs and qualifiers.
+unsafe fn struct_type(context: LLVMContextRef, mut ty: LLVMMetadataRef) -> Option<LLVMMetadataRef> {
+    // bound the walk in case of a reference cycle
+    for _ in 0..32 {
+        match LLVMGetMetadataKind(ty) {
+            LLVMMetadataKind::LLVMDICompositeTypeMetadataKind => return Some(ty),
+            LLVMMetadataKind::LLVMDIDerivedTypeMetadataKind
+                if [DW_TAG_typedef, DW_TAG_const_type, DW_TAG_volatile_type]
+                    .iter()
+                    .any(|tag| tag.0 == LLVMGetDINodeTag(ty)) =>
+            {
+                // the operand 3 of derived types is their base type
+                let base = LLVMGetOperand(LLVMMetadataAsValue(context, ty), 3);
+                if base.is_null() {
+                    return None;
+                }
+                ty = LLVMValueAsMetadata(base);
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+// Returns the members of the struct `ty`.
+unsafe fn members(context: LLVMContextRef, ty: LLVMMetadataRef) -> Vec<LLVMMetadataRef> {
+    // the operand 4 of composite types is the tuple of their elements
+    let elements = LLVMGetOperand(LLVMMetadataAsValue(context, ty), 4);
+    if elements.is_null() {
+        return Vec::new();
+    }
+    let mut members = vec![ptr::null_mut(); LLVMGetMDNodeNumOperands(elements) as usize];
+    LLVMGetMDNodeOperands(elements, members.as_mut_ptr());
+    members
+        .into_iter()
+        .map(|member| LLVMValueAsMetadata(member))
+        .collect()
+}
+
+unsafe fn type_name<'a>(ty: LLVMMetadataRef) -> &'a [u8] {
+    let mut len = 0;
+    let name = LLVMDITypeGetName(ty, &mut len);
+    if name.is_null() {
+        return &[];
+    }
+    std::slice::from_raw_parts(name.cast(), len)
+}
+
+// Replaces `global` by a global with a pointer appended to its value, the field of the `pinning`
+// attribute, since the BTF of a map definition must fit in the definition. Returns the offset of
+// the field and the size of the new global, in bits.
+unsafe fn add_pinning_field(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    global: LLVMValueRef,
+) -> (u64, u64) {
+    let data_layout = LLVMGetModuleDataLayout(module);
+    let pointer_type = LLVMPointerType(LLVMInt32TypeInContext(context), 0);
+    let mut fields = [LLVMGlobalGetValueType(global), pointer_type];
+    let ty = LLVMStructTypeInContext(context, fields.as_mut_ptr(), fields.len() as u32, 0);
+    let mut values = [LLVMGetInitializer(global), LLVMConstNull(pointer_type)];
+
+    let new_global = LLVMAddGlobal(module, ty, c"".as_ptr());
+    LLVMSetInitializer(
+        new_global,
+        LLVMConstStructInContext(context, values.as_mut_ptr(), values.len() as u32, 0),
+    );
+    LLVMSetSection(new_global, LLVMGetSection(global));
+    LLVMSetLinkage(new_global, LLVMGetLinkage(global));
+    LLVMSetVisibility(new_global, LLVMGetVisibility(global));
+    LLVMSetGlobalConstant(new_global, LLVMIsGlobalConstant(global));
+    LLVMSetAlignment(
+        new_global,
+        LLVMGetAlignment(global).max(LLVMABIAlignmentOfType(data_layout, ty)),
+    );
+    if let Some(entries) = MetadataEntries::new(global) {
+        for (metadata, kind) in entries.iter() {
+            LLVMGlobalSetMetadata(new_global, kind, metadata);
+        }
+    }
+
+    let name = symbol_name(global).to_owned();
+    LLVMReplaceAllUsesWith(global, LLVMConstPointerCast(new_global, LLVMTypeOf(global)));
+    LLVMDeleteGlobal(global);
+    LLVMSetValueName2(new_global, name.as_ptr().cast(), name.len());
+
+    (
+        LLVMOffsetOfElement(data_layout, ty, 1) * 8,
+        LLVMABISizeOfType(data_layout, ty) * 8,
+    )
+}
@@ -0,0 +1,190 @@
+use llvm_sys::{
+    core::{
+        LLVMBuildRet, LLVMBuildRetVoid, LLVMConstAllOnes, LLVMConstNull,
+        LLVMCreateBuilderInContext, LLVMDisposeBuilder, LLVMGetBasicBlockParent,
+        LLVMGetCalledValue, LLVMGetEnumAttributeKindForName, LLVMGetFirstUse,
+        LLVMGetInstructionOpcode, LLVMGetInstructionParent, LLVMGetNextInstruction, LLVMGetNextUse,
+        LLVMGetReturnType, LLVMGetTypeKind, LLVMGetUser, LLVMGlobalGetValueType,
+        LLVMInstructionEraseFromParent, LLVMIsACallInst, LLVMIsAFunction, LLVMIsDeclaration,
+        LLVMPositionBuilderBefore, LLVMRemoveCallSiteEnumAttribute,
+    },
+    prelude::{LLVMBuilderRef, LLVMContextRef, LLVMModuleRef, LLVMValueRef},
+    LLVMAttributeFunctionIndex, LLVMOpcode, LLVMTypeKind,
+};
+
+use super::{
+    iter::{IterBasicBlocks as _, IterInstructions as _, IterModuleFunctions as _},
+    remove_attribute, symbol_name,
+};
+
+/// The kfunc recent BPF backends lower traps to.
+const BPF_TRAP: &str = "__bpf_trap";
+
+/// Rewrites the calls to `llvm.trap` and [`BPF_TRAP`] which are followed by `unreachable` into
+/// returns from the function: of -1 for functions returning integers, of null for those
+/// returning anything else.
+///
+/// The functions which now return lose their `noreturn` attribute, and the `unreachable`
+/// following the calls to them are rewritten into returns too, up to the entry points, so that no
+/// return is left where LLVM assumes none happens.
+///
+/// Returns the number of calls rewritten.
+pub unsafe fn rewrite_traps(context: LLVMContextRef, module: LLVMModuleRef) -> usize {
+    let mut traps = Vec::new();
+    for function in module.functions_iter() {
+        if LLVMIsDeclaration(function) != 0 {
+            continue;
+        }
+        for block in function.basic_blocks_iter() {
+            traps.extend(
+                block
+                    .instructions_iter()
+                    .filter(|&instruction| is_trap(instruction))
+                    .map(|instruction| (function, instruction)),
+            );
+        }
+    }
+    if traps.is_empty() {
+        return 0;
+    }
+
+    let builder = LLVMCreateBuilderInContext(context);
+    let mut returning = Vec::new();
+    for &(function, call) in &traps {
+        replace_with_return(builder, function, call);
+        if !returning.contains(&function) {
+            returning.push(function);
+        }
+    }
+    // the callers of functions which now return may now return too
+    let noreturn = LLVMGetEnumAttributeKindForName(c"noreturn".as_ptr(), "noreturn".len());
+    let mut i = 0;
+    while let Some(&function) = returning.get(i) {
+        i += 1;
+        remove_attribute(function, "noreturn");
+        for call in calls_to(function) {
+            LLVMRemoveCallSiteEnumAttribute(call, LLVMAttributeFunctionIndex, noreturn);
+            if !is_followed_by_unreachable(call) {
+                continue;
+            }
+            let caller = LLVMGetBasicBlockParent(LLVMGetInstructionParent(call));
+            // the call is kept, only the `unreachable` is replaced
+            replace_with_return(builder, caller, LLVMGetNextInstruction(call));
+            if !returning.contains(&caller) {
+                returning.push(caller);
+            }
+        }
+    }
+    LLVMDisposeBuilder(builder);
+    traps.len()
+}
+
+// Replaces `instruction`, a call to a trap or an `unreachable`, and the `unreachable` following
+// it with a return from `function`.
+unsafe fn replace_with_return(
+    builder: LLVMBuilderRef,
+    function: LLVMValueRef,
+    instruction: LLVMValueRef,
+) {
+    let unreachable = if LLVMGetInstructionOpcode(instruction) == LLVMOpcode::LLVMUnreachable {
+        None
+    } else {
+        Some(LLVMGetNextInstruction(instruction))
+    };
+    LLVMPositionBuilderBefore(builder, instruction);
+    let return_type = LLVMGetReturnType(LLVMGlobalGetValueType(function));
+    let _: LLVMValueRef = match LLVMGetTypeKind(return_type) {
+        LLVMTypeKind::LLVMVoidTypeKind => LLVMBuildRetVoid(builder),
+        LLVMTypeKind::LLVMIntegerTypeKind => LLVMBuildRet(builder, LLVMConstAllOnes(return_type)),
+        _ => LLVMBuildRet(builder, LLVMConstNull(return_type)),
+    };
+    if let Some(unreachable) = unreachable {
+        LLVMInstructionEraseFromParent(unreachable);
+    }
+    LLVMInstructionEraseFromParent(instruction);
+}
+
+// Returns the calls to `function`.
+unsafe fn calls_to(function: LLVMValueRef) -> Vec<LLVMValueRef> {
+    let mut calls = Vec::new();
+    let mut used = LLVMGetFirstUse(function);
+    while !used.is_null() {
+        let user = LLVMGetUser(used);
+        if !LLVMIsACallInst(user).is_null() && LLVMGetCalledValue(user) == function {
+            calls.push(user);
+        }
+        used = LLVMGetNextUse(used);
+    }
+    calls
+}
+
+unsafe fn is_followed_by_unreachable(instruction: LLVMValueRef) -> bool {
+    let next = LLVMGetNextInstruction(instruction);
+    !next.is_null() && LLVMGetInstructionOpcode(next) == LLVMOpcode::LLVMUnreachable
+}
+
+// Returns whether `instruction` is a call to a trap followed by `unreachable`, the only form
+// which can be rewritten without touching the control flow.
+unsafe fn is_trap(instruction: LLVMValueRef) -> bool {
+    if LLVMIsACallInst(instruction).is_null() {
+        return false;
+    }
+    let callee = LLVMGetCalledValue(instruction);
+    if LLVMIsAFunction(callee).is_null() || !matches!(symbol_name(callee), "llvm.trap" | BPF_TRAP) {
+        return false;
+    }
+    is_followed_by_unreachable(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use llvm_sys::core::{LLVMContextCreate, LLVMContextDispose, LLVMDisposeModule};
+
+    use super::*;
+    use crate::llvm::{ir_to_string, parse_ir, verify_module};
+
+    #[test]
+    fn test_rewrite_traps_noreturn() {
+        const IR: &str = r#"
+target triple = "bpfel"
+
+declare void @llvm.trap() cold noreturn nounwind
+
+define internal void @fail() noreturn {
+  call void @llvm.trap()
+  unreachable
+}
+
+define i32 @prog(i32 %x) {
+entry:
+  %bad = icmp eq i32 %x, 0
+  br i1 %bad, label %error, label %ok
+
+error:
+  call void @fail() noreturn
+  unreachable
+
+ok:
+  ret i32 %x
+}
+"#;
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, IR).unwrap();
+            assert_eq!(rewrite_traps(context, module), 1);
+            verify_module(module).unwrap();
+            let ir = ir_to_string(module);
+            // the function which now returns isn't noreturn anymore, and its caller returns
+            // after calling it
+            assert!(ir.contains("define internal void @fail() {"), "{ir}");
+            assert!(ir.contains("ret void"), "{ir}");
+            assert!(
+                ir.contains("error:\n  call void @fail()\n  ret i32 -1\n"),
+                "{ir}"
+            );
+            assert!(!ir.contains("unreachable"), "{ir}");
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+}
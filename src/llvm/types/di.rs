@@ -10,8 +10,9 @@ use llvm_sys::{
     core::{LLVMGetNumOperands, LLVMGetOperand, LLVMReplaceMDNodeOperandWith, LLVMValueAsMetadata},
     debuginfo::{
         LLVMDIFileGetFilename, LLVMDIFlags, LLVMDIScopeGetFile, LLVMDISubprogramGetLine,
-        LLVMDITypeGetFlags, LLVMDITypeGetLine, LLVMDITypeGetName, LLVMDITypeGetOffsetInBits,
-        LLVMGetDINodeTag,
+        LLVMDITypeGetAlignInBits, LLVMDITypeGetFlags, LLVMDITypeGetLine, LLVMDITypeGetName,
+        LLVMDITypeGetOffsetInBits, LLVMDITypeGetSizeInBits, LLVMGetDINodeTag, LLVMGetMetadataKind,
+        LLVMMetadataKind,
     },
     prelude::{LLVMContextRef, LLVMMetadataRef, LLVMValueRef},
 };
@@ -78,7 +79,10 @@ impl DIFile<'_> {
 /// operand indices within metadata nodes.
 #[repr(u32)]
 enum DITypeOperand {
-    /// Name of the type.
+    /// Scope of the type, eg the namespace or the type it's declared in. Namespaces store their
+    /// scope at the same index.
+    Scope = 1,
+    /// Name of the type. Namespaces store their name at the same index.
     /// [Reference in LLVM code](https://github.com/llvm/llvm-project/blob/llvmorg-17.0.3/llvm/include/llvm/IR/DebugInfoMetadata.h#L743).
     Name = 2,
 }
@@ -137,6 +141,26 @@ impl DIType<'_> {
     pub fn offset_in_bits(&self) -> usize {
         unsafe { LLVMDITypeGetOffsetInBits(self.metadata_ref) as usize }
     }
+
+    /// Returns the name of the type, eg the name of the field for a member of a composite type.
+    pub fn name(&self) -> Option<&CStr> {
+        unsafe { di_type_name(self.metadata_ref) }
+    }
+
+    /// Returns the size of the type in bits.
+    pub fn size_in_bits(&self) -> u64 {
+        unsafe { LLVMDITypeGetSizeInBits(self.metadata_ref) }
+    }
+
+    /// Returns the alignment of the type in bits, `0` if it's the natural alignment.
+    pub fn align_in_bits(&self) -> u32 {
+        unsafe { LLVMDITypeGetAlignInBits(self.metadata_ref) }
+    }
+
+    /// Returns the flags associated with the type.
+    pub fn flags(&self) -> LLVMDIFlags {
+        unsafe { LLVMDITypeGetFlags(self.metadata_ref) }
+    }
 }
 
 impl<'ctx> From<DIDerivedType<'ctx>> for DIType<'ctx> {
@@ -262,6 +286,27 @@ impl DICompositeType<'_> {
         unsafe { di_type_name(self.metadata_ref) }
     }
 
+    /// Returns the name of the composite type prefixed by the namespaces and types it's declared
+    /// in, eg `my_crate::module::Type`.
+    pub fn qualified_name(&self) -> Option<String> {
+        let mut path = vec![self.name()?.to_string_lossy().into_owned()];
+        let mut scope = unsafe { LLVMGetOperand(self.value_ref, DITypeOperand::Scope as u32) };
+        while !scope.is_null() {
+            match unsafe { LLVMGetMetadataKind(LLVMValueAsMetadata(scope)) } {
+                LLVMMetadataKind::LLVMDINamespaceMetadataKind
+                | LLVMMetadataKind::LLVMDICompositeTypeMetadataKind => {}
+                _ => break,
+            }
+            let name = unsafe { LLVMGetOperand(scope, DITypeOperand::Name as u32) };
+            if !name.is_null() {
+                path.push(mdstring_to_str(name).to_owned());
+            }
+            scope = unsafe { LLVMGetOperand(scope, DITypeOperand::Scope as u32) };
+        }
+        path.reverse();
+        Some(path.join("::"))
+    }
+
     /// Returns the file that the composite type belongs to.
     pub fn file(&self) -> DIFile {
         unsafe {
@@ -280,6 +325,16 @@ impl DICompositeType<'_> {
         unsafe { LLVMDITypeGetLine(self.metadata_ref) }
     }
 
+    /// Returns the size of the composite type in bits.
+    pub fn size_in_bits(&self) -> u64 {
+        unsafe { LLVMDITypeGetSizeInBits(self.metadata_ref) }
+    }
+
+    /// Returns the alignment of the composite type in bits, `0` if it's the natural alignment.
+    pub fn align_in_bits(&self) -> u32 {
+        unsafe { LLVMDITypeGetAlignInBits(self.metadata_ref) }
+    }
+
     /// Replaces the elements of the composite type with a new metadata node.
     /// The provided metadata node should contain new composite type elements
     /// as operants. The metadata node can be empty if the intention is to
@@ -430,3 +485,80 @@ impl DISubprogram<'_> {
         };
     }
 }
+
+/// Represents the operands for a [`DIGlobalVariable`]. The enum values
+/// correspond to the operand indices within metadata nodes.
+#[repr(u32)]
+enum DIGlobalVariableOperand {
+    /// Name of the variable, as inherited from `DIVariable`.
+    Name = 1,
+    /// Type of the variable, as inherited from `DIVariable`.
+    Ty = 3,
+}
+
+/// Represents the debug information for a global variable in LLVM IR.
+pub struct DIGlobalVariable<'ctx> {
+    value_ref: LLVMValueRef,
+    _marker: PhantomData<&'ctx ()>,
+}
+
+impl DIGlobalVariable<'_> {
+    /// Constructs a new [`DIGlobalVariable`] from the given `value`.
+    ///
+    /// # Safety
+    ///
+    /// This method assumes that the provided `value` corresponds to a valid
+    /// instance of [LLVM `DIGlobalVariable`](https://llvm.org/doxygen/classllvm_1_1DIGlobalVariable.html).
+    /// It's the caller's responsibility to ensure this invariant, as this
+    /// method doesn't perform any validation checks.
+    pub(crate) unsafe fn from_value_ref(value_ref: LLVMValueRef) -> Self {
+        Self {
+            value_ref,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the name of the variable.
+    pub fn name(&self) -> Option<&str> {
+        let operand =
+            unsafe { LLVMGetOperand(self.value_ref, DIGlobalVariableOperand::Name as u32) };
+        NonNull::new(operand).map(|_| mdstring_to_str(operand))
+    }
+
+    /// Replaces the name of the variable with a new name.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NulError` if the new name contains a NUL byte, as it cannot
+    /// be converted into a `CString`.
+    pub fn replace_name(&mut self, context: LLVMContextRef, name: &str) -> Result<(), NulError> {
+        super::ir::replace_name(
+            self.value_ref,
+            context,
+            DIGlobalVariableOperand::Name as u32,
+            name,
+        )
+    }
+
+    /// Returns the size in bits of the type of the variable, or `None` if the type doesn't record
+    /// a size (eg typedefs).
+    pub fn type_size_in_bits(&self) -> Option<u64> {
+        let operand = unsafe { LLVMGetOperand(self.value_ref, DIGlobalVariableOperand::Ty as u32) };
+        NonNull::new(operand)
+            .map(|_| unsafe { LLVMDITypeGetSizeInBits(LLVMValueAsMetadata(operand)) })
+            .filter(|size| *size != 0)
+    }
+
+    /// Returns the type of the variable.
+    pub fn ty(&self) -> Option<LLVMMetadataRef> {
+        let operand = unsafe { LLVMGetOperand(self.value_ref, DIGlobalVariableOperand::Ty as u32) };
+        NonNull::new(operand).map(|_| unsafe { LLVMValueAsMetadata(operand) })
+    }
+
+    /// Replaces the type of the variable with `ty`.
+    pub fn replace_type(&mut self, ty: LLVMMetadataRef) {
+        unsafe {
+            LLVMReplaceMDNodeOperandWith(self.value_ref, DIGlobalVariableOperand::Ty as u32, ty)
+        }
+    }
+}
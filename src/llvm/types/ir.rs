@@ -6,14 +6,17 @@ use std::{
 
 use llvm_sys::{
     core::{
-        LLVMCountParams, LLVMDisposeValueMetadataEntries, LLVMGetNumOperands, LLVMGetOperand,
-        LLVMGetParam, LLVMGlobalCopyAllMetadata, LLVMIsAFunction, LLVMIsAGlobalObject,
-        LLVMIsAInstruction, LLVMIsAMDNode, LLVMIsAUser, LLVMMDNodeInContext2,
-        LLVMMDStringInContext2, LLVMMetadataAsValue, LLVMPrintValueToString,
+        LLVMCountParams, LLVMDisposeValueMetadataEntries, LLVMGetMDKindIDInContext,
+        LLVMGetNumOperands, LLVMGetOperand, LLVMGetParam, LLVMGlobalCopyAllMetadata,
+        LLVMIsAFunction, LLVMIsAGlobalObject, LLVMIsAInstruction, LLVMIsAMDNode, LLVMIsAUser,
+        LLVMMDNodeInContext2, LLVMMDStringInContext2, LLVMMetadataAsValue, LLVMPrintValueToString,
         LLVMReplaceMDNodeOperandWith, LLVMValueAsMetadata, LLVMValueMetadataEntriesGetKind,
         LLVMValueMetadataEntriesGetMetadata,
     },
-    debuginfo::{LLVMGetMetadataKind, LLVMGetSubprogram, LLVMMetadataKind, LLVMSetSubprogram},
+    debuginfo::{
+        LLVMDIGlobalVariableExpressionGetVariable, LLVMGetMetadataKind, LLVMGetSubprogram,
+        LLVMMetadataKind, LLVMSetSubprogram,
+    },
     prelude::{
         LLVMBasicBlockRef, LLVMContextRef, LLVMMetadataRef, LLVMValueMetadataEntry, LLVMValueRef,
     },
@@ -22,7 +25,7 @@ use llvm_sys::{
 use crate::llvm::{
     iter::IterBasicBlocks as _,
     symbol_name,
-    types::di::{DICompositeType, DIDerivedType, DISubprogram, DIType},
+    types::di::{DICompositeType, DIDerivedType, DIGlobalVariable, DISubprogram, DIType},
     Message,
 };
 
@@ -51,11 +54,7 @@ impl std::fmt::Debug for Value<'_> {
             Message {
                 ptr: unsafe { LLVMPrintValueToString(value) },
             }
-            .as_c_str()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string()
+            .to_string_lossy()
         };
         match self {
             Self::MDNode(node) => f
@@ -337,3 +336,22 @@ impl<'ctx> Function<'ctx> {
         unsafe { LLVMSetSubprogram(self.value_ref, LLVMValueAsMetadata(subprogram.value_ref)) };
     }
 }
+
+/// Returns the debug info variables attached to the global variable `value`.
+pub(crate) fn global_variable_debug_info<'ctx>(
+    context: LLVMContextRef,
+    value: LLVMValueRef,
+) -> Vec<DIGlobalVariable<'ctx>> {
+    let dbg_kind = unsafe { LLVMGetMDKindIDInContext(context, c"dbg".as_ptr(), 3) };
+    let Some(entries) = MetadataEntries::new(value) else {
+        return Vec::new();
+    };
+    entries
+        .iter()
+        .filter(|(_, kind)| *kind == dbg_kind)
+        .map(|(expression, _)| unsafe {
+            let variable = LLVMDIGlobalVariableExpressionGetVariable(expression);
+            DIGlobalVariable::from_value_ref(LLVMMetadataAsValue(context, variable))
+        })
+        .collect()
+}
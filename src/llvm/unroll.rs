@@ -0,0 +1,299 @@
+use std::collections::HashSet;
+
+use llvm_sys::{
+    core::{
+        LLVMGetBasicBlockTerminator, LLVMGetCalledValue, LLVMGetEntryBasicBlock,
+        LLVMGetMDKindIDInContext, LLVMGetMDNodeNumOperands, LLVMGetMDNodeOperands, LLVMGetMetadata,
+        LLVMGetNumSuccessors, LLVMGetSuccessor, LLVMIsACallInst, LLVMIsAFunction,
+        LLVMIsDeclaration, LLVMMDNodeInContext2, LLVMMDStringInContext2, LLVMMetadataAsValue,
+        LLVMSetMetadata, LLVMValueAsMetadata,
+    },
+    debuginfo::{LLVMMetadataReplaceAllUsesWith, LLVMTemporaryMDNode},
+    prelude::{LLVMBasicBlockRef, LLVMContextRef, LLVMModuleRef, LLVMValueRef},
+};
+
+use super::{
+    iter::{IterBasicBlocks as _, IterInstructions as _, IterModuleFunctions as _},
+    symbol_name,
+};
+use crate::glob;
+
+/// Marks the loops of the functions of `module` matching `patterns`, and of the functions they
+/// call, for unrolling, as `#pragma unroll` does in C. All the functions are marked when
+/// `patterns` is empty.
+///
+/// Returns the number of loops marked.
+pub unsafe fn mark_loops_for_unrolling(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    patterns: &[String],
+) -> usize {
+    let defined = module
+        .functions_iter()
+        .filter(|&function| LLVMIsDeclaration(function) == 0);
+    let functions: Vec<_> = if patterns.is_empty() {
+        defined.collect()
+    } else {
+        reachable_functions(defined.filter(|&function| {
+            let name = symbol_name(function);
+            patterns.iter().any(|pattern| glob::matches(pattern, name))
+        }))
+    };
+
+    let loop_kind = LLVMGetMDKindIDInContext(context, c"llvm.loop".as_ptr(), 9);
+    let unroll = "llvm.loop.unroll.enable";
+    let unroll = LLVMMDStringInContext2(context, unroll.as_ptr().cast(), unroll.len());
+    let unroll = LLVMMDNodeInContext2(context, [unroll].as_mut_ptr(), 1);
+    let mut marked = 0;
+    for function in functions {
+        for latch in latches(function) {
+            let branch = LLVMGetBasicBlockTerminator(latch);
+            // the first operand of a loop ID refers to itself, the others are properties
+            let mut properties = vec![LLVMTemporaryMDNode(context, [].as_mut_ptr(), 0)];
+            let id = LLVMGetMetadata(branch, loop_kind);
+            if !id.is_null() {
+                let mut operands =
+                    vec![std::ptr::null_mut(); LLVMGetMDNodeNumOperands(id) as usize];
+                LLVMGetMDNodeOperands(id, operands.as_mut_ptr());
+                properties.extend(
+                    operands
+                        .into_iter()
+                        .skip(1)
+                        .map(|operand| LLVMValueAsMetadata(operand)),
+                );
+            }
+            properties.push(unroll);
+            let id = LLVMMDNodeInContext2(context, properties.as_mut_ptr(), properties.len());
+            // makes the node distinct, since it now refers to itself
+            LLVMMetadataReplaceAllUsesWith(properties[0], id);
+            LLVMSetMetadata(branch, loop_kind, LLVMMetadataAsValue(context, id));
+            marked += 1;
+        }
+    }
+    marked
+}
+
+// Returns `roots` along with the functions they call, directly or not.
+unsafe fn reachable_functions(roots: impl Iterator<Item = LLVMValueRef>) -> Vec<LLVMValueRef> {
+    let mut functions: Vec<_> = roots.collect();
+    let mut seen: HashSet<_> = functions.iter().copied().collect();
+    let mut i = 0;
+    while let Some(&function) = functions.get(i) {
+        i += 1;
+        for block in function.basic_blocks_iter() {
+            for instruction in block.instructions_iter() {
+                if LLVMIsACallInst(instruction).is_null() {
+                    continue;
+                }
+                let callee = LLVMGetCalledValue(instruction);
+                if !LLVMIsAFunction(callee).is_null()
+                    && LLVMIsDeclaration(callee) == 0
+                    && seen.insert(callee)
+                {
+                    functions.push(callee);
+                }
+            }
+        }
+    }
+    functions
+}
+
+// Returns the blocks of `function` which branch back to the header of a loop, ie to a block
+// being visited in a depth first traversal of the control flow graph.
+unsafe fn latches(function: LLVMValueRef) -> Vec<LLVMBasicBlockRef> {
+    let mut latches = Vec::new();
+    let entry = LLVMGetEntryBasicBlock(function);
+    let mut visited = HashSet::from([entry]);
+    let mut on_stack = HashSet::from([entry]);
+    // the blocks being visited, with the index of their next successor
+    let mut stack = vec![(entry, 0)];
+    while let Some((block, next)) = stack.last_mut() {
+        let block = *block;
+        let terminator = LLVMGetBasicBlockTerminator(block);
+        let successors = if terminator.is_null() {
+            0
+        } else {
+            LLVMGetNumSuccessors(terminator)
+        };
+        if *next == successors {
+            let _: bool = on_stack.remove(&block);
+            let _: Option<_> = stack.pop();
+            continue;
+        }
+        let successor = LLVMGetSuccessor(terminator, *next);
+        *next += 1;
+        if on_stack.contains(&successor) {
+            if !latches.contains(&block) {
+                latches.push(block);
+            }
+        } else if visited.insert(successor) {
+            let _: bool = on_stack.insert(successor);
+            stack.push((successor, 0));
+        }
+    }
+    latches
+}
+
+#[cfg(test)]
+mod tests {
+    use llvm_sys::core::{LLVMContextCreate, LLVMContextDispose, LLVMDisposeModule};
+
+    use super::*;
+    use crate::llvm::{ir_to_string, parse_ir, verify_module};
+
+    // `prog` calls `helper`, and `other` calls no one, each of them has a loop.
+    const IR: &str = r#"
+target triple = "bpfel"
+
+define void @helper(ptr %p) {
+entry:
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %entry ], [ %next, %loop ]
+  store volatile i32 %i, ptr %p
+  %next = add i32 %i, 1
+  %done = icmp eq i32 %next, 16
+  br i1 %done, label %exit, label %loop
+
+exit:
+  ret void
+}
+
+define void @prog(ptr %p) {
+entry:
+  call void @helper(ptr %p)
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %entry ], [ %next, %loop ]
+  store volatile i32 %i, ptr %p
+  %next = add i32 %i, 1
+  %done = icmp eq i32 %next, 16
+  br i1 %done, label %exit, label %loop, !llvm.loop !0
+
+exit:
+  ret void
+}
+
+define void @other(ptr %p) {
+entry:
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %entry ], [ %next, %loop ]
+  store volatile i32 %i, ptr %p
+  %next = add i32 %i, 1
+  %done = icmp eq i32 %next, 16
+  br i1 %done, label %exit, label %loop
+
+exit:
+  ret void
+}
+
+!0 = distinct !{!0, !1}
+!1 = !{!"llvm.loop.mustprogress"}
+"#;
+
+    // Returns the properties of the loop ID `id` of `ir`, eg `llvm.loop.mustprogress`.
+    fn loop_properties(ir: &str, id: &str) -> Vec<String> {
+        let node = |id: &str| {
+            ir.lines()
+                .find_map(|line| line.strip_prefix(&format!("{id} = ")))
+                .unwrap()
+                .trim_start_matches("distinct ")
+                .strip_prefix("!{")
+                .and_then(|node| node.strip_suffix('}'))
+                .unwrap()
+        };
+        node(id)
+            .split(", ")
+            .skip(1)
+            .map(|property| node(property).trim_matches(['!', '"']).to_owned())
+            .collect()
+    }
+
+    // Marks the loops of the functions matching `patterns`, returning how many were and the
+    // functions whose loop has properties, along with them.
+    fn mark(patterns: &[&str]) -> (usize, Vec<(String, Vec<String>)>) {
+        let patterns: Vec<String> = patterns.iter().map(|&p| p.to_owned()).collect();
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = parse_ir(context, IR).unwrap();
+            let marked = mark_loops_for_unrolling(context, module, &patterns);
+            verify_module(module).unwrap();
+            let ir = ir_to_string(module);
+            let mut function = "";
+            let mut loops = Vec::new();
+            for line in ir.lines() {
+                if let Some(name) = line.strip_prefix("define void @") {
+                    function = &name[..name.find('(').unwrap()];
+                }
+                if let Some((_, id)) = line.split_once("!llvm.loop ") {
+                    loops.push((function.to_owned(), loop_properties(&ir, id)));
+                }
+            }
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+            (marked, loops)
+        }
+    }
+
+    fn marked(function: &str, properties: &[&str]) -> (String, Vec<String>) {
+        (
+            function.to_owned(),
+            properties.iter().map(|&p| p.to_owned()).collect(),
+        )
+    }
+
+    #[test]
+    fn test_mark_all_loops() {
+        assert_eq!(
+            mark(&[]),
+            (
+                3,
+                vec![
+                    marked("helper", &["llvm.loop.unroll.enable"]),
+                    // the properties the loop had are kept
+                    marked(
+                        "prog",
+                        &["llvm.loop.mustprogress", "llvm.loop.unroll.enable"]
+                    ),
+                    marked("other", &["llvm.loop.unroll.enable"]),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_mark_function_loops() {
+        // the loops of the functions called are marked too
+        assert_eq!(
+            mark(&["prog"]),
+            (
+                2,
+                vec![
+                    marked("helper", &["llvm.loop.unroll.enable"]),
+                    marked(
+                        "prog",
+                        &["llvm.loop.mustprogress", "llvm.loop.unroll.enable"]
+                    ),
+                ]
+            )
+        );
+        assert_eq!(
+            mark(&["oth*"]),
+            (
+                1,
+                vec![
+                    marked("prog", &["llvm.loop.mustprogress"]),
+                    marked("other", &["llvm.loop.unroll.enable"]),
+                ]
+            )
+        );
+        assert_eq!(
+            mark(&["missing"]),
+            (0, vec![marked("prog", &["llvm.loop.mustprogress"])])
+        );
+    }
+}
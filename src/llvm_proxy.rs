@@ -0,0 +1,375 @@
+//! Selection of the LLVM shared library loaded by `aya-rustc-llvm-proxy`.
+//!
+//! The proxy loads LLVM from the libdir of rustc, which some distros strip of the LLVM bundled
+//! with rustc. When that happens [`configure`] falls back to the library named by
+//! `LLVM_PROXY_LIB`, then to a system `libLLVM.so.<major>`, and points the proxy at it. Since the
+//! proxy searches `LD_LIBRARY_PATH` first, the fallback is exposed there through a directory
+//! holding only a link to the selected library. The directory is private to the user, in
+//! `$XDG_RUNTIME_DIR` or else in the temporary directory, since whoever can write to it chooses
+//! the code the linker runs.
+
+use std::{
+    env,
+    ffi::{CStr, CString},
+    fmt, fs, io,
+    os::unix::{
+        ffi::OsStrExt as _,
+        fs::{symlink, DirBuilderExt as _, MetadataExt as _},
+    },
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use thiserror::Error;
+
+/// The major version of LLVM that `llvm-sys` is built for.
+pub const LLVM_MAJOR: u32 = 19;
+
+/// The environment variable naming the LLVM shared library to use when rustc doesn't have one.
+pub const LLVM_PROXY_LIB: &str = "LLVM_PROXY_LIB";
+
+/// Why a candidate library was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    NotFound,
+    LoadFailed(String),
+    /// The library is LLVM of another major version, `None` for versions too old to report it.
+    WrongVersion(Option<u32>),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "not found"),
+            Self::LoadFailed(msg) => write!(f, "failed to load: {msg}"),
+            Self::WrongVersion(Some(major)) => write!(f, "LLVM {major}, expected {LLVM_MAJOR}"),
+            Self::WrongVersion(None) => write!(f, "LLVM older than 16, expected {LLVM_MAJOR}"),
+        }
+    }
+}
+
+/// Error finding a usable LLVM shared library.
+#[derive(Debug, Error)]
+pub enum LlvmProxyError {
+    #[error(
+        "no usable LLVM {LLVM_MAJOR} shared library found, tried:{}",
+        .0.iter().map(|(path, why)| format!("\n  {}: {why}", path.display())).collect::<String>()
+    )]
+    NotFound(Vec<(PathBuf, Rejection)>),
+    #[error("failed to expose {0} to the LLVM proxy: {1}")]
+    Link(PathBuf, #[source] std::io::Error),
+}
+
+/// Where a candidate library comes from, in the order they're tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The libdir of rustc, where the proxy looks by itself.
+    Rustc,
+    /// The `LLVM_PROXY_LIB` environment variable.
+    Env,
+    /// The system library directories.
+    System,
+}
+
+/// Returns the candidate libraries, in the order they're tried.
+pub fn candidates() -> Vec<(Source, PathBuf)> {
+    let mut candidates = Vec::new();
+    if let Some(libdir) = rustc_libdir() {
+        let libs = libraries_in(&libdir, |name| name.starts_with("libLLVM"));
+        if libs.is_empty() {
+            candidates.push((Source::Rustc, libdir.join("libLLVM.so")));
+        }
+        candidates.extend(libs.into_iter().map(|lib| (Source::Rustc, lib)));
+    }
+    if let Some(lib) = env::var_os(LLVM_PROXY_LIB) {
+        candidates.push((Source::Env, PathBuf::from(lib)));
+    }
+    for dir in system_libdirs() {
+        let libs = libraries_in(&dir, |name| {
+            name == format!("libLLVM-{LLVM_MAJOR}.so")
+                || name == format!("libLLVM.so.{LLVM_MAJOR}")
+                || name.starts_with(&format!("libLLVM.so.{LLVM_MAJOR}."))
+        });
+        if libs.is_empty() {
+            candidates.push((Source::System, dir.join(format!("libLLVM.so.{LLVM_MAJOR}"))));
+        }
+        candidates.extend(libs.into_iter().map(|lib| (Source::System, lib)));
+    }
+    candidates
+}
+
+/// Returns the first candidate library which is LLVM [`LLVM_MAJOR`], along with where it comes
+/// from.
+pub fn find() -> Result<(Source, PathBuf), LlvmProxyError> {
+    let mut rejected = Vec::new();
+    for (source, path) in candidates() {
+        match check(&path) {
+            Ok(()) => return Ok((source, path)),
+            Err(why) => rejected.push((path, why)),
+        }
+    }
+    Err(LlvmProxyError::NotFound(rejected))
+}
+
+/// Makes the proxy load a usable LLVM, falling back from the LLVM of rustc to `LLVM_PROXY_LIB`
+/// and to the system LLVM. Must be called before any LLVM function and before spawning threads,
+/// since it may set `LD_LIBRARY_PATH`.
+///
+/// When `LD_LIBRARY_PATH` already starts with the directory of the link, eg in processes spawned
+/// by a configured linker, the library it links to is used as is.
+pub fn configure() -> Result<PathBuf, LlvmProxyError> {
+    let dir = link_dir();
+    let link = dir.join(format!("libLLVM.so.{LLVM_MAJOR}"));
+    let configured = env::var_os("LD_LIBRARY_PATH")
+        .is_some_and(|paths| env::split_paths(&paths).next().as_deref() == Some(dir.as_path()));
+    if configured {
+        if let Ok(path) = fs::read_link(&link) {
+            return Ok(path);
+        }
+    }
+
+    let (source, path) = find()?;
+    if source == Source::Rustc {
+        return Ok(path);
+    }
+    let expose = || -> io::Result<()> {
+        create_private_dir(&dir)?;
+        if fs::read_link(&link).ok().as_deref() != Some(&path) {
+            let tmp = dir.join(format!(".libLLVM.so.{}", std::process::id()));
+            let _: Result<(), _> = fs::remove_file(&tmp);
+            symlink(&path, &tmp)?;
+            fs::rename(&tmp, &link)?;
+        }
+        Ok(())
+    };
+    expose().map_err(|e| LlvmProxyError::Link(path.clone(), e))?;
+    let mut paths = vec![dir];
+    if let Some(existing) = env::var_os("LD_LIBRARY_PATH") {
+        paths.extend(env::split_paths(&existing));
+    }
+    let joined = env::join_paths(paths).expect("library paths contain no separator");
+    env::set_var("LD_LIBRARY_PATH", joined);
+    Ok(path)
+}
+
+// The directory holding the link to the selected library.
+fn link_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Path::new(&dir).join("bpf-linker-llvm"),
+        _ => {
+            // SAFETY: getuid can't fail.
+            let uid = unsafe { libc::getuid() };
+            env::temp_dir().join(format!("bpf-linker-llvm-{uid}"))
+        }
+    }
+}
+
+// Creates `dir` if needed and checks that only the current user can write to it, since its path
+// is predictable and another user may have created it first, or added libraries to it.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    // SAFETY: getuid can't fail.
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is not a directory private to the current user",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Returns the LLVM major version in the file name of the library `path`, eg 19 for
+// `libLLVM.so.19.1` or rustc's `libLLVM.so.19.1-rust-1.84.0-stable` and
+// `libLLVM-19-rust-1.80.0-stable.so`.
+fn major_from_name(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    let version = name
+        .strip_prefix("libLLVM.so.")
+        .or_else(|| name.strip_prefix("libLLVM-"))?;
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+// Checks that `path` is LLVM `LLVM_MAJOR`, from its name when it has the version or else by
+// loading it, which is slow for a library the size of LLVM.
+fn check(path: &Path) -> Result<(), Rejection> {
+    if !path.exists() {
+        return Err(Rejection::NotFound);
+    }
+    if let Some(major) = major_from_name(path) {
+        return match major {
+            LLVM_MAJOR => Ok(()),
+            major => Err(Rejection::WrongVersion(Some(major))),
+        };
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Rejection::LoadFailed("path contains a NUL byte".to_owned()))?;
+    unsafe {
+        let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_LAZY | libc::RTLD_LOCAL);
+        if handle.is_null() {
+            let err = libc::dlerror();
+            let msg = if err.is_null() {
+                "unknown error".to_owned()
+            } else {
+                CStr::from_ptr(err).to_string_lossy().into_owned()
+            };
+            return Err(Rejection::LoadFailed(msg));
+        }
+        // LLVMGetVersion was added in LLVM 16
+        let get_version = libc::dlsym(handle, c"LLVMGetVersion".as_ptr());
+        let major = (!get_version.is_null()).then(|| {
+            let get_version: unsafe extern "C" fn(*mut u32, *mut u32, *mut u32) =
+                std::mem::transmute(get_version);
+            let (mut major, mut minor, mut patch) = (0, 0, 0);
+            get_version(&mut major, &mut minor, &mut patch);
+            major
+        });
+        let _: libc::c_int = libc::dlclose(handle);
+        match major {
+            Some(LLVM_MAJOR) => Ok(()),
+            major => Err(Rejection::WrongVersion(major)),
+        }
+    }
+}
+
+// The libdir of `RUSTC` if set, else of the toolchain rustup runs, which doesn't need spawning
+// rustc, else of `rustc`.
+fn rustc_libdir() -> Option<PathBuf> {
+    let rustc = env::var_os("RUSTC");
+    if let (None, Some(home), Some(toolchain)) = (
+        &rustc,
+        env::var_os("RUSTUP_HOME"),
+        env::var_os("RUSTUP_TOOLCHAIN"),
+    ) {
+        let libdir = Path::new(&home)
+            .join("toolchains")
+            .join(toolchain)
+            .join("lib");
+        if libdir.is_dir() {
+            return Some(libdir);
+        }
+    }
+    let rustc = rustc.unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc)
+        .args(["--print", "sysroot"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let sysroot = String::from_utf8(output.stdout).ok()?;
+    Some(Path::new(sysroot.trim()).join("lib"))
+}
+
+fn system_libdirs() -> Vec<PathBuf> {
+    let arch = env::consts::ARCH;
+    [
+        format!("/usr/lib/llvm-{LLVM_MAJOR}/lib"),
+        format!("/usr/lib/llvm/{LLVM_MAJOR}/lib64"),
+        format!("/usr/lib/llvm/{LLVM_MAJOR}/lib"),
+        format!("/usr/lib/{arch}-linux-gnu"),
+        "/usr/lib64".to_owned(),
+        "/usr/lib".to_owned(),
+        "/usr/local/lib".to_owned(),
+    ]
+    .into_iter()
+    .map(PathBuf::from)
+    .collect()
+}
+
+// Returns the shared libraries in `dir` whose name matches `matches`, sorted by name.
+fn libraries_in(dir: &Path, matches: impl Fn(&str) -> bool) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut libs: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.contains(".so") && matches(&name)
+        })
+        .map(|entry| entry.path())
+        .collect();
+    libs.sort();
+    libs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_found_message() {
+        let err = LlvmProxyError::NotFound(vec![
+            (PathBuf::from("/a/libLLVM.so"), Rejection::NotFound),
+            (
+                PathBuf::from("/b/libLLVM.so.18"),
+                Rejection::WrongVersion(Some(18)),
+            ),
+        ]);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "no usable LLVM {LLVM_MAJOR} shared library found, tried:\n  /a/libLLVM.so: not \
+                 found\n  /b/libLLVM.so.18: LLVM 18, expected {LLVM_MAJOR}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_major_from_name() {
+        for (name, major) in [
+            ("libLLVM.so.19.1", Some(19)),
+            ("libLLVM.so.19", Some(19)),
+            ("libLLVM.so.19.1-rust-1.84.0-stable", Some(19)),
+            ("libLLVM-18-rust-1.80.0-stable.so", Some(18)),
+            ("libLLVM-19.so", Some(19)),
+            ("libLLVM.so", None),
+            ("libfoo.so.19", None),
+        ] {
+            assert_eq!(major_from_name(Path::new(name)), major, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_create_private_dir() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("llvm");
+        create_private_dir(&dir).unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        // already there
+        create_private_dir(&dir).unwrap();
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        let err = create_private_dir(&dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let link = tmp.path().join("link");
+        symlink(tmp.path(), &link).unwrap();
+        let err = create_private_dir(&link).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_check_missing() {
+        assert_eq!(
+            check(Path::new("/nonexistent/libLLVM.so")),
+            Err(Rejection::NotFound)
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Parsing of the `.llvmcmd` section that accompanies bitcode embedded in object files.
+//!
+//! The section holds the NUL separated codegen options the bitcode was produced with (for clang
+//! these are the `-cc1` arguments). rustc leaves it empty, in which case nothing can be inferred.
+
+/// The options recorded in `.llvmcmd` which affect how the bitcode links.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct EmbeddedCmdline {
+    /// The optimization level, eg `0`, `2` or `s`.
+    pub opt_level: Option<String>,
+    /// Whether the bitcode carries the type information needed to generate BTF. `None` if the
+    /// section doesn't say.
+    pub debug_info: Option<bool>,
+}
+
+impl EmbeddedCmdline {
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        let mut cmdline = Self::default();
+        let args = data
+            .split(|b| *b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(String::from_utf8_lossy);
+        for arg in args {
+            if let Some(kind) = arg.strip_prefix("-debug-info-kind=") {
+                // line tables don't describe any type
+                cmdline.debug_info =
+                    Some(!matches!(kind, "line-tables-only" | "line-directives-only"));
+            } else if let Some(level) = arg.strip_prefix("-O") {
+                // -O alone means -O1
+                cmdline.opt_level = Some(if level.is_empty() { "1" } else { level }.to_owned());
+            }
+        }
+        if cmdline.debug_info.is_none() && cmdline.opt_level.is_some() {
+            // clang only omits -debug-info-kind when building without debug info
+            cmdline.debug_info = Some(false);
+        }
+        cmdline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(EmbeddedCmdline::parse(b""), EmbeddedCmdline::default());
+        assert_eq!(
+            EmbeddedCmdline::parse(b"-triple\0bpf\0-O2\0-debug-info-kind=constructor\0"),
+            EmbeddedCmdline {
+                opt_level: Some("2".to_owned()),
+                debug_info: Some(true),
+            }
+        );
+        assert_eq!(
+            EmbeddedCmdline::parse(b"-O\0-debug-info-kind=line-tables-only"),
+            EmbeddedCmdline {
+                opt_level: Some("1".to_owned()),
+                debug_info: Some(false),
+            }
+        );
+        assert_eq!(
+            EmbeddedCmdline::parse(b"-Os\0-emit-obj"),
+            EmbeddedCmdline {
+                opt_level: Some("s".to_owned()),
+                debug_info: Some(false),
+            }
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! Inspection of the object file written by the linker, to validate it right after linking.
+
+use std::path::Path;
+
+use llvm_sys::core::{LLVMContextCreate, LLVMContextDispose};
+use sha2::{Digest as _, Sha256};
+
+use crate::{
+    btf::{Btf, BtfKind},
+    hash::to_hex,
+    llvm::{self, ObjectSymbol},
+    LinkerError,
+};
+
+/// The type of a BPF program, inferred from the name of its section like libbpf does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgramType {
+    KProbe,
+    KRetProbe,
+    UProbe,
+    URetProbe,
+    TracePoint,
+    RawTracePoint,
+    BtfTracePoint,
+    FEntry,
+    FExit,
+    FModRet,
+    Extension,
+    Lsm,
+    Iter,
+    Syscall,
+    StructOps,
+    PerfEvent,
+    Xdp,
+    SchedClassifier,
+    SchedAction,
+    SocketFilter,
+    SockOps,
+    SkSkb,
+    SkMsg,
+    SkLookup,
+    FlowDissector,
+    LircMode2,
+    CgroupSkb,
+    CgroupSock,
+    CgroupSockAddr,
+    CgroupSockopt,
+    CgroupSysctl,
+    CgroupDevice,
+    /// The section name doesn't follow any known convention.
+    Unknown,
+}
+
+impl ProgramType {
+    /// Infers the program type from the name of the section of the program, eg `xdp` or
+    /// `kprobe/do_unlinkat`.
+    pub fn from_section(section: &str) -> Self {
+        use ProgramType::*;
+        let mut parts = section.split('/');
+        // variants such as `xdp.frags` or `kprobe.multi` have the type of their base
+        let kind = parts.next().unwrap_or_default();
+        let kind = kind.split('.').next().unwrap_or_default();
+        match kind {
+            "kprobe" | "ksyscall" => KProbe,
+            "kretprobe" | "kretsyscall" => KRetProbe,
+            "uprobe" | "usdt" => UProbe,
+            "uretprobe" => URetProbe,
+            "tracepoint" | "tp" => TracePoint,
+            "raw_tracepoint" | "raw_tp" => RawTracePoint,
+            "tp_btf" => BtfTracePoint,
+            "fentry" => FEntry,
+            "fexit" => FExit,
+            "fmod_ret" => FModRet,
+            "freplace" => Extension,
+            "lsm" | "lsm_cgroup" => Lsm,
+            "iter" => Iter,
+            "syscall" => Syscall,
+            "struct_ops" => StructOps,
+            "perf_event" => PerfEvent,
+            "xdp" => Xdp,
+            "tc" | "tcx" | "classifier" => SchedClassifier,
+            "action" => SchedAction,
+            "socket" => SocketFilter,
+            "sockops" => SockOps,
+            "sk_skb" => SkSkb,
+            "sk_msg" => SkMsg,
+            "sk_lookup" => SkLookup,
+            "flow_dissector" => FlowDissector,
+            "lirc_mode2" => LircMode2,
+            "cgroup_skb" => CgroupSkb,
+            "cgroup" => match parts.next().unwrap_or_default() {
+                "skb" => CgroupSkb,
+                "sock" | "sock_create" | "sock_release" | "post_bind4" | "post_bind6" => CgroupSock,
+                "bind4" | "bind6" | "connect4" | "connect6" | "connect_unix" | "getpeername4"
+                | "getpeername6" | "getpeername_unix" | "getsockname4" | "getsockname6"
+                | "getsockname_unix" | "sendmsg4" | "sendmsg6" | "sendmsg_unix" | "recvmsg4"
+                | "recvmsg6" | "recvmsg_unix" => CgroupSockAddr,
+                "getsockopt" | "setsockopt" => CgroupSockopt,
+                "sysctl" => CgroupSysctl,
+                "dev" => CgroupDevice,
+                _ => Unknown,
+            },
+            _ => Unknown,
+        }
+    }
+}
+
+/// A program defined in the output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    pub name: String,
+    pub section: String,
+    pub program_type: ProgramType,
+}
+
+/// A map defined in the output. The properties are `None` when the definition doesn't set them,
+/// leaving them to the loader.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Map {
+    pub name: String,
+    pub section: String,
+    /// The `bpf_map_type`, eg `1` for `BPF_MAP_TYPE_HASH`.
+    pub map_type: Option<u32>,
+    pub key_size: Option<u32>,
+    pub value_size: Option<u32>,
+    pub max_entries: Option<u32>,
+    pub map_flags: Option<u32>,
+    pub pinning: Option<u32>,
+}
+
+/// An object file written by the linker.
+#[derive(Clone, Debug)]
+pub struct LinkerOutput {
+    data: Vec<u8>,
+}
+
+impl LinkerOutput {
+    /// Wraps the contents of an object file.
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    /// Reads the object file at `path`.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, LinkerError> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).map_err(|e| LinkerError::IoError(path.to_owned(), e))?;
+        Ok(Self::new(data))
+    }
+
+    /// Returns the programs defined in the output, ie the functions in the sections named after
+    /// program types. Functions in `.text` are BPF-to-BPF calls, not programs.
+    pub fn programs(&self) -> Result<Vec<Program>, LinkerError> {
+        Ok(self
+            .symbols()?
+            .into_iter()
+            .filter(|symbol| is_program_section(&symbol.section))
+            .map(|ObjectSymbol { name, section, .. }| Program {
+                program_type: ProgramType::from_section(&section),
+                name,
+                section,
+            })
+            .collect())
+    }
+
+    /// Returns the maps defined in the output, both the legacy `bpf_map_def` maps of the `maps`
+    /// sections and the BTF maps of the `.maps` section.
+    pub fn maps(&self) -> Result<Vec<Map>, LinkerError> {
+        let symbols = self.symbols()?;
+        let mut maps = Vec::new();
+        let mut btf = None;
+        for ObjectSymbol {
+            name,
+            section,
+            offset,
+            size,
+        } in symbols
+        {
+            let map = if section == ".maps" {
+                if btf.is_none() {
+                    let data = self.section(".BTF")?.ok_or_else(|| {
+                        LinkerError::InvalidOutput("BTF maps without a .BTF section".to_owned())
+                    })?;
+                    btf = Some(
+                        Btf::parse(&data).map_err(|e| LinkerError::InvalidOutput(e.to_string()))?,
+                    );
+                }
+                btf_map(btf.as_ref().unwrap(), name, section)
+            } else if section == "maps" || section.starts_with("maps/") {
+                let data = self.section(&section)?.unwrap_or_default();
+                let def = data
+                    .get(offset as usize..(offset + size) as usize)
+                    .unwrap_or_default();
+                legacy_map(def, self.is_big_endian(), name, section)
+            } else {
+                continue;
+            };
+            maps.push(map);
+        }
+        Ok(maps)
+    }
+
+    /// Returns the SHA-256 of what the output means to a loader, as a hex string. Two outputs
+    /// with the same hash have the same code, data, BTF, relocations and symbols, so build
+    /// pipelines can use it to tell whether a program changed and needs to be redeployed.
+    ///
+    /// The debug info, the `.comment` and linker metadata sections and the layout of the symbol
+    /// and string tables are left out, as they record paths and tool versions which change from
+    /// one build environment to the next.
+    pub fn content_hash(&self) -> Result<String, LinkerError> {
+        let sections =
+            unsafe { llvm::object_sections(&self.data) }.map_err(LinkerError::InvalidOutput)?;
+        let mut symbols = self.symbols()?;
+        symbols
+            .sort_by(|a, b| (&a.section, a.offset, &a.name).cmp(&(&b.section, b.offset, &b.name)));
+
+        let mut hasher = Sha256::new();
+        for (name, contents) in &sections {
+            if is_unstable_section(name) {
+                continue;
+            }
+            hasher.update(name.as_bytes());
+            hasher.update(b"\0");
+            hasher.update((contents.len() as u64).to_le_bytes());
+            hasher.update(contents);
+        }
+        for ObjectSymbol {
+            name,
+            section,
+            offset,
+            size,
+        } in &symbols
+        {
+            hasher.update(name.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(section.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(offset.to_le_bytes());
+            hasher.update(size.to_le_bytes());
+        }
+        Ok(to_hex(&hasher.finalize()))
+    }
+
+    fn is_big_endian(&self) -> bool {
+        // EI_DATA is ELFDATA2MSB
+        self.data.get(5) == Some(&2)
+    }
+
+    fn symbols(&self) -> Result<Vec<ObjectSymbol>, LinkerError> {
+        with_context(|context| unsafe { llvm::object_symbols(context, &self.data) })
+            .map_err(LinkerError::InvalidOutput)
+    }
+
+    pub(crate) fn section(&self, name: &str) -> Result<Option<Vec<u8>>, LinkerError> {
+        unsafe { llvm::section_contents(&self.data, name) }.map_err(LinkerError::InvalidOutput)
+    }
+}
+
+pub(crate) fn with_context<T>(f: impl FnOnce(llvm_sys::prelude::LLVMContextRef) -> T) -> T {
+    unsafe {
+        let context = LLVMContextCreate();
+        let ret = f(context);
+        LLVMContextDispose(context);
+        ret
+    }
+}
+
+// Sections which differ between builds of the same program, or whose contents are hashed through
+// the symbols instead.
+fn is_unstable_section(section: &str) -> bool {
+    let section = section
+        .strip_prefix(".rel")
+        .map_or(section, |rel| rel.strip_prefix('a').unwrap_or(rel));
+    section.starts_with(".debug")
+        || matches!(
+            section,
+            "" | ".comment"
+                | ".bpf.linker.meta"
+                | ".symtab"
+                | ".strtab"
+                | ".shstrtab"
+                | ".llvm_addrsig"
+        )
+}
+
+fn is_program_section(section: &str) -> bool {
+    !section.starts_with('.')
+        && !matches!(section, "license" | "version" | "maps")
+        && !section.starts_with("maps/")
+}
+
+// Reads a `bpf_map_def`, whose fields are type, key_size, value_size, max_entries, map_flags, and
+// for aya, id and pinning.
+fn legacy_map(def: &[u8], big_endian: bool, name: String, section: String) -> Map {
+    let field = |i: usize| {
+        let bytes = def.get(i * 4..i * 4 + 4)?.try_into().unwrap();
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    Map {
+        name,
+        section,
+        map_type: field(0),
+        key_size: field(1),
+        value_size: field(2),
+        max_entries: field(3),
+        map_flags: field(4),
+        pinning: field(6),
+    }
+}
+
+// BTF map definitions are structs whose integer properties are encoded as `int (*name)[value]`
+// and whose key and value types as `type *key`.
+fn btf_map(btf: &Btf, name: String, section: String) -> Map {
+    let mut map = Map {
+        name,
+        section,
+        ..Default::default()
+    };
+    let Some(def) = btf
+        .find(BtfKind::Var, &map.name)
+        .and_then(|var| btf.resolve(btf.get(var)?.size_or_type))
+    else {
+        return map;
+    };
+    for (name_off, ty, _offset) in def.members() {
+        let pointee = match btf.resolve(ty) {
+            Some(ty) if ty.kind == BtfKind::Ptr => ty.size_or_type,
+            _ => continue,
+        };
+        let value = || btf.resolve(pointee)?.array().map(|(_, nelems)| nelems);
+        match btf.string(name_off) {
+            "type" => map.map_type = value(),
+            "max_entries" => map.max_entries = value(),
+            "map_flags" => map.map_flags = value(),
+            "pinning" => map.pinning = value(),
+            "key_size" => map.key_size = value(),
+            "value_size" => map.value_size = value(),
+            "key" => map.key_size = btf.size_of(pointee),
+            "value" => map.value_size = btf.size_of(pointee),
+            _ => {}
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btf::tests::{btf_bytes, info};
+
+    #[test]
+    fn test_program_type() {
+        assert_eq!(ProgramType::from_section("xdp"), ProgramType::Xdp);
+        assert_eq!(ProgramType::from_section("xdp.frags"), ProgramType::Xdp);
+        assert_eq!(
+            ProgramType::from_section("kprobe/do_unlinkat"),
+            ProgramType::KProbe
+        );
+        assert_eq!(
+            ProgramType::from_section("cgroup/connect4"),
+            ProgramType::CgroupSockAddr
+        );
+        assert_eq!(
+            ProgramType::from_section("cgroup/foo"),
+            ProgramType::Unknown
+        );
+        assert_eq!(ProgramType::from_section("foo"), ProgramType::Unknown);
+    }
+
+    #[test]
+    fn test_is_unstable_section() {
+        for section in [
+            ".debug_info",
+            ".rel.debug_line",
+            ".rela.debug_str",
+            ".comment",
+            ".strtab",
+        ] {
+            assert!(is_unstable_section(section), "{section}");
+        }
+        for section in [
+            ".text",
+            ".rel.text",
+            "xdp",
+            ".relxdp",
+            ".BTF",
+            ".BTF.ext",
+            ".maps",
+        ] {
+            assert!(!is_unstable_section(section), "{section}");
+        }
+    }
+
+    #[test]
+    fn test_legacy_map() {
+        let def: Vec<u8> = [1u32, 4, 8, 1024, 0, 0, 1]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect();
+        let map = legacy_map(&def, true, "m".to_owned(), "maps".to_owned());
+        assert_eq!(map.map_type, Some(1));
+        assert_eq!(map.key_size, Some(4));
+        assert_eq!(map.value_size, Some(8));
+        assert_eq!(map.max_entries, Some(1024));
+        assert_eq!(map.pinning, Some(1));
+        let map = legacy_map(&def[..20], true, "m".to_owned(), "maps".to_owned());
+        assert_eq!(map.pinning, None);
+    }
+
+    #[test]
+    fn test_btf_map() {
+        let strings = b"\0int\0type\0max_entries\0key\0m\0";
+        let data = btf_bytes(
+            &[
+                // [1] int
+                &[1, info(1, 0), 4, 32],
+                // [2] int[2], the map type
+                &[0, info(3, 0), 0, 1, 1, 2],
+                // [3] int (*)[2]
+                &[0, info(2, 0), 2],
+                // [4] int[16], the max entries
+                &[0, info(3, 0), 0, 1, 1, 16],
+                // [5] int (*)[16]
+                &[0, info(2, 0), 4],
+                // [6] int *
+                &[0, info(2, 0), 1],
+                // [7] struct { type; max_entries; key; }
+                &[0, info(4, 3), 24, 5, 3, 0, 10, 5, 64, 22, 6, 128],
+                // [8] m
+                &[26, info(14, 0), 7, 1],
+            ],
+            strings,
+        );
+        let btf = Btf::parse(&data).unwrap();
+        assert_eq!(
+            btf_map(&btf, "m".to_owned(), ".maps".to_owned()),
+            Map {
+                name: "m".to_owned(),
+                section: ".maps".to_owned(),
+                map_type: Some(2),
+                key_size: Some(4),
+                max_entries: Some(16),
+                ..Default::default()
+            }
+        );
+    }
+}
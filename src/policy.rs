@@ -0,0 +1,80 @@
+//! Per-symbol decisions made by library users, see [`SymbolPolicy`].
+
+pub use crate::llvm::SymbolKind;
+
+/// A symbol defined by the linked module, as seen by a [`SymbolPolicy`].
+#[derive(Clone, Copy, Debug)]
+pub struct PolicySymbol<'a> {
+    /// The name of the symbol.
+    pub name: &'a str,
+    /// Whether the symbol is a function or a global variable.
+    pub kind: SymbolKind,
+    /// The section of the symbol, if one was set explicitly, eg with `#[link_section]` or
+    /// [`LinkerOptions::symbol_sections`](crate::LinkerOptions::symbol_sections).
+    pub section: Option<&'a str>,
+    /// Whether the options export the symbol, eg with [`LinkerOptions::export_symbols`](
+    /// crate::LinkerOptions::export_symbols).
+    pub exported: bool,
+}
+
+/// How a [`SymbolPolicy`] wants a symbol linked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolLinkage {
+    /// Exported symbols are global, the others are internalized and removed when unreferenced.
+    Default,
+    /// Never removed by optimization, like the symbols matching
+    /// [`LinkerOptions::keep_symbols`](crate::LinkerOptions::keep_symbols).
+    Keep,
+    /// Weak in the object, so that a loader linking several objects lets another definition
+    /// override it. Only applies to exported symbols.
+    Weak,
+}
+
+/// Decides what the linker does with each symbol the linked module defines, for policies which
+/// the export, keep and section options can't express, eg exporting the programs of some
+/// sections only.
+///
+/// The methods are called once per function and global variable defined by the inputs, at the
+/// beginning of optimization. Returning `None`, which the default implementations do, leaves the
+/// decision to the options.
+///
+/// ```no_run
+/// use bpf_linker::{Linker, LinkerOptions, PolicySymbol, SymbolPolicy};
+///
+/// struct ExportXdp;
+///
+/// impl SymbolPolicy for ExportXdp {
+///     fn decide_export(&self, symbol: &PolicySymbol<'_>) -> Option<bool> {
+///         symbol
+///             .section
+///             .is_some_and(|section| section.starts_with("xdp"))
+///             .then_some(true)
+///     }
+/// }
+///
+/// let options = LinkerOptions::builder().input("prog.bc").output("prog.o").build()?;
+/// let mut linker = Linker::new(options)?;
+/// linker.set_symbol_policy(ExportXdp);
+/// linker.link()?;
+/// # Ok::<(), bpf_linker::LinkerError>(())
+/// ```
+pub trait SymbolPolicy {
+    /// Whether to export `symbol`: `Some(true)` exports it, `Some(false)` internalizes it even
+    /// when the options export it. Memory builtins and struct_ops are always exported.
+    fn decide_export(&self, symbol: &PolicySymbol<'_>) -> Option<bool> {
+        let _ = symbol;
+        None
+    }
+
+    /// The section to move `symbol` to.
+    fn decide_section(&self, symbol: &PolicySymbol<'_>) -> Option<String> {
+        let _ = symbol;
+        None
+    }
+
+    /// How to link `symbol`.
+    fn decide_linkage(&self, symbol: &PolicySymbol<'_>) -> Option<SymbolLinkage> {
+        let _ = symbol;
+        None
+    }
+}
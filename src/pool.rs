@@ -0,0 +1,330 @@
+//! Reuse of LLVM contexts and target machines across links, for processes linking many programs
+//! one after the other.
+
+use std::mem;
+
+use llvm_sys::{
+    core::LLVMContextDispose,
+    prelude::LLVMContextRef,
+    target_machine::{LLVMDisposeTargetMachine, LLVMTargetMachineRef},
+};
+
+use crate::{CodeModel, Linker, LinkerError, LinkerOptions, LinkerStats, OptLevel, RelocModel};
+
+/// The number of links after which a context is disposed of, as LLVM never frees the types and
+/// constants created in a context.
+const MAX_CONTEXT_LINKS: usize = 64;
+
+/// A pool of LLVM contexts and target machines reused by sequential links.
+///
+/// Creating a context and a target machine for each link is a significant part of the time it
+/// takes to link small programs. The pool keeps them from one link to the next: one context, and
+/// up to `capacity` target machines, the least recently used being disposed of first. The context
+/// is replaced every 64 links to bound the memory it holds.
+///
+/// LLVM options are global to the process and can't be unset, so the LLVM command line of a
+/// link, made of [`LinkerOptions::llvm_args`] and of the options bpf-linker sets itself, stays in
+/// effect for the later links. So that it doesn't silently apply to them, eg the loop unrolling
+/// thresholds raised by [`LinkerOptions::unroll_loops`], a pooled link fails with
+/// [`LinkerError::PooledLlvmArgs`] when the command line in effect has arguments which its own
+/// doesn't, be it because of another value, eg a different [`LinkerOptions::remarks_filter`], or
+/// of another option. Links may add arguments: all the links of a pool should use the same LLVM
+/// command line.
+///
+/// ```no_run
+/// use bpf_linker::{LinkerOptions, LinkerPool};
+///
+/// let mut pool = LinkerPool::new(2);
+/// for program in ["a", "b"] {
+///     let options = LinkerOptions::builder()
+///         .input(format!("{program}.bc"))
+///         .output(format!("{program}.o"))
+///         .export(program)
+///         .build()?;
+///     let _stats = pool.link(options)?;
+/// }
+/// pool.clear();
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct LinkerPool {
+    state: PoolState,
+}
+
+impl LinkerPool {
+    /// Creates a pool keeping up to `capacity` target machines.
+    pub fn new(capacity: usize) -> Self {
+        let mut state = PoolState::default();
+        state.capacity = capacity;
+        LinkerPool { state }
+    }
+
+    /// Links with `options`, like [`Linker::link`], reusing the context and target machines of
+    /// the previous links. Returns the statistics of the link.
+    pub fn link(&mut self, options: LinkerOptions) -> Result<LinkerStats, LinkerError> {
+        let mut linker = match Linker::pooled(options, mem::take(&mut self.state)) {
+            Ok(linker) => linker,
+            Err((err, state)) => {
+                self.state = state;
+                return Err(err);
+            }
+        };
+        let ret = linker.link();
+        self.state = linker.release_pool();
+        ret.map(|()| linker.stats().clone())
+    }
+
+    /// Disposes of the context and target machines kept by the pool. The next link creates new
+    /// ones.
+    pub fn clear(&mut self) {
+        self.state.clear();
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct PoolState {
+    context: Option<LLVMContextRef>,
+    // links done in `context`
+    context_links: usize,
+    // idle target machines, the least recently used first
+    target_machines: Vec<(TargetMachineKey, LLVMTargetMachineRef)>,
+    capacity: usize,
+}
+
+/// What a target machine is created from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TargetMachineKey {
+    pub(crate) triple: String,
+    pub(crate) cpu: String,
+    pub(crate) features: String,
+    pub(crate) code_model: CodeModel,
+    pub(crate) reloc_model: RelocModel,
+    pub(crate) optimize: OptLevel,
+}
+
+impl PoolState {
+    pub(crate) fn take_context(&mut self) -> Option<LLVMContextRef> {
+        self.context.take()
+    }
+
+    pub(crate) fn put_context(&mut self, context: LLVMContextRef) {
+        self.context_links += 1;
+        if self.context_links >= MAX_CONTEXT_LINKS {
+            self.context_links = 0;
+            unsafe { LLVMContextDispose(context) };
+        } else {
+            self.context = Some(context);
+        }
+    }
+
+    pub(crate) fn take_target_machine(
+        &mut self,
+        key: &TargetMachineKey,
+    ) -> Option<LLVMTargetMachineRef> {
+        let index = self.target_machines.iter().position(|(k, _)| k == key)?;
+        Some(self.target_machines.remove(index).1)
+    }
+
+    pub(crate) fn put_target_machine(
+        &mut self,
+        key: TargetMachineKey,
+        target_machine: LLVMTargetMachineRef,
+    ) {
+        self.target_machines.push((key, target_machine));
+        while self.target_machines.len() > self.capacity {
+            let (_, target_machine) = self.target_machines.remove(0);
+            unsafe { LLVMDisposeTargetMachine(target_machine) };
+        }
+    }
+
+    fn clear(&mut self) {
+        if let Some(context) = self.context.take() {
+            unsafe { LLVMContextDispose(context) };
+        }
+        self.context_links = 0;
+        for (_, target_machine) in self.target_machines.drain(..) {
+            unsafe { LLVMDisposeTargetMachine(target_machine) };
+        }
+    }
+}
+
+impl Drop for PoolState {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process::Command};
+
+    use llvm_sys::core::LLVMContextCreate;
+
+    use super::*;
+    use crate::llvm;
+
+    fn key(cpu: &str) -> TargetMachineKey {
+        TargetMachineKey {
+            triple: "bpfel".to_owned(),
+            cpu: cpu.to_owned(),
+            features: String::new(),
+            code_model: CodeModel::Default,
+            reloc_model: RelocModel::Default,
+            optimize: OptLevel::Default,
+        }
+    }
+
+    fn target_machine(key: &TargetMachineKey) -> LLVMTargetMachineRef {
+        unsafe {
+            llvm::init_target();
+            let target = llvm::target_from_triple(c"bpfel").unwrap();
+            llvm::create_target_machine(
+                target,
+                &key.triple,
+                &key.cpu,
+                &key.features,
+                key.code_model,
+                key.reloc_model,
+                key.optimize,
+            )
+            .unwrap()
+        }
+    }
+
+    #[test]
+    fn test_context_reuse() {
+        let mut state = PoolState::default();
+        let context = unsafe { LLVMContextCreate() };
+        state.put_context(context);
+        assert_eq!(state.take_context(), Some(context));
+        assert_eq!(state.take_context(), None);
+
+        // the context is disposed of after MAX_CONTEXT_LINKS links
+        for _ in 1..MAX_CONTEXT_LINKS {
+            state.put_context(context);
+            assert_eq!(state.take_context(), Some(context));
+        }
+        state.put_context(context);
+        assert_eq!(state.take_context(), None);
+        assert_eq!(state.context_links, 0);
+    }
+
+    #[test]
+    fn test_target_machine_reuse() {
+        let mut state = PoolState {
+            capacity: 2,
+            ..Default::default()
+        };
+        let (v1, v2) = (key("v1"), key("v2"));
+        let target_machine = target_machine(&v1);
+        state.put_target_machine(v1.clone(), target_machine);
+        assert_eq!(state.take_target_machine(&v2), None);
+        assert_eq!(state.take_target_machine(&v1), Some(target_machine));
+        assert_eq!(state.take_target_machine(&v1), None);
+        state.put_target_machine(v1, target_machine);
+    }
+
+    #[test]
+    fn test_target_machine_lru() {
+        let mut state = PoolState {
+            capacity: 2,
+            ..Default::default()
+        };
+        let (v1, v2, v3) = (key("v1"), key("v2"), key("v3"));
+        let tm1 = target_machine(&v1);
+        let tm2 = target_machine(&v2);
+        state.put_target_machine(v1.clone(), tm1);
+        state.put_target_machine(v2.clone(), tm2);
+        // v1 becomes the most recently used, so v2 is disposed of to make room for v3
+        assert_eq!(state.take_target_machine(&v1), Some(tm1));
+        state.put_target_machine(v1.clone(), tm1);
+        let tm3 = target_machine(&v3);
+        state.put_target_machine(v3.clone(), tm3);
+        assert_eq!(state.take_target_machine(&v2), None);
+        assert_eq!(state.take_target_machine(&v1), Some(tm1));
+        assert_eq!(state.take_target_machine(&v3), Some(tm3));
+        state.put_target_machine(v1, tm1);
+        state.put_target_machine(v3, tm3);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut pool = LinkerPool::new(1);
+        let v1 = key("v1");
+        pool.state.put_context(unsafe { LLVMContextCreate() });
+        pool.state
+            .put_target_machine(v1.clone(), target_machine(&v1));
+        pool.clear();
+        assert_eq!(pool.state.take_context(), None);
+        assert_eq!(pool.state.context_links, 0);
+        assert_eq!(pool.state.take_target_machine(&v1), None);
+    }
+
+    #[test]
+    fn test_link_llvm_args() {
+        // LLVM options are global to the process and other tests set some, so the links run in a
+        // process of their own
+        if env::var_os("BPF_LINKER_TEST_POOL_PROCESS").is_none() {
+            let status = Command::new(env::current_exe().unwrap())
+                .args(["--exact", "pool::tests::test_link_llvm_args"])
+                .env("BPF_LINKER_TEST_POOL_PROCESS", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        const IR: &str = r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) {
+  ret i32 0
+}
+"#;
+        let bitcode = unsafe {
+            let context = LLVMContextCreate();
+            let bitcode = llvm::ir_to_bitcode(context, IR).unwrap();
+            LLVMContextDispose(context);
+            bitcode
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let options = |llvm_arg: Option<&str>| {
+            let options = LinkerOptions::builder()
+                .input_buffer("prog.ll", bitcode.clone())
+                .output(dir.path().join("prog.o"))
+                .export("prog");
+            llvm_arg
+                .into_iter()
+                .fold(options, |options, arg| options.llvm_arg(arg))
+                .build()
+                .unwrap()
+        };
+        let unrolled = || {
+            let mut options = options(Some("--inline-threshold=225"));
+            options.unroll_loops = true;
+            options.unroll_functions = vec!["prog".to_owned()];
+            options
+        };
+
+        let mut pool = LinkerPool::new(1);
+        pool.link(options(None)).unwrap();
+        // links may add LLVM options, and repeat the ones set by earlier links
+        pool.link(options(Some("--inline-threshold=225"))).unwrap();
+        pool.link(options(Some("--inline-threshold=225"))).unwrap();
+        pool.link(unrolled()).unwrap();
+        // but can't run with the ones they don't set, nor change them
+        let stale = |options| match pool.link(options) {
+            Err(LinkerError::PooledLlvmArgs(stale)) => stale,
+            ret => panic!("{ret:?}"),
+        };
+        let pragma_unroll = format!("--pragma-unroll-threshold={}", u32::MAX);
+        assert_eq!(
+            stale(options(Some("--inline-threshold=225"))),
+            [pragma_unroll.clone()]
+        );
+        assert_eq!(
+            stale(options(Some("--inline-threshold=300"))),
+            ["--inline-threshold=225".to_owned(), pragma_unroll]
+        );
+        pool.link(unrolled()).unwrap();
+    }
+}
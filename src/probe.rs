@@ -0,0 +1,150 @@
+//! Detection of the BPF instruction set version the running kernel supports, for `--cpu native`.
+
+use std::fs;
+
+use tracing::debug;
+
+use crate::Cpu;
+
+/// Returns the newest CPU version the running kernel loads programs for. The kernel is probed by
+/// loading a program using an instruction of each version, like `bpftool feature probe` does.
+/// When loading programs isn't allowed, eg without `CAP_BPF`, the version is inferred from the
+/// kernel release instead.
+pub(crate) fn native_cpu() -> Result<Cpu, String> {
+    match probe_instructions() {
+        Ok(cpu) => Ok(cpu),
+        Err(err) => {
+            debug!("can't probe the kernel, inferring the CPU from its release: {err}");
+            let release = fs::read_to_string("/proc/sys/kernel/osrelease")
+                .map_err(|e| format!("can't probe the kernel ({err}) or read its release ({e})"))?;
+            cpu_for_release(release.trim())
+                .ok_or_else(|| format!("unexpected kernel release `{}`", release.trim()))
+        }
+    }
+}
+
+// Returns the newest CPU version supported by the kernel release `release`, eg `6.8.0-45-generic`.
+fn cpu_for_release(release: &str) -> Option<Cpu> {
+    let mut numbers = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|number| number.parse::<u32>().ok());
+    let version = (numbers.next()??, numbers.next()??);
+    Some(match version {
+        v if v >= (6, 6) => Cpu::V4,
+        v if v >= (5, 1) => Cpu::V3,
+        v if v >= (4, 14) => Cpu::V2,
+        _ => Cpu::V1,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn probe_instructions() -> Result<Cpu, String> {
+    use std::{io, mem};
+
+    /// `struct bpf_insn`.
+    #[repr(C)]
+    struct Insn {
+        code: u8,
+        regs: u8,
+        off: i16,
+        imm: i32,
+    }
+
+    const fn insn(code: u8, off: i16, imm: i32) -> Insn {
+        // only r0 is used
+        Insn {
+            code,
+            regs: 0,
+            off,
+            imm,
+        }
+    }
+
+    /// The start of the `BPF_PROG_LOAD` variant of `union bpf_attr`.
+    #[repr(C)]
+    struct ProgLoadAttr {
+        prog_type: u32,
+        insn_cnt: u32,
+        insns: u64,
+        license: u64,
+        log_level: u32,
+        log_size: u32,
+        log_buf: u64,
+        kern_version: u32,
+        prog_flags: u32,
+    }
+
+    const BPF_PROG_LOAD: libc::c_long = 5;
+    const BPF_PROG_TYPE_SOCKET_FILTER: u32 = 1;
+    // r0 = 0
+    const MOV_R0_0: Insn = insn(0xb7, 0, 0);
+    const EXIT: Insn = insn(0x95, 0, 0);
+
+    fn load(insns: &[Insn]) -> io::Result<()> {
+        let license = c"GPL";
+        let attr = ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_SOCKET_FILTER,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: 0,
+            log_size: 0,
+            log_buf: 0,
+            kern_version: 0,
+            prog_flags: 0,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_PROG_LOAD,
+                &attr as *const ProgLoadAttr,
+                mem::size_of::<ProgLoadAttr>(),
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let _: libc::c_int = unsafe { libc::close(fd as libc::c_int) };
+        Ok(())
+    }
+
+    // a program any kernel loads, telling whether programs can be loaded at all
+    load(&[MOV_R0_0, EXIT]).map_err(|e| e.to_string())?;
+    let probes = [
+        // r0 = (s8) r0, a sign extending move
+        (Cpu::V4, insn(0xbf, 8, 0)),
+        // if w0 < 1 goto +0, a 32-bit jump
+        (Cpu::V3, insn(0xa6, 0, 1)),
+        // if r0 < 1 goto +0, a less than jump
+        (Cpu::V2, insn(0xa5, 0, 1)),
+    ];
+    for (cpu, probe) in probes {
+        match load(&[MOV_R0_0, probe, EXIT]) {
+            Ok(()) => return Ok(cpu),
+            Err(err) => debug!("the kernel doesn't support {cpu}: {err}"),
+        }
+    }
+    Ok(Cpu::V1)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_instructions() -> Result<Cpu, String> {
+    Err("BPF programs can only be loaded on Linux".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_for_release() {
+        let cpu = |release| cpu_for_release(release).map(|cpu| cpu.to_string());
+        assert_eq!(cpu("6.8.0-45-generic").as_deref(), Some("v4"));
+        assert_eq!(cpu("6.6").as_deref(), Some("v4"));
+        assert_eq!(cpu("6.1.0-27-amd64").as_deref(), Some("v3"));
+        assert_eq!(cpu("5.4.0").as_deref(), Some("v3"));
+        assert_eq!(cpu("4.19.0").as_deref(), Some("v2"));
+        assert_eq!(cpu("4.9.337").as_deref(), Some("v1"));
+        assert_eq!(cpu("unknown").as_deref(), None);
+    }
+}
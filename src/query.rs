@@ -0,0 +1,149 @@
+//! Queries to LLVM answered by a child process.
+//!
+//! LLVM exits the process on invalid command line arguments, only warns about unknown CPU
+//! features, and prints the features it supports rather than returning them. So these are
+//! answered by running an executable which calls [`answer_llvm_query`] at the start of `main`,
+//! like `bpf-linker` does, and reading what it prints.
+
+use std::{
+    env,
+    path::Path,
+    process::{self, Command, Stdio},
+};
+
+use tracing::{debug, error};
+
+use crate::{
+    cli::is_bpf_target, linker::invalid_llvm_arg, llvm, Cpu, CpuFeature, LinkerError, LinkerOptions,
+};
+
+// Set to the query to answer in the environment of the child.
+const QUERY_ENV: &str = "BPF_LINKER_LLVM_QUERY";
+
+// The name of the program LLVM is given, which its messages start with.
+const PROGRAM: &str = "bpf-linker";
+
+/// Answers the LLVM query of the parent process and exits, if the process was spawned to answer
+/// one by [`check_llvm_options`] or [`cpu_features`]. Returns otherwise.
+pub fn answer_llvm_query() {
+    let Some(query) = env::var_os(QUERY_ENV) else {
+        return;
+    };
+    let args: Vec<String> = env::args().skip(1).collect();
+    unsafe {
+        llvm::init_target();
+        match query.to_str() {
+            Some("args") => {
+                let mut command_line = vec![PROGRAM];
+                command_line.extend(args.iter().map(String::as_str));
+                llvm::parse_command_line(&command_line, "BPF linker");
+            }
+            Some("cpu-features") => {
+                let (Ok(target), [cpu]) = (llvm::target_from_triple(c"bpf"), args.as_slice())
+                else {
+                    process::exit(1)
+                };
+                llvm::print_cpu_features(target, "bpf", cpu);
+            }
+            _ => process::exit(1),
+        }
+    }
+    process::exit(0)
+}
+
+// Runs `exe` to answer `query` about `args`. Returns whether it succeeded and what it printed, or
+// `None` if it can't be run.
+fn query(exe: &Path, query: &str, args: &[&str]) -> Option<(bool, String)> {
+    let output = Command::new(exe)
+        .env(QUERY_ENV, query)
+        .args(args)
+        .stdin(Stdio::null())
+        .output();
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            debug!("can't run {} to query LLVM: {e}", exe.display());
+            return None;
+        }
+    };
+    let mut printed = String::from_utf8_lossy(&output.stdout).into_owned();
+    printed.push_str(&String::from_utf8_lossy(&output.stderr));
+    Some((output.status.success(), printed.trim().to_owned()))
+}
+
+/// Returns the CPU features that the BPF backend of the LLVM in use supports for `cpu`, as listed
+/// by `exe`, see [the module documentation](self).
+pub fn cpu_features(exe: &Path, cpu: Cpu) -> Result<Vec<CpuFeature>, LinkerError> {
+    let cpu = cpu.resolve()?;
+    let Some((true, output)) = query(exe, "cpu-features", &[cpu.to_str()]) else {
+        return Err(LinkerError::CpuFeaturesError);
+    };
+    Ok(llvm::parse_cpu_features(&output)
+        .into_iter()
+        .map(|(name, description)| CpuFeature { name, description })
+        .collect())
+}
+
+/// Checks the options which LLVM doesn't validate: [`LinkerOptions::llvm_args`], which it
+/// rejects by exiting the process, and [`LinkerOptions::cpu_features`], which it ignores when
+/// unknown. The checks run `exe`, see [the module documentation](self), and are skipped when it
+/// can't be run.
+pub fn check_llvm_options(exe: &Path, options: &LinkerOptions) -> Result<(), LinkerError> {
+    check_llvm_args(exe, &options.llvm_args)?;
+    check_cpu_features(exe, options)
+}
+
+// LLVMParseCommandLineOptions ignores unknown arguments and, depending on the LLVM version,
+// either prints invalid values to stderr and carries on or exits the process. So check that every
+// argument is an option LLVM knows, as listed by `--help-list-hidden`, then that LLVM parses the
+// arguments without failing or complaining.
+fn check_llvm_args(exe: &Path, llvm_args: &[String]) -> Result<(), LinkerError> {
+    if llvm_args.is_empty() {
+        return Ok(());
+    }
+    let invalid = |message: &str| {
+        error!("LLVM rejected its command line: {message}");
+        LinkerError::InvalidLlvmArg(invalid_llvm_arg(llvm_args, message))
+    };
+    if let Some((_, listing)) = query(exe, "args", &["--help-list-hidden"]) {
+        let known = llvm::option_names(&listing);
+        if !known.is_empty() {
+            for arg in llvm_args {
+                if !llvm::is_known_option(&known, arg) {
+                    return Err(invalid(&format!("Unknown command line argument '{arg}'")));
+                }
+            }
+        }
+    }
+    let args: Vec<&str> = llvm_args.iter().map(String::as_str).collect();
+    match query(exe, "args", &args) {
+        Some((false, output)) => Err(invalid(&output)),
+        Some((true, output)) if !output.is_empty() => Err(invalid(&output)),
+        _ => Ok(()),
+    }
+}
+
+// LLVM only warns about unknown CPU features and ignores them, so check them against the ones the
+// BPF backend supports before doing any work.
+fn check_cpu_features(exe: &Path, options: &LinkerOptions) -> Result<(), LinkerError> {
+    let LinkerOptions {
+        target,
+        cpu,
+        cpu_features,
+        ..
+    } = options;
+    if cpu_features.is_empty() || target.as_deref().is_some_and(|t| !is_bpf_target(t)) {
+        return Ok(());
+    }
+    // not being able to list the features shouldn't prevent linking
+    let Ok(available) = self::cpu_features(exe, *cpu) else {
+        return Ok(());
+    };
+    for feature in cpu_features.split(',').filter(|f| !f.is_empty()) {
+        let name = feature.trim_start_matches(['+', '-']);
+        if !available.iter().any(|f| f.name == name) {
+            return Err(LinkerError::InvalidCpuFeature(feature.to_owned()));
+        }
+    }
+    Ok(())
+}
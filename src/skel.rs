@@ -0,0 +1,468 @@
+//! Generation of skeletons, the user space side of the output.
+//!
+//! The C skeleton is a libbpf skeleton header, like `bpftool gen skeleton` generates. It embeds
+//! the object file and declares a struct with an accessor for each map and program, along with
+//! the `<name>__open`, `<name>__load`, `<name>__attach` and `<name>__destroy` functions built on
+//! the skeleton API of libbpf.
+//!
+//! The Rust skeleton is a module meant to be `include!`d by aya applications, with constants
+//! naming the programs and maps and the structs of the BTF, so that user space stays in sync with
+//! the programs at build time.
+//!
+//! The BTF ids file maps the exported functions to their BTF type ids, which the loader needs eg
+//! to register struct_ops or to refer to programs by BTF id.
+
+use std::{fmt::Write as _, path::Path};
+
+use crate::{
+    btf::{self, Btf},
+    LinkerError, LinkerOutput,
+};
+
+/// Returns the name of the skeleton written to `output`: its file name up to the first dot, made
+/// a valid C identifier, eg `prog` for `prog.skel.h`.
+pub(crate) fn skeleton_name(output: &Path) -> String {
+    let file_name = output
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let stem = file_name.split('.').next().unwrap_or_default();
+    match c_identifier(stem) {
+        name if name.is_empty() => "bpf".to_owned(),
+        name => name,
+    }
+}
+
+/// Generates the skeleton header `name` for the object file `object`.
+pub(crate) fn c_skeleton(name: &str, object: &[u8]) -> Result<String, LinkerError> {
+    let output = LinkerOutput::new(object.to_vec());
+    let maps: Vec<String> = output.maps()?.into_iter().map(|map| map.name).collect();
+    let programs: Vec<String> = output
+        .programs()?
+        .into_iter()
+        .map(|program| program.name)
+        .collect();
+    Ok(render(name, &maps, &programs, object))
+}
+
+fn render(name: &str, maps: &[String], programs: &[String], object: &[u8]) -> String {
+    let guard = name.to_uppercase();
+    let mut skel = format!(
+        "/* SPDX-License-Identifier: (LGPL-2.1 OR BSD-2-Clause) */\n\
+         /* THIS FILE IS AUTOGENERATED BY BPF-LINKER! */\n\
+         #ifndef __{guard}_SKEL_H__\n\
+         #define __{guard}_SKEL_H__\n\
+         \n\
+         #include <errno.h>\n\
+         #include <stdlib.h>\n\
+         #include <bpf/libbpf.h>\n\
+         \n\
+         struct {name} {{\n\
+         \tstruct bpf_object_skeleton *skeleton;\n\
+         \tstruct bpf_object *obj;\n"
+    );
+    // C doesn't allow empty structs
+    let fields = |skel: &mut String, group: &str, ty: &str, names: &[String]| {
+        if names.is_empty() {
+            return;
+        }
+        writeln!(skel, "\tstruct {{").unwrap();
+        for name in names {
+            writeln!(skel, "\t\tstruct {ty} *{};", c_identifier(name)).unwrap();
+        }
+        writeln!(skel, "\t}} {group};").unwrap();
+    };
+    fields(&mut skel, "maps", "bpf_map", maps);
+    fields(&mut skel, "progs", "bpf_program", programs);
+    fields(&mut skel, "links", "bpf_link", programs);
+    write!(
+        skel,
+        "}};\n\
+         \n\
+         static inline const void *{name}__elf_bytes(size_t *sz);\n\
+         \n\
+         static void {name}__destroy(struct {name} *obj)\n\
+         {{\n\
+         \tif (!obj)\n\
+         \t\treturn;\n\
+         \tif (obj->skeleton)\n\
+         \t\tbpf_object__destroy_skeleton(obj->skeleton);\n\
+         \tfree(obj);\n\
+         }}\n\
+         \n\
+         static inline int {name}__create_skeleton(struct {name} *obj)\n\
+         {{\n\
+         \tstruct bpf_object_skeleton *s;\n\
+         \tint err;\n\
+         \n\
+         \ts = (struct bpf_object_skeleton *)calloc(1, sizeof(*s));\n\
+         \tif (!s) {{\n\
+         \t\terr = -ENOMEM;\n\
+         \t\tgoto err;\n\
+         \t}}\n\
+         \n\
+         \ts->sz = sizeof(*s);\n\
+         \ts->name = \"{name}\";\n\
+         \ts->obj = &obj->obj;\n"
+    )
+    .unwrap();
+    if !maps.is_empty() {
+        write!(
+            skel,
+            "\n\
+             \ts->map_cnt = {};\n\
+             \ts->map_skel_sz = sizeof(*s->maps);\n\
+             \ts->maps = (struct bpf_map_skeleton *)calloc(s->map_cnt, s->map_skel_sz);\n\
+             \tif (!s->maps) {{\n\
+             \t\terr = -ENOMEM;\n\
+             \t\tgoto err;\n\
+             \t}}\n",
+            maps.len()
+        )
+        .unwrap();
+        for (i, map) in maps.iter().enumerate() {
+            let field = c_identifier(map);
+            writeln!(skel, "\ts->maps[{i}].name = \"{map}\";").unwrap();
+            writeln!(skel, "\ts->maps[{i}].map = &obj->maps.{field};").unwrap();
+        }
+    }
+    if !programs.is_empty() {
+        write!(
+            skel,
+            "\n\
+             \ts->prog_cnt = {};\n\
+             \ts->prog_skel_sz = sizeof(*s->progs);\n\
+             \ts->progs = (struct bpf_prog_skeleton *)calloc(s->prog_cnt, s->prog_skel_sz);\n\
+             \tif (!s->progs) {{\n\
+             \t\terr = -ENOMEM;\n\
+             \t\tgoto err;\n\
+             \t}}\n",
+            programs.len()
+        )
+        .unwrap();
+        for (i, program) in programs.iter().enumerate() {
+            let field = c_identifier(program);
+            writeln!(skel, "\ts->progs[{i}].name = \"{program}\";").unwrap();
+            writeln!(skel, "\ts->progs[{i}].prog = &obj->progs.{field};").unwrap();
+            writeln!(skel, "\ts->progs[{i}].link = &obj->links.{field};").unwrap();
+        }
+    }
+    write!(
+        skel,
+        "\n\
+         \ts->data = {name}__elf_bytes(&s->data_sz);\n\
+         \n\
+         \tobj->skeleton = s;\n\
+         \treturn 0;\n\
+         err:\n\
+         \tbpf_object__destroy_skeleton(s);\n\
+         \treturn err;\n\
+         }}\n\
+         \n\
+         static inline struct {name} *{name}__open_opts(const struct bpf_object_open_opts *opts)\n\
+         {{\n\
+         \tstruct {name} *obj;\n\
+         \tint err;\n\
+         \n\
+         \tobj = (struct {name} *)calloc(1, sizeof(*obj));\n\
+         \tif (!obj) {{\n\
+         \t\terrno = ENOMEM;\n\
+         \t\treturn NULL;\n\
+         \t}}\n\
+         \n\
+         \terr = {name}__create_skeleton(obj);\n\
+         \tif (err)\n\
+         \t\tgoto err_out;\n\
+         \n\
+         \terr = bpf_object__open_skeleton(obj->skeleton, opts);\n\
+         \tif (err)\n\
+         \t\tgoto err_out;\n\
+         \n\
+         \treturn obj;\n\
+         err_out:\n\
+         \t{name}__destroy(obj);\n\
+         \terrno = -err;\n\
+         \treturn NULL;\n\
+         }}\n\
+         \n\
+         static inline struct {name} *{name}__open(void)\n\
+         {{\n\
+         \treturn {name}__open_opts(NULL);\n\
+         }}\n\
+         \n\
+         static inline int {name}__load(struct {name} *obj)\n\
+         {{\n\
+         \treturn bpf_object__load_skeleton(obj->skeleton);\n\
+         }}\n\
+         \n\
+         static inline struct {name} *{name}__open_and_load(void)\n\
+         {{\n\
+         \tstruct {name} *obj;\n\
+         \tint err;\n\
+         \n\
+         \tobj = {name}__open();\n\
+         \tif (!obj)\n\
+         \t\treturn NULL;\n\
+         \terr = {name}__load(obj);\n\
+         \tif (err) {{\n\
+         \t\t{name}__destroy(obj);\n\
+         \t\terrno = -err;\n\
+         \t\treturn NULL;\n\
+         \t}}\n\
+         \treturn obj;\n\
+         }}\n\
+         \n\
+         static inline int {name}__attach(struct {name} *obj)\n\
+         {{\n\
+         \treturn bpf_object__attach_skeleton(obj->skeleton);\n\
+         }}\n\
+         \n\
+         static inline void {name}__detach(struct {name} *obj)\n\
+         {{\n\
+         \tbpf_object__detach_skeleton(obj->skeleton);\n\
+         }}\n\
+         \n\
+         static inline const void *{name}__elf_bytes(size_t *sz)\n\
+         {{\n\
+         \tstatic const char data[] __attribute__((__aligned__(8))) =\n"
+    )
+    .unwrap();
+    for chunk in object.chunks(32) {
+        skel.push_str("\t\t\"");
+        for byte in chunk {
+            write!(skel, "\\x{byte:02x}").unwrap();
+        }
+        skel.push_str("\"\n");
+    }
+    if object.is_empty() {
+        skel.push_str("\t\t\"\"\n");
+    }
+    write!(
+        skel,
+        "\t\t;\n\
+         \n\
+         \t*sz = sizeof(data) - 1;\n\
+         \treturn (const void *)data;\n\
+         }}\n\
+         \n\
+         #endif /* __{guard}_SKEL_H__ */\n"
+    )
+    .unwrap();
+    skel
+}
+
+/// Generates the Rust skeleton for the object file `object`.
+pub(crate) fn rust_skeleton(object: &[u8]) -> Result<String, LinkerError> {
+    let output = LinkerOutput::new(object.to_vec());
+    let programs: Vec<(String, String)> = output
+        .programs()?
+        .into_iter()
+        .map(|program| (program.name, program.section))
+        .collect();
+    let maps: Vec<(String, String)> = output
+        .maps()?
+        .into_iter()
+        .map(|map| (map.name, map.section))
+        .collect();
+    let types = match output.section(".BTF")? {
+        Some(data) => {
+            let btf = Btf::parse(&data).map_err(|e| LinkerError::InvalidOutput(e.to_string()))?;
+            btf::to_rust(&btf)
+        }
+        None => String::new(),
+    };
+    Ok(render_rust(&programs, &maps, &types))
+}
+
+fn render_rust(programs: &[(String, String)], maps: &[(String, String)], types: &str) -> String {
+    let mut skel = "// Generated by bpf-linker, do not edit.\n".to_owned();
+    let consts = |skel: &mut String, module: &str, doc: &str, names: &[(String, String)]| {
+        writeln!(skel, "\n/// {doc}").unwrap();
+        writeln!(skel, "pub mod {module} {{").unwrap();
+        for (name, section) in names {
+            let ident = c_identifier(name).to_uppercase();
+            writeln!(skel, "    /// In section `{section}`.").unwrap();
+            writeln!(skel, "    pub const {ident}: &str = {name:?};").unwrap();
+        }
+        writeln!(skel, "}}").unwrap();
+    };
+    consts(
+        &mut skel,
+        "programs",
+        "The names of the programs.",
+        programs,
+    );
+    consts(&mut skel, "maps", "The names of the maps.", maps);
+    skel.push_str(
+        "\n/// The structs and unions of the BTF of the programs.\n\
+         #[allow(non_camel_case_types, non_snake_case, dead_code)]\n\
+         pub mod types {\n",
+    );
+    for line in types.trim_end().lines() {
+        if line.is_empty() {
+            skel.push('\n');
+        } else {
+            writeln!(skel, "    {line}").unwrap();
+        }
+    }
+    skel.push_str("}\n");
+    skel
+}
+
+/// Generates the file mapping the exported functions of the object file `object` to their BTF
+/// type ids, as C defines prefixed by the skeleton `name` if `c` is set and as Rust constants
+/// otherwise.
+pub(crate) fn btf_ids(name: &str, object: &[u8], c: bool) -> Result<String, LinkerError> {
+    let output = LinkerOutput::new(object.to_vec());
+    let Some(data) = output.section(".BTF")? else {
+        return Err(LinkerError::InvalidOutput(
+            "no BTF to take the ids from, link with --btf".to_owned(),
+        ));
+    };
+    let btf = Btf::parse(&data).map_err(|e| LinkerError::InvalidOutput(e.to_string()))?;
+    let ids: Vec<_> = btf
+        .types
+        .iter()
+        .enumerate()
+        // the linkage of functions is in vlen, 1 for global functions
+        .filter(|(_, ty)| ty.kind == btf::BtfKind::Func && ty.vlen == 1)
+        .map(|(id, ty)| (btf.string(ty.name_off).to_owned(), id as u32))
+        .collect();
+    Ok(render_btf_ids(name, &ids, c))
+}
+
+fn render_btf_ids(name: &str, ids: &[(String, u32)], c: bool) -> String {
+    let mut out = "// Generated by bpf-linker, do not edit.\n\n".to_owned();
+    if c {
+        let guard = format!("__{}_BTF_IDS_H__", name.to_uppercase());
+        writeln!(out, "#ifndef {guard}\n#define {guard}\n").unwrap();
+        for (function, id) in ids {
+            let ident = format!("{name}__{}", c_identifier(function)).to_uppercase();
+            writeln!(out, "#define {ident}_BTF_ID {id}").unwrap();
+        }
+        writeln!(out, "\n#endif /* {guard} */").unwrap();
+    } else {
+        out.push_str("/// The BTF type ids of the exported functions.\npub mod btf_ids {\n");
+        for (function, id) in ids {
+            let ident = c_identifier(function).to_uppercase();
+            writeln!(out, "    /// `{function}`.").unwrap();
+            writeln!(out, "    pub const {ident}: u32 = {id};").unwrap();
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+// Replaces the characters which can't appear in a C identifier with underscores.
+fn c_identifier(name: &str) -> String {
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skeleton_name() {
+        assert_eq!(skeleton_name(Path::new("out/prog.skel.h")), "prog");
+        assert_eq!(skeleton_name(Path::new("my-prog.h")), "my_prog");
+        assert_eq!(skeleton_name(Path::new("1st.h")), "_1st");
+        assert_eq!(skeleton_name(Path::new(".h")), "bpf");
+    }
+
+    #[test]
+    fn test_render_rust() {
+        let skel = render_rust(
+            &[("xdp_main".to_owned(), "xdp".to_owned())],
+            &[("EVENTS".to_owned(), ".maps".to_owned())],
+            "#[repr(C)]\npub struct event {\n}\n\n",
+        );
+        assert_eq!(
+            skel,
+            r#"// Generated by bpf-linker, do not edit.
+
+/// The names of the programs.
+pub mod programs {
+    /// In section `xdp`.
+    pub const XDP_MAIN: &str = "xdp_main";
+}
+
+/// The names of the maps.
+pub mod maps {
+    /// In section `.maps`.
+    pub const EVENTS: &str = "EVENTS";
+}
+
+/// The structs and unions of the BTF of the programs.
+#[allow(non_camel_case_types, non_snake_case, dead_code)]
+pub mod types {
+    #[repr(C)]
+    pub struct event {
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_render_btf_ids() {
+        let ids = [("xdp_main".to_owned(), 3), ("tc.egress".to_owned(), 7)];
+        assert_eq!(
+            render_btf_ids("prog", &ids, false),
+            "// Generated by bpf-linker, do not edit.
+
+/// The BTF type ids of the exported functions.
+pub mod btf_ids {
+    /// `xdp_main`.
+    pub const XDP_MAIN: u32 = 3;
+    /// `tc.egress`.
+    pub const TC_EGRESS: u32 = 7;
+}
+"
+        );
+        assert_eq!(
+            render_btf_ids("prog", &ids, true),
+            "// Generated by bpf-linker, do not edit.
+
+#ifndef __PROG_BTF_IDS_H__
+#define __PROG_BTF_IDS_H__
+
+#define PROG__XDP_MAIN_BTF_ID 3
+#define PROG__TC_EGRESS_BTF_ID 7
+
+#endif /* __PROG_BTF_IDS_H__ */
+"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let skel = render(
+            "prog",
+            &["events".to_owned()],
+            &["xdp_main".to_owned()],
+            b"\x7fELF",
+        );
+        for line in [
+            "struct prog {",
+            "\t\tstruct bpf_map *events;",
+            "\t\tstruct bpf_program *xdp_main;",
+            "\t\tstruct bpf_link *xdp_main;",
+            "\ts->maps[0].name = \"events\";",
+            "\ts->progs[0].link = &obj->links.xdp_main;",
+            "\t\t\"\\x7f\\x45\\x4c\\x46\"",
+            "#endif /* __PROG_SKEL_H__ */",
+        ] {
+            assert!(skel.lines().any(|l| l == line), "{line:?} not in\n{skel}");
+        }
+        let skel = render("prog", &[], &[], b"");
+        assert!(!skel.contains("maps"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Estimation of the stack used by BPF functions from their machine code.
+//!
+//! BPF functions address their stack frame through the read-only frame pointer `r10`, so the
+//! deepest offset below `r10` that a function accesses, directly or through a register holding
+//! `r10` plus a constant, is the size of its frame. Branches are ignored: every instruction is
+//! assumed to be reachable, which can only overestimate.
+
+/// The stack size the verifier allows for a BPF program.
+pub(crate) const MAX_STACK_SIZE: u64 = 512;
+
+const INSN_SIZE: usize = 8;
+const FRAME_POINTER: usize = 10;
+
+const CLASS_LDX: u8 = 0x01;
+const CLASS_ST: u8 = 0x02;
+const CLASS_STX: u8 = 0x03;
+const CLASS_JMP: u8 = 0x05;
+
+const LD_IMM64: u8 = 0x18;
+const ALU64_ADD_K: u8 = 0x07;
+const ALU64_MOV_X: u8 = 0xbf;
+const JMP_CALL: u8 = 0x85;
+
+/// Returns the number of bytes of stack used by the function whose machine code is `code`.
+pub(crate) fn stack_usage(code: &[u8], big_endian: bool) -> u64 {
+    // the offset from r10 of the registers currently pointing into the stack frame
+    let mut frame_offsets = [None; 11];
+    frame_offsets[FRAME_POINTER] = Some(0i64);
+    let mut depth = 0;
+
+    let mut insns = code.chunks_exact(INSN_SIZE);
+    while let Some(insn) = insns.next() {
+        let opcode = insn[0];
+        let (dst, src) = if big_endian {
+            (insn[1] >> 4, insn[1] & 0xf)
+        } else {
+            (insn[1] & 0xf, insn[1] >> 4)
+        };
+        let (dst, src) = (usize::from(dst), usize::from(src));
+        let off = [insn[2], insn[3]];
+        let off = i64::from(if big_endian {
+            i16::from_be_bytes(off)
+        } else {
+            i16::from_le_bytes(off)
+        });
+        let imm = [insn[4], insn[5], insn[6], insn[7]];
+        let imm = i64::from(if big_endian {
+            i32::from_be_bytes(imm)
+        } else {
+            i32::from_le_bytes(imm)
+        });
+
+        let written = match opcode {
+            LD_IMM64 => {
+                // the immediate spans two instructions
+                let _: Option<&[u8]> = insns.next();
+                Some(dst)
+            }
+            ALU64_MOV_X => {
+                let offset = frame_offsets.get(src).copied().flatten();
+                if let Some(reg) = frame_offsets.get_mut(dst) {
+                    *reg = offset;
+                }
+                None
+            }
+            ALU64_ADD_K => {
+                if let Some(Some(offset)) = frame_offsets.get_mut(dst) {
+                    *offset += imm;
+                    depth = depth.max(-*offset);
+                }
+                None
+            }
+            JMP_CALL => {
+                // calls clobber r0-r5
+                frame_offsets[..=5].fill(None);
+                None
+            }
+            _ => match opcode & 0x7 {
+                CLASS_LDX => {
+                    if let Some(base) = frame_offsets.get(src).copied().flatten() {
+                        depth = depth.max(-(base + off));
+                    }
+                    Some(dst)
+                }
+                CLASS_ST | CLASS_STX => {
+                    if let Some(base) = frame_offsets.get(dst).copied().flatten() {
+                        depth = depth.max(-(base + off));
+                    }
+                    None
+                }
+                CLASS_JMP => None,
+                _ => Some(dst),
+            },
+        };
+        if let Some(reg) = written.filter(|reg| *reg != FRAME_POINTER) {
+            if let Some(offset) = frame_offsets.get_mut(reg) {
+                *offset = None;
+            }
+        }
+    }
+
+    depth.max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insn(opcode: u8, dst: u8, src: u8, off: i16, imm: i32) -> [u8; 8] {
+        let off = off.to_le_bytes();
+        let imm = imm.to_le_bytes();
+        [
+            opcode,
+            (src << 4) | dst,
+            off[0],
+            off[1],
+            imm[0],
+            imm[1],
+            imm[2],
+            imm[3],
+        ]
+    }
+
+    #[test]
+    fn test_stack_usage() {
+        // *(u64 *)(r10 - 8) = r1; r0 = *(u32 *)(r10 - 24); exit
+        let code = [
+            insn(0x7b, 10, 1, -8, 0),
+            insn(0x61, 0, 10, -24, 0),
+            insn(0x95, 0, 0, 0, 0),
+        ]
+        .concat();
+        assert_eq!(stack_usage(&code, false), 24);
+
+        // r1 = r10; r1 += -600; call 1; r1 = r10; r1 = 0; *(u8 *)(r1 - 8) = 0
+        let code = [
+            insn(ALU64_MOV_X, 1, 10, 0, 0),
+            insn(ALU64_ADD_K, 1, 0, 0, -600),
+            insn(JMP_CALL, 0, 0, 0, 1),
+            insn(ALU64_MOV_X, 1, 10, 0, 0),
+            insn(0xb7, 1, 0, 0, 0),
+            insn(0x72, 1, 0, -8, 0),
+        ]
+        .concat();
+        assert_eq!(stack_usage(&code, false), 600);
+
+        assert_eq!(stack_usage(&insn(0x95, 0, 0, 0, 0), false), 0);
+    }
+
+    #[test]
+    fn test_stack_usage_big_endian() {
+        // *(u64 *)(r10 - 16) = r1
+        let code = [0x7b, 0xa1, 0xff, 0xf0, 0, 0, 0, 0];
+        assert_eq!(stack_usage(&code, true), 16);
+    }
+}
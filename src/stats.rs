@@ -0,0 +1,300 @@
+//! Statistics collected while linking, meant to track link time and output size over time.
+
+use std::{fmt::Write as _, fs, time::Duration};
+
+use crate::{
+    explain::{self, SymbolExplanation},
+    stack::MAX_STACK_SIZE,
+};
+
+/// Statistics about a link.
+#[derive(Clone, Debug, Default)]
+pub struct LinkerStats {
+    /// Number of modules linked, counting every archive member separately.
+    pub input_modules: usize,
+    /// Number of functions defined in the linked module before internalization.
+    pub functions_before_internalize: usize,
+    /// Number of defined functions left with external linkage by internalization.
+    pub functions_exported: usize,
+    /// Number of functions defined in the module after optimization.
+    pub functions_after_optimize: usize,
+    /// Time spent optimizing, including internalization and debug info sanitization.
+    pub optimize_time: Duration,
+    /// Time spent generating the output.
+    pub codegen_time: Duration,
+    /// Name and size of the sections of the output. Only collected for object file output.
+    pub section_sizes: Vec<(String, u64)>,
+    /// Time spent in each stage of the link, in the order the stages ran.
+    pub stage_times: Vec<(&'static str, Duration)>,
+    /// Peak resident set size of the process during each stage of the link, in bytes, in the
+    /// order the stages ran. Only collected on Linux, when
+    /// [`LinkerOptions::measure_stage_memory`](crate::LinkerOptions::measure_stage_memory) is set.
+    pub stage_peak_rss: Vec<(&'static str, u64)>,
+    /// Bytes of stack used by each function of the output, sorted by name. Only collected when
+    /// [`LinkerOptions::stack_usage`](crate::LinkerOptions::stack_usage) is set.
+    pub stack_usage: Vec<(String, u64)>,
+    /// Functions removed by optimization, because nothing referenced them or because they were
+    /// inlined into every caller, with their number of IR instructions, sorted by name. Only
+    /// collected when
+    /// [`LinkerOptions::removed_functions`](crate::LinkerOptions::removed_functions) is set.
+    pub removed_functions: Vec<(String, usize)>,
+    /// Maps removed because nothing referenced them, sorted by name. Only collected when
+    /// [`LinkerOptions::gc_maps`](crate::LinkerOptions::gc_maps) is set.
+    pub removed_maps: Vec<String>,
+    /// Functions instrumented for profiling, the id of each function being its index. Only
+    /// collected when
+    /// [`LinkerOptions::instrument_functions`](crate::LinkerOptions::instrument_functions) is set.
+    pub instrumented_functions: Vec<String>,
+    /// Exported names which no input defines, sorted.
+    pub unmatched_exports: Vec<String>,
+    /// What internalization and optimization did to the symbols of
+    /// [`LinkerOptions::why_internalized`](crate::LinkerOptions::why_internalized), in the same
+    /// order.
+    pub symbol_explanations: Vec<SymbolExplanation>,
+}
+
+impl LinkerStats {
+    /// Number of functions removed by optimizations, mostly by dead code elimination.
+    pub fn functions_removed(&self) -> usize {
+        self.functions_before_internalize
+            .saturating_sub(self.functions_after_optimize)
+    }
+
+    /// Renders the statistics as a single line JSON object.
+    pub fn to_json(&self) -> String {
+        let Self {
+            input_modules,
+            functions_before_internalize,
+            functions_exported,
+            functions_after_optimize,
+            optimize_time,
+            codegen_time,
+            section_sizes,
+            stage_times,
+            stage_peak_rss,
+            stack_usage,
+            removed_functions,
+            removed_maps,
+            instrumented_functions,
+            unmatched_exports,
+            symbol_explanations: _,
+        } = self;
+        let mut json = format!(
+            "{{\"input_modules\":{input_modules},\"functions\":{{\
+             \"before_internalize\":{functions_before_internalize},\
+             \"exported\":{functions_exported},\
+             \"after_optimize\":{functions_after_optimize},\
+             \"removed\":{}}},\
+             \"optimize_time_ms\":{},\"codegen_time_ms\":{},\"sections\":[",
+            self.functions_removed(),
+            optimize_time.as_secs_f64() * 1000.0,
+            codegen_time.as_secs_f64() * 1000.0,
+        );
+        for (i, (name, size)) in section_sizes.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            push_json_string(&mut json, name);
+            write!(json, ",\"size\":{size}}}").unwrap();
+        }
+        json.push_str("],\"stages\":[");
+        for (i, (name, time)) in stage_times.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            push_json_string(&mut json, name);
+            write!(json, ",\"time_ms\":{}}}", time.as_secs_f64() * 1000.0).unwrap();
+        }
+        json.push_str("],\"stage_peak_rss\":[");
+        for (i, (name, bytes)) in stage_peak_rss.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            push_json_string(&mut json, name);
+            write!(json, ",\"bytes\":{bytes}}}").unwrap();
+        }
+        json.push_str("],\"stack_usage\":[");
+        for (i, (name, size)) in stack_usage.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            push_json_string(&mut json, name);
+            write!(json, ",\"size\":{size}}}").unwrap();
+        }
+        json.push_str("],\"removed_functions\":[");
+        for (i, (name, instructions)) in removed_functions.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            push_json_string(&mut json, name);
+            write!(
+                json,
+                ",\"instructions\":{instructions},\"estimated_size\":{}}}",
+                estimated_size(*instructions)
+            )
+            .unwrap();
+        }
+        json.push_str("],\"removed_maps\":[");
+        for (i, name) in removed_maps.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_string(&mut json, name);
+        }
+        json.push_str("],\"instrumented_functions\":[");
+        for (i, name) in instrumented_functions.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_string(&mut json, name);
+        }
+        json.push_str("],\"unmatched_exports\":[");
+        for (i, name) in unmatched_exports.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_string(&mut json, name);
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Renders the time spent in each stage as a table, with the peak RSS of each stage when it
+    /// was measured.
+    pub fn timings_table(&self) -> String {
+        let memory =
+            !self.stage_peak_rss.is_empty() && self.stage_peak_rss.len() == self.stage_times.len();
+        let mut table = format!("{:<24}{:>12}", "stage", "time (ms)");
+        if memory {
+            write!(table, "{:>16}", "peak RSS (KiB)").unwrap();
+        }
+        table.push('\n');
+        for (i, (name, time)) in self.stage_times.iter().enumerate() {
+            write!(table, "{name:<24}{:>12.3}", time.as_secs_f64() * 1000.0).unwrap();
+            if memory {
+                write!(table, "{:>16}", self.stage_peak_rss[i].1 / 1024).unwrap();
+            }
+            table.push('\n');
+        }
+        let total: Duration = self.stage_times.iter().map(|(_, time)| *time).sum();
+        writeln!(
+            table,
+            "{:<24}{:>12.3}",
+            "total",
+            total.as_secs_f64() * 1000.0
+        )
+        .unwrap();
+        table
+    }
+
+    /// Renders the stack usage of each function as a table, marking the functions over the
+    /// verifier stack limit.
+    pub fn stack_usage_table(&self) -> String {
+        let mut table = format!("{:<48}{:>12}\n", "function", "stack (B)");
+        for (name, size) in &self.stack_usage {
+            let mark = if *size > MAX_STACK_SIZE {
+                "  (over the limit)"
+            } else {
+                ""
+            };
+            writeln!(table, "{name:<48}{size:>12}{mark}").unwrap();
+        }
+        table
+    }
+
+    /// Renders the functions removed by optimization as a table, with their number of IR
+    /// instructions and the size of the code they'd have been compiled to, estimated from it.
+    pub fn removed_functions_table(&self) -> String {
+        let mut table = format!(
+            "{:<48}{:>14}{:>16}\n",
+            "removed function", "instructions", "est. size (B)"
+        );
+        let mut total = 0;
+        for (name, instructions) in &self.removed_functions {
+            let size = estimated_size(*instructions);
+            writeln!(table, "{name:<48}{instructions:>14}{size:>16}").unwrap();
+            total += size;
+        }
+        writeln!(table, "{:<48}{:>14}{total:>16}", "total", "").unwrap();
+        table
+    }
+
+    /// Renders the explanations of what happened to the symbols of
+    /// [`LinkerOptions::why_internalized`](crate::LinkerOptions::why_internalized), one indented
+    /// step per line.
+    pub fn symbol_explanations_report(&self) -> String {
+        explain::report(&self.symbol_explanations)
+    }
+
+    /// Renders the size of the sections that make up a BPF object as a table: `.text`, the
+    /// program sections, the map sections, BTF and read-only data. Debug info, relocations and
+    /// symbol tables are left out.
+    pub fn section_sizes_table(&self) -> String {
+        let mut table = format!("{:<48}{:>12}\n", "section", "size (B)");
+        let mut total = 0;
+        for (name, size) in self
+            .section_sizes
+            .iter()
+            .filter(|(name, _)| is_bpf_section(name))
+        {
+            writeln!(table, "{name:<48}{size:>12}").unwrap();
+            total += size;
+        }
+        writeln!(table, "{:<48}{total:>12}", "total").unwrap();
+        table
+    }
+}
+
+/// Resets the peak resident set size which Linux keeps for the process, so that [`peak_rss`]
+/// returns the peak from now on. Returns false when it can't be reset, eg on other systems.
+pub(crate) fn reset_peak_rss() -> bool {
+    fs::write("/proc/self/clear_refs", "5").is_ok()
+}
+
+/// Returns the peak resident set size of the process in bytes, read from `/proc/self/status`.
+pub(crate) fn peak_rss() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+// Program sections are named after the program type, eg `xdp` or `kprobe/foo`, while the sections
+// added by the toolchain start with a dot.
+fn is_bpf_section(name: &str) -> bool {
+    match name {
+        ".text" | ".maps" | "maps" | ".BTF" | ".BTF.ext" => true,
+        "" | "license" | "version" => false,
+        name => name.starts_with(".rodata") || !name.starts_with('.'),
+    }
+}
+
+// Estimates the size of the code `instructions` IR instructions compile to, each taking about one
+// 8 byte BPF instruction.
+fn estimated_size(instructions: usize) -> u64 {
+    instructions as u64 * 8
+}
+
+fn push_json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
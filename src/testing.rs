@@ -0,0 +1,223 @@
+//! Helpers for writing linker integration tests, enabled by the `testing` feature.
+//!
+//! They let downstream crates link IR or bitcode held in memory and look at the BTF of the
+//! result, without spawning `bpf-linker` or setting up compiletest.
+//!
+//! ```no_run
+//! use bpf_linker::{testing, LinkerOptions};
+//!
+//! let object = testing::compile_ir(
+//!     r#"
+//!     target triple = "bpfel"
+//!     define i32 @prog(ptr %ctx) section "xdp" { ret i32 2 }
+//!     "#,
+//!     &["prog"],
+//! )?;
+//! let btf = testing::dump_btf(&object)?;
+//! # Ok::<(), bpf_linker::testing::TestingError>(())
+//! ```
+
+use std::{
+    env,
+    fmt::Write as _,
+    fs, io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use thiserror::Error;
+
+use crate::{
+    btf::{Btf, BtfError, BtfKind},
+    disasm, llvm,
+    output::with_context,
+    Linker, LinkerError, LinkerOptions, LinkerOptionsBuilder, LinkerOutput,
+};
+
+/// Error of the testing helpers.
+#[derive(Debug, Error)]
+pub enum TestingError {
+    /// The IR could not be parsed.
+    #[error("invalid IR: {0}")]
+    InvalidIr(String),
+    /// Linking failed.
+    #[error(transparent)]
+    Linker(#[from] LinkerError),
+    /// The output could not be read back.
+    #[error("error reading the output: {0}")]
+    Io(#[from] io::Error),
+    /// The output has no `.BTF` section.
+    #[error("the object has no .BTF section")]
+    MissingBtf,
+    /// The `.BTF` section of the output is invalid.
+    #[error(transparent)]
+    Btf(#[from] BtfError),
+}
+
+/// Parses the textual LLVM IR `ir` and returns it as bitcode, ready to be linked.
+pub fn ir_to_bitcode(ir: &str) -> Result<Vec<u8>, TestingError> {
+    with_context(|context| unsafe { llvm::ir_to_bitcode(context, ir) })
+        .map_err(TestingError::InvalidIr)
+}
+
+/// Links the in-memory inputs `inputs`, given as (name, bitcode or object file), with the options
+/// of `options`, and returns the contents of the output.
+pub fn link_buffers(
+    options: LinkerOptionsBuilder,
+    inputs: &[(&str, &[u8])],
+) -> Result<Vec<u8>, TestingError> {
+    static OUTPUTS: AtomicUsize = AtomicUsize::new(0);
+
+    let output = env::temp_dir().join(format!(
+        "bpf-linker-testing-{}-{}.o",
+        std::process::id(),
+        OUTPUTS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let options = inputs
+        .iter()
+        .fold(options, |options, (name, bytes)| {
+            options.input_buffer(*name, *bytes)
+        })
+        .output(&output)
+        .build()?;
+    let ret = Linker::new(options).and_then(|mut linker| linker.link());
+    let data = ret
+        .map_err(TestingError::from)
+        .and_then(|()| Ok(fs::read(&output)?));
+    let _: io::Result<()> = fs::remove_file(&output);
+    data
+}
+
+/// Compiles the textual LLVM IR `ir` to an object file, exporting the symbols `exports`. BTF is
+/// generated if the IR has debug info.
+pub fn compile_ir(ir: &str, exports: &[&str]) -> Result<Vec<u8>, TestingError> {
+    let bitcode = ir_to_bitcode(ir)?;
+    let options = exports
+        .iter()
+        .fold(LinkerOptions::builder().btf(true), |options, export| {
+            options.export(*export)
+        });
+    link_buffers(options, &[("input.ll", &bitcode)])
+}
+
+/// Returns the BTF of the object file `object` as text, one type per line with its members
+/// indented below it, eg:
+///
+/// ```text
+/// [1] INT 'int' size=4 bits_offset=0 nr_bits=32 encoding=0x1
+/// [2] STRUCT 'node' size=16 vlen=2
+///     'next' type_id=3 bits_offset=0
+///     'value' type_id=1 bits_offset=64
+/// ```
+pub fn dump_btf(object: &[u8]) -> Result<String, TestingError> {
+    let data = LinkerOutput::new(object.to_vec())
+        .section(".BTF")?
+        .ok_or(TestingError::MissingBtf)?;
+    Ok(format_btf(&Btf::parse(&data)?))
+}
+
+/// Returns the disassembly of the code sections of the object file `object`, annotated with the
+/// BTF functions and source lines of the instructions, as `--emit disasm` writes it.
+pub fn disassemble(object: &[u8]) -> Result<String, TestingError> {
+    Ok(disasm::disassemble(object)?)
+}
+
+fn format_btf(btf: &Btf) -> String {
+    let mut out = String::new();
+    let name = |off: u32| match btf.string(off) {
+        "" => "(anon)".to_owned(),
+        name => format!("'{name}'"),
+    };
+    for (id, ty) in btf.types.iter().enumerate().skip(1) {
+        // FuncProto is FUNC_PROTO
+        let kind: String = format!("{:?}", ty.kind)
+            .chars()
+            .enumerate()
+            .flat_map(|(i, c)| {
+                (i > 0 && c.is_uppercase())
+                    .then_some('_')
+                    .into_iter()
+                    .chain(c.to_uppercase())
+            })
+            .collect();
+        let _: std::fmt::Result = write!(out, "[{id}] {kind} {}", name(ty.name_off));
+        let size_or_type = if ty.refers_to_type() {
+            format!("type_id={}", ty.size_or_type)
+        } else {
+            format!("size={}", ty.size_or_type)
+        };
+        let line = match ty.kind {
+            BtfKind::Int => format!(
+                " {size_or_type} bits_offset={} nr_bits={} encoding={:#x}",
+                (ty.extra[0] >> 16) & 0xff,
+                ty.extra[0] & 0xff,
+                (ty.extra[0] >> 24) & 0xf
+            ),
+            BtfKind::Array => format!(
+                " type_id={} index_type_id={} nr_elems={}",
+                ty.extra[0], ty.extra[1], ty.extra[2]
+            ),
+            BtfKind::Fwd => String::new(),
+            BtfKind::Var | BtfKind::DeclTag => format!(" {size_or_type} {:#x}", ty.extra[0]),
+            BtfKind::Struct
+            | BtfKind::Union
+            | BtfKind::Enum
+            | BtfKind::Enum64
+            | BtfKind::FuncProto
+            | BtfKind::Datasec => format!(" {size_or_type} vlen={}", ty.vlen),
+            _ => format!(" {size_or_type}"),
+        };
+        out.push_str(&line);
+        out.push('\n');
+        let fields = match ty.kind {
+            BtfKind::Struct | BtfKind::Union | BtfKind::Enum64 | BtfKind::Datasec => 3,
+            BtfKind::Enum | BtfKind::FuncProto => 2,
+            _ => continue,
+        };
+        for member in ty.extra.chunks_exact(fields) {
+            let line = match ty.kind {
+                BtfKind::Struct | BtfKind::Union => format!(
+                    "{} type_id={} bits_offset={}",
+                    name(member[0]),
+                    member[1],
+                    member[2]
+                ),
+                BtfKind::Enum => format!("{} val={}", name(member[0]), member[1] as i32),
+                BtfKind::Enum64 => format!(
+                    "{} val={}",
+                    name(member[0]),
+                    (u64::from(member[2]) << 32) | u64::from(member[1])
+                ),
+                BtfKind::FuncProto => format!("{} type_id={}", name(member[0]), member[1]),
+                _ => format!(
+                    "type_id={} offset={} size={}",
+                    member[0], member[1], member[2]
+                ),
+            };
+            let _: std::fmt::Result = writeln!(out, "    {line}");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btf::tests::{btf_bytes, info};
+
+    #[test]
+    fn test_format_btf() {
+        let data = btf_bytes(
+            &[
+                &[1, info(1, 0), 4, (1 << 24) | 32],
+                &[5, info(4, 1), 4, 7, 1, 0],
+                &[0, info(2, 0), 2],
+            ],
+            b"\0int\0s\0a\0",
+        );
+        assert_eq!(
+            format_btf(&Btf::parse(&data).unwrap()),
+            "[1] INT 'int' size=4 bits_offset=0 nr_bits=32 encoding=0x1\n[2] STRUCT 's' size=4 \
+             vlen=1\n    'a' type_id=1 bits_offset=0\n[3] PTR (anon) type_id=2\n"
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Parsing of GNU thin archives.
+//!
+//! Thin archives have the same layout as regular archives, but their members only carry a header
+//! and refer to files on disk, by path relative to the archive. Only the symbol table and the
+//! long name table have their contents stored in the archive.
+
+use std::{io, str};
+
+pub(crate) const MAGIC: &[u8; 8] = b"!<thin>\n";
+
+const HEADER_SIZE: usize = 60;
+
+/// Returns the paths of the members of the thin archive in `data`, as stored in the archive.
+pub(crate) fn members(data: &[u8]) -> io::Result<Vec<String>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    let mut data = data
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| invalid("not a thin archive"))?;
+    let mut long_names: &[u8] = &[];
+    let mut members = Vec::new();
+    while !data.is_empty() {
+        if data.len() < HEADER_SIZE {
+            return Err(invalid("truncated member header"));
+        }
+        let (header, rest) = data.split_at(HEADER_SIZE);
+        data = rest;
+        if &header[58..60] != b"`\n" {
+            return Err(invalid("invalid member header"));
+        }
+        let name = str::from_utf8(&header[..16])
+            .map_err(|_| invalid("invalid member name"))?
+            .trim_end();
+        let size: usize = str::from_utf8(&header[48..58])
+            .ok()
+            .and_then(|size| size.trim_end().parse().ok())
+            .ok_or_else(|| invalid("invalid member size"))?;
+
+        match name {
+            // the symbol table and the long name table are the only members stored in the archive
+            "/" | "/SYM64/" | "//" => {
+                let padded = size + size % 2;
+                if data.len() < size {
+                    return Err(invalid("truncated member"));
+                }
+                if name == "//" {
+                    long_names = &data[..size];
+                }
+                data = &data[padded.min(data.len())..];
+            }
+            name => {
+                let name = match name.strip_prefix('/') {
+                    Some(offset) => {
+                        let offset: usize =
+                            offset.parse().map_err(|_| invalid("invalid long name"))?;
+                        let long_name = long_names
+                            .get(offset..)
+                            .ok_or_else(|| invalid("invalid long name offset"))?;
+                        let end = long_name
+                            .windows(2)
+                            .position(|w| w == b"/\n")
+                            .unwrap_or(long_name.len());
+                        str::from_utf8(&long_name[..end])
+                            .map_err(|_| invalid("invalid member name"))?
+                    }
+                    None => name.strip_suffix('/').unwrap_or(name),
+                };
+                members.push(name.to_owned());
+            }
+        }
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, size: usize) -> String {
+        format!("{name:<16}{:<12}{:<6}{:<6}{:<8}{size:<10}`\n", 0, 0, 0, 644)
+    }
+
+    #[test]
+    fn test_members() {
+        let long_names = "a_rather_long_member_name.o/\nsub/other_long_name.o/\n";
+        let archive = [
+            "!<thin>\n".to_owned(),
+            header("/", 4),
+            "\0\0\0\0".to_owned(),
+            header("//", long_names.len()),
+            long_names.to_owned(),
+            header("short.o/", 1234),
+            header("/0", 5678),
+            header("/29", 42),
+        ]
+        .concat();
+        assert_eq!(
+            members(archive.as_bytes()).unwrap(),
+            [
+                "short.o",
+                "a_rather_long_member_name.o",
+                "sub/other_long_name.o"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_members_invalid() {
+        assert!(members(b"!<arch>\n").is_err());
+        let archive = ["!<thin>\n".to_owned(), header("/99", 1)].concat();
+        assert!(members(archive.as_bytes()).is_err());
+        let archive = format!("!<thin>\n{}", &header("short.o/", 1)[..30]);
+        assert!(members(archive.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Link-time policy checks supplied by users as [Rhai](https://rhai.rs) scripts.
+//!
+//! The script runs after optimization, right before code generation, and gets the following
+//! variables in scope:
+//!
+//! - `functions`: array of maps with `name`, `section`, `global`, `declaration` and `params` (the
+//!   IR types of the parameters) keys.
+//! - `globals`: array of maps with `name`, `section`, `global` and `declaration` keys.
+//!
+//! Violations are reported by calling `fail(message)`. The link fails if any were reported, eg:
+//!
+//! ```rhai
+//! for f in functions {
+//!     if f.section.starts_with("xdp") && f.params.len() != 1 {
+//!         fail(`${f.name}: xdp programs take exactly one argument`);
+//!     }
+//! }
+//! ```
+//!
+//! With the `aya-obj` feature, the emitted object can also be checked by parsing it like the aya
+//! loader does, see `check_aya_obj`.
+
+use std::{cell::RefCell, fs, path::Path, rc::Rc};
+
+use rhai::{Array, Dynamic, Engine, Map, Scope};
+
+use crate::{
+    llvm::{Symbol, SymbolKind},
+    LinkerError,
+};
+
+pub(crate) fn run_script(path: &Path, symbols: &[Symbol]) -> Result<(), LinkerError> {
+    let script = fs::read_to_string(path).map_err(|e| LinkerError::IoError(path.to_owned(), e))?;
+
+    let violations = Rc::new(RefCell::new(Vec::new()));
+    let mut engine = Engine::new();
+    let _: &mut Engine = engine.register_fn("fail", {
+        let violations = Rc::clone(&violations);
+        move |message: &str| violations.borrow_mut().push(message.to_owned())
+    });
+
+    let mut functions = Array::new();
+    let mut globals = Array::new();
+    for symbol in symbols {
+        let mut map = Map::from_iter([
+            ("name".into(), Dynamic::from(symbol.name.clone())),
+            (
+                "section".into(),
+                Dynamic::from(symbol.section.clone().unwrap_or_default()),
+            ),
+            ("global".into(), Dynamic::from(symbol.global)),
+            ("declaration".into(), Dynamic::from(symbol.declaration)),
+        ]);
+        match symbol.kind {
+            SymbolKind::Function => {
+                let params: Array = symbol
+                    .param_types
+                    .iter()
+                    .cloned()
+                    .map(Dynamic::from)
+                    .collect();
+                map.extend([("params".into(), Dynamic::from(params))]);
+                functions.push(map.into());
+            }
+            SymbolKind::Global => globals.push(map.into()),
+        }
+    }
+
+    let mut scope = Scope::new();
+    let _: &mut Scope = scope.push("functions", functions).push("globals", globals);
+
+    engine
+        .run_with_scope(&mut scope, &script)
+        .map_err(|e| LinkerError::ValidationScriptError(path.to_owned(), e.to_string()))?;
+
+    let violations = violations.take();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(LinkerError::ValidationFailed(violations))
+    }
+}
+
+/// Parses `object` like aya loads it, returning the parse error if aya couldn't load it.
+#[cfg(feature = "aya-obj")]
+pub(crate) fn check_aya_obj(object: &[u8]) -> Result<Option<String>, LinkerError> {
+    Ok(aya_obj::Object::parse(object).err().map(|e| {
+        // the source carries the details, eg which section is invalid
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(e) = source {
+            message.push_str(&format!(": {e}"));
+            source = e.source();
+        }
+        message
+    }))
+}
+
+#[cfg(not(feature = "aya-obj"))]
+pub(crate) fn check_aya_obj(_object: &[u8]) -> Result<Option<String>, LinkerError> {
+    Err(LinkerError::AyaObjError(
+        "bpf-linker was built without the `aya-obj` feature".to_owned(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Vec<Symbol> {
+        vec![
+            Symbol {
+                name: "prog".to_owned(),
+                kind: SymbolKind::Function,
+                section: Some("xdp".to_owned()),
+                global: true,
+                declaration: false,
+                param_types: vec!["ptr".to_owned(), "i32".to_owned()],
+            },
+            Symbol {
+                name: "COUNTERS".to_owned(),
+                kind: SymbolKind::Global,
+                section: Some(".maps".to_owned()),
+                global: true,
+                declaration: false,
+                param_types: Vec::new(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_run_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |script: &str| {
+            let path = dir.path().join("policy.rhai");
+            fs::write(&path, script).unwrap();
+            run_script(&path, &symbols())
+        };
+
+        run(r#"
+for f in functions {
+    if f.section.starts_with("xdp") && f.params.len() != 2 {
+        fail(`${f.name}: wrong arguments`);
+    }
+}
+"#)
+        .unwrap();
+        assert!(matches!(
+            run(r#"
+for f in functions {
+    if f.section.starts_with("xdp") && f.params.len() != 1 {
+        fail(`${f.name}: xdp programs take exactly one argument`);
+    }
+}
+for g in globals {
+    if g.global && g.section == ".maps" {
+        fail(`${g.name} is a global map`);
+    }
+}
+"#),
+            Err(LinkerError::ValidationFailed(violations)) if violations == [
+                "prog: xdp programs take exactly one argument",
+                "COUNTERS is a global map",
+            ]
+        ));
+        assert!(matches!(
+            run("fail("),
+            Err(LinkerError::ValidationScriptError(..))
+        ));
+    }
+
+    #[cfg(feature = "aya-obj")]
+    #[test]
+    fn test_check_aya_obj() {
+        assert!(check_aya_obj(b"not an object").unwrap().is_some());
+    }
+}
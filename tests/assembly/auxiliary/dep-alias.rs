@@ -0,0 +1,19 @@
+// no-prefer-dynamic
+// compile-flags: --crate-type rlib -C opt-level=2 -Z merge-functions=aliases
+#![no_std]
+
+// The bodies are the same, so merging functions turns `prog` into an alias of the mangled
+// `helper`. Only `prog` is exported from the crates linking this one.
+
+#[inline(never)]
+#[link_section = "xdp"]
+pub extern "C" fn helper(ctx: *const u32) -> u32 {
+    unsafe { *ctx }
+}
+
+#[inline(never)]
+#[export_name = "prog"]
+#[link_section = "xdp"]
+pub extern "C" fn prog(ctx: *const u32) -> u32 {
+    unsafe { *ctx }
+}
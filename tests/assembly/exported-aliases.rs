@@ -0,0 +1,16 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib
+//
+// Exporting an alias keeps its aliasee, even when it isn't exported itself: otherwise it's
+// internalized and optimized away, leaving the alias undefined.
+#![no_std]
+
+// aux-build: loop-panic-handler.rs
+extern crate loop_panic_handler;
+
+// aux-build: dep-alias.rs
+extern crate dep_alias;
+
+// CHECK: 6helper:
+// CHECK: .globl prog
+// CHECK: .set prog, {{.*}}6helper
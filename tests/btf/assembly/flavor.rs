@@ -0,0 +1,30 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-arg=--emit=obj -C link-arg=--btf -C debuginfo=2
+
+#![no_std]
+#![allow(non_camel_case_types)]
+
+pub struct task_struct {
+    pub pid: u32,
+}
+
+// A shadow of `task_struct` for older kernels, with its own layout.
+pub struct task_struct___older {
+    pub pid: u32,
+    pub tgid: u32,
+}
+
+#[no_mangle]
+static TASK: task_struct = task_struct { pid: 0 };
+
+#[no_mangle]
+static OLDER_TASK: task_struct___older = task_struct___older { pid: 0, tgid: 0 };
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// CHECK: <STRUCT> 'task_struct' sz:4 n:1
+// CHECK: <STRUCT> 'task_struct' sz:8 n:2
+// CHECK-NOT: task_struct___older
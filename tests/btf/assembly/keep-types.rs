@@ -0,0 +1,31 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-arg=--emit=obj -C link-arg=--btf -C link-arg=--btf-keep-type=*::Kept -C debuginfo=2
+
+#![no_std]
+
+pub struct Kept {
+    pub a: u32,
+    pub b: u64,
+}
+
+pub struct Dropped {
+    pub c: u32,
+}
+
+// Not exported, so removed by optimization along with the last references to both types.
+pub fn uses_types(kept: &Kept, dropped: &Dropped) -> u64 {
+    kept.a as u64 + kept.b + dropped.c as u64
+}
+
+#[no_mangle]
+pub static VALUE: u32 = 0;
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// CHECK-NOT: 'Dropped'
+// CHECK: <STRUCT> 'Kept' sz:16 n:2
+// CHECK-NOT: 'Dropped'
+// CHECK-NOT: __bpf_linker_btf_keep
@@ -0,0 +1,75 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-arg=--emit=obj -C link-arg=--btf -C debuginfo=2
+
+#![no_std]
+
+use core::marker::PhantomData;
+
+#[repr(C)]
+pub struct Layout {
+    pub header: Header,
+    pub packed: Packed,
+    pub outer: Outer,
+}
+
+// Zero-sized members take no space in BTF.
+#[repr(C)]
+pub struct Header {
+    pub marker: PhantomData<u64>,
+    pub unit: (),
+    pub len: u32,
+    pub flags: u8,
+}
+
+// Members of packed structs only need to be byte aligned.
+#[repr(C, packed)]
+pub struct Packed {
+    pub tag: u8,
+    pub value: u32,
+}
+
+// Members aligned past what BPF can access stay at their offset.
+#[repr(C)]
+pub struct Outer {
+    pub a: u32,
+    pub wide: Wide,
+}
+
+#[repr(C, align(16))]
+pub struct Wide {
+    pub x: u64,
+}
+
+#[no_mangle]
+static LAYOUT: Layout = Layout {
+    header: Header {
+        marker: PhantomData,
+        unit: (),
+        len: 0,
+        flags: 0,
+    },
+    packed: Packed { tag: 0, value: 0 },
+    outer: Outer {
+        a: 0,
+        wide: Wide { x: 0 },
+    },
+};
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// CHECK: <STRUCT> 'Layout' sz:48 n:3
+// CHECK-NEXT: 'header' off:0
+// CHECK-NEXT: 'packed' off:64
+// CHECK-NEXT: 'outer' off:128
+// CHECK: <STRUCT> 'Header' sz:8 n:2
+// CHECK-NEXT: 'len' off:0
+// CHECK-NEXT: 'flags' off:32
+// CHECK: <STRUCT> 'Packed' sz:5 n:2
+// CHECK-NEXT: 'tag' off:0
+// CHECK-NEXT: 'value' off:8
+// CHECK: <STRUCT> 'Outer' sz:32 n:2
+// CHECK-NEXT: 'a' off:0
+// CHECK-NEXT: 'wide' off:128
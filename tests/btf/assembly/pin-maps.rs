@@ -0,0 +1,28 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-arg=--emit=obj -C link-arg=--btf -C link-arg=--pin-maps=EVENTS -C debuginfo=2
+
+#![no_std]
+
+// A map definition as libbpf declares them, eg `__uint(type, BPF_MAP_TYPE_RINGBUF)`.
+#[repr(C)]
+pub struct RingBufDef {
+    pub r#type: *const [i32; 27],
+    pub max_entries: *const [i32; 4096],
+}
+
+unsafe impl Sync for RingBufDef {}
+
+#[no_mangle]
+#[link_section = ".maps"]
+static EVENTS: RingBufDef = RingBufDef {
+    r#type: core::ptr::null(),
+    max_entries: core::ptr::null(),
+};
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// CHECK: <STRUCT> 'RingBufDef' sz:24 n:3
+// CHECK: 'pinning' off:128
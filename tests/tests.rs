@@ -146,3 +146,40 @@ fn compile_test() {
         }),
     );
 }
+
+#[test]
+fn check_llvm_options() {
+    use bpf_linker::{Cpu, LinkerError, LinkerOptions, LinkerOptionsBuilder};
+
+    let exe = Path::new(env!("CARGO_BIN_EXE_bpf-linker"));
+    let check = |builder: LinkerOptionsBuilder| {
+        let options = builder
+            .input("prog.o")
+            .output("prog.bpf.o")
+            .build()
+            .unwrap();
+        bpf_linker::check_llvm_options(exe, &options)
+    };
+
+    check(
+        LinkerOptions::builder()
+            .llvm_arg("--unroll-threshold=5")
+            .feature("+alu32"),
+    )
+    .unwrap();
+    match check(LinkerOptions::builder().llvm_arg("--no-such-option")) {
+        Err(LinkerError::InvalidLlvmArg(arg)) => assert_eq!(arg, "--no-such-option"),
+        ret => panic!("unexpected {ret:?}"),
+    }
+    match check(LinkerOptions::builder().llvm_arg("--unroll-threshold=many")) {
+        Err(LinkerError::InvalidLlvmArg(arg)) => assert_eq!(arg, "--unroll-threshold=many"),
+        ret => panic!("unexpected {ret:?}"),
+    }
+    match check(LinkerOptions::builder().feature("+no-such-feature")) {
+        Err(LinkerError::InvalidCpuFeature(feature)) => assert_eq!(feature, "+no-such-feature"),
+        ret => panic!("unexpected {ret:?}"),
+    }
+
+    let features = bpf_linker::cpu_features(exe, Cpu::Generic).unwrap();
+    assert!(features.iter().any(|feature| feature.name == "alu32"));
+}
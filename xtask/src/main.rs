@@ -0,0 +1,41 @@
+//! Development tasks, run with `cargo xtask <task>`:
+//!
+//! - `bench [args]`: links the benchmark corpora and reports the time and peak RSS of each stage,
+//!   see `benches/link.rs` for the arguments.
+
+use std::{
+    env,
+    ffi::OsString,
+    process::{Command, ExitCode},
+};
+
+const USAGE: &str = "usage: cargo xtask bench [args]";
+
+fn main() -> ExitCode {
+    let mut args = env::args_os().skip(1);
+    let Some(task) = args.next() else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    match task.to_str() {
+        Some("bench") => {
+            let cargo = env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"));
+            let mut bench = Command::new(cargo);
+            let _: &mut Command = bench
+                .args(["bench", "--package", "bpf-linker", "--bench", "link", "--"])
+                .args(args);
+            match bench.status() {
+                Ok(status) if status.success() => ExitCode::SUCCESS,
+                Ok(_) => ExitCode::FAILURE,
+                Err(err) => {
+                    eprintln!("failed to run {bench:?}: {err}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        _ => {
+            eprintln!("unknown task {task:?}\n{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}